@@ -0,0 +1,69 @@
+// ロガーのスレッドからサーバの接続 (/stream) へデータを配信する仕組み
+//
+// 購読者毎に長さに上限のある待ち行列を持ち、
+// 受け取りが追いつかない購読者や切断された購読者は配信時に取り除きます
+
+use std::sync::{ mpsc, Arc, Mutex, };
+
+use super::error;
+use super::log_cache;
+
+/// 1つの送り手から複数の購読者へ同じデータを配信します
+pub struct Broadcaster<T> {
+    subscribers: Mutex<Vec<mpsc::SyncSender<T>>>,
+    max_subscribers: usize,
+}
+impl<T: Clone> Broadcaster<T> {
+    pub fn new(max_subscribers: usize) -> Self {
+        Broadcaster {
+            subscribers: Mutex::new(Vec::new()),
+            max_subscribers,
+        }
+    }
+
+    /// 最大 queue_length 個のデータを溜められる購読者を追加します
+    /// 購読者数が上限に達していればエラーを返します
+    pub fn subscribe(
+        &self,
+        queue_length: usize,
+    ) -> Result<mpsc::Receiver<T>, error::Error> {
+        let mut subscribers = self.subscribers.lock()?;
+        if subscribers.len() >= self.max_subscribers {
+            return Err("too many subscribers".into());
+        }
+        let (sender, receiver) = mpsc::sync_channel(queue_length);
+        subscribers.push(sender);
+        Ok(receiver)
+    }
+
+    /// 全ての購読者にデータを送ります
+    ///
+    /// 待ち行列が一杯の購読者は遅すぎるものとして取り除きます
+    /// (Receiver からは切断として見えます)
+    /// 戻り値は送れた購読者の数です
+    pub fn publish(&self, message: T) -> usize {
+        let Ok(mut subscribers) = self.subscribers.lock() else {
+            return 0;
+        };
+        subscribers.retain(|sender| match sender.try_send(message.clone()) {
+            Ok(()) => true,
+            Err(mpsc::TrySendError::Full(_)) => {
+                eprintln!("dropping a subscriber which cannot keep up");
+                false
+            },
+            Err(mpsc::TrySendError::Disconnected(_)) => false,
+        });
+        subscribers.len()
+    }
+
+    /// 現在の購読者数 (切断済みでもまだ配信していなければ含みます)
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().map(|s| s.len()).unwrap_or(0)
+    }
+}
+
+pub type SharedUsageBroadcaster = Arc<Broadcaster<Arc<log_cache::TickUsages>>>;
+/// 最大 max_subscribers 人に tick 毎の使用状況を配信する Broadcaster を作成します
+pub fn create_shared_broadcaster(max_subscribers: usize) -> SharedUsageBroadcaster {
+    Arc::new(Broadcaster::new(max_subscribers))
+}
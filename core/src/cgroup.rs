@@ -0,0 +1,214 @@
+// cgroup v2 のファイルから直接コンテナの使用状況を読み取ります
+//
+// Docker API の stats と同じ Stats 構造体を返すので、
+// 使用率の計算やログの形式は collector.backend によらず共通です
+// cgroup_root と proc_root を差し替えれば一時ディレクトリ上の
+// 偽の sysfs/procfs でも動作します
+
+use std::collections::{ BTreeMap, HashMap, };
+use std::path::{ Path, PathBuf };
+
+use super::error;
+use super::host;
+use super::log::{ CpuStats, IoStats, MemoryBreakdown, MemoryStats, NetStats, PidsStats, Stats };
+use super::time;
+
+/// /proc/stat の1 tick (USER_HZ) のミリ秒
+/// (Linux ではアーキテクチャによらず 100Hz です)
+pub const MILLIS_PER_USER_HZ: u64 = 10;
+
+/// ホスト全体の値 (コンテナ毎の Stats に共通で設定します)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostStats {
+    /// 全CPUの累積時間 (ミリ秒)、Docker API の system_cpu_usage に相当します
+    pub system: Option<u64>,
+    pub ncpu: Option<u8>,
+    /// 物理メモリ量 (バイト)、メモリ制限が無いコンテナの上限に使います
+    pub memory_total: Option<u64>,
+}
+
+/// "key value" 形式の行が並ぶファイル (cpu.stat, memory.stat, memory.events) を読み取ります
+pub fn parse_flat_keyed(text: &str) -> HashMap<&str, u64> {
+    text.lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(' ')?;
+            Some((key, value.trim().parse::<u64>().ok()?))
+        })
+        .collect()
+}
+
+/// 単一の値のファイル (memory.current, memory.max, memory.swap.current) を読み取ります
+/// "max" (制限無し) や読み取れない場合は None を返します
+pub fn parse_single_value(text: &str) -> Option<u64> {
+    text.trim().parse::<u64>().ok()
+}
+
+/// io.stat からデバイス ("major:minor") 毎の累積値を読み取ります
+/// "8:0 rbytes=1 wbytes=2 rios=3 wios=4 dbytes=0 dios=0"
+pub fn parse_io_stat(text: &str) -> BTreeMap<String, IoStats> {
+    let mut devices = BTreeMap::<String, IoStats>::new();
+    for line in text.lines() {
+        let mut fields = line.split_whitespace();
+        let Some(device) = fields.next() else { continue };
+        let device = devices.entry(device.to_string()).or_default();
+        for field in fields {
+            let Some((key, value)) = field.split_once('=') else { continue };
+            let Ok(value) = value.parse::<u64>() else { continue };
+            let field = match key {
+                "rbytes" => &mut device.read,
+                "wbytes" => &mut device.write,
+                "rios" => &mut device.read_ops,
+                "wios" => &mut device.write_ops,
+                _ => continue,
+            };
+            *field.get_or_insert(0) += value;
+        }
+    }
+    devices
+}
+
+/// /proc/<pid>/net/dev からループバック以外のインターフェース毎の累積値を読み取ります
+pub fn parse_net_dev(text: &str) -> BTreeMap<String, NetStats> {
+    // 先頭2行はヘッダ
+    text.lines().skip(2).filter_map(|line| {
+        let (name, counters) = line.split_once(':')?;
+        let name = name.trim();
+        if name == "lo" {
+            return None;
+        }
+        // 受信: bytes packets errs drop fifo frame compressed multicast
+        // 送信: bytes packets errs drop fifo colls carrier compressed
+        let counters = counters.split_whitespace()
+            .map(|c| c.parse::<u64>().ok())
+            .collect::<Vec<Option<u64>>>();
+        let counter = |i: usize| counters.get(i).copied().flatten();
+        Some((name.to_string(), NetStats {
+            recv: counter(0),
+            recv_packets: counter(1),
+            recv_errors: counter(2),
+            recv_dropped: counter(3),
+            send: counter(8),
+            send_packets: counter(9),
+            send_errors: counter(10),
+            send_dropped: counter(11),
+            ..Default::default()
+        }))
+    }).collect()
+}
+
+/// /proc/stat と /proc/meminfo からホスト全体の値を読み取ります
+/// (ホストの使用状況 (host::read_stats) と同じく読み取ります)
+pub fn read_host_stats(proc_root: &Path) -> Result<HostStats, error::Error> {
+    let cpu = host::parse_stat(&std::fs::read_to_string(proc_root.join("stat"))?);
+    let memory = host::parse_meminfo(&std::fs::read_to_string(proc_root.join("meminfo"))?);
+
+    Ok(HostStats {
+        system: cpu.system,
+        ncpu: cpu.ncpu,
+        memory_total: memory.available,
+    })
+}
+
+/// コンテナの cgroup ディレクトリを探します
+///
+/// /proc/<pid>/cgroup ("0::/system.slice/docker-<id>.scope") を優先し、
+/// 読めなければ systemd と cgroupfs の各 cgroup ドライバの既定の場所を探します
+pub fn find_cgroup_dir(
+    cgroup_root: &Path,
+    proc_root: &Path,
+    container_id: &str,
+    pid: Option<u32>,
+) -> Option<PathBuf> {
+    let from_proc = pid
+        .and_then(|pid| std::fs::read_to_string(
+            proc_root.join(pid.to_string()).join("cgroup")
+        ).ok())
+        .and_then(|text| text.lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .map(|path| cgroup_root.join(path.trim_start_matches('/')))
+        );
+    from_proc.into_iter()
+        .chain([
+            cgroup_root.join("system.slice").join(format!("docker-{}.scope", container_id)),
+            cgroup_root.join("docker").join(container_id),
+        ])
+        .find(|dir| dir.join("cpu.stat").is_file())
+}
+
+/// cgroup ディレクトリと /proc/<pid>/net/dev から使用状況を読み取ります
+///
+/// 読めないファイルの値は None になりますが、
+/// cpu.stat が読めない場合はコンテナが無いものとしてエラーを返します
+pub fn read_stats(
+    cgroup_dir: &Path,
+    proc_root: &Path,
+    pid: Option<u32>,
+    host: &HostStats,
+) -> Result<Stats, error::Error> {
+    let read = |name: &str| std::fs::read_to_string(cgroup_dir.join(name)).ok();
+
+    let cpu_stat = std::fs::read_to_string(cgroup_dir.join("cpu.stat"))?;
+    let cpu_stat = parse_flat_keyed(&cpu_stat);
+    let usec_to_ms = |key: &str| cpu_stat.get(key).map(|usec| usec / 1000);
+    // cgroup v2 にはCPU毎の値がありません
+    let cpu = CpuStats {
+        total: usec_to_ms("usage_usec"),
+        system: host.system,
+        ncpu: host.ncpu,
+        user: usec_to_ms("user_usec"),
+        kernel: usec_to_ms("system_usec"),
+        periods: cpu_stat.get("nr_periods").copied(),
+        throttled_periods: cpu_stat.get("nr_throttled").copied(),
+        throttled_time: usec_to_ms("throttled_usec"),
+        percpu: Vec::new(),
+    };
+
+    // Docker CLI と同様に、回収可能な inactive_file を除いたものを使用量とします
+    let memory_stat = read("memory.stat").unwrap_or_default();
+    let memory_stat = parse_flat_keyed(&memory_stat);
+    let inactive_file = memory_stat.get("inactive_file")
+        .copied()
+        .unwrap_or(0);
+    let used = read("memory.current")
+        .and_then(|text| parse_single_value(&text))
+        .map(|current| current.saturating_sub(inactive_file));
+    let available = read("memory.max")
+        .and_then(|text| parse_single_value(&text))
+        .or(host.memory_total);
+    let breakdown = MemoryBreakdown {
+        swap: read("memory.swap.current")
+            .and_then(|text| parse_single_value(&text)),
+        failcnt: read("memory.events")
+            .and_then(|text| parse_flat_keyed(&text).get("max").copied()),
+        ..MemoryBreakdown::from_memory_stat(|key| memory_stat.get(key).copied())
+    };
+
+    let io = read("io.stat")
+        .map(|text| parse_io_stat(&text))
+        .unwrap_or_default();
+
+    let net = pid
+        .and_then(|pid| std::fs::read_to_string(
+            proc_root.join(pid.to_string()).join("net").join("dev")
+        ).ok())
+        .map(|text| parse_net_dev(&text))
+        .unwrap_or_default();
+
+    // pids.max が "max" なら制限無し
+    let pids = PidsStats {
+        current: read("pids.current").and_then(|text| parse_single_value(&text)),
+        limit: read("pids.max").and_then(|text| parse_single_value(&text)),
+    };
+
+    Ok(Stats {
+        time: Some(time::format_time(&std::time::SystemTime::now())),
+        // 識別情報は Docker API から取得して呼び出し側で設定します
+        info: Default::default(),
+        cpu,
+        memory: MemoryStats { used, available, breakdown },
+        io: IoStats::from_devices(io),
+        net: NetStats::from_interfaces(net),
+        pids,
+        host: None,
+    })
+}
@@ -0,0 +1,667 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use super::error;
+
+/// 設定ファイルが指定されなかった場合に読み込みを試みるパス
+/// (存在しなければ無視します)
+pub const DEFAULT_CONFIG_PATH: &str = "./cephylas.toml";
+
+/// 環境変数名の接頭辞
+/// "log.dir" は CEPHYLAS_LOG_DIR になります
+const ENV_PREFIX: &str = "CEPHYLAS_";
+
+/// 設定項目のキー一覧です
+///
+/// 同じキーを以下の形式で指定でき、下に行くほど優先されます
+/// - 既定値
+/// - 設定ファイル: [log] セクション内の dir = "./log"
+/// - 環境変数: CEPHYLAS_LOG_DIR=./log
+/// - コマンドライン引数: --log-dir ./log
+pub const KEYS: &[&str] = &[
+    "docker.socket",
+    "collector.backend",
+    "collector.cgroup_root",
+    "collector.proc_root",
+    "collector.host_mounts",
+    "collector.workers",
+    "collector.timeout_secs",
+    "collector.max_backoff_secs",
+    "log.dir",
+    "log.format",
+    "log.fsync",
+    "log.fsync_interval_secs",
+    "log.tick_secs",
+    "log.daily_generations",
+    "log.weekly_generations",
+    "log.monthly_generations",
+    "log.weekly_bucket_secs",
+    "log.monthly_bucket_secs",
+    "log.labels",
+    "cache.max_length",
+    "server.listen",
+    "server.workers",
+    "server.queue_length",
+    "server.timeout_secs",
+    "server.max_streams",
+];
+
+/// 使用状況の収集方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CollectorBackend {
+    /// Docker API の /containers/{id}/stats をコンテナ毎に呼び出します
+    #[default]
+    Docker,
+    /// cgroup v2 のファイルを直接読みます (Docker はコンテナ一覧の取得のみ)
+    Cgroup,
+}
+impl std::fmt::Display for CollectorBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CollectorBackend::Docker => write!(f, "docker"),
+            CollectorBackend::Cgroup => write!(f, "cgroup"),
+        }
+    }
+}
+
+/// 日次ログの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// 固定長レコードのセグメント (log_daily.seg) に書きます
+    #[default]
+    Binary,
+    /// json の行 (log_daily) に書きます
+    Json,
+    /// 両方に書きます (json は他のツールで読むための出力です)
+    Both,
+}
+impl LogFormat {
+    pub fn writes_binary(&self) -> bool {
+        matches!(self, LogFormat::Binary | LogFormat::Both)
+    }
+    pub fn writes_json(&self) -> bool {
+        matches!(self, LogFormat::Json | LogFormat::Both)
+    }
+}
+impl std::fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Binary => write!(f, "binary"),
+            LogFormat::Json => write!(f, "json"),
+            LogFormat::Both => write!(f, "both"),
+        }
+    }
+}
+
+/// 日次ログをディスクへ書き出す (fsync する) 頻度
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    /// tick 毎に書き出します
+    #[default]
+    Tick,
+    /// log.fsync_interval_secs 毎に書き出します
+    Interval,
+    /// OS に任せます (終了時のみ書き出します)
+    Never,
+}
+impl std::fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsyncPolicy::Tick => write!(f, "tick"),
+            FsyncPolicy::Interval => write!(f, "interval"),
+            FsyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// デーモン全体の設定値です
+/// 各モジュールは定数ではなくこの構造体から設定を読みます
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub docker_socket: PathBuf,
+    pub collector: CollectorBackend,
+    /// cgroup v2 のマウント先 (コンテナ内で動かす場合はホストのものをマウントします)
+    pub cgroup_root: PathBuf,
+    /// procfs のマウント先 (同上、コンテナのネットワーク統計とホスト全体の使用状況の読み取りに使います)
+    pub proc_root: PathBuf,
+    /// ホスト (_host) の容量を記録するマウントポイント
+    pub host_mounts: Vec<PathBuf>,
+    /// コンテナの使用状況を並列に取得するスレッド数
+    pub collector_workers: usize,
+    /// Docker API の読み書き毎のタイムアウト
+    pub collector_timeout: std::time::Duration,
+    /// Docker に接続できない間、再試行の間隔を延ばす上限
+    pub collector_max_backoff: std::time::Duration,
+    pub log_dir: PathBuf,
+    pub log_format: LogFormat,
+    pub log_fsync: FsyncPolicy,
+    /// log_fsync が Interval の場合の書き出す間隔
+    pub log_fsync_interval: std::time::Duration,
+    pub tick: std::time::Duration,
+    /// ローテーション後に保持する世代数 (log_daily.1 〜 log_daily.N)
+    pub daily_generations: usize,
+    pub weekly_generations: usize,
+    pub monthly_generations: usize,
+    /// log_weekly, log_monthly に集計する際のバケット幅 (秒)
+    pub weekly_bucket_seconds: u64,
+    pub monthly_bucket_seconds: u64,
+    /// ログに記録するコンテナのラベルのキー (/groups で集計に使います)
+    pub labels: Vec<String>,
+    pub max_log_length: usize,
+    pub listen: String,
+    /// 接続を処理するスレッド数
+    pub workers: usize,
+    /// 処理待ちの接続数の上限 (超えた接続には503を返します)
+    pub queue_length: usize,
+    /// 接続毎の読み書きのタイムアウト
+    pub connection_timeout: std::time::Duration,
+    /// 同時に配信できる /stream の接続数
+    pub max_streams: usize,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            docker_socket: PathBuf::from("/var/run/docker.sock"),
+            collector: CollectorBackend::default(),
+            cgroup_root: PathBuf::from("/sys/fs/cgroup"),
+            proc_root: PathBuf::from("/proc"),
+            host_mounts: vec![PathBuf::from("/")],
+            collector_workers: 4,
+            collector_timeout: std::time::Duration::from_secs(5),
+            collector_max_backoff: std::time::Duration::from_secs(300),
+            log_dir: PathBuf::from("./log"),
+            log_format: LogFormat::default(),
+            log_fsync: FsyncPolicy::default(),
+            log_fsync_interval: std::time::Duration::from_secs(60),
+            tick: std::time::Duration::from_secs(10),
+            daily_generations: 7,
+            weekly_generations: 5,
+            monthly_generations: 12,
+            weekly_bucket_seconds: 300,
+            monthly_bucket_seconds: 3600,
+            labels: vec![
+                "com.docker.compose.project".to_string(),
+                "com.docker.compose.service".to_string(),
+            ],
+            max_log_length: 8640,
+            listen: "0.0.0.0:7878".to_string(),
+            workers: 4,
+            queue_length: 64,
+            connection_timeout: std::time::Duration::from_secs(5),
+            max_streams: 16,
+        }
+    }
+}
+
+fn config_error<S: AsRef<str>>(key: &str, message: S) -> error::Error {
+    error::Error::ConfigError(format!("{}: {}", key, message.as_ref()))
+}
+
+fn parse_number<T: std::str::FromStr>(
+    key: &str,
+    value: &str,
+) -> Result<T, error::Error>
+where
+    T::Err: std::fmt::Display
+{
+    value.parse::<T>()
+        .map_err(|e| config_error(key, format!("\"{}\" ({})", value, e)))
+}
+
+/// 設定ファイルの値から引用符とコメントを取り除きます
+fn unquote(raw: &str) -> Result<String, error::Error> {
+    let raw = raw.trim();
+    if let Some(rest) = raw.strip_prefix('"') {
+        let end = rest.find('"')
+            .ok_or(format!("unterminated string: {}", raw))?;
+        let trailing = rest[end + 1..].trim();
+        if !trailing.is_empty() && !trailing.starts_with('#') {
+            return Err(format!("unexpected text after string: {}", raw).into());
+        }
+        Ok(rest[..end].to_string())
+    } else {
+        Ok(raw.split('#').next().unwrap_or("").trim().to_string())
+    }
+}
+
+impl Config {
+    /// キーに対応する設定値を文字列から解釈して設定します
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), error::Error> {
+        match key {
+            "docker.socket" => self.docker_socket = PathBuf::from(value),
+            "collector.backend" => self.collector = match value {
+                "docker" => CollectorBackend::Docker,
+                "cgroup" => CollectorBackend::Cgroup,
+                _ => return Err(config_error(
+                    key,
+                    format!("\"{}\" must be \"docker\" or \"cgroup\"", value),
+                )),
+            },
+            "collector.cgroup_root" => self.cgroup_root = PathBuf::from(value),
+            "collector.proc_root" => self.proc_root = PathBuf::from(value),
+            "collector.host_mounts" => self.host_mounts = value.split(',')
+                .map(str::trim)
+                .filter(|mount| !mount.is_empty())
+                .map(PathBuf::from)
+                .collect(),
+            "collector.workers" => self.collector_workers =
+                parse_number::<usize>(key, value)?,
+            "collector.timeout_secs" => self.collector_timeout =
+                std::time::Duration::from_secs(parse_number::<u64>(key, value)?),
+            "collector.max_backoff_secs" => self.collector_max_backoff =
+                std::time::Duration::from_secs(parse_number::<u64>(key, value)?),
+            "log.dir" => self.log_dir = PathBuf::from(value),
+            "log.format" => self.log_format = match value {
+                "binary" => LogFormat::Binary,
+                "json" => LogFormat::Json,
+                "both" => LogFormat::Both,
+                _ => return Err(config_error(
+                    key,
+                    format!("\"{}\" must be \"binary\", \"json\" or \"both\"", value),
+                )),
+            },
+            "log.fsync" => self.log_fsync = match value {
+                "tick" => FsyncPolicy::Tick,
+                "interval" => FsyncPolicy::Interval,
+                "never" => FsyncPolicy::Never,
+                _ => return Err(config_error(
+                    key,
+                    format!("\"{}\" must be \"tick\", \"interval\" or \"never\"", value),
+                )),
+            },
+            "log.fsync_interval_secs" => self.log_fsync_interval =
+                std::time::Duration::from_secs(parse_number::<u64>(key, value)?),
+            "log.tick_secs" => self.tick = std::time::Duration::from_secs(
+                parse_number::<u64>(key, value)?
+            ),
+            "log.daily_generations" => self.daily_generations =
+                parse_number::<usize>(key, value)?,
+            "log.weekly_generations" => self.weekly_generations =
+                parse_number::<usize>(key, value)?,
+            "log.monthly_generations" => self.monthly_generations =
+                parse_number::<usize>(key, value)?,
+            "log.weekly_bucket_secs" => self.weekly_bucket_seconds =
+                parse_number::<u64>(key, value)?,
+            "log.monthly_bucket_secs" => self.monthly_bucket_seconds =
+                parse_number::<u64>(key, value)?,
+            "log.labels" => self.labels = value.split(',')
+                .map(str::trim)
+                .filter(|label| !label.is_empty())
+                .map(str::to_string)
+                .collect(),
+            "cache.max_length" => self.max_log_length =
+                parse_number::<usize>(key, value)?,
+            "server.listen" => self.listen = value.to_string(),
+            "server.workers" => self.workers =
+                parse_number::<usize>(key, value)?,
+            "server.queue_length" => self.queue_length =
+                parse_number::<usize>(key, value)?,
+            "server.timeout_secs" => self.connection_timeout =
+                std::time::Duration::from_secs(parse_number::<u64>(key, value)?),
+            "server.max_streams" => self.max_streams =
+                parse_number::<usize>(key, value)?,
+            _ => return Err(config_error(key, "unknown configuration key")),
+        }
+        Ok(())
+    }
+
+    /// キーに対応する設定値を設定ファイルの形式で返します
+    pub fn get(&self, key: &str) -> Option<String> {
+        match key {
+            "docker.socket" =>
+                Some(format!("\"{}\"", self.docker_socket.display())),
+            "collector.backend" => Some(format!("\"{}\"", self.collector)),
+            "collector.cgroup_root" =>
+                Some(format!("\"{}\"", self.cgroup_root.display())),
+            "collector.proc_root" =>
+                Some(format!("\"{}\"", self.proc_root.display())),
+            "collector.host_mounts" => Some(format!(
+                "\"{}\"",
+                self.host_mounts.iter()
+                    .map(|mount| mount.display().to_string())
+                    .collect::<Vec<String>>()
+                    .join(","),
+            )),
+            "collector.workers" => Some(self.collector_workers.to_string()),
+            "collector.timeout_secs" =>
+                Some(self.collector_timeout.as_secs().to_string()),
+            "collector.max_backoff_secs" =>
+                Some(self.collector_max_backoff.as_secs().to_string()),
+            "log.dir" => Some(format!("\"{}\"", self.log_dir.display())),
+            "log.format" => Some(format!("\"{}\"", self.log_format)),
+            "log.fsync" => Some(format!("\"{}\"", self.log_fsync)),
+            "log.fsync_interval_secs" =>
+                Some(self.log_fsync_interval.as_secs().to_string()),
+            "log.tick_secs" => Some(self.tick.as_secs().to_string()),
+            "log.daily_generations" =>
+                Some(self.daily_generations.to_string()),
+            "log.weekly_generations" =>
+                Some(self.weekly_generations.to_string()),
+            "log.monthly_generations" =>
+                Some(self.monthly_generations.to_string()),
+            "log.weekly_bucket_secs" =>
+                Some(self.weekly_bucket_seconds.to_string()),
+            "log.monthly_bucket_secs" =>
+                Some(self.monthly_bucket_seconds.to_string()),
+            "log.labels" => Some(format!("\"{}\"", self.labels.join(","))),
+            "cache.max_length" => Some(self.max_log_length.to_string()),
+            "server.listen" => Some(format!("\"{}\"", self.listen)),
+            "server.workers" => Some(self.workers.to_string()),
+            "server.queue_length" => Some(self.queue_length.to_string()),
+            "server.timeout_secs" =>
+                Some(self.connection_timeout.as_secs().to_string()),
+            "server.max_streams" => Some(self.max_streams.to_string()),
+            _ => None,
+        }
+    }
+
+    /// 日次ログファイルのパス
+    pub fn daily_log_path(&self) -> PathBuf {
+        self.log_dir.join("log_daily")
+    }
+
+    /// 日次ログのセグメント (ディレクトリ) のパス
+    pub fn daily_segment_path(&self) -> PathBuf {
+        self.log_dir.join("log_daily.seg")
+    }
+
+    /// コンテナのイベントログのパス
+    pub fn events_log_path(&self) -> PathBuf {
+        self.log_dir.join("log_events")
+    }
+
+    /// 設定値の組み合わせが妥当か確認します
+    pub fn validate(&self) -> Result<(), error::Error> {
+        if self.docker_socket.as_os_str().is_empty() {
+            return Err(config_error("docker.socket", "must not be empty"));
+        }
+        if self.collector == CollectorBackend::Cgroup {
+            for (key, path) in [
+                ("collector.cgroup_root", &self.cgroup_root),
+                ("collector.proc_root", &self.proc_root),
+            ] {
+                if path.as_os_str().is_empty() {
+                    return Err(config_error(key, "must not be empty"));
+                }
+            }
+        }
+        for (key, value) in [
+            ("collector.workers", self.collector_workers),
+            ("collector.timeout_secs", self.collector_timeout.as_secs() as usize),
+            ("collector.max_backoff_secs", self.collector_max_backoff.as_secs() as usize),
+        ] {
+            if value < 1 {
+                return Err(config_error(key, "must be 1 or more"));
+            }
+        }
+        if self.log_dir.as_os_str().is_empty() {
+            return Err(config_error("log.dir", "must not be empty"));
+        }
+        // ログには millis を u16 で記録するので 65 秒が上限です
+        // (IO速度の計算で秒単位に切り捨てるので 1 秒未満も不可)
+        let tick_secs = self.tick.as_secs();
+        if !(1..=65).contains(&tick_secs) {
+            return Err(config_error(
+                "log.tick_secs",
+                format!("must be between 1 and 65, got {}", tick_secs),
+            ));
+        }
+        // 集計は log_daily.1 から行うので最低1世代は必要です
+        for (key, generations) in [
+            ("log.daily_generations", self.daily_generations),
+            ("log.weekly_generations", self.weekly_generations),
+            ("log.monthly_generations", self.monthly_generations),
+        ] {
+            if generations < 1 {
+                return Err(config_error(key, "must be 1 or more"));
+            }
+        }
+        if self.log_fsync == FsyncPolicy::Interval && self.log_fsync_interval.as_secs() < 1 {
+            return Err(config_error("log.fsync_interval_secs", "must be 1 or more"));
+        }
+        if self.weekly_bucket_seconds < 1 || self.monthly_bucket_seconds < 1 {
+            return Err(config_error(
+                "log.weekly_bucket_secs, log.monthly_bucket_secs",
+                "must be 1 or more",
+            ));
+        }
+        if self.max_log_length < 3 {
+            return Err(config_error(
+                "cache.max_length",
+                format!("must be 3 or more, got {}", self.max_log_length),
+            ));
+        }
+        for (key, value) in [
+            ("server.workers", self.workers),
+            ("server.queue_length", self.queue_length),
+            ("server.timeout_secs", self.connection_timeout.as_secs() as usize),
+            ("server.max_streams", self.max_streams),
+        ] {
+            if value < 1 {
+                return Err(config_error(key, "must be 1 or more"));
+            }
+        }
+        if self.listen.parse::<std::net::SocketAddr>().is_err() {
+            return Err(config_error(
+                "server.listen",
+                format!("\"{}\" is not a socket address", self.listen),
+            ));
+        }
+        Ok(())
+    }
+
+    /// TOML風 (INI風) の設定ファイルの内容を適用します
+    ///
+    /// ```text
+    /// # comment
+    /// [log]
+    /// dir = "./log"
+    /// tick_secs = 10
+    /// ```
+    pub fn apply_file(&mut self, content: &str) -> Result<(), error::Error> {
+        let mut section = String::new();
+        for (iline, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let in_line = |message: String| error::Error::ConfigError(
+                format!("line {}: {}", iline + 1, message)
+            );
+            if let Some(name) = line.strip_prefix('[') {
+                section = name.strip_suffix(']')
+                    .ok_or_else(|| in_line(format!("invalid section: {}", line)))?
+                    .trim()
+                    .to_string();
+                continue;
+            }
+            let (key, value) = line.split_once('=')
+                .ok_or_else(|| in_line(format!("expected key = value: {}", line)))?;
+            let key = if section.is_empty() {
+                key.trim().to_string()
+            } else {
+                format!("{}.{}", section, key.trim())
+            };
+            let value = unquote(value)
+                .map_err(|e| in_line(e.to_string()))?;
+            self.set(&key, &value)
+                .map_err(|e| in_line(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// CEPHYLAS_ で始まる環境変数を適用します
+    /// (CEPHYLAS_CONFIG は設定ファイルのパスなので対象外です)
+    pub fn apply_env<I: IntoIterator<Item = (String, String)>>(
+        &mut self,
+        vars: I,
+    ) -> Result<(), error::Error> {
+        let vars: HashMap<String, String> = vars.into_iter().collect();
+        for key in KEYS {
+            let name = env_name(key);
+            if let Some(value) = vars.get(&name) {
+                self.set(key, value)
+                    .map_err(|e| error::Error::ConfigError(
+                        format!("{}: {}", name, e)
+                    ))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 既定値、設定ファイル、環境変数、コマンドライン引数の順に
+    /// 設定を読み込み、最後に検証します
+    pub fn load<I: IntoIterator<Item = (String, String)>>(
+        command_line: &CommandLine,
+        vars: I,
+    ) -> Result<Self, error::Error> {
+        let vars: Vec<(String, String)> = vars.into_iter().collect();
+        let mut config = Config::default();
+
+        let explicit_path = command_line.config_path.clone()
+            .or_else(|| vars.iter()
+                .find(|(k, _)| k == &format!("{}CONFIG", ENV_PREFIX))
+                .map(|(_, v)| PathBuf::from(v))
+            );
+        let config_path = explicit_path.clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+        match std::fs::read_to_string(&config_path) {
+            Ok(content) => config.apply_file(&content)
+                .map_err(|e| error::Error::ConfigError(
+                    format!("{}: {}", config_path.display(), e)
+                ))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound
+                && explicit_path.is_none() => { /* optional */ },
+            Err(e) => return Err(error::Error::ConfigError(
+                format!("{}: {}", config_path.display(), e)
+            )),
+        }
+
+        config.apply_env(vars)?;
+        for (key, value) in &command_line.overrides {
+            config.set(key, value)
+                .map_err(|e| error::Error::ConfigError(
+                    format!("--{}: {}", flag_name(key), e)
+                ))?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// 設定ファイルと同じ形式で全設定を出力します (--print-config)
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut current_section = "";
+        for key in KEYS {
+            let (section, name) = key.split_once('.').unwrap_or(("", key));
+            if section != current_section {
+                if !current_section.is_empty() {
+                    writeln!(f)?;
+                }
+                writeln!(f, "[{}]", section)?;
+                current_section = section;
+            }
+            writeln!(
+                f, "{} = {}",
+                name, self.get(key).unwrap_or_default()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// "log.tick_secs" -> "CEPHYLAS_LOG_TICK_SECS"
+fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase())
+}
+
+/// "log.tick_secs" -> "log-tick-secs"
+fn flag_name(key: &str) -> String {
+    key.replace(['.', '_'], "-")
+}
+
+/// --help で表示する使い方の説明です
+pub fn usage() -> String {
+    let mut usage = "\
+usage: cephylas [--config <path>] [--print-config] [--<key> <value>]...
+       cephylas --convert-log <json log>
+       cephylas --export-json <segment>
+
+options:
+  --config <path>    configuration file (default: ./cephylas.toml)
+  --print-config     print the effective configuration and exit
+  --convert-log <path>
+                     convert a json daily log (log_daily.N) into a segment
+                     (log_daily.seg.N) and exit
+  --export-json <path>
+                     print a segment as json lines and exit
+  --help             print this message and exit
+
+settings (option / environment variable):
+".to_string();
+    for key in KEYS {
+        usage += &format!("  --{:<20} {}\n", flag_name(key), env_name(key));
+    }
+    usage
+}
+
+/// コマンドライン引数の解釈結果です
+#[derive(Debug, Default)]
+pub struct CommandLine {
+    pub config_path: Option<PathBuf>,
+    pub print_config: bool,
+    pub help: bool,
+    /// --convert-log で指定された json 形式の日次ログ
+    pub convert_log: Option<PathBuf>,
+    /// --export-json で指定されたセグメント
+    pub export_json: Option<PathBuf>,
+    /// (設定キー, 値) の組
+    pub overrides: Vec<(String, String)>,
+}
+impl CommandLine {
+    /// プログラム名を除いた引数を解釈します
+    /// --key value と --key=value の両方の形式を受け付けます
+    pub fn parse<I: IntoIterator<Item = String>>(
+        args: I,
+    ) -> Result<Self, error::Error> {
+        let mut command_line = CommandLine::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let flag = arg.strip_prefix("--")
+                .ok_or_else(|| error::Error::ConfigError(
+                    format!("unexpected argument: {}", arg)
+                ))?;
+            let (flag, inline_value) = match flag.split_once('=') {
+                Some((f, v)) => (f, Some(v.to_string())),
+                None => (flag, None),
+            };
+            match flag {
+                "print-config" => command_line.print_config = true,
+                "help" => command_line.help = true,
+                _ => {
+                    let mut value = || inline_value.clone()
+                        .or_else(|| args.next())
+                        .ok_or_else(|| error::Error::ConfigError(
+                            format!("--{}: missing value", flag)
+                        ));
+                    match flag {
+                        "config" => command_line.config_path = Some(PathBuf::from(value()?)),
+                        "convert-log" => command_line.convert_log = Some(PathBuf::from(value()?)),
+                        "export-json" => command_line.export_json = Some(PathBuf::from(value()?)),
+                        _ => {
+                            let key = KEYS.iter()
+                                .find(|k| flag_name(k) == flag)
+                                .ok_or_else(|| error::Error::ConfigError(
+                                    format!("unknown option: --{}", flag)
+                                ))?;
+                            command_line.overrides.push((key.to_string(), value()?));
+                        },
+                    }
+                },
+            }
+        }
+        Ok(command_line)
+    }
+}
@@ -0,0 +1,165 @@
+// 時系列データの間引きアルゴリズム
+//
+// いずれも (x, y) の点列を受け取り、x は昇順に並んでいるものとします
+// 元の点を選ぶアルゴリズムは選んだ点のインデックスを昇順で返し、
+// 平均を取るアルゴリズムはバケットの範囲を返します
+
+use std::ops::Range;
+
+use super::error;
+
+/// 間引きアルゴリズムの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Algorithm {
+    /// Largest-Triangle-Three-Buckets
+    #[default]
+    Lttb,
+    /// バケット毎の最小値と最大値
+    MinMax,
+    /// バケット毎の最初・最小・最大・最後の点
+    M4,
+    /// バケット毎の平均値
+    Mean,
+    /// 間引きしない
+    Raw,
+}
+impl std::str::FromStr for Algorithm {
+    type Err = error::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lttb" => Ok(Algorithm::Lttb),
+            "minmax" => Ok(Algorithm::MinMax),
+            "m4" => Ok(Algorithm::M4),
+            "mean" => Ok(Algorithm::Mean),
+            "raw" => Ok(Algorithm::Raw),
+            _ => Err(error::Error::ParseError(
+                format!("unknown downsampling algorithm: {}", s)
+            )),
+        }
+    }
+}
+
+/// n 個の点を nbuckets 個のなるべく均等なバケットに分けます
+pub fn buckets(n: usize, nbuckets: usize) -> Vec<Range<usize>> {
+    let nbuckets = nbuckets.clamp(1, n.max(1));
+    (0..nbuckets)
+        .map(|i| (i * n / nbuckets)..((i + 1) * n / nbuckets))
+        .filter(|r| !r.is_empty())
+        .collect()
+}
+
+fn triangle_area(
+    p0: (f64, f64),
+    p1: (f64, f64),
+    p2: (f64, f64),
+) -> f64 {
+    let x1 = p1.0 - p0.0;
+    let y1 = p1.1 - p0.1;
+    let x2 = p2.0 - p0.0;
+    let y2 = p2.1 - p0.1;
+
+    (x2 * y1 - x1 * y2).abs() * 0.5
+}
+
+/// Largest-Triangle-Three-Buckets
+///
+/// 最初と最後の点は必ず残し、間の点を nsample - 2 個のバケットに分けます
+/// 各バケットからは、直前に選んだ点と次のバケットの平均点とで作る
+/// 三角形の面積が最大になる点を選びます
+pub fn lttb(points: &[(f64, f64)], nsample: usize) -> Vec<usize> {
+    let n = points.len();
+    if nsample >= n || nsample < 3 {
+        return (0..n).collect();
+    }
+
+    // i 番目のバケットの開始位置 (最初の点を除いて均等に分割)
+    let bucket_start = |i: usize| i * (n - 2) / (nsample - 2) + 1;
+
+    let mut samples = Vec::with_capacity(nsample);
+    let mut last = 0;
+    samples.push(last);
+    for i in 0..(nsample - 2) {
+        // 次のバケットの平均点 (最後のバケットの次は最後の点)
+        let next = bucket_start(i + 1)..bucket_start(i + 2).min(n);
+        let count = next.len() as f64;
+        let average = points[next].iter()
+            .fold((0.0, 0.0), |acc, p| (acc.0 + p.0, acc.1 + p.1));
+        let average = (average.0 / count, average.1 / count);
+
+        let mut max_area = -1.0;
+        let mut max_area_index = bucket_start(i);
+        for j in bucket_start(i)..bucket_start(i + 1) {
+            let area = triangle_area(points[last], points[j], average);
+            if area > max_area {
+                max_area = area;
+                max_area_index = j;
+            }
+        }
+        samples.push(max_area_index);
+        last = max_area_index;
+    }
+    samples.push(n - 1);
+
+    samples
+}
+
+/// 各バケットから最小値と最大値の点を選びます (バケット数は nsample / 2)
+pub fn min_max(points: &[(f64, f64)], nsample: usize) -> Vec<usize> {
+    select_in_buckets(points, nsample, 2, |range| {
+        let (min, max) = extremes(points, range);
+        vec![min, max]
+    })
+}
+
+/// 各バケットから最初・最小・最大・最後の点を選びます
+/// (バケット数は nsample / 4)
+pub fn m4(points: &[(f64, f64)], nsample: usize) -> Vec<usize> {
+    select_in_buckets(points, nsample, 4, |range| {
+        let (min, max) = extremes(points, range.clone());
+        vec![range.start, min, max, range.end - 1]
+    })
+}
+
+/// 各バケットの範囲を返します (平均は呼び出し側で計算します)
+pub fn mean(points: &[(f64, f64)], nsample: usize) -> Vec<Range<usize>> {
+    let n = points.len();
+    if nsample >= n {
+        return (0..n).map(|i| i..(i + 1)).collect();
+    }
+    buckets(n, nsample)
+}
+
+fn extremes(points: &[(f64, f64)], range: Range<usize>) -> (usize, usize) {
+    let mut min = range.start;
+    let mut max = range.start;
+    for i in range {
+        if points[i].1 < points[min].1 { min = i; }
+        if points[i].1 > points[max].1 { max = i; }
+    }
+    (min, max)
+}
+
+fn select_in_buckets<F: Fn(Range<usize>) -> Vec<usize>>(
+    points: &[(f64, f64)],
+    nsample: usize,
+    points_per_bucket: usize,
+    select: F,
+) -> Vec<usize> {
+    let n = points.len();
+    if nsample >= n {
+        return (0..n).collect();
+    }
+    let mut samples = buckets(n, nsample / points_per_bucket)
+        .into_iter()
+        .flat_map(|range| {
+            let mut selected = select(range);
+            selected.sort_unstable();
+            selected.dedup();
+            selected
+        })
+        .collect::<Vec<usize>>();
+    samples.dedup();
+    // nsample が points_per_bucket 未満でもバケットは1つ残るので、nsample 点に切り詰めます
+    samples.truncate(nsample);
+    samples
+}
@@ -1,18 +1,70 @@
 
-
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
     IOError(std::io::Error),
     JsonError(json::JsonError),
     TimeError(std::time::SystemTimeError),
+    ConfigError(String),
+    /// Docker のソケットに接続できない
+    DockerUnreachable(std::io::Error),
+    /// Docker API が 200 以外のステータスを返した
+    DockerApiError { status: u16, message: String },
+    /// ログや Docker API の応答、時刻などを解釈できない
+    ParseError(String),
+    /// 記録されていないコンテナが指定された
+    UnknownContainer(String),
+    /// どのルートにもマッチしない、または指定されたデータが無い
+    NotFound(String),
+    /// クエリパラメータが不正
+    BadQuery(String),
+    /// ロックを持ったスレッドが panic した
+    LockPoisoned(String),
     OtherError(String),
 }
+impl Error {
+    /// エラーの種類を表す識別子 (API のエラー応答の code に使います)
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::IOError(_) => "io_error",
+            Error::JsonError(_) | Error::ParseError(_) => "parse_error",
+            Error::TimeError(_) => "time_error",
+            Error::ConfigError(_) => "config_error",
+            Error::DockerUnreachable(_) => "docker_unreachable",
+            Error::DockerApiError { .. } => "docker_api_error",
+            Error::UnknownContainer(_) => "unknown_container",
+            Error::NotFound(_) => "not_found",
+            Error::BadQuery(_) => "bad_query",
+            Error::LockPoisoned(_) => "lock_poisoned",
+            Error::OtherError(_) => "internal_error",
+        }
+    }
+}
 
 impl std::error::Error for Error {}
 impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) 
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)
         -> std::fmt::Result
-    { write!(f, "{:?}", self) }
+    {
+        match self {
+            Error::IOError(e) => write!(f, "{}", e),
+            Error::JsonError(e) => write!(f, "invalid json: {}", e),
+            Error::TimeError(e) => write!(f, "{}", e),
+            Error::DockerUnreachable(e) =>
+                write!(f, "cannot connect to docker: {}", e),
+            Error::DockerApiError { status, message } =>
+                write!(f, "docker returned status {}: {}", status, message),
+            Error::UnknownContainer(name) =>
+                write!(f, "unknown container: {}", name),
+            Error::LockPoisoned(message) =>
+                write!(f, "lock poisoned: {}", message),
+            Error::ConfigError(message)
+            | Error::ParseError(message)
+            | Error::NotFound(message)
+            | Error::BadQuery(message)
+            | Error::OtherError(message) => write!(f, "{}", message),
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -30,6 +82,11 @@ impl From<std::time::SystemTimeError> for Error {
         Error::TimeError(value)
     }
 }
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(value: std::sync::PoisonError<T>) -> Self {
+        Error::LockPoisoned(value.to_string())
+    }
+}
 impl From<&str> for Error {
     fn from(value: &str) -> Self {
         Error::OtherError(value.to_string())
@@ -40,4 +97,3 @@ impl From<String> for Error {
         Error::OtherError(value)
     }
 }
-
@@ -0,0 +1,364 @@
+// Docker の /events API によるコンテナのライフサイクルイベントの記録
+//
+// tick の間に起動・停止・OOM Kill されたコンテナは使用状況のログには現れないので、
+// /events をストリームで購読してイベントログ (log_events) に追記し、
+// キャッシュにも保持して /events と /containers/{name}/events で返します
+
+use std::collections::VecDeque;
+use std::io::{ BufRead, Write };
+
+use super::config;
+use super::error;
+use super::log_cache;
+use super::supervisor;
+use super::time;
+
+/// コンテナのイベントのみを購読します ({"type":["container"]} をエンコードしたもの)
+const DOCKER_API_EVENTS: &str = "/events?filters=%7B%22type%22%3A%5B%22container%22%5D%7D";
+
+/// 記録するイベントの種類
+/// (health_status は Docker からは "health_status: healthy" の形式で届きます)
+pub const ACTIONS: &[&str] = &["start", "stop", "die", "oom", "restart", "health_status"];
+
+/// 購読が切れた場合に再接続するまでの待ち時間
+const RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// chunked 形式の1チャンクのサイズの上限 (バイト)
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerEvent {
+    /// UNIX時間 (ミリ秒)
+    pub time: i64,
+    pub container: String,
+    pub id: String,
+    /// ACTIONS のいずれか
+    pub action: String,
+    /// die の場合の終了コード
+    pub exit_code: Option<i64>,
+    /// health_status の場合の状態 (healthy, unhealthy など)
+    pub health: Option<String>,
+}
+impl std::fmt::Display for ContainerEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let health = self.health.as_deref()
+            .map_or("null".to_string(), json::stringify);
+        write!(
+            f,
+            "{{\"time\":\"{}\",\"container\":{},\"id\":{},\"action\":{},\"exitCode\":{},\"health\":{}}}",
+            time::format_epoch_millis(self.time),
+            json::stringify(self.container.as_str()),
+            json::stringify(self.id.as_str()),
+            json::stringify(self.action.as_str()),
+            self.exit_code.map_or("null".to_string(), |c| c.to_string()),
+            health,
+        )
+    }
+}
+
+/// Docker の /events のイベントを変換します
+/// 記録対象でないイベントなら None を返します
+///
+/// 戻り値の i64 は重複の判定に使うナノ秒単位の時刻です
+pub fn parse_docker_event(json: &json::JsonValue) -> Option<(i64, ContainerEvent)> {
+    if json["Type"] != "container" {
+        return None;
+    }
+    let (action, health) = match json["Action"].as_str()?.split_once(':') {
+        Some((action, health)) => (action, Some(health.trim().to_string())),
+        None => (json["Action"].as_str()?, None),
+    };
+    if !ACTIONS.contains(&action) {
+        return None;
+    }
+    let attributes = &json["Actor"]["Attributes"];
+    let nanos = json["timeNano"].as_i64()
+        .or_else(|| json["time"].as_i64().map(|secs| secs * 1_000_000_000))?;
+
+    Some((nanos, ContainerEvent {
+        time: nanos / 1_000_000,
+        container: attributes["name"].as_str()?.to_string(),
+        id: json["Actor"]["ID"].as_str().unwrap_or_default().to_string(),
+        action: action.to_string(),
+        exit_code: attributes["exitCode"].as_str()
+            .and_then(|code| code.parse::<i64>().ok()),
+        health,
+    }))
+}
+
+/// イベントログの1行を変換します
+pub fn json_to_event(json: &json::JsonValue) -> Option<ContainerEvent> {
+    Some(ContainerEvent {
+        time: time::parse_epoch_millis(json["time"].as_str()?).ok()?,
+        container: json["container"].as_str()?.to_string(),
+        id: json["id"].as_str().unwrap_or_default().to_string(),
+        action: json["action"].as_str()?.to_string(),
+        exit_code: json["exitCode"].as_i64(),
+        health: json["health"].as_str().map(str::to_string),
+    })
+}
+
+/// 最大 max_length 個のイベントを時刻順に保持するキャッシュ
+pub struct EventCache {
+    events: VecDeque<ContainerEvent>,
+    max_length: usize,
+    /// 古いイベントを捨てたか否か
+    truncated: bool,
+}
+impl EventCache {
+    pub fn new(max_length: usize) -> Self {
+        EventCache {
+            events: VecDeque::new(),
+            max_length,
+            truncated: false,
+        }
+    }
+
+    /// 末尾にイベントを追加し、max_length を超えるならば先頭を削除します
+    pub fn push(&mut self, event: ContainerEvent) {
+        if self.events.len() >= self.max_length {
+            self.events.pop_front();
+            self.truncated = true;
+        }
+        self.events.push_back(event);
+    }
+
+    /// 期間内のイベントを返します (container_name を指定するとそのコンテナのみ)
+    ///
+    /// 捨てたイベントが期間に含まれる可能性があれば None を返すので、
+    /// その場合はイベントログから read_event_log_range で読んでください
+    pub fn range(
+        &self,
+        time_range: &log_cache::TimeRange,
+        container_name: Option<&str>,
+    ) -> Option<Vec<ContainerEvent>> {
+        let earliest = self.events.front().map(|e| e.time);
+        let older_than_cache = self.truncated
+            && earliest.is_none_or(|earliest| time_range.from.is_none_or(|from| from < earliest));
+        if older_than_cache {
+            return None;
+        }
+        Some(filter_events(self.events.iter(), time_range, container_name))
+    }
+}
+
+fn filter_events<'a, I: Iterator<Item = &'a ContainerEvent>>(
+    events: I,
+    time_range: &log_cache::TimeRange,
+    container_name: Option<&str>,
+) -> Vec<ContainerEvent> {
+    events
+        .filter(|e| time_range.contains(e.time))
+        .filter(|e| container_name.is_none_or(|name| e.container == name))
+        .cloned()
+        .collect()
+}
+
+/// イベントログを読み、キャッシュに載る分 (新しい方から) をキャッシュに追加します
+pub fn read_event_log(
+    config: &config::Config,
+    log_cache: &log_cache::SharedUsageCache,
+) -> Result<(), error::Error> {
+    let events = read_event_log_file(config)?;
+    let mut lock = log_cache.write()?;
+    let nevents_to_skip = events.len().saturating_sub(config.max_log_length);
+    for event in events.into_iter().skip(nevents_to_skip) {
+        lock.events.push(event);
+    }
+    lock.events.truncated |= nevents_to_skip > 0;
+    Ok(())
+}
+
+/// イベントログから期間内のイベントを読みます
+pub fn read_event_log_range(
+    config: &config::Config,
+    time_range: &log_cache::TimeRange,
+    container_name: Option<&str>,
+) -> Result<Vec<ContainerEvent>, error::Error> {
+    let events = read_event_log_file(config)?;
+    Ok(filter_events(events.iter(), time_range, container_name))
+}
+
+/// イベントログを全て読みます (ファイルが無ければ空です)
+/// 壊れた行は読み飛ばします
+fn read_event_log_file(
+    config: &config::Config,
+) -> Result<Vec<ContainerEvent>, error::Error> {
+    let file = match std::fs::File::open(config.events_log_path()) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut events = Vec::new();
+    for line in std::io::BufReader::new(file).lines() {
+        let line = line?;
+        if let Some(event) = json::parse(&line).ok()
+            .and_then(|json| json_to_event(&json))
+        {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
+
+/// HTTP レスポンスを読み、本文を1行ずつ f に渡します
+/// (Docker は /events を chunked 形式で返しますが、1つのイベントが
+/// 複数のチャンクに分かれることもあるので改行で区切り直します)
+pub fn read_response_lines<R: BufRead, F: FnMut(&str) -> Result<(), error::Error>>(
+    reader: &mut R,
+    mut f: F,
+) -> Result<(), error::Error> {
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if status != "200" {
+        return Err(match status.parse::<u16>() {
+            Ok(status) => error::Error::DockerApiError {
+                status,
+                message: status_line.trim().to_string(),
+            },
+            Err(_) => error::Error::ParseError(
+                format!("docker returned unexpected status: {}", status_line.trim())
+            ),
+        });
+    }
+    let mut chunked = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Err("connection closed while reading headers".into());
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("transfer-encoding")
+                && value.trim().eq_ignore_ascii_case("chunked")
+            {
+                chunked = true;
+            }
+        }
+    }
+
+    let mut emit_lines = |buffer: &mut Vec<u8>| -> Result<(), error::Error> {
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line = buffer.drain(..=end).collect::<Vec<u8>>();
+            let line = String::from_utf8_lossy(&line);
+            if !line.trim().is_empty() {
+                f(line.trim())?;
+            }
+        }
+        Ok(())
+    };
+    let mut buffer = Vec::new();
+    if !chunked {
+        reader.read_to_end(&mut buffer)?;
+        buffer.push(b'\n');
+        return emit_lines(&mut buffer);
+    }
+    loop {
+        let mut size_line = String::new();
+        if reader.read_line(&mut size_line)? == 0 {
+            return Ok(());
+        }
+        // チャンク拡張 (";" 以降) は無視します
+        let size = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| format!("invalid chunk size: {}", size_line.trim()))?;
+        if size > MAX_CHUNK_SIZE {
+            return Err(format!("chunk too large: {} bytes", size).into());
+        }
+        if size == 0 {
+            buffer.push(b'\n');
+            return emit_lines(&mut buffer);
+        }
+        let start = buffer.len();
+        buffer.resize(start + size, 0);
+        reader.read_exact(&mut buffer[start..])?;
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf)?;
+        emit_lines(&mut buffer)?;
+    }
+}
+
+/// Docker の /events を購読し続け、イベントをイベントログとキャッシュに記録します
+///
+/// 接続が切れた場合は RECONNECT_DELAY 後に、最後に記録したイベントの時刻から
+/// 購読し直します (同じ時刻以前のイベントは重複として捨てます)
+/// 終了が要求されたら接続を閉じて戻ります
+pub fn watch_events(
+    config: &config::Config,
+    log_cache: &log_cache::SharedUsageCache,
+    shutdown: &supervisor::SharedShutdown,
+) -> Result<(), error::Error> {
+    let mut last_nanos = log_cache.read()?
+        .events.events.back()
+        .map_or(0, |e| e.time * 1_000_000 + 999_999);
+    loop {
+        match stream_events(config, log_cache, shutdown, &mut last_nanos) {
+            Ok(()) => eprintln!("docker events stream closed"),
+            Err(e) => eprintln!("docker events stream error: {}", e),
+        }
+        if shutdown.sleep(RECONNECT_DELAY) {
+            return Ok(());
+        }
+    }
+}
+
+fn stream_events(
+    config: &config::Config,
+    log_cache: &log_cache::SharedUsageCache,
+    shutdown: &supervisor::SharedShutdown,
+    last_nanos: &mut i64,
+) -> Result<(), error::Error> {
+    let mut stream = std::os::unix::net::UnixStream::connect(&config.docker_socket)
+        .map_err(error::Error::DockerUnreachable)?;
+    // 読み込み待ちで止まっているので、接続を閉じて起こします
+    let hook_stream = stream.try_clone()?;
+    shutdown.on_request("events", move || {
+        let _ = hook_stream.shutdown(std::net::Shutdown::Both);
+    });
+    let url = if *last_nanos > 0 {
+        format!("{}&since={}", DOCKER_API_EVENTS, *last_nanos / 1_000_000_000)
+    } else {
+        DOCKER_API_EVENTS.to_string()
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: localhost\r\n\
+         Connection: close\r\n\
+         \r\n",
+        url
+    );
+    stream.write_all(request.as_bytes())?;
+
+    let mut reader = std::io::BufReader::new(stream);
+    read_response_lines(&mut reader, |line| {
+        let json = json::parse(line)?;
+        let Some((nanos, event)) = parse_docker_event(&json) else {
+            return Ok(());
+        };
+        if nanos <= *last_nanos {
+            return Ok(());
+        }
+        *last_nanos = nanos;
+        append_event(config, &event)?;
+        log_cache.write()?
+            .events.push(event);
+        Ok(())
+    })
+}
+
+/// イベントログに1行追記します
+pub fn append_event(
+    config: &config::Config,
+    event: &ContainerEvent,
+) -> Result<(), error::Error> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(config.events_log_path())?;
+    file.write_all(format!("{}\r\n", event).as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,337 @@
+// /proc と statvfs(3) からホスト全体の使用状況を読み取ります
+//
+// コンテナと同じ Stats 構造体を返し、疑似コンテナ HOST_NAME としてログと
+// キャッシュに記録するので、使用率の計算や /containers/_host/cpu などの
+// ルートはコンテナと共通です
+// ロードアベレージとファイルシステムはホストのみの値なので Stats::host に持ちます
+// 各ファイルの解析はファイルの内容を受け取る関数に分けてあり、
+// proc_root を差し替えれば偽の procfs でも動作します
+
+use std::collections::{ BTreeMap, HashMap, };
+use std::path::{ Path, PathBuf };
+
+use super::cgroup;
+use super::error;
+use super::log::{ CpuStats, IoStats, MemoryBreakdown, MemoryStats, NetStats, PidsStats, Stats };
+use super::log::option_to_string;
+use super::time;
+
+/// ホストを記録する疑似コンテナの名前
+/// (Docker のコンテナ名は英数字で始まるので重なりません)
+pub const HOST_NAME: &str = "_host";
+
+/// /proc/diskstats のセクタのバイト数 (デバイスによらず 512 です)
+const BYTES_PER_SECTOR: u64 = 512;
+
+/// ロードアベレージ (/proc/loadavg)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LoadAverage {
+    pub one: f32,
+    pub five: f32,
+    pub fifteen: f32,
+}
+impl std::fmt::Display for LoadAverage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{\"one\":{:.2},\"five\":{:.2},\"fifteen\":{:.2}}}",
+            self.one, self.five, self.fifteen,
+        )
+    }
+}
+
+/// マウントポイント毎のファイルシステムの容量 (バイト)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FilesystemStats {
+    pub total: u64,
+    pub free: u64,
+    /// 一般ユーザーが使える空き容量 (root 用の予約分を除きます)
+    pub available: u64,
+}
+impl FilesystemStats {
+    pub fn used(&self) -> u64 {
+        self.total.saturating_sub(self.free)
+    }
+    /// df と同じく、一般ユーザーが使える容量に対する使用量の割合 (%)
+    pub fn percentage(&self) -> Option<f32> {
+        let capacity = self.used() + self.available;
+        if capacity == 0 {
+            None
+        } else {
+            Some(self.used() as f32 / capacity as f32 * 100_f32)
+        }
+    }
+}
+impl std::fmt::Display for FilesystemStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{\"total\":{},\"used\":{},\"available\":{},\"percentage\":{}}}",
+            self.total,
+            self.used(),
+            self.available,
+            option_to_string(self.percentage()),
+        )
+    }
+}
+
+/// ホストのみの値 (累積値ではないので Stats と Usage で共通です)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostUsage {
+    pub load: LoadAverage,
+    /// マウントポイント毎の容量 (設定 collector.host_mounts の順ではなくパス順)
+    pub filesystems: BTreeMap<String, FilesystemStats>,
+}
+impl HostUsage {
+    /// "load":{..},"filesystems":{..} のように json オブジェクトの中身として出力します
+    pub fn json_fields(&self) -> String {
+        let filesystems = self.filesystems.iter()
+            .map(|(mount, fs)| format!("{}:{}", json::stringify(mount.as_str()), fs))
+            .collect::<Vec<String>>()
+            .join(",");
+        format!("\"load\":{},\"filesystems\":{{{}}}", self.load, filesystems)
+    }
+    /// ログの1コンテナ分の json から読みます ("load" が無ければ None)
+    pub fn from_json(json: &json::JsonValue) -> Option<Self> {
+        Some(HostUsage {
+            load: LoadAverage {
+                one: json["load"]["one"].as_f32()?,
+                five: json["load"]["five"].as_f32()?,
+                fifteen: json["load"]["fifteen"].as_f32()?,
+            },
+            filesystems: json["filesystems"].entries()
+                .filter_map(|(mount, fs)| Some((mount.to_string(), FilesystemStats {
+                    total: fs["total"].as_u64()?,
+                    free: fs["total"].as_u64()?.saturating_sub(fs["used"].as_u64()?),
+                    available: fs["available"].as_u64()?,
+                })))
+                .collect(),
+        })
+    }
+}
+
+/// /proc/stat の cpu 行の各時間 (ミリ秒) を返します
+/// user nice system idle iowait irq softirq steal の8項目のみ読み、
+/// guest, guest_nice は user, nice に含まれているので読みません
+fn parse_cpu_times(line: &str) -> Vec<u64> {
+    line.split_whitespace()
+        .skip(1)
+        .take(8)
+        .filter_map(|v| v.parse::<u64>().ok())
+        .map(|v| v * cgroup::MILLIS_PER_USER_HZ)
+        .collect()
+}
+
+/// idle と iowait 以外の時間
+fn busy_time(times: &[u64]) -> u64 {
+    times.iter().sum::<u64>()
+        - times.get(3).copied().unwrap_or(0)
+        - times.get(4).copied().unwrap_or(0)
+}
+
+/// /proc/stat からCPU時間を読み取ります
+///
+/// total は idle と iowait 以外の合計、system は全ての合計で、
+/// コンテナと同じく total / system * ncpu が使用率 (1コア = 100%) になります
+pub fn parse_stat(text: &str) -> CpuStats {
+    let mut cpu = CpuStats::default();
+    for line in text.lines() {
+        if line.starts_with("cpu ") {
+            let times = parse_cpu_times(line);
+            if times.len() < 7 {
+                continue;
+            }
+            cpu.total = Some(busy_time(&times));
+            cpu.system = Some(times.iter().sum());
+            cpu.user = Some(times[0] + times[1]);
+            cpu.kernel = Some(times[2] + times[5] + times[6]);
+        } else if line.starts_with("cpu")
+            && line[3..].starts_with(|c: char| c.is_ascii_digit())
+        {
+            cpu.percpu.push(busy_time(&parse_cpu_times(line)));
+        }
+    }
+    if !cpu.percpu.is_empty() {
+        cpu.ncpu = Some(cpu.percpu.len().min(u8::MAX as usize) as u8);
+    }
+    cpu
+}
+
+/// /proc/meminfo からメモリの使用状況を読み取ります
+///
+/// used は MemTotal - MemAvailable (再利用できるキャッシュを除いた量)、
+/// available は MemTotal です (コンテナの上限と同じく使用率の分母になります)
+pub fn parse_meminfo(text: &str) -> MemoryStats {
+    let values = text.lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            let kb = value.trim().trim_end_matches("kB").trim().parse::<u64>().ok()?;
+            Some((key, kb * 1024))
+        })
+        .collect::<HashMap<&str, u64>>();
+    let value = |key: &str| values.get(key).copied();
+    let total = value("MemTotal");
+    MemoryStats {
+        used: total.zip(value("MemAvailable")).map(|(t, a)| t.saturating_sub(a)),
+        available: total,
+        breakdown: MemoryBreakdown {
+            rss: value("AnonPages"),
+            cache: value("Cached"),
+            active_file: value("Active(file)"),
+            inactive_file: value("Inactive(file)"),
+            shmem: value("Shmem"),
+            swap: value("SwapTotal").zip(value("SwapFree"))
+                .map(|(t, f)| t.saturating_sub(f)),
+            kernel_stack: value("KernelStack"),
+            slab: value("Slab"),
+            ..Default::default()
+        },
+    }
+}
+
+/// /proc/loadavg からロードアベレージと全プロセス (スレッド) 数を読み取ります
+/// "0.50 0.40 0.30 2/345 6789"
+pub fn parse_loadavg(text: &str) -> Option<(LoadAverage, u64)> {
+    let fields = text.split_whitespace().collect::<Vec<&str>>();
+    let load = LoadAverage {
+        one: fields.first()?.parse().ok()?,
+        five: fields.get(1)?.parse().ok()?,
+        fifteen: fields.get(2)?.parse().ok()?,
+    };
+    let (_running, total) = fields.get(3)?.split_once('/')?;
+    Some((load, total.parse().ok()?))
+}
+
+/// /proc/diskstats からデバイス ("major:minor") 毎の累積値を読み取ります
+///
+/// 合計が重複しないように、パーティション (sda1 や nvme0n1p1 のように
+/// 末尾の数字を除いた名前のデバイスが他にあるもの) と loop, ram デバイスは除きます
+pub fn parse_diskstats(text: &str) -> BTreeMap<String, IoStats> {
+    let rows = text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .filter(|fields| fields.len() >= 10)
+        .collect::<Vec<Vec<&str>>>();
+    let names = rows.iter().map(|fields| fields[2]).collect::<Vec<&str>>();
+    let is_partition = |name: &str| {
+        let parent = name.trim_end_matches(|c: char| c.is_ascii_digit());
+        if parent.len() == name.len() {
+            return false;
+        }
+        names.contains(&parent)
+            || parent.strip_suffix('p').is_some_and(|parent| names.contains(&parent))
+    };
+    rows.iter()
+        .filter(|fields| !fields[2].starts_with("loop")
+            && !fields[2].starts_with("ram")
+            && !is_partition(fields[2])
+        )
+        .map(|fields| {
+            // major minor name reads merged sectors ms writes merged sectors ms ..
+            let counter = |i: usize| fields[i].parse::<u64>().ok();
+            (format!("{}:{}", fields[0], fields[1]), IoStats {
+                read: counter(5).map(|sectors| sectors * BYTES_PER_SECTOR),
+                write: counter(9).map(|sectors| sectors * BYTES_PER_SECTOR),
+                read_ops: counter(3),
+                write_ops: counter(7),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+/// statvfs(3) の結果 (64bit Linux の glibc, musl のレイアウト)
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+#[repr(C)]
+#[derive(Default)]
+struct StatVfs {
+    f_bsize: u64,
+    f_frsize: u64,
+    f_blocks: u64,
+    f_bfree: u64,
+    f_bavail: u64,
+    f_files: u64,
+    f_ffree: u64,
+    f_favail: u64,
+    f_fsid: u64,
+    f_flag: u64,
+    f_namemax: u64,
+    f_spare: [std::ffi::c_int; 6],
+}
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+extern "C" {
+    fn statvfs(path: *const std::ffi::c_char, buf: *mut StatVfs) -> std::ffi::c_int;
+}
+
+/// マウントポイントのファイルシステムの容量を読み取ります
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+pub fn read_filesystem(mount: &Path) -> Result<FilesystemStats, error::Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(mount.as_os_str().as_bytes())
+        .map_err(|e| e.to_string())?;
+    let mut buf = StatVfs::default();
+    // SAFETY: path は NUL 終端された文字列で、buf は statvfs 構造体と同じレイアウトです
+    if unsafe { statvfs(path.as_ptr(), &mut buf) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let bytes = |blocks: u64| blocks.saturating_mul(buf.f_frsize);
+    Ok(FilesystemStats {
+        total: bytes(buf.f_blocks),
+        free: bytes(buf.f_bfree),
+        available: bytes(buf.f_bavail),
+    })
+}
+
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+pub fn read_filesystem(_mount: &Path) -> Result<FilesystemStats, error::Error> {
+    Err("statvfs is only supported on 64-bit Linux".into())
+}
+
+/// proc_root 以下のファイルと mounts のファイルシステムから
+/// ホスト全体の使用状況を読み取ります
+///
+/// ネットワークはループバックとコンテナ側の veth を除いたインターフェースの合計です
+/// (veth の通信はコンテナの値として記録済みです)
+/// 読めないマウントポイントは警告を出して除きます
+pub fn read_stats(proc_root: &Path, mounts: &[PathBuf]) -> Result<Stats, error::Error> {
+    let read = |name: &str| std::fs::read_to_string(proc_root.join(name));
+
+    let cpu = parse_stat(&read("stat")?);
+    let memory = parse_meminfo(&read("meminfo")?);
+    let (load, processes) = parse_loadavg(&read("loadavg")?)
+        .ok_or("cannot parse loadavg")?;
+    let io = read("diskstats")
+        .map(|text| parse_diskstats(&text))
+        .unwrap_or_default();
+    let net = read("net/dev")
+        .map(|text| cgroup::parse_net_dev(&text))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(name, _)| !name.starts_with("veth"))
+        .collect();
+    let pids = PidsStats {
+        current: Some(processes),
+        limit: read("sys/kernel/pid_max").ok()
+            .and_then(|text| cgroup::parse_single_value(&text)),
+    };
+
+    let mut filesystems = BTreeMap::new();
+    for mount in mounts {
+        match read_filesystem(mount) {
+            Ok(fs) => { filesystems.insert(mount.display().to_string(), fs); },
+            Err(e) => eprintln!("cannot read filesystem {}: {}", mount.display(), e),
+        }
+    }
+
+    Ok(Stats {
+        time: Some(time::format_time(&std::time::SystemTime::now())),
+        info: Default::default(),
+        cpu,
+        memory,
+        io: IoStats::from_devices(io),
+        net: NetStats::from_interfaces(net),
+        pids,
+        host: Some(HostUsage { load, filesystems }),
+    })
+}
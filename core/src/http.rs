@@ -0,0 +1,366 @@
+// HTTP/1.1 リクエストの読み取り (RFC 9110, 9112)
+//
+// サーバは1接続につき1リクエストだけを処理して接続を閉じるので、
+// パイプライン化されたリクエストや chunked 形式の本文には対応しません
+
+use std::io::{ BufRead, Read, Write };
+
+use super::error;
+
+/// リクエスト行とヘッダの合計サイズの上限 (バイト)
+pub const MAX_HEADER_SIZE: usize = 8 * 1024;
+/// ヘッダの数の上限
+pub const MAX_HEADER_COUNT: usize = 100;
+/// 本文のサイズの上限 (バイト)
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// デコード済みのクエリパラメータ (キー, 値) の組
+pub type Query = Vec<(String, String)>;
+
+/// 読み取ったリクエスト
+///
+/// パスはセグメント毎にパーセントデコードしてあるので、
+/// %2F を含むコンテナ名も1つのセグメントとして扱えます
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// リクエスト行に書かれたままのリクエストターゲット
+    pub target: String,
+    pub http_version: String,
+    /// デコード済みのパスセグメント (空のセグメントは除きます)
+    pub path_segments: Vec<String>,
+    /// デコード済みのクエリパラメータ (出現順)
+    pub query: Query,
+    /// ヘッダ名は小文字に変換してあります
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+impl Request {
+    /// 名前が一致する最初のヘッダの値 (大文字小文字は区別しません)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// リクエストを読み取れなかった理由
+#[derive(Debug)]
+pub enum RequestError {
+    /// 読み取り中の I/O エラー (タイムアウトを含みます)
+    IOError(std::io::Error),
+    /// クライアントに返すべきステータスコードとメッセージ
+    Status(u16, String),
+}
+impl RequestError {
+    fn status(code: u16, message: impl Into<String>) -> Self {
+        RequestError::Status(code, message.into())
+    }
+}
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::IOError(e) => write!(f, "{}", e),
+            RequestError::Status(code, message) =>
+                write!(f, "{} {}: {}", code, reason_phrase(*code), message),
+        }
+    }
+}
+impl From<std::io::Error> for RequestError {
+    fn from(value: std::io::Error) -> Self {
+        RequestError::IOError(value)
+    }
+}
+impl From<RequestError> for error::Error {
+    fn from(value: RequestError) -> Self {
+        match value {
+            RequestError::IOError(e) => error::Error::IOError(e),
+            e => error::Error::OtherError(e.to_string()),
+        }
+    }
+}
+
+/// ステータスコードに対応する理由句
+pub fn reason_phrase(code: u16) -> &'static str {
+    match code {
+        100 => "Continue",
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        417 => "Expectation Failed",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+/// 1行 (LF まで) を読み取り、行末の CRLF または LF を除いて返します
+/// remaining はヘッダに使えるバイト数で、読み取った分だけ減らします
+fn read_line<R: BufRead>(
+    reader: &mut R,
+    remaining: &mut usize,
+) -> Result<Option<Vec<u8>>, RequestError> {
+    let mut line = Vec::new();
+    let nread = reader.by_ref()
+        .take(*remaining as u64)
+        .read_until(b'\n', &mut line)?;
+    if nread == 0 {
+        return Ok(None);
+    }
+    *remaining -= nread;
+    if line.last() != Some(&b'\n') {
+        if *remaining == 0 {
+            return Err(RequestError::status(431, "request header is too large"));
+        }
+        return Err(RequestError::status(400, "unexpected end of request header"));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// パーセントエンコードされた文字列をデコードします
+/// 不正なエスケープやデコード結果が UTF-8 でない場合はエラーを返します
+pub fn percent_decode(s: &str) -> Result<String, error::Error> {
+    let invalid = || error::Error::ParseError(
+        format!("invalid percent-encoding: {}", s)
+    );
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)
+                .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()))
+                .ok_or_else(invalid)?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
+
+/// クエリ文字列を (キー, 値) の組に分けてデコードします
+///
+/// "+" は空白に変換しません
+/// (from=2024-10-18T21:34:56+09:00 のような時刻をそのまま書けるようにするため)
+pub fn parse_query(query: &str) -> Result<Query, error::Error> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((percent_decode(key)?, percent_decode(value)?))
+        })
+        .collect()
+}
+
+/// リクエストターゲットをパスセグメントとクエリに分けます
+///
+/// origin-form ("/containers?last=1h")、absolute-form ("http://host/containers")、
+/// asterisk-form ("*", OPTIONS のみ) を受け付けます
+fn parse_target(
+    method: &str,
+    target: &str,
+) -> Result<(Vec<String>, Query), RequestError> {
+    if target == "*" {
+        if method != "OPTIONS" {
+            return Err(RequestError::status(400, "'*' is only allowed for OPTIONS"));
+        }
+        return Ok((Vec::new(), Vec::new()));
+    }
+    let origin = if target.starts_with('/') {
+        target
+    } else if let Some((scheme, rest)) = target.split_once("://") {
+        if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+            return Err(RequestError::status(400, format!("invalid request target: {}", target)));
+        }
+        rest.find(['/', '?']).map(|i| &rest[i..]).unwrap_or("/")
+    } else {
+        return Err(RequestError::status(400, format!("invalid request target: {}", target)));
+    };
+
+    let origin = origin.split_once('#').map(|(o, _)| o).unwrap_or(origin);
+    let (path, query) = origin.split_once('?').unwrap_or((origin, ""));
+    let bad_request = |e: error::Error| RequestError::status(400, e.to_string());
+    let path_segments = path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(percent_decode)
+        .collect::<Result<Vec<String>, error::Error>>()
+        .map_err(bad_request)?;
+    let query = parse_query(query).map_err(bad_request)?;
+    Ok((path_segments, query))
+}
+
+/// 本文の長さを Content-Length から決めます
+/// 値が不正な場合や、異なる値が複数ある場合はエラーを返します
+fn content_length(headers: &[(String, String)]) -> Result<usize, RequestError> {
+    let mut length = None;
+    for (_, value) in headers.iter().filter(|(n, _)| n == "content-length") {
+        for value in value.split(',').map(|v| v.trim()) {
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(RequestError::status(400, format!("invalid Content-Length: {}", value)));
+            }
+            let value = value.parse::<usize>()
+                .map_err(|_| RequestError::status(413, "request body is too large"))?;
+            if length.is_some_and(|l| l != value) {
+                return Err(RequestError::status(400, "conflicting Content-Length headers"));
+            }
+            length = Some(value);
+        }
+    }
+    Ok(length.unwrap_or(0))
+}
+
+/// reader からリクエストを1つ読み取ります
+///
+/// 何も受け取らずに接続が閉じられた場合は None を返します
+/// "Expect: 100-continue" が付いていれば、本文を読む前に
+/// writer へ "100 Continue" を書き込みます
+pub fn read_request<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<Option<Request>, RequestError> {
+    let mut remaining = MAX_HEADER_SIZE;
+
+    // リクエスト行の前の空行は無視します (RFC 9112 2.2)
+    let request_line = loop {
+        match read_line(reader, &mut remaining)? {
+            None => return Ok(None),
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+        }
+    };
+    let request_line = String::from_utf8(request_line)
+        .map_err(|_| RequestError::status(400, "request line is not valid UTF-8"))?;
+    let (method, target, http_version) = match request_line.split(' ').collect::<Vec<&str>>()[..] {
+        [method, target, http_version]
+            if !method.is_empty() && method.bytes().all(is_token_char) && !target.is_empty() =>
+            (method.to_string(), target.to_string(), http_version.to_string()),
+        _ => return Err(RequestError::status(400, format!("invalid request line: {}", request_line))),
+    };
+    match http_version.as_str() {
+        "HTTP/1.1" | "HTTP/1.0" => {},
+        v if v.starts_with("HTTP/") =>
+            return Err(RequestError::status(505, format!("unsupported version: {}", v))),
+        v => return Err(RequestError::status(400, format!("invalid version: {}", v))),
+    }
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader, &mut remaining)?
+            .ok_or_else(|| RequestError::status(400, "unexpected end of request header"))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() >= MAX_HEADER_COUNT {
+            return Err(RequestError::status(431, "too many header fields"));
+        }
+        if line[0] == b' ' || line[0] == b'\t' {
+            return Err(RequestError::status(400, "obsolete line folding is not allowed"));
+        }
+        let line = String::from_utf8_lossy(&line);
+        let (name, value) = line.split_once(':')
+            .filter(|(name, _)| !name.is_empty() && name.bytes().all(is_token_char))
+            .ok_or_else(|| RequestError::status(400, format!("invalid header field: {}", line)))?;
+        headers.push((
+            name.to_ascii_lowercase(),
+            value.trim_matches([' ', '\t']).to_string(),
+        ));
+    }
+
+    if http_version == "HTTP/1.1"
+        && headers.iter().filter(|(n, _)| n == "host").count() != 1
+    {
+        return Err(RequestError::status(400, "exactly one Host header is required"));
+    }
+    let (path_segments, query) = parse_target(&method, &target)?;
+    if headers.iter().any(|(n, _)| n == "transfer-encoding") {
+        return Err(RequestError::status(501, "Transfer-Encoding is not supported"));
+    }
+    let length = content_length(&headers)?;
+    if length > MAX_BODY_SIZE {
+        return Err(RequestError::status(413, "request body is too large"));
+    }
+    if let Some((_, expect)) = headers.iter().find(|(n, _)| n == "expect") {
+        if !expect.eq_ignore_ascii_case("100-continue") {
+            return Err(RequestError::status(417, format!("unsupported expectation: {}", expect)));
+        }
+        // HTTP/1.0 のクライアントは 100 Continue を理解しません
+        if http_version == "HTTP/1.1" && length > 0 {
+            writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            writer.flush()?;
+        }
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof =>
+            RequestError::status(400, "request body is shorter than Content-Length"),
+        _ => RequestError::IOError(e),
+    })?;
+
+    Ok(Some(Request {
+        method,
+        target,
+        http_version,
+        path_segments,
+        query,
+        headers,
+        body,
+    }))
+}
+
+/// HEAD リクエスト用に、ヘッダの終わり ("\r\n\r\n") より後を捨てる Write
+///
+/// GET と同じルートを使って、Content-Length などは GET と同じ値を返せます
+pub struct HeadWriter<W: Write> {
+    inner: W,
+    /// 直近に書き込んだ最大4バイト
+    tail: Vec<u8>,
+    header_done: bool,
+}
+impl<W: Write> HeadWriter<W> {
+    pub fn new(inner: W) -> Self {
+        HeadWriter { inner, tail: Vec::with_capacity(4), header_done: false }
+    }
+}
+impl<W: Write> Write for HeadWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.header_done {
+            return Ok(buf.len());
+        }
+        for (i, b) in buf.iter().enumerate() {
+            if self.tail.len() == 4 {
+                self.tail.remove(0);
+            }
+            self.tail.push(*b);
+            if self.tail == b"\r\n\r\n" {
+                self.header_done = true;
+                self.inner.write_all(&buf[..=i])?;
+                return Ok(buf.len());
+            }
+        }
+        self.inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,22 @@
+pub mod broadcast;
+pub mod cgroup;
+pub mod config;
+pub mod downsample;
+pub mod error;
+pub mod events;
+pub mod host;
+pub mod http;
+pub mod log;
+pub mod log_cache;
+pub mod log_rotation;
+pub mod metrics;
+pub mod server;
+pub mod store;
+pub mod supervisor;
+pub mod thread_pool;
+pub mod time;
+
+#[cfg(test)]
+extern crate self as cephylas;
+#[cfg(test)]
+mod tests;
@@ -1,64 +1,316 @@
 
-use std::collections::HashMap;
+use std::collections::{ BTreeMap, HashMap, };
 
-use json;
+use super::broadcast;
+use super::cgroup;
+use super::config;
 use super::error;
+use super::host;
 use super::log_cache;
-
-const DAILY_LOG_PATH: &str = "./log/log_daily";
+use super::log_rotation;
+use super::store;
+use super::supervisor;
+use super::thread_pool;
+use super::time;
 
 const DOCKER_API_CONTAINERS: &str = "/containers/json";
 const DOCKER_API_STATS: &str = "/containers/{}/stats?stream=false&one-shot=true";
+const DOCKER_API_INSPECT: &str = "/containers/{}/json";
+/// 使用状況の取得を待たせておけるコンテナの数
+/// (超えたコンテナはその tick の取得に失敗したものとして扱います)
+const COLLECTOR_QUEUE_LENGTH: usize = 1024;
 
 //
 // resource usage data structures
 //
-#[derive(Debug)]
+/// CPUの累積値 (時間はミリ秒)
+#[derive(Debug, Clone, Default)]
 pub struct CpuStats {
-    total: Option<u64>,
-    system: Option<u64>,
-    ncpu: Option<u8>, // more than 256 cores??
+    pub total: Option<u64>,
+    pub system: Option<u64>,
+    pub ncpu: Option<u8>, // more than 256 cores??
+    /// ユーザーモードの累積時間
+    pub user: Option<u64>,
+    /// カーネルモードの累積時間
+    pub kernel: Option<u64>,
+    /// CFS の制御期間の数 (cpus の制限が無ければ 0)
+    pub periods: Option<u64>,
+    /// 制限に達して待たされた期間の数
+    pub throttled_periods: Option<u64>,
+    /// 制限に達して待たされた累積時間
+    pub throttled_time: Option<u64>,
+    /// CPU毎の累積時間 (cgroup v1 の場合のみ取得できます)
+    pub percpu: Vec<u64>,
 }
-#[derive(Debug)]
+/// メモリの内訳 (rss から slab まではバイト、pgfault 以降は累積回数)
+///
+/// cgroup v1 と v2 で memory.stat のキー名が異なるので from_memory_stat で吸収します
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryBreakdown {
+    /// 匿名メモリ (v1: rss, v2: anon)
+    pub rss: Option<u64>,
+    /// ページキャッシュ (v1: cache, v2: file)
+    pub cache: Option<u64>,
+    pub active_file: Option<u64>,
+    pub inactive_file: Option<u64>,
+    pub shmem: Option<u64>,
+    pub swap: Option<u64>,
+    pub kernel_stack: Option<u64>,
+    pub slab: Option<u64>,
+    pub pgfault: Option<u64>,
+    pub pgmajfault: Option<u64>,
+    /// 使用量が上限に達した回数 (v1: memory.failcnt, v2: memory.events の max)
+    pub failcnt: Option<u64>,
+}
+impl MemoryBreakdown {
+    /// ログや /containers/{name}/memory/{field} で使うフィールド名
+    pub const FIELDS: &'static [&'static str] = &[
+        "rss", "cache", "activeFile", "inactiveFile", "shmem", "swap",
+        "kernelStack", "slab", "pgfault", "pgmajfault", "failcnt",
+    ];
+
+    /// memory.stat のキーから値を取り出す関数を受け取り、内訳を作ります
+    /// (swap と failcnt は v1 の場合のみ memory.stat から読めます)
+    pub fn from_memory_stat<F: Fn(&str) -> Option<u64>>(stat: F) -> Self {
+        MemoryBreakdown {
+            rss: stat("rss").or_else(|| stat("anon")),
+            cache: stat("cache").or_else(|| stat("file")),
+            active_file: stat("active_file"),
+            inactive_file: stat("inactive_file"),
+            shmem: stat("shmem"),
+            swap: stat("swap"),
+            kernel_stack: stat("kernel_stack"),
+            slab: stat("slab"),
+            pgfault: stat("pgfault"),
+            pgmajfault: stat("pgmajfault"),
+            failcnt: None,
+        }
+    }
+
+    /// FIELDS のフィールド名に対応する値 (未知のフィールド名なら None)
+    pub fn field(&self, name: &str) -> Option<Option<u64>> {
+        Some(match name {
+            "rss" => self.rss,
+            "cache" => self.cache,
+            "activeFile" => self.active_file,
+            "inactiveFile" => self.inactive_file,
+            "shmem" => self.shmem,
+            "swap" => self.swap,
+            "kernelStack" => self.kernel_stack,
+            "slab" => self.slab,
+            "pgfault" => self.pgfault,
+            "pgmajfault" => self.pgmajfault,
+            "failcnt" => self.failcnt,
+            _ => return None,
+        })
+    }
+
+    fn field_mut(&mut self, name: &str) -> Option<&mut Option<u64>> {
+        Some(match name {
+            "rss" => &mut self.rss,
+            "cache" => &mut self.cache,
+            "activeFile" => &mut self.active_file,
+            "inactiveFile" => &mut self.inactive_file,
+            "shmem" => &mut self.shmem,
+            "swap" => &mut self.swap,
+            "kernelStack" => &mut self.kernel_stack,
+            "slab" => &mut self.slab,
+            "pgfault" => &mut self.pgfault,
+            "pgmajfault" => &mut self.pgmajfault,
+            "failcnt" => &mut self.failcnt,
+            _ => return None,
+        })
+    }
+
+    /// FIELDS の各フィールドについて f が返した値を設定します
+    pub fn from_fields<F: Fn(&str) -> Option<u64>>(f: F) -> Self {
+        let mut breakdown = MemoryBreakdown::default();
+        for name in Self::FIELDS {
+            if let Some(field) = breakdown.field_mut(name) {
+                *field = f(name);
+            }
+        }
+        breakdown
+    }
+
+    /// ,"rss":..,"cache":.. のように json オブジェクトの続きとして出力します
+    fn json_fields(&self) -> String {
+        Self::FIELDS.iter()
+            .map(|name| format!(
+                ",\"{}\":{}",
+                name,
+                option_to_string(self.field(name).flatten()),
+            ))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct MemoryStats {
-    used: Option<u64>,
-    available: Option<u64>,
+    pub used: Option<u64>,
+    pub available: Option<u64>,
+    pub breakdown: MemoryBreakdown,
 }
-#[derive(Debug)]
+/// ブロックデバイスIOの累積値 (バイト数と回数)
+///
+/// コンテナの値は全デバイスの合計で、devices に "major:minor" 毎の
+/// 値を持ちます (デバイス毎の値の devices は空です)
+#[derive(Debug, Clone, Default)]
 pub struct IoStats {
-    read: Option<u64>,
-    write: Option<u64>,
+    pub read: Option<u64>,
+    pub write: Option<u64>,
+    pub read_ops: Option<u64>,
+    pub write_ops: Option<u64>,
+    pub devices: BTreeMap<String, IoStats>,
 }
-#[derive(Debug)]
+impl IoStats {
+    /// デバイス毎の値から合計を計算します
+    /// (どのデバイスにも値が無いフィールドは None)
+    pub fn from_devices(devices: BTreeMap<String, IoStats>) -> Self {
+        let sum = |f: fn(&IoStats) -> Option<u64>| devices.values()
+            .filter_map(f)
+            .reduce(|a, b| a.saturating_add(b));
+        IoStats {
+            read: sum(|d| d.read),
+            write: sum(|d| d.write),
+            read_ops: sum(|d| d.read_ops),
+            write_ops: sum(|d| d.write_ops),
+            devices,
+        }
+    }
+}
+/// ネットワークの累積値
+///
+/// コンテナの値は全インターフェースの合計で、interfaces にインターフェース毎の
+/// 値を持ちます (インターフェース毎の値の interfaces は空です)
+#[derive(Debug, Clone, Default)]
 pub struct NetStats {
-    send: Option<u64>,
-    recv: Option<u64>,
+    pub send: Option<u64>,
+    pub recv: Option<u64>,
+    pub send_packets: Option<u64>,
+    pub recv_packets: Option<u64>,
+    pub send_errors: Option<u64>,
+    pub recv_errors: Option<u64>,
+    pub send_dropped: Option<u64>,
+    pub recv_dropped: Option<u64>,
+    pub interfaces: BTreeMap<String, NetStats>,
 }
-#[derive(Debug)]
+impl NetStats {
+    /// インターフェース毎の値から合計を計算します
+    /// (どのインターフェースにも値が無いフィールドは None)
+    pub fn from_interfaces(interfaces: BTreeMap<String, NetStats>) -> Self {
+        let sum = |f: fn(&NetStats) -> Option<u64>| interfaces.values()
+            .filter_map(f)
+            .reduce(|a, b| a.saturating_add(b));
+        NetStats {
+            send: sum(|n| n.send),
+            recv: sum(|n| n.recv),
+            send_packets: sum(|n| n.send_packets),
+            recv_packets: sum(|n| n.recv_packets),
+            send_errors: sum(|n| n.send_errors),
+            recv_errors: sum(|n| n.recv_errors),
+            send_dropped: sum(|n| n.send_dropped),
+            recv_dropped: sum(|n| n.recv_dropped),
+            interfaces,
+        }
+    }
+}
+/// プロセス (スレッド) 数
+#[derive(Debug, Clone, Default)]
+pub struct PidsStats {
+    pub current: Option<u64>,
+    /// 上限 (制限が無ければ None)
+    pub limit: Option<u64>,
+}
+/// コンテナの識別情報 (Docker API の /containers/json)
+///
+/// 同じ名前でコンテナが作り直されたり、名前が変更されたりしても
+/// ID で区別できるように使用状況と一緒に記録します
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContainerInfo {
+    pub id: String,
+    pub image: String,
+    /// 作成日時 (UNIX時間, 秒)
+    pub created: Option<i64>,
+    /// 設定 log.labels で指定したキーのラベルのみ (空ならログに出力しません)
+    pub labels: BTreeMap<String, String>,
+}
+impl ContainerInfo {
+    /// "id":..,"image":..,"created":..,"labels":{..} のように json オブジェクトの中身として出力します
+    pub fn json_fields(&self) -> String {
+        let mut fields = format!(
+            "\"id\":{},\"image\":{},\"created\":{}",
+            json::stringify(self.id.as_str()),
+            json::stringify(self.image.as_str()),
+            self.created
+                .map_or("null".to_string(), |c| format!("\"{}\"", time::format_epoch_seconds(c))),
+        );
+        if !self.labels.is_empty() {
+            fields += &format!(",\"labels\":{}", labels_to_string(&self.labels));
+        }
+        fields
+    }
+    /// ログの1コンテナ分の json から読みます ("id" が無ければ None)
+    pub fn from_json(json: &json::JsonValue) -> Option<Self> {
+        Some(ContainerInfo {
+            id: json["id"].as_str()?.to_string(),
+            image: json["image"].as_str().unwrap_or_default().to_string(),
+            created: json["created"].as_str()
+                .and_then(|c| time::parse_epoch_seconds(c).ok()),
+            labels: json_to_labels(&json["labels"]),
+        })
+    }
+}
+#[derive(Debug, Clone)]
 pub struct Stats {
-    time: Option<String>,
-    cpu: CpuStats,
-    memory: MemoryStats,
-    io: IoStats,
-    net: NetStats,
+    pub time: Option<String>,
+    /// Docker API から取得できなければ空です
+    pub info: ContainerInfo,
+    pub cpu: CpuStats,
+    pub memory: MemoryStats,
+    pub io: IoStats,
+    pub net: NetStats,
+    pub pids: PidsStats,
+    /// ホスト (host::HOST_NAME) のみの値
+    pub host: Option<host::HostUsage>,
 }
 impl Default for Stats {
     fn default() -> Self {
         Stats {
             time: None,
-            cpu: CpuStats { total: None, system: None, ncpu: None, },
-            memory: MemoryStats { used: None, available: None, },
-            io: IoStats { read: None, write: None },
-            net: NetStats { recv: None, send: None },
+            info: ContainerInfo::default(),
+            cpu: CpuStats::default(),
+            memory: MemoryStats {
+                used: None,
+                available: None,
+                breakdown: MemoryBreakdown::default(),
+            },
+            io: IoStats::default(),
+            net: NetStats::default(),
+            pids: PidsStats::default(),
+            host: None,
         }
     }
 }
-#[derive(Debug)]
+/// CPUの使用状況
+///
+/// percentage と throttle_ratio, percpu 以外は前の tick からの差分 (ミリ秒, 回数) です
+/// percpu は空ならログに出力しません
+#[derive(Debug, Clone, Default)]
 pub struct CpuUsage {
-    percentage: Option<f32>,
-    total: Option<u64>,
-    system: Option<u64>,
-    ncpu: Option<u8>,
+    pub percentage: Option<f32>,
+    pub total: Option<u64>,
+    pub system: Option<u64>,
+    pub ncpu: Option<u8>,
+    pub user: Option<u64>,
+    pub kernel: Option<u64>,
+    pub periods: Option<u64>,
+    pub throttled_periods: Option<u64>,
+    pub throttled_time: Option<u64>,
+    /// 制御期間のうち制限に達した期間の割合 (%)
+    pub throttle_ratio: Option<f32>,
+    /// CPU毎の使用率 (%)
+    pub percpu: Vec<f32>,
 }
 pub fn option_to_string<T>(value: Option<T>) -> String 
 where
@@ -71,115 +323,211 @@ where
         None => "null".to_string(),
     }
 }
+/// CPU毎の使用率を [1.00,2.00] の形式で出力します
+pub fn percpu_to_string(percpu: &[f32]) -> String {
+    let values = percpu.iter()
+        .map(|p| format!("{:.2}", p))
+        .collect::<Vec<String>>()
+        .join(",");
+    format!("[{}]", values)
+}
+/// ラベルを {"key":"value",..} の形式で出力します
+pub fn labels_to_string(labels: &BTreeMap<String, String>) -> String {
+    let entries = labels.iter()
+        .map(|(key, value)| format!(
+            "{}:{}", json::stringify(key.as_str()), json::stringify(value.as_str())
+        ))
+        .collect::<Vec<String>>()
+        .join(",");
+    format!("{{{}}}", entries)
+}
+/// {"key":"value",..} からラベルを読みます (文字列以外の値は無視します)
+fn json_to_labels(json: &json::JsonValue) -> BTreeMap<String, String> {
+    json.entries()
+        .filter_map(|(key, value)| Some((key.to_string(), value.as_str()?.to_string())))
+        .collect()
+}
 impl std::fmt::Display for CpuUsage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{{\"percentage\":{0},\"total\":{1},\"system\":{2},\"ncpu\":{3}}}",
+            "{{\"percentage\":{0},\"total\":{1},\"system\":{2},\"ncpu\":{3},\
+             \"user\":{4},\"kernel\":{5},\"periods\":{6},\"throttledPeriods\":{7},\
+             \"throttledTime\":{8},\"throttleRatio\":{9}",
             option_to_string(self.percentage),
             option_to_string(self.total),
             option_to_string(self.system),
             option_to_string(self.ncpu),
-        )
-    }
-}
-impl Default for CpuUsage {
-    fn default() -> Self {
-        CpuUsage { percentage: None, total: None, system: None, ncpu: None }
+            option_to_string(self.user),
+            option_to_string(self.kernel),
+            option_to_string(self.periods),
+            option_to_string(self.throttled_periods),
+            option_to_string(self.throttled_time),
+            option_to_string(self.throttle_ratio),
+        )?;
+        if !self.percpu.is_empty() {
+            write!(f, ",\"percpu\":{}", percpu_to_string(&self.percpu))?;
+        }
+        write!(f, "}}")
     }
 }
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct MemoryUsage {
-    percentage: Option<f32>,
-    used: Option<u64>,
-    available: Option<u64>,
+    pub percentage: Option<f32>,
+    pub used: Option<u64>,
+    pub available: Option<u64>,
+    pub breakdown: MemoryBreakdown,
 }
 impl std::fmt::Display for MemoryUsage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{{\"percentage\":{0},\"used\":{1},\"available\":{2}}}",
+            "{{\"percentage\":{0},\"used\":{1},\"available\":{2}{3}}}",
             option_to_string(self.percentage),
             option_to_string(self.used),
             option_to_string(self.available),
+            self.breakdown.json_fields(),
         )
     }
 }
-impl Default for MemoryUsage {
-    fn default() -> Self {
-        MemoryUsage { percentage: None, used: None, available: None }
-    }
-}
+/// ブロックデバイスIOの使用状況
+///
+/// devices は "major:minor" 毎の値で、空ならログに出力しません
 #[allow(non_snake_case)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct IoUsage {
-    readkB: Option<u64>,
-    writekB: Option<u64>,
-    readkBps: Option<u32>,
-    writekBps: Option<u32>,
+    pub readkB: Option<u64>,
+    pub writekB: Option<u64>,
+    pub readkBps: Option<u32>,
+    pub writekBps: Option<u32>,
+    pub readIops: Option<u32>,
+    pub writeIops: Option<u32>,
+    pub devices: BTreeMap<String, IoUsage>,
 }
 impl std::fmt::Display for IoUsage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{{\"readkB\":{0},\"writekB\":{1},\"readkBps\":{2},\"writekBps\":{3}}}",
+            "{{\"readkB\":{0},\"writekB\":{1},\"readkBps\":{2},\"writekBps\":{3},\
+             \"readIops\":{4},\"writeIops\":{5}",
             option_to_string(self.readkB),
             option_to_string(self.writekB),
             option_to_string(self.readkBps),
             option_to_string(self.writekBps),
-        )
-    }
-}
-impl Default for IoUsage {
-    fn default() -> Self {
-        IoUsage { readkB: None, writekB: None, readkBps: None, writekBps: None }
+            option_to_string(self.readIops),
+            option_to_string(self.writeIops),
+        )?;
+        if !self.devices.is_empty() {
+            let devices = self.devices.iter()
+                .map(|(name, usage)| format!("{}:{}", json::stringify(name.as_str()), usage))
+                .collect::<Vec<String>>()
+                .join(",");
+            write!(f, ",\"devices\":{{{}}}", devices)?;
+        }
+        write!(f, "}}")
     }
 }
+/// ネットワークの使用状況
+///
+/// パケット数・エラー数・破棄数は累積値です
+/// interfaces はインターフェース毎の値で、空ならログに出力しません
 #[allow(non_snake_case)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct NetUsage {
-    recvkB: Option<u64>,
-    sendkB: Option<u64>,
-    recvkBps: Option<u32>,
-    sendkBps: Option<u32>,
+    pub recvkB: Option<u64>,
+    pub sendkB: Option<u64>,
+    pub recvkBps: Option<u32>,
+    pub sendkBps: Option<u32>,
+    pub recvPackets: Option<u64>,
+    pub sendPackets: Option<u64>,
+    pub recvErrors: Option<u64>,
+    pub sendErrors: Option<u64>,
+    pub recvDropped: Option<u64>,
+    pub sendDropped: Option<u64>,
+    pub interfaces: BTreeMap<String, NetUsage>,
 }
 impl std::fmt::Display for NetUsage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{{\"recvkB\":{0},\"sendkB\":{1},\"recvkBps\":{2},\"sendkBps\":{3}}}",
+            "{{\"recvkB\":{0},\"sendkB\":{1},\"recvkBps\":{2},\"sendkBps\":{3},\
+             \"recvPackets\":{4},\"sendPackets\":{5},\"recvErrors\":{6},\"sendErrors\":{7},\
+             \"recvDropped\":{8},\"sendDropped\":{9}",
             option_to_string(self.recvkB),
             option_to_string(self.sendkB),
             option_to_string(self.recvkBps),
             option_to_string(self.sendkBps),
-        )
+            option_to_string(self.recvPackets),
+            option_to_string(self.sendPackets),
+            option_to_string(self.recvErrors),
+            option_to_string(self.sendErrors),
+            option_to_string(self.recvDropped),
+            option_to_string(self.sendDropped),
+        )?;
+        if !self.interfaces.is_empty() {
+            let interfaces = self.interfaces.iter()
+                .map(|(name, usage)| format!("{}:{}", json::stringify(name.as_str()), usage))
+                .collect::<Vec<String>>()
+                .join(",");
+            write!(f, ",\"interfaces\":{{{}}}", interfaces)?;
+        }
+        write!(f, "}}")
     }
 }
-impl Default for NetUsage {
-    fn default() -> Self {
-        NetUsage { recvkB: None, sendkB: None, recvkBps: None, sendkBps: None }
+/// プロセス (スレッド) 数の使用状況
+/// percentage は上限に対する割合で、上限が無ければ None です
+#[derive(Debug, Clone, Default)]
+pub struct PidsUsage {
+    pub percentage: Option<f32>,
+    pub current: Option<u64>,
+    pub limit: Option<u64>,
+}
+impl std::fmt::Display for PidsUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{\"percentage\":{0},\"current\":{1},\"limit\":{2}}}",
+            option_to_string(self.percentage),
+            option_to_string(self.current),
+            option_to_string(self.limit),
+        )
     }
 }
-#[derive(Debug)]
+/// コンテナ1つ分の使用状況
+/// info は識別情報を記録する前のログから読んだ場合は None です
+#[derive(Debug, Clone, Default)]
 pub struct Usage {
-    cpu: CpuUsage,
-    memory: MemoryUsage,
-    io: IoUsage,
-    net: NetUsage,
+    pub info: Option<ContainerInfo>,
+    pub cpu: CpuUsage,
+    pub memory: MemoryUsage,
+    pub io: IoUsage,
+    pub net: NetUsage,
+    pub pids: PidsUsage,
+    /// ホスト (host::HOST_NAME) のみの値
+    pub host: Option<host::HostUsage>,
 }
 impl std::fmt::Display for Usage {
     fn fmt(
         &self, 
         f: &mut std::fmt::Formatter<'_>
     ) -> std::fmt::Result {
+        write!(f, "{{")?;
+        if let Some(info) = &self.info {
+            write!(f, "{},", info.json_fields())?;
+        }
         write!(
             f,
-            "{{\"cpu\":{cpu},\"memory\":{memory},\"io\":{io},\"net\":{net}}}",
+            "\"cpu\":{cpu},\"memory\":{memory},\"io\":{io},\"net\":{net},\"pids\":{pids}",
             cpu = self.cpu,
             memory = self.memory,
             io = self.io,
             net = self.net,
-        )
+            pids = self.pids,
+        )?;
+        if let Some(host) = &self.host {
+            write!(f, ",{}", host.json_fields())?;
+        }
+        write!(f, "}}")
     }
 }
 #[allow(non_snake_case)]
@@ -210,15 +558,20 @@ impl std::fmt::Display for Usages {
     }
 }
 
+/// Docker API を呼び出し、ヘッダを含むレスポンス全体を返します
+/// timeout は読み書き毎のタイムアウトです (応答しないコンテナで止まらないようにします)
 fn call_docker_api<
     P: AsRef<std::path::Path>,
     S: AsRef<str>,
 >(
     socket_path: P,
     url: S,
-) -> Result<String, std::io::Error> {
-    let mut stream = 
-        std::os::unix::net::UnixStream::connect(socket_path)?;
+    timeout: std::time::Duration,
+) -> Result<String, error::Error> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket_path)
+        .map_err(error::Error::DockerUnreachable)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
     let request = format!(
         "GET {} HTTP/1.1\r\n\
          Host: localhost\r\n\
@@ -232,14 +585,41 @@ fn call_docker_api<
     let mut response = String::new();
     std::io::Read::read_to_string(&mut stream, &mut response)?;
 
+    // 再起動中などで応答せずに切られた場合
+    if response.is_empty() {
+        return Err(error::Error::DockerUnreachable(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "connection closed without response",
+        )));
+    }
+    let status = response.lines().next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| error::Error::ParseError(
+            "docker returned no status line".to_string()
+        ))?;
+    if status != 200 {
+        let message = response.split_once("\r\n\r\n")
+            .map(|(_, body)| body.trim())
+            .unwrap_or_default();
+        return Err(error::Error::DockerApiError {
+            status,
+            message: json::parse(message).ok()
+                .and_then(|body| body["message"].as_str().map(str::to_string))
+                .unwrap_or_else(|| message.to_string()),
+        });
+    }
+
     Ok(response)
 }
 
-fn get_container_names<T: AsRef<std::path::Path>>(
-    socket_path: T,
-) -> Result<Vec<String>, error::Error> {
+/// 実行中のコンテナの (名前, 識別情報) を返します
+/// ラベルは設定 log.labels に含まれるキーのもののみ残します
+fn get_containers(
+    config: &config::Config,
+) -> Result<Vec<(String, ContainerInfo)>, error::Error> {
     let response = call_docker_api(
-        socket_path, DOCKER_API_CONTAINERS,
+        &config.docker_socket, DOCKER_API_CONTAINERS, config.collector_timeout,
     )?;
     let mut lines = response.lines();
     let body = lines.find(|l| l.starts_with('['))
@@ -248,23 +628,51 @@ fn get_container_names<T: AsRef<std::path::Path>>(
     
     let members = json_body.members();
     let mut failed_to_get_name = false;
-    let container_names: Vec<String> = members.map(
-        |ref m| m["Names"][0].as_str()
-            .unwrap_or_else(|| {
-                failed_to_get_name = true;
-                ""
-            })
-            .replace("/", "")
-            .to_string()
+    let containers: Vec<(String, ContainerInfo)> = members.map(
+        |m| (
+            m["Names"][0].as_str()
+                .unwrap_or_else(|| {
+                    failed_to_get_name = true;
+                    ""
+                })
+                .replace("/", "")
+                .to_string(),
+            ContainerInfo {
+                id: m["Id"].as_str().unwrap_or_default().to_string(),
+                image: m["Image"].as_str().unwrap_or_default().to_string(),
+                created: m["Created"].as_i64(),
+                labels: json_to_labels(&m["Labels"]).into_iter()
+                    .filter(|(key, _)| config.labels.contains(key))
+                    .collect(),
+            },
+        )
     ).collect();
 
     if failed_to_get_name {
-        return Err(error::Error::OtherError(
+        return Err(error::Error::ParseError(
             "failed to get container name.".to_string()
         ));
     }
 
-    Ok(container_names)
+    Ok(containers)
+}
+
+/// コンテナのメインプロセスのPID (ホストのPID名前空間)
+fn get_container_pid(
+    config: &config::Config,
+    container_id: &str,
+) -> Result<u32, error::Error> {
+    let response = call_docker_api(
+        &config.docker_socket,
+        DOCKER_API_INSPECT.replace("{}", container_id),
+        config.collector_timeout,
+    )?;
+    let body = response.lines()
+        .find(|l| l.starts_with('{'))
+        .ok_or("cannot find response json body")?;
+    json::parse(body)?["State"]["Pid"].as_u32()
+        .filter(|pid| *pid != 0)
+        .ok_or(format!("container {} has no process", container_id).into())
 }
 
 fn get_container_stats<
@@ -273,10 +681,12 @@ fn get_container_stats<
 >(
     socket_path: T,
     container_name: U,
+    timeout: std::time::Duration,
 ) -> Result<Stats, error::Error> {
     let response = call_docker_api(
         socket_path, 
         DOCKER_API_STATS.replace("{}", container_name.as_ref()),
+        timeout,
     )?;
     let stats_data = response.lines()
         .find(|l| l.starts_with('{'))
@@ -302,80 +712,218 @@ fn reshape_json(
         json["cpu_stats"]["online_cpus"]
         .as_u16()
         .map(|n| n as u8);
+    let ns_to_ms = |v: &json::JsonValue| v.as_u64().map(|v| v / 1_000_000);
+    let cpu_usage = &json["cpu_stats"]["cpu_usage"];
+    let throttling = &json["cpu_stats"]["throttling_data"];
+    // percpu_usage は cgroup v2 では返されません
+    let percpu = cpu_usage["percpu_usage"].members()
+        .filter_map(|v| v.as_u64())
+        .map(|v| v / 1_000_000)
+        .collect::<Vec<u64>>();
     let used_memory =
         json["memory_stats"]["usage"].as_u64()
         .zip(
             json["memory_stats"]["stats"]["cache"]
                 .as_u64()
+                // cgroup v2 には cache が無いので、Docker CLI と同様に inactive_file を使います
+                .or(json["memory_stats"]["stats"]["inactive_file"].as_u64())
                 .or(Some(0_u64)) // cache entry might not exist
         ).map(|(a, b)| a.saturating_sub(b));
+    let memory_breakdown = MemoryBreakdown {
+        failcnt: json["memory_stats"]["failcnt"].as_u64(),
+        ..MemoryBreakdown::from_memory_stat(
+            |key| json["memory_stats"]["stats"][key].as_u64()
+        )
+    };
     let available_memory =
         json["memory_stats"]["limit"]
         .as_u64();
-    let net_rx =
-        json["networks"]["eth0"]["rx_bytes"]
-        .as_u64();
-    let net_tx =
-        json["networks"]["eth0"]["tx_bytes"]
-        .as_u64();
-    let blkio_read =
-        json["blkio_stats"]["io_service_bytes_recursive"]
-        .members()
-        .find(|ref m| m["op"] == "read")
-        .and_then(|v| v["value"].as_u64());
-    let blkio_write =
-        json["blkio_stats"]["io_service_bytes_recursive"]
-        .members()
-        .find(|ref m| m["op"] == "write")
-        .and_then(|v| v["value"].as_u64());
+    // host ネットワークなど networks が無い場合は全て None になります
+    let interfaces = json["networks"].entries()
+        .map(|(name, n)| (name.to_string(), NetStats {
+            recv: n["rx_bytes"].as_u64(),
+            send: n["tx_bytes"].as_u64(),
+            recv_packets: n["rx_packets"].as_u64(),
+            send_packets: n["tx_packets"].as_u64(),
+            recv_errors: n["rx_errors"].as_u64(),
+            send_errors: n["tx_errors"].as_u64(),
+            recv_dropped: n["rx_dropped"].as_u64(),
+            send_dropped: n["tx_dropped"].as_u64(),
+            ..Default::default()
+        }))
+        .collect::<BTreeMap<String, NetStats>>();
+    // デバイス ("major:minor") 毎に集計します
+    // op は cgroup v1 では "Read"/"Write"、v2 では "read"/"write" です
+    let mut devices = BTreeMap::<String, IoStats>::new();
+    for (key, is_bytes) in [
+        ("io_service_bytes_recursive", true),
+        ("io_serviced_recursive", false),
+    ] {
+        for entry in json["blkio_stats"][key].members() {
+            let (Some(major), Some(minor), Some(op), Some(value)) = (
+                entry["major"].as_u64(),
+                entry["minor"].as_u64(),
+                entry["op"].as_str(),
+                entry["value"].as_u64(),
+            ) else { continue };
+            let device = devices.entry(format!("{}:{}", major, minor)).or_default();
+            let field = match (op.to_ascii_lowercase().as_str(), is_bytes) {
+                ("read", true) => &mut device.read,
+                ("write", true) => &mut device.write,
+                ("read", false) => &mut device.read_ops,
+                ("write", false) => &mut device.write_ops,
+                _ => continue,
+            };
+            *field.get_or_insert(0) += value;
+        }
+    }
+    // 制限が無い場合 limit は返されないか、u64 の最大値になります
+    let pids = PidsStats {
+        current: json["pids_stats"]["current"].as_u64(),
+        limit: json["pids_stats"]["limit"].as_u64()
+            .filter(|limit| *limit != 0 && *limit != u64::MAX),
+    };
     let time = json["read"].as_str();
 
     Stats {
         time: time.map(|s| s.to_string()), 
+        info: ContainerInfo {
+            id: json["id"].as_str().unwrap_or_default().to_string(),
+            ..Default::default()
+        },
         cpu: CpuStats { 
             total, system, 
-            ncpu: number_cpus
+            ncpu: number_cpus,
+            user: ns_to_ms(&cpu_usage["usage_in_usermode"]),
+            kernel: ns_to_ms(&cpu_usage["usage_in_kernelmode"]),
+            periods: throttling["periods"].as_u64(),
+            throttled_periods: throttling["throttled_periods"].as_u64(),
+            throttled_time: ns_to_ms(&throttling["throttled_time"]),
+            percpu,
         },
         memory: MemoryStats {
             used: used_memory, 
-            available: available_memory
-        },
-        io: IoStats {
-            read: blkio_read,
-            write: blkio_write, 
+            available: available_memory,
+            breakdown: memory_breakdown,
         },
-        net: NetStats {
-            send: net_tx,
-            recv: net_rx,
-        }
+        io: IoStats::from_devices(devices),
+        net: NetStats::from_interfaces(interfaces),
+        pids,
+        host: None,
     }
 }
 
-fn get_containers_stats<T: AsRef<std::path::Path>>(
-    socket_path: T,
+/// 全コンテナの使用状況を pool のスレッドで並列に取得し、
+/// 取得できたコンテナの累積値と、取得に失敗したコンテナの数を返します
+///
+/// 失敗したコンテナ (エラー、タイムアウト、時刻が取得できない) はログに出力して除くので、
+/// そのコンテナだけがこの tick と次の tick で欠測になります
+/// コンテナの一覧が取得できなければエラーを返します
+pub fn get_containers_stats(
+    config: &config::Config,
+    pool: &thread_pool::ThreadPool,
 ) -> Result<
-    HashMap<String, Stats>, 
+    (HashMap<String, Stats>, usize),
     error::Error
 > {
-    let container_names = get_container_names(&socket_path)?;
+    let containers = get_containers(config)?;
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut failures = 0;
+    for (container_name, info) in containers {
+        let sender = sender.clone();
+        let socket_path = config.docker_socket.clone();
+        let timeout = config.collector_timeout;
+        let job_container_name = container_name.clone();
+        let queued = pool.execute(move || {
+            // in one-shot mode, pre-stats are not available.
+            // we have to take diff by ourselves
+            let result = get_container_stats(&socket_path, &job_container_name, timeout);
+            let _ = sender.send((job_container_name, info, result));
+        });
+        if let Err(e) = queued {
+            eprintln!("{}: {}", container_name, e);
+            failures += 1;
+        }
+    }
+    // 処理が終わる (panic した場合も含めて sender が drop される) まで受け取ります
+    drop(sender);
 
     let mut stats_map: HashMap<String, Stats>
          = HashMap::new();
-    for container_name in container_names {
-        // in one-shot mode, pre-stats are not available.
-        // we have to take diff by ourselves
-        let stats = get_container_stats(
-            &socket_path, &container_name
-        )?;
-        if stats.time == Some("0001-01-01T00:00:00Z".to_string()) {
+    for (container_name, info, result) in receiver {
+        match result {
             // it's terrible, docker api sometimes returns unix epoc ZERO.
-            break;
+            Ok(stats) if stats.time == Some("0001-01-01T00:00:00Z".to_string()) => {
+                eprintln!("{}: docker api returned no stats", container_name);
+                failures += 1;
+            },
+            Ok(stats) => {
+                stats_map.insert(container_name, Stats { info, ..stats });
+            },
+            Err(e) => {
+                eprintln!("{}: {}", container_name, e);
+                failures += 1;
+            },
         }
+    }
+
+    Ok((stats_map, failures))
+}
 
-        stats_map.insert(container_name, stats);
+/// cgroup v2 のファイルから全コンテナの使用状況を読み取ります
+///
+/// Docker API はコンテナの一覧と、初めて見るコンテナのPIDの取得にだけ使います
+/// (PIDはコンテナIDをキーに pids に保持し、終了したコンテナの分は削除します)
+/// get_containers_stats と同じく、取得に失敗したコンテナの数も返します
+fn get_containers_stats_from_cgroup(
+    config: &config::Config,
+    pids: &mut HashMap<String, u32>,
+) -> Result<
+    (HashMap<String, Stats>, usize),
+    error::Error
+> {
+    let containers = get_containers(config)?;
+    pids.retain(|id, _| containers.iter().any(|(_, info)| info.id == *id));
+
+    let host = cgroup::read_host_stats(&config.proc_root)?;
+    let mut stats_map: HashMap<String, Stats>
+         = HashMap::new();
+    let mut failures = 0;
+    for (container_name, info) in containers {
+        let container_id = info.id.clone();
+        let pid = match pids.get(&container_id) {
+            Some(pid) => Some(*pid),
+            None => match get_container_pid(config, &container_id) {
+                Ok(pid) => {
+                    pids.insert(container_id.clone(), pid);
+                    Some(pid)
+                },
+                Err(e) => {
+                    eprintln!("{}: {}", container_name, e);
+                    None
+                },
+            },
+        };
+        let Some(cgroup_dir) = cgroup::find_cgroup_dir(
+            &config.cgroup_root, &config.proc_root, &container_id, pid,
+        ) else {
+            eprintln!("{}: cannot find cgroup directory", container_name);
+            failures += 1;
+            continue;
+        };
+        match cgroup::read_stats(&cgroup_dir, &config.proc_root, pid, &host) {
+            Ok(stats) => {
+                stats_map.insert(container_name, Stats { info, ..stats });
+            },
+            Err(e) => {
+                eprintln!("{}: {}", container_name, e);
+                failures += 1;
+            },
+        }
     }
 
-    Ok(stats_map)
+    Ok((stats_map, failures))
 }
 
 fn get_now_as_millis() -> Result<u128, std::time::SystemTimeError> {
@@ -384,7 +932,11 @@ fn get_now_as_millis() -> Result<u128, std::time::SystemTimeError> {
     Ok(duration.as_millis())
 }
 
+/// tick の時刻 time (UNIX時間, ミリ秒) の使用状況を計算します
+///
+/// stats の時刻はコンテナ (Docker の read) とホストで異なるため、tick の時刻を使います
 fn calc_usages(
+    time: i64,
     millis: &u16,
     stats: &HashMap<String, Stats>,
     prev_stats: &HashMap<String, Stats>,
@@ -392,10 +944,8 @@ fn calc_usages(
 
     let container_names = stats.keys();
 
-    let time = stats.values().next()
-        .and_then(|s| s.time.clone())
-        .expect("time entry should exist");
-    let millis = millis.clone();
+    let time = time::format_epoch_millis(time);
+    let millis = *millis;
     let mut usages = Usages {
         time, millis, 
         usages: HashMap::new(),
@@ -403,7 +953,13 @@ fn calc_usages(
     for container_name in container_names {
         let stats = &stats[container_name];
         //println!("calc stats: {}", stats);
-        let prev_stats = &prev_stats[container_name];
+        // 新しく起動したコンテナや、同じ名前で作り直されたコンテナは
+        // 前の値が無いので次の tick から記録します
+        let Some(prev_stats) = prev_stats.get(container_name)
+            .filter(|prev| prev.info.id == stats.info.id)
+        else {
+            continue;
+        };
         //println!("calc prev_stats: {}", prev_stats);
 
         // CPU calculations
@@ -420,6 +976,27 @@ fn calc_usages(
                  (a as f32) / (b as f32) * (c as f32) * 100_f32
             );
         //println!("cpu_percentage: {:?}", cpu_percentage);
+        let cpu_diff = |f: fn(&CpuStats) -> Option<u64>| f(&stats.cpu)
+            .zip(f(&prev_stats.cpu))
+            .map(|(a, b)| a.saturating_sub(b));
+        let periods_delta = cpu_diff(|c| c.periods);
+        let throttled_periods_delta = cpu_diff(|c| c.throttled_periods);
+        // cpus の制限が無ければ制御期間が進まないので None になります
+        let throttle_ratio = throttled_periods_delta
+            .zip(periods_delta.filter(|p| *p > 0))
+            .map(|(a, b)| (a as f32) / (b as f32) * 100_f32);
+        // system_cpu_usage は全CPUの合計なので、1CPU分は system_cpu_delta / ncpu です
+        let percpu = match (system_cpu_delta.filter(|d| *d > 0), stats.cpu.ncpu) {
+            (Some(system_delta), Some(ncpu))
+                if stats.cpu.percpu.len() == prev_stats.cpu.percpu.len() =>
+                stats.cpu.percpu.iter()
+                    .zip(&prev_stats.cpu.percpu)
+                    .map(|(a, b)| a.saturating_sub(*b) as f32
+                         / system_delta as f32 * ncpu as f32 * 100_f32
+                    )
+                    .collect(),
+            _ => Vec::new(),
+        };
 
         // Memory calculations
         let memory_percentage = stats.memory.used
@@ -427,51 +1004,47 @@ fn calc_usages(
             .map(|(a,b)| (a as f32) / (b as f32) * 100_f32);
 
         // IO calculations
-        let io_read_kb_per_s = stats.io.read
-            .zip(prev_stats.io.read)
-            .map(|(a,b)| a.saturating_sub(b) 
-                 / 1000 / (millis as u64 / 1000)
-            );
-        let io_write_kb_per_s = stats.io.write
-            .zip(prev_stats.io.write)
-            .map(|(a,b)| a.saturating_sub(b) 
-                 / 1000 / (millis as u64 / 1000)
-            );
+        let io_usage = calc_io_usage(millis, &stats.io, Some(&prev_stats.io));
 
         // Net calculations
-        let net_send_kb_per_s = stats.net.send
-            .zip(prev_stats.net.send)
-            .map(|(a,b)| a.saturating_sub(b) / millis as u64);
-        let net_recv_kb_per_s = stats.net.recv
-            .zip(prev_stats.net.recv)
-            .map(|(a,b)| a.saturating_sub(b) / millis as u64);
+        let net_usage = calc_net_usage(millis, &stats.net, Some(&prev_stats.net));
+
+        // PIDs calculations
+        let pids_percentage = stats.pids.current
+            .zip(stats.pids.limit)
+            .map(|(a, b)| (a as f32) / (b as f32) * 100_f32);
 
         usages.usages.insert(
             container_name.to_string(),
             Usage {
+                info: Some(stats.info.clone()).filter(|info| !info.id.is_empty()),
                 cpu: CpuUsage {
                     percentage: cpu_percentage,
                     total: cpu_delta,
                     system: system_cpu_delta,
                     ncpu: stats.cpu.ncpu,
+                    user: cpu_diff(|c| c.user),
+                    kernel: cpu_diff(|c| c.kernel),
+                    periods: periods_delta,
+                    throttled_periods: throttled_periods_delta,
+                    throttled_time: cpu_diff(|c| c.throttled_time),
+                    throttle_ratio,
+                    percpu,
                 },
                 memory: MemoryUsage {
                     percentage: memory_percentage,
                     used: stats.memory.used,
                     available: stats.memory.available,
+                    breakdown: stats.memory.breakdown.clone(),
                 },
-                io: IoUsage {
-                    readkBps: io_read_kb_per_s.map(|n| n as u32),
-                    writekBps: io_write_kb_per_s.map(|n| n as u32),
-                    readkB: stats.io.read.map(|x| x / 1000),
-                    writekB: stats.io.write.map(|x| x / 1000),
-                },
-                net: NetUsage {
-                    sendkBps: net_send_kb_per_s.map(|n| n as u32),
-                    recvkBps: net_recv_kb_per_s.map(|n| n as u32),
-                    sendkB: stats.net.send.map(|x| x / 1000),
-                    recvkB: stats.net.recv.map(|x| x / 1000),
+                io: io_usage,
+                net: net_usage,
+                pids: PidsUsage {
+                    percentage: pids_percentage,
+                    current: stats.pids.current,
+                    limit: stats.pids.limit,
                 },
+                host: stats.host.clone(),
             }
         );
     }
@@ -479,17 +1052,81 @@ fn calc_usages(
     Ok(usages)
 }
 
-fn log_daily<T: AsRef<std::path::Path>, S: AsRef<str>>(
-    file_path: T,
+/// ブロックデバイスIOの使用状況を計算します
+/// デバイス毎の値も同様に計算します (前回の値が無ければ速度は None)
+fn calc_io_usage(
+    millis: u16,
+    stats: &IoStats,
+    prev_stats: Option<&IoStats>,
+) -> IoUsage {
+    let per_ms = |value: Option<u64>, prev: Option<u64>| value
+        .zip(prev)
+        .map(|(a,b)| a.saturating_sub(b) as f64 / millis as f64);
+    IoUsage {
+        // バイト/ミリ秒 = kB/秒
+        readkBps: per_ms(stats.read, prev_stats.and_then(|p| p.read))
+            .map(|v| v as u32),
+        writekBps: per_ms(stats.write, prev_stats.and_then(|p| p.write))
+            .map(|v| v as u32),
+        readkB: stats.read.map(|x| x / 1000),
+        writekB: stats.write.map(|x| x / 1000),
+        readIops: per_ms(stats.read_ops, prev_stats.and_then(|p| p.read_ops))
+            .map(|v| (v * 1000.0).round() as u32),
+        writeIops: per_ms(stats.write_ops, prev_stats.and_then(|p| p.write_ops))
+            .map(|v| (v * 1000.0).round() as u32),
+        devices: stats.devices.iter()
+            .map(|(name, device)| (
+                name.clone(),
+                calc_io_usage(
+                    millis,
+                    device,
+                    prev_stats.and_then(|p| p.devices.get(name)),
+                ),
+            ))
+            .collect(),
+    }
+}
+
+/// ネットワークの使用状況を計算します
+/// インターフェース毎の値も同様に計算します (前回の値が無ければ速度は None)
+fn calc_net_usage(
+    millis: u16,
+    stats: &NetStats,
+    prev_stats: Option<&NetStats>,
+) -> NetUsage {
+    let kb_per_s = |value: Option<u64>, prev: Option<u64>| value
+        .zip(prev)
+        .map(|(a,b)| (a.saturating_sub(b) / millis as u64) as u32);
+    NetUsage {
+        sendkBps: kb_per_s(stats.send, prev_stats.and_then(|p| p.send)),
+        recvkBps: kb_per_s(stats.recv, prev_stats.and_then(|p| p.recv)),
+        sendkB: stats.send.map(|x| x / 1000),
+        recvkB: stats.recv.map(|x| x / 1000),
+        recvPackets: stats.recv_packets,
+        sendPackets: stats.send_packets,
+        recvErrors: stats.recv_errors,
+        sendErrors: stats.send_errors,
+        recvDropped: stats.recv_dropped,
+        sendDropped: stats.send_dropped,
+        interfaces: stats.interfaces.iter()
+            .map(|(name, interface)| (
+                name.clone(),
+                calc_net_usage(
+                    millis,
+                    interface,
+                    prev_stats.and_then(|p| p.interfaces.get(name)),
+                ),
+            ))
+            .collect(),
+    }
+}
+
+fn log_daily<S: AsRef<str>>(
+    file: &mut std::fs::File,
     content: S,
 ) -> Result<(), error::Error> {
-    let mut file = std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .write(true)
-        .open(file_path)?;
     std::io::Write::write_all(
-        &mut file, 
+        file, 
         ("".to_string() + content.as_ref() + "\r\n").as_bytes()
     )?;
 
@@ -522,81 +1159,226 @@ pub fn custom_dump(json: &json::JsonValue) -> String {
     }
 }
 
+/// time は usages.time を UNIX時間 (ミリ秒) に変換したものです
 fn insert_usages_to_cache(
     container_name: &String,
     usages: &Usages,
+    time: i64,
     log_cache: &mut log_cache::UsageCache,
 ) {
     log_cache.cpu.insert(
         container_name.clone(),
         log_cache::TimedCpuUsage {
-            time: usages.time.clone(),
+            time,
             percentage:
                 usages.usages[container_name].cpu.percentage,
+            user:
+                usages.usages[container_name].cpu.user,
+            kernel:
+                usages.usages[container_name].cpu.kernel,
+            throttled_time:
+                usages.usages[container_name].cpu.throttled_time,
+            throttle_ratio:
+                usages.usages[container_name].cpu.throttle_ratio,
+            percpu:
+                usages.usages[container_name].cpu.percpu.clone(),
         }
     );
     log_cache.memory.insert(
         container_name.clone(),
         log_cache::TimedMemoryUsage {
-            time: usages.time.clone(),
+            time,
             percentage:
-                usages.usages[container_name].memory.percentage
+                usages.usages[container_name].memory.percentage,
+            used:
+                usages.usages[container_name].memory.used,
+            breakdown:
+                usages.usages[container_name].memory.breakdown.clone(),
         }
     );
     log_cache.io.insert(
         container_name.clone(),
         log_cache::TimedIoUsage {
-            time: usages.time.clone(),
+            time,
             readkBps:
                 usages.usages[container_name].io.readkBps,
             writekBps:
                 usages.usages[container_name].io.writekBps,
+            readIops:
+                usages.usages[container_name].io.readIops,
+            writeIops:
+                usages.usages[container_name].io.writeIops,
         }
     );
     log_cache.net.insert(
         container_name.clone(),
         log_cache::TimedNetUsage {
-            time: usages.time.clone(),
+            time,
             recvkBps:
                 usages.usages[container_name].net.recvkBps,
             sendkBps: 
                 usages.usages[container_name].net.sendkBps,
         }
     );
+    if let Some(info) = &usages.usages[container_name].info {
+        log_cache.record_container(container_name, info, time);
+    }
+    log_cache.pids.insert(
+        container_name.clone(),
+        log_cache::TimedPidsUsage {
+            time,
+            percentage:
+                usages.usages[container_name].pids.percentage,
+            current:
+                usages.usages[container_name].pids.current,
+        }
+    );
+    for (device, io) in &usages.usages[container_name].io.devices {
+        log_cache.io_devices.insert(
+            log_cache::device_key(container_name, device),
+            log_cache::TimedIoUsage {
+                time,
+                readkBps: io.readkBps,
+                writekBps: io.writekBps,
+                readIops: io.readIops,
+                writeIops: io.writeIops,
+            }
+        );
+    }
+    for (interface, net) in &usages.usages[container_name].net.interfaces {
+        log_cache.net_interfaces.insert(
+            log_cache::interface_key(container_name, interface),
+            log_cache::TimedNetUsage {
+                time,
+                recvkBps: net.recvkBps,
+                sendkBps: net.sendkBps,
+            }
+        );
+    }
+    if let Some(host) = &usages.usages[container_name].host {
+        log_cache.load.insert(
+            container_name.clone(),
+            log_cache::TimedLoadUsage { time, load: host.load },
+        );
+        log_cache.filesystems.insert(
+            container_name.clone(),
+            log_cache::TimedFilesystemUsage {
+                time,
+                filesystems: host.filesystems.clone(),
+            },
+        );
+    }
 
 }
 
 pub fn log_json(
-    log_cache: &log_cache::SharedUsageCache
+    config: &config::Config,
+    log_cache: &log_cache::SharedUsageCache,
+    broadcaster: &broadcast::SharedUsageBroadcaster,
+    shutdown: &supervisor::SharedShutdown,
 ) -> Result<(), error::Error> {
-    let socket_path = "/var/run/docker.sock";
+    let daily_log_path = config.daily_log_path();
+    let daily_segment_path = config.daily_segment_path();
+    let collector_pool = thread_pool::ThreadPool::new(
+        "collector", config.collector_workers, COLLECTOR_QUEUE_LENGTH,
+    )?;
 
     // create log dir if not exists
-    if std::fs::exists("./log")? {
-        println!("./log directory exists.");
+    if std::fs::exists(&config.log_dir)? {
+        println!("{} directory exists.", config.log_dir.display());
     } else {
-        println!("creating ./log directory...");
-        std::fs::create_dir("./log")?;
+        println!("creating {} directory...", config.log_dir.display());
+        std::fs::create_dir_all(&config.log_dir)?;
     }
 
     let now_as_millis = get_now_as_millis()?;
-    let tick = std::time::Duration::from_secs(10);
+    let tick = config.tick;
     let mut timing = now_as_millis + (
           tick.as_millis() //* 2  // NOTE if tick is short, maybe more wait needed.
         - now_as_millis % tick.as_millis()
     );
 
+    let day_of = |millis: u128| (millis / 1000) as i64 / time::SECONDS_PER_DAY;
+    let mut last_day = None;
+    for path in [&daily_log_path, &daily_segment_path.join(store::INDEX_FILE)] {
+        last_day = last_day.max(log_rotation::last_written_day(path)?);
+    }
+    let mut last_day = last_day.unwrap_or(day_of(now_as_millis));
+
     let mut prev_stats: HashMap<String, Stats>
         = HashMap::new();
+    // collector.backend = "cgroup" で使うコンテナID毎のPID
+    let mut pids: HashMap<String, u32> = HashMap::new();
+    // Docker に接続できなかった後、次に再試行する時刻 (ミリ秒)
+    let mut retry_at: u128 = 0;
+    // 最後に日次ログをディスクへ書き出した時刻 (log.fsync = "interval" で使います)
+    let mut last_sync = std::time::Instant::now();
+    // 日次ログは開いたまま追記し、ローテーションで移動した後に開き直します
+    let mut daily_log = DailyLog::default();
     loop {
         let millis_to_wait = timing.saturating_sub(get_now_as_millis()?) as u64;
         println!("waiting {} millis...", millis_to_wait);
 
-        std::thread::sleep(
-            std::time::Duration::from_millis(millis_to_wait)
-        );
+        // 終了が要求されたら、書き込み済みのログをディスクへ同期して戻ります
+        if shutdown.sleep(std::time::Duration::from_millis(millis_to_wait)) {
+            daily_log.sync()?;
+            println!("logger stopped.");
+            return Ok(());
+        }
 
-        let stats = get_containers_stats(&socket_path)?;
+        let today = day_of(timing);
+        if today != last_day {
+            // 移動する前に書き出し、ローテーション後のファイルを開き直します
+            if config.log_fsync != config::FsyncPolicy::Never {
+                daily_log.sync()?;
+            }
+            daily_log = DailyLog::default();
+            if let Err(e) = log_rotation::rotate(config, last_day, today) {
+                eprintln!("failed to rotate logs: {}", e);
+            }
+            last_day = today;
+        }
+
+        let mut stats = if timing < retry_at {
+            // 再試行までの tick はホストのみ記録します
+            log_cache.write()?
+                .collection
+                .record_skipped();
+            HashMap::new()
+        } else {
+            let started = std::time::Instant::now();
+            let result = match config.collector {
+                config::CollectorBackend::Docker =>
+                    get_containers_stats(config, &collector_pool),
+                config::CollectorBackend::Cgroup =>
+                    get_containers_stats_from_cgroup(config, &mut pids),
+            };
+            let mut lock = log_cache.write()?;
+            match result {
+                Ok((stats, failures)) => {
+                    lock.collection.record(started.elapsed(), stats.len(), failures, timing as i64);
+                    stats
+                },
+                // Docker に接続できない間はコンテナが記録されず、ログの空白になります
+                Err(e) => {
+                    lock.collection.record_error(e.to_string(), timing as i64);
+                    let retry_after = lock.collection
+                        .retry_after(tick, config.collector_max_backoff);
+                    eprintln!(
+                        "failed to collect stats: {} (retrying in {} secs)",
+                        e, retry_after.as_secs(),
+                    );
+                    retry_at = timing + retry_after.as_millis();
+                    HashMap::new()
+                },
+            }
+        };
+        match host::read_stats(&config.proc_root, &config.host_mounts) {
+            Ok(host_stats) => {
+                stats.insert(host::HOST_NAME.to_string(), host_stats);
+            },
+            Err(e) => eprintln!("failed to read host stats: {}", e),
+        }
         //println!("stats: {}", stats.dump());
         //println!("prev_stats: {}", prev_stats.dump());
 
@@ -606,43 +1388,152 @@ pub fn log_json(
             
         let log_condition = first_stat
             .zip(first_prev_stat)
-            .map(|(a, b)| !a.cpu.total.is_none() 
-                 && !b.cpu.total.is_none()
+            .map(|(a, b)| a.cpu.total.is_some()
+                 && b.cpu.total.is_some()
             )
             .unwrap_or(false);
 
         println!("log condition: {}", log_condition);
 
         if log_condition {
+            let time = timing as i64;
             let usage_result = calc_usages(
+                time,
                 &(tick.as_millis() as u16), 
                 &stats, &prev_stats
             );
             if let Ok(usage) = usage_result {
                 //println!("{}", usage);
-                log_daily(DAILY_LOG_PATH, usage.to_string())?;
-                let mut lock = log_cache.write()
-                    .expect("failed to get write lock for log_cache");
+                if config.log_format.writes_json() {
+                    log_daily(daily_log.json(&daily_log_path)?, usage.to_string())?;
+                }
+                if config.log_format.writes_binary() {
+                    daily_log.segment(&daily_segment_path)?
+                        .append(time, usage.millis, &usage.usages)?;
+                }
+                let sync = match config.log_fsync {
+                    config::FsyncPolicy::Tick => true,
+                    config::FsyncPolicy::Interval =>
+                        last_sync.elapsed() >= config.log_fsync_interval,
+                    config::FsyncPolicy::Never => false,
+                };
+                if sync {
+                    daily_log.sync()?;
+                    last_sync = std::time::Instant::now();
+                }
+                let mut lock = log_cache.write()?;
 
                 let container_names = 
                     usage.usages.keys().cloned()
                     .collect::<Vec<String>>();
+                lock.latest.clear();
                 for container_name in container_names {
                     // usage中のデータをキャッシュへ移動
                     insert_usages_to_cache(
                         &container_name, 
                         &usage, 
-                        &mut (*lock),
+                        time,
+                        &mut lock,
+                    );
+                    lock.latest.insert(
+                        container_name.clone(),
+                        log_cache::LatestUsage {
+                            time,
+                            stats: stats[&container_name].clone(),
+                            usage: usage.usages[&container_name].clone(),
+                        },
                     );
                 }
+                drop(lock);
+
+                // /stream の接続へ配信
+                broadcaster.publish(std::sync::Arc::new(log_cache::TickUsages {
+                    time,
+                    usages: usage.usages,
+                }));
             }
         }
 
+        log_cache.write()?
+            .collection
+            .last_tick = Some(timing as i64);
         timing += tick.as_millis();
         prev_stats = stats;
     }
 }
 
+/// 追記中の日次ログ (json とセグメント) のファイル
+///
+/// 最初に書き込む際に開き、ローテーションまで開いたままにします
+#[derive(Default)]
+struct DailyLog {
+    json: Option<std::fs::File>,
+    segment: Option<store::SegmentWriter>,
+}
+impl DailyLog {
+    fn json(&mut self, path: &std::path::Path) -> Result<&mut std::fs::File, error::Error> {
+        let file = match self.json.take() {
+            Some(file) => file,
+            None => std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        };
+        Ok(self.json.insert(file))
+    }
+
+    fn segment(&mut self, path: &std::path::Path) -> Result<&mut store::SegmentWriter, error::Error> {
+        let writer = match self.segment.take() {
+            Some(writer) => writer,
+            None => store::SegmentWriter::open(path)?,
+        };
+        Ok(self.segment.insert(writer))
+    }
+
+    /// 書き込んだ内容をディスクへ書き出します
+    fn sync(&self) -> Result<(), error::Error> {
+        if let Some(json) = &self.json {
+            json.sync_all()?;
+        }
+        if let Some(segment) = &self.segment {
+            segment.sync()?;
+        }
+        Ok(())
+    }
+}
+
+fn json_to_io_usage(json: &json::JsonValue) -> IoUsage {
+    IoUsage {
+        readkB: json["readkB"].as_u64(),
+        writekB: json["writekB"].as_u64(),
+        readkBps: json["readkBps"].as_u32(),
+        writekBps: json["writekBps"].as_u32(),
+        readIops: json["readIops"].as_u32(),
+        writeIops: json["writeIops"].as_u32(),
+        devices: json["devices"].entries()
+            .map(|(name, v)| (name.to_string(), json_to_io_usage(v)))
+            .collect(),
+    }
+}
+
+fn json_to_net_usage(json: &json::JsonValue) -> NetUsage {
+    NetUsage {
+        recvkB: json["recvkB"].as_u64(),
+        sendkB: json["sendkB"].as_u64(),
+        recvkBps: json["recvkBps"].as_u32(),
+        sendkBps: json["sendkBps"].as_u32(),
+        recvPackets: json["recvPackets"].as_u64(),
+        sendPackets: json["sendPackets"].as_u64(),
+        recvErrors: json["recvErrors"].as_u64(),
+        sendErrors: json["sendErrors"].as_u64(),
+        recvDropped: json["recvDropped"].as_u64(),
+        sendDropped: json["sendDropped"].as_u64(),
+        interfaces: json["interfaces"].entries()
+            .map(|(name, v)| (name.to_string(), json_to_net_usage(v)))
+            .collect(),
+    }
+}
+
 fn json_to_usage(
     json: &json::JsonValue
 ) -> Result<Usages, error::Error> {
@@ -658,108 +1549,473 @@ fn json_to_usage(
         usages:
             json["stats"].entries()
                 .map(|(k, v)| (k.to_string(), Usage {
+                    info: ContainerInfo::from_json(v),
                     cpu: CpuUsage {
                         percentage: v["cpu"]["percentage"].as_f32(),
                         total: v["cpu"]["total"].as_u64(),
                         system: v["cpu"]["system"].as_u64(),
                         ncpu: v["cpu"]["ncpu"].as_u8(),
+                        user: v["cpu"]["user"].as_u64(),
+                        kernel: v["cpu"]["kernel"].as_u64(),
+                        periods: v["cpu"]["periods"].as_u64(),
+                        throttled_periods: v["cpu"]["throttledPeriods"].as_u64(),
+                        throttled_time: v["cpu"]["throttledTime"].as_u64(),
+                        throttle_ratio: v["cpu"]["throttleRatio"].as_f32(),
+                        percpu: v["cpu"]["percpu"].members()
+                            .filter_map(|p| p.as_f32())
+                            .collect(),
                     },
                     memory: MemoryUsage {
                         percentage: v["memory"]["percentage"].as_f32(),
                         used: v["memory"]["used"].as_u64(),
                         available: v["memory"]["available"].as_u64(),
+                        breakdown: MemoryBreakdown::from_fields(
+                            |name| v["memory"][name].as_u64()
+                        ),
+                    },
+                    io: json_to_io_usage(&v["io"]),
+                    net: json_to_net_usage(&v["net"]),
+                    pids: PidsUsage {
+                        percentage: v["pids"]["percentage"].as_f32(),
+                        current: v["pids"]["current"].as_u64(),
+                        limit: v["pids"]["limit"].as_u64(),
+                    },
+                    host: host::HostUsage::from_json(v),
+                }))
+                .collect(),
+    })
+}
+
+/// 集計済みの行 (log_weekly, log_monthly) を平均値の Usages に変換します
+fn summary_to_usage(
+    json: &json::JsonValue
+) -> Result<Usages, error::Error> {
+    let avg = |v: &json::JsonValue| v["avg"].as_f64();
+
+    Ok(Usages {
+        time:
+            json["time"].as_str()
+                .ok_or("time entry not found".to_string())?
+                .to_string(),
+        // 集計済みの行には tick 間隔がありません
+        millis: 0,
+        usages:
+            json["stats"].entries()
+                .map(|(k, v)| (k.to_string(), Usage {
+                    info: ContainerInfo::from_json(v),
+                    cpu: CpuUsage {
+                        percentage: avg(&v["cpu"]["percentage"])
+                            .map(|x| x as f32),
+                        user: avg(&v["cpu"]["user"]).map(|x| x as u64),
+                        kernel: avg(&v["cpu"]["kernel"]).map(|x| x as u64),
+                        throttled_time: avg(&v["cpu"]["throttledTime"]).map(|x| x as u64),
+                        throttle_ratio: avg(&v["cpu"]["throttleRatio"])
+                            .map(|x| x as f32),
+                        ..Default::default()
+                    },
+                    memory: MemoryUsage {
+                        percentage: avg(&v["memory"]["percentage"])
+                            .map(|x| x as f32),
+                        used: avg(&v["memory"]["used"]).map(|x| x as u64),
+                        breakdown: MemoryBreakdown::from_fields(
+                            |name| avg(&v["memory"][name]).map(|x| x as u64)
+                        ),
+                        ..Default::default()
                     },
                     io: IoUsage {
-                        readkB: v["io"]["readkB"].as_u64(),
-                        writekB: v["io"]["sendkB"].as_u64(),
-                        readkBps: v["io"]["readkBps"].as_u32(),
-                        writekBps: v["io"]["sendkBps"].as_u32(),
+                        readkBps: avg(&v["io"]["readkBps"]).map(|x| x as u32),
+                        writekBps: avg(&v["io"]["writekBps"]).map(|x| x as u32),
+                        readIops: avg(&v["io"]["readIops"]).map(|x| x as u32),
+                        writeIops: avg(&v["io"]["writeIops"]).map(|x| x as u32),
+                        ..Default::default()
                     },
                     net: NetUsage {
-                        recvkB: v["net"]["recvkB"].as_u64(),
-                        sendkB: v["net"]["sendkB"].as_u64(),
-                        recvkBps: v["net"]["recvkBps"].as_u32(),
-                        sendkBps: v["net"]["sendkBps"].as_u32(),
-                    }
+                        recvkBps: avg(&v["net"]["recvkBps"]).map(|x| x as u32),
+                        sendkBps: avg(&v["net"]["sendkBps"]).map(|x| x as u32),
+                        ..Default::default()
+                    },
+                    pids: PidsUsage {
+                        percentage: avg(&v["pids"]["percentage"])
+                            .map(|x| x as f32),
+                        current: avg(&v["pids"]["current"]).map(|x| x as u64),
+                        ..Default::default()
+                    },
+                    // ファイルシステムは集計しないのでロードアベレージのみです
+                    host: avg(&v["load"]["one"])
+                        .zip(avg(&v["load"]["five"]))
+                        .zip(avg(&v["load"]["fifteen"]))
+                        .map(|((one, five), fifteen)| host::HostUsage {
+                            load: host::LoadAverage {
+                                one: one as f32,
+                                five: five as f32,
+                                fifteen: fifteen as f32,
+                            },
+                            filesystems: BTreeMap::new(),
+                        }),
                 }))
                 .collect(),
     })
 }
 
-pub fn read_log(
-    log_cache: &log_cache::SharedUsageCache
-) -> Result<(), error::Error> {
-
-    let nlines = check_nlines()?;
-    //println!("nlines: {}", nlines);
-    let nlines_to_skip = nlines.saturating_sub(log_cache::MAX_LOG_LENGTH as u64);
-    //println!("nlines to skip: {}", nlines_to_skip);
+/// セグメントの tick を json 形式のログの行と同じ形にします
+fn tick_to_usages(tick: store::Tick) -> (i64, Usages) {
+    (tick.time, Usages {
+        time: time::format_epoch_millis(tick.time),
+        millis: tick.millis,
+        usages: tick.usages,
+    })
+}
 
+/// ログファイル1つを読み、時刻が [from, before) の範囲の行を
+/// 時刻 (UNIX時間, ミリ秒) と共に返します
+/// (セグメントの場合は index から範囲の tick のみを読みます)
+///
+/// 返り値の2つ目は壊れていて読み飛ばした行 (tick) の数です
+fn read_log_file<T: AsRef<std::path::Path>>(
+    file_path: T,
+    from: i64,
+    before: Option<i64>,
+) -> Result<(Vec<(i64, Usages)>, usize), error::Error> {
+    if store::is_segment(&file_path) {
+        let read = store::read_segment(&file_path, from, before)?;
+        let entries = read.ticks
+            .into_iter()
+            .map(tick_to_usages)
+            .collect::<Vec<(i64, Usages)>>();
+        println!(
+            "{}: {} ticks are used, {} ticks are skipped.",
+            file_path.as_ref().display(),
+            entries.len(),
+            read.skipped,
+        );
+        return Ok((entries, read.skipped));
+    }
     let file = std::fs::OpenOptions::new()
         .read(true)
         .create(false)
         .write(false)
-        .open(DAILY_LOG_PATH)?;
+        .open(&file_path)?;
     let reader = std::io::BufReader::new(file);
     let mut iline = 0;
     let mut iline_success = 0;
+    let mut entries = Vec::new();
     for line in std::io::BufRead::lines(reader) {
         iline += 1;
-        if iline < nlines_to_skip { continue; }
-
-        let line = line?;
-        match json::parse(&line) {
-            Ok(json) => {
-                match json_to_usage(&json) {
-                    Ok(usages) => {
-                        if usages.time == "0001-01-01T00:00:00Z".to_string() {
-                            // it's terrible. docker api sometimes returns unix epoc ZERO.
-                            break;
-                        }
-                        //println!("{:?}", usages);
-                        let mut lock = log_cache.write()
-                            .expect("cannot lock log_cache"); 
-                        let container_names = 
-                            usages.usages.keys().cloned()
-                            .collect::<Vec<String>>();
-                        for container_name in container_names {
-                            insert_usages_to_cache(
-                                &container_name, 
-                                &usages, 
-                                &mut (*lock)
-                            );
-                        }
-                        iline_success += 1;
-                    },
+
+        let line = match line {
+            Ok(line) => line,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                eprintln!("error in log: {}", e);
+                continue;
+            },
+            Err(e) => return Err(e.into()),
+        };
+        let parsed = json::parse(&line)
+            .map_err(error::Error::from)
+            .and_then(|json| if json.has_key("seconds") {
+                summary_to_usage(&json)
+            } else {
+                json_to_usage(&json)
+            });
+        match parsed {
+            Ok(usages) => {
+                if usages.time == "0001-01-01T00:00:00Z" {
+                    // it's terrible. docker api sometimes returns unix epoc ZERO.
+                    continue;
+                }
+                let epoch_millis = match time::parse_epoch_millis(&usages.time) {
+                    Ok(t) => t,
                     Err(e) => {
                         eprintln!("error in log: {}", e);
+                        continue;
                     },
+                };
+                iline_success += 1;
+                if epoch_millis < from
+                    || before.is_some_and(|b| epoch_millis >= b)
+                {
+                    continue;
                 }
+                entries.push((epoch_millis, usages));
+            },
+            Err(e) => {
+                eprintln!("error in log: {}", e);
             },
+        }
+    }
+
+    println!(
+        "{}: {}/{} lines are successfully processed, {} lines are used.",
+        file_path.as_ref().display(),
+        iline_success,
+        iline,
+        entries.len(),
+    );
+
+    Ok((entries, iline - iline_success))
+}
+
+/// セグメントの内容を json 形式のログの行として返します
+pub fn export_json_lines<T: AsRef<std::path::Path>>(
+    segment_path: T,
+) -> Result<Vec<String>, error::Error> {
+    Ok(store::read_segment(segment_path, i64::MIN, None)?
+        .ticks
+        .into_iter()
+        .map(|tick| tick_to_usages(tick).1.to_string())
+        .collect())
+}
+
+/// json 形式の日次ログをセグメントへ変換し、変換した tick の数を返します
+/// (既にあるセグメントへ追記して重複させないよう、segment_path が存在すればエラーにします)
+pub fn convert_json_log<T: AsRef<std::path::Path>, U: AsRef<std::path::Path>>(
+    json_log_path: T,
+    segment_path: U,
+) -> Result<usize, error::Error> {
+    if std::fs::exists(&segment_path)? {
+        return Err(format!("{} already exists", segment_path.as_ref().display()).into());
+    }
+    let (entries, _) = read_log_file(json_log_path, i64::MIN, None)?;
+    let mut writer = store::SegmentWriter::open(&segment_path)?;
+    for (time, usages) in &entries {
+        writer.append(*time, usages.millis, &usages.usages)?;
+    }
+    writer.sync()?;
+
+    Ok(entries.len())
+}
+
+/// 時刻 (UNIX時間, ミリ秒) 順のログの行
+type LogEntries = Vec<(i64, Usages)>;
+
+/// 期間 [from, to) のデータをログファイルから集め、時刻順に返します
+///
+/// log_daily.seg, log_daily → log_daily.seg.1, log_daily.1 … → log_weekly … → log_monthly … の順に
+/// まだ読んでいない古い期間のデータを探し、
+/// 細かいデータが無い期間だけ粗い集計データで埋めます
+///
+/// 読めないファイルは飛ばし、ファイル毎の読み込み結果と共に返します
+fn collect_log_entries(
+    config: &config::Config,
+    from: i64,
+    to: Option<i64>,
+) -> Result<(LogEntries, Vec<log_cache::LogFileReport>), error::Error> {
+    let mut chunks: Vec<Vec<(i64, Usages)>> = Vec::new();
+    let mut reports = Vec::new();
+    let mut covered_from: Option<i64> = to;
+    for file_path in log_rotation::log_files_finest_first(config) {
+        if covered_from.is_some_and(|t| t <= from) {
+            break;
+        }
+        if !std::fs::exists(&file_path)? {
+            continue;
+        }
+        let mut report = log_cache::LogFileReport {
+            path: file_path.display().to_string(),
+            ..Default::default()
+        };
+        match read_log_file(&file_path, from, covered_from) {
+            Ok((entries, skipped)) => {
+                if let Some(earliest) = entries.iter().map(|(t, _)| *t).min() {
+                    covered_from = Some(earliest);
+                }
+                report.records = entries.len();
+                report.skipped = skipped;
+                chunks.push(entries);
+            },
+            Err(e) => {
+                eprintln!("cannot read {}: {}", file_path.display(), e);
+                report.error = Some(e.to_string());
+            },
+        }
+        reports.push(report);
+    }
+
+    Ok((chunks.into_iter().rev().flatten().collect(), reports))
+}
+
+/// json 形式の日次ログの書きかけの最終行 (改行で終わっていない行) を切り詰めます
+fn recover_json_log(path: &std::path::Path) -> Result<store::Recovery, error::Error> {
+    let mut file = match std::fs::OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(store::Recovery::default()),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+
+    // 最後の改行を後ろから探します
+    let mut valid_len = 0;
+    let mut end = len;
+    let mut buffer = vec![0; 64 * 1024];
+    while end > 0 {
+        let start = end.saturating_sub(buffer.len() as u64);
+        let chunk = &mut buffer[..(end - start) as usize];
+        std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(start))?;
+        std::io::Read::read_exact(&mut file, chunk)?;
+        if let Some(i) = chunk.iter().rposition(|b| *b == b'\n') {
+            valid_len = start + i as u64 + 1;
+            break;
+        }
+        end = start;
+    }
+    if valid_len == len {
+        return Ok(store::Recovery::default());
+    }
+
+    eprintln!("{}: dropped the torn last line ({} bytes)", path.display(), len - valid_len);
+    file.set_len(valid_len)?;
+    file.sync_all()?;
+    Ok(store::Recovery { skipped: 1, truncated_bytes: len - valid_len })
+}
+
+/// 追記中だった日次ログ (json とセグメント) の書きかけの末尾を切り詰めます
+///
+/// セグメントのヘッダが壊れている場合は、追記を続けられるように
+/// <セグメント>.corrupt-<UNIX時間> へ退避します
+fn recover_daily_logs(
+    config: &config::Config,
+) -> Result<Vec<log_cache::LogFileReport>, error::Error> {
+    let mut reports = Vec::new();
+    let report = |path: &std::path::Path, recovery: store::Recovery| log_cache::LogFileReport {
+        path: path.display().to_string(),
+        skipped: recovery.skipped,
+        truncated_bytes: recovery.truncated_bytes,
+        ..Default::default()
+    };
+
+    let json_path = config.daily_log_path();
+    reports.push(report(&json_path, recover_json_log(&json_path)?));
+
+    let segment_path = config.daily_segment_path();
+    if store::is_segment(&segment_path) {
+        match store::recover(&segment_path) {
+            Ok(recovery) => reports.push(report(&segment_path, recovery)),
             Err(e) => {
-                eprintln!("error in log json format: {}", e);
+                let moved_to = segment_path.with_file_name(format!(
+                    "{}.corrupt-{}",
+                    segment_path.file_name().unwrap_or_default().to_string_lossy(),
+                    time::now_epoch_millis(),
+                ));
+                eprintln!(
+                    "cannot recover {}: {} (moved to {})",
+                    segment_path.display(), e, moved_to.display(),
+                );
+                std::fs::rename(&segment_path, &moved_to)?;
+                reports.push(log_cache::LogFileReport {
+                    error: Some(format!("{} (moved to {})", e, moved_to.display())),
+                    ..report(&segment_path, store::Recovery::default())
+                });
             },
         }
     }
 
+    Ok(reports.into_iter()
+        .filter(|r| r.skipped > 0 || r.truncated_bytes > 0 || r.error.is_some())
+        .collect())
+}
+
+/// 起動時にログファイルからキャッシュを復元します
+/// (キャッシュに載る期間 max_log_length * tick 分を読みます)
+///
+/// 読む前に日次ログの書きかけの末尾を切り詰め、
+/// 切り詰めた数や読み飛ばした数を log_cache.startup に記録します
+pub fn read_log(
+    config: &config::Config,
+    log_cache: &log_cache::SharedUsageCache
+) -> Result<(), error::Error> {
+
+    let started = std::time::Instant::now();
+    let recovered = recover_daily_logs(config)?;
+
+    let window_start = time::now_epoch_millis()
+        - (config.max_log_length as u128 * config.tick.as_millis()) as i64;
+    let (entries, mut files) = collect_log_entries(config, window_start, None)?;
+    for recovery in recovered {
+        match files.iter_mut().find(|f| f.path == recovery.path) {
+            Some(file) => {
+                file.skipped += recovery.skipped;
+                file.truncated_bytes += recovery.truncated_bytes;
+                file.error = file.error.take().or(recovery.error);
+            },
+            None => files.push(recovery),
+        }
+    }
+
+    let nentries = entries.len();
+    let nentries_to_skip = nentries.saturating_sub(config.max_log_length);
+
+    let mut lock = log_cache.write()
+        .expect("cannot lock log_cache"); 
+    for (time, usages) in entries.into_iter()
+        .skip(nentries_to_skip)
+    {
+        let container_names = 
+            usages.usages.keys().cloned()
+            .collect::<Vec<String>>();
+        for container_name in container_names {
+            insert_usages_to_cache(
+                &container_name, 
+                &usages, 
+                time,
+                &mut lock
+            );
+        }
+    }
+
+    lock.startup = log_cache::StartupReport {
+        time: Some(time::now_epoch_millis()),
+        duration: started.elapsed(),
+        loaded: nentries - nentries_to_skip,
+        files,
+    };
     println!(
-        "{}/{} lines are successfully processed.", 
-        iline_success, 
-        nlines - nlines_to_skip
+        "{} entries are loaded into the cache, {} records are skipped, {} bytes are truncated.",
+        lock.startup.loaded,
+        lock.startup.skipped(),
+        lock.startup.truncated_bytes(),
     );
 
     Ok(())
 }
 
+/// time_range の開始を、保持しているログファイルが含み得る期間に切り詰めます
+/// (from=0 のような要求でも、読む期間が保持期間を超えないようにします)
+pub fn retained_range(
+    config: &config::Config,
+    time_range: &log_cache::TimeRange,
+) -> log_cache::TimeRange {
+    let today = time::now_epoch_millis().div_euclid(time::SECONDS_PER_DAY * 1000);
+    let retained_since = log_rotation::retained_since(config, today);
+    log_cache::TimeRange {
+        from: Some(time_range.from.map_or(retained_since, |from| from.max(retained_since))),
+        to: time_range.to,
+    }
+}
+
+/// キャッシュより古い期間を要求された場合に、
+/// 期間 time_range のデータをログファイルから読み込みます
+/// (返り値はキャッシュと同じ形ですが、共有はされません)
+pub fn read_log_range(
+    config: &config::Config,
+    time_range: &log_cache::TimeRange,
+) -> Result<log_cache::UsageCache, error::Error> {
+    let (entries, _) = collect_log_entries(
+        config,
+        time_range.from.unwrap_or(i64::MIN),
+        time_range.to,
+    )?;
 
-fn check_nlines() -> Result<u64, error::Error> {
-    let file = std::fs::OpenOptions::new()
-        .read(true)
-        .open(DAILY_LOG_PATH)?;
-    let reader = std::io::BufReader::new(file);
-    let mut nlines: u64 = 0;
-    for _ in std::io::BufRead::lines(reader) {
-        nlines += 1;
+    let mut usage_cache = log_cache::UsageCache::new(entries.len().max(1));
+    for (time, usages) in entries {
+        for container_name in usages.usages.keys() {
+            insert_usages_to_cache(
+                container_name,
+                &usages,
+                time,
+                &mut usage_cache,
+            );
+        }
     }
-    Ok(nlines)
+
+    Ok(usage_cache)
 }
@@ -1,27 +1,33 @@
 
-use std::collections::{ VecDeque, HashMap, };
+use std::borrow::Cow;
+use std::collections::{ BTreeMap, VecDeque, HashMap, };
 use std::sync::{ Arc, RwLock, };
 
+use super::downsample;
+use super::events;
+use super::host;
+use super::log;
 use super::log::option_to_string;
+use super::supervisor;
+use super::time;
 
-pub const MAX_LOG_LENGTH: usize = 8640;
-
-/// 最大 MAX_LOG_LENGTH 個の要素を記録するVecのwrapper
+/// 最大 max_length 個の要素を記録するVecのwrapper
 pub struct LogVec<T> {
-    v: VecDeque<T>
+    v: VecDeque<T>,
+    max_length: usize,
 }
 impl<T> LogVec<T> 
     where T: ToString
 {
-    /// MAX_LOG_LENGTHのcapacityを設定した状態で初期化します
-    pub fn new() -> Self {
-        let v = VecDeque::with_capacity(MAX_LOG_LENGTH);
-        LogVec { v }
+    /// max_lengthのcapacityを設定した状態で初期化します
+    pub fn new(max_length: usize) -> Self {
+        let v = VecDeque::with_capacity(max_length);
+        LogVec { v, max_length }
     }
-    /// 末尾にデータを追加し、MAX_LOG_LENGTHを超えるならば
+    /// 末尾にデータを追加し、max_lengthを超えるならば
     /// 先頭データを削除します
-    pub fn push(self: &mut Self, d: T) {
-        if self.v.len() >= MAX_LOG_LENGTH {
+    pub fn push(&mut self, d: T) {
+        if self.v.len() >= self.max_length {
             self.v.pop_front();
         }
         self.v.push_back(d);
@@ -33,35 +39,109 @@ impl<T> LogVec<T>
 
 pub struct DownsampleOption {
     pub nsample: usize,
+    pub algorithm: downsample::Algorithm,
 }
 impl Default for DownsampleOption {
     fn default() -> Self {
-        DownsampleOption { nsample: 512 }
+        DownsampleOption {
+            nsample: 512,
+            algorithm: downsample::Algorithm::default(),
+        }
+    }
+}
+
+/// バケット内の平均値を計算できるデータ型
+/// (downsample::Algorithm::Mean で使用します)
+pub trait Mean: Sized {
+    fn mean(samples: &[&Self]) -> Self;
+}
+
+/// 同じ tick の複数のコンテナの値を合計できるデータ型
+/// (/groups/{label_key}/{label_value}/.. で使用します)
+pub trait Sum: Sized {
+    fn sum(samples: &[&Self]) -> Self;
+}
+
+/// /groups でグループ内のコンテナの値をまとめる方法
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Aggregation {
+    #[default]
+    Sum,
+    Mean,
+}
+impl std::str::FromStr for Aggregation {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sum" => Ok(Aggregation::Sum),
+            "avg" => Ok(Aggregation::Mean),
+            _ => Err(format!("unknown aggregation: {}", s)),
+        }
     }
 }
 
-fn calculate_triangle_area(
-    p0: &(f32, f32),
-    p1: &(f32, f32),
-    p2: &(f32, f32),
-) -> f32 {
-    let x1 = p1.0 - p0.0;
-    let y1 = p1.1 - p0.1;
-    let x2 = p2.0 - p0.0;
-    let y2 = p2.1 - p0.1;
+/// UsageCacheMap::aggregate の結果のキー
+pub const GROUP_KEY: &str = "group";
 
-    (x2 * y1 - x1 * y2).abs() * 0.5
+/// 時刻 (UNIX時間, ミリ秒) を持つデータ型
+pub trait Timed {
+    fn time(&self) -> i64;
+}
+
+/// データを取り出す期間 [from, to) (UNIX時間, ミリ秒)
+/// None の側は制限しません
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TimeRange {
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+impl TimeRange {
+    pub fn contains(&self, time: i64) -> bool {
+        self.from.is_none_or(|from| from <= time)
+            && self.to.is_none_or(|to| time < to)
+    }
+    /// 両方の期間に含まれる期間
+    pub fn intersect(&self, other: &TimeRange) -> TimeRange {
+        let pick = |a: Option<i64>, b: Option<i64>, f: fn(i64, i64) -> i64| match (a, b) {
+            (Some(a), Some(b)) => Some(f(a, b)),
+            (a, b) => a.or(b),
+        };
+        TimeRange {
+            from: pick(self.from, other.from, i64::max),
+            to: pick(self.to, other.to, i64::min),
+        }
+    }
+}
+
+/// None を除いた平均値を返します (全て None なら None)
+fn mean_of<I: Iterator<Item = Option<f64>>>(values: I) -> Option<f64> {
+    let (sum, count) = values.flatten()
+        .fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+    if count == 0 { None } else { Some(sum / count as f64) }
+}
+
+/// None を除いた合計値を返します (全て None なら None)
+fn sum_of<I: Iterator<Item = Option<f64>>>(values: I) -> Option<f64> {
+    values.flatten().fold(None, |sum, v| Some(sum.unwrap_or(0.0) + v))
+}
+
+/// バケット内の時刻 (UNIX時間, ミリ秒) の平均値
+fn mean_time<T, F: Fn(&T) -> i64>(samples: &[&T], time: F) -> i64 {
+    let sum = samples.iter().map(|s| time(s) as i128).sum::<i128>();
+    (sum / samples.len() as i128) as i64
 }
 
 pub struct UsageCacheMap<T> {
-    map: HashMap<String, LogVec<T>>
+    map: HashMap<String, LogVec<T>>,
+    max_length: usize,
 }
 impl<T> UsageCacheMap<T> 
     where T: ToString
 {
-    pub fn new() -> Self {
+    pub fn new(max_length: usize) -> Self {
         UsageCacheMap {
-            map: HashMap::<String, LogVec<T>>::new()
+            map: HashMap::<String, LogVec<T>>::new(),
+            max_length,
         }
     }
     pub fn insert(
@@ -70,8 +150,8 @@ impl<T> UsageCacheMap<T>
         usage: T
     ) {
         self.map.entry(container_name)
-            .or_insert(
-                LogVec::<T>::new()
+            .or_insert_with(
+                || LogVec::<T>::new(self.max_length)
             ).push(usage);
     }
     /// 記録されたコンテナ名をソートしてから返します
@@ -87,128 +167,249 @@ impl<T> UsageCacheMap<T>
     ) -> Option<&LogVec<T>> {
         self.map.get(container_name)
     }
-    pub fn downsample<F: Fn(&T) -> (f32, f32)>(
+    /// 全コンテナのうち最も古いデータの時刻
+    /// (キャッシュがこの時刻以降をカバーしていることを表します)
+    pub fn earliest_time(&self) -> Option<i64>
+        where T: Timed
+    {
+        self.map.values()
+            .filter_map(|log_vec| log_vec.v.front().map(|d| d.time()))
+            .min()
+    }
+    /// time_range に含まれるデータのインデックスの範囲を返します
+    /// データは時刻順に並んでいるので二分探索します
+    pub fn range(
         &self,
         container_name: &str,
+        time_range: &TimeRange,
+    ) -> Option<std::ops::Range<usize>>
+        where T: Timed
+    {
+        let data = &self.map.get(container_name)?.v;
+        let start = time_range.from
+            .map(|from| data.partition_point(|d| d.time() < from))
+            .unwrap_or(0);
+        let end = time_range.to
+            .map(|to| data.partition_point(|d| d.time() < to))
+            .unwrap_or(data.len())
+            .max(start);
+        Some(start..end)
+    }
+    /// time_range に含まれるデータを downsample_option に従って間引きます
+    /// Mean 以外のアルゴリズムでは元のデータへの参照を返します
+    pub fn downsample<F: Fn(&T) -> (f64, f64)>(
+        &self,
+        container_name: &str,
+        time_range: &TimeRange,
         downsample_option: &DownsampleOption,
         fxy: F, // データ型からXY座標を取得するための関数 
-    ) -> Option<Vec<&T>> {
+    ) -> Option<Vec<Cow<'_, T>>>
+        where T: Clone + Mean + Timed
+    {
+        let range = self.range(container_name, time_range)?;
+        let offset = range.start;
         let data = &self.map.get(container_name)?.v;
-        let n = data.len();
+        let points = data.range(range).map(&fxy).collect::<Vec<(f64, f64)>>();
         let nsample = downsample_option.nsample;
-        if nsample >= n || nsample < 3 {
-            return Some(data.iter().collect());
-        }
-        let mut samples: Vec<&T> = Vec::with_capacity(nsample);
-        let bucket_size = (n - 2) as f32 / (nsample - 2) as f32;
-        samples.push(&data[0]);
-
-        // 本来LTTBアルゴリズムでは次のバケットの平均点を用いるが
-        // 間違って今のバケットの平均値を使っても一応動く
-        // (Largest Triangle in Three Bukets じゃなくて
-        //  Single Buckets になる?
-        // )
-        let mut last_point = None;
-        for i in 0..(nsample - 2) {
-            let mut max_area = -1.0;
-            let mut max_area_point = None;
-
-            let istart = 
-                ((i as f32 * bucket_size).floor() as usize)
-                .max(1);
-            let iend = 
-                ((i as f32 + 1.0) * bucket_size).floor() as usize;
-            let istart_next =
-                ((i as f32 + 1.0) * bucket_size).floor() as usize;
-            let iend_next =
-                (((i as f32 + 2.0) * bucket_size).floor() as usize)
-                .min(n - 1);
-            let (average_x, average_y) = 
-                data.iter()
-                .skip(istart_next)
-                .take(iend_next - istart_next)
-                .map(|d| fxy(d))
-                .reduce(|acc, curr| (acc.0 + curr.0, acc.1 + curr.1))
-                .map(|s| (
-                    s.0 / (iend - istart) as f32, 
-                    s.1 / (iend - istart) as f32
-                ))
-                .unwrap_or(fxy(&data[0]));
-            for j in istart..iend {
-                let area = calculate_triangle_area(
-                    &fxy(last_point.unwrap_or(&data[0])),
-                    &fxy(&data[j]),
-                    &(average_x, average_y),
-                );
-                if area > max_area {
-                    max_area = area;
-                    max_area_point = Some(&data[j]);
-                }
-            }
-            if let Some(point) = max_area_point {
-                samples.push(point);
-                last_point = Some(point);
+
+        let indices = match downsample_option.algorithm {
+            downsample::Algorithm::Lttb => downsample::lttb(&points, nsample),
+            downsample::Algorithm::MinMax => downsample::min_max(&points, nsample),
+            downsample::Algorithm::M4 => downsample::m4(&points, nsample),
+            downsample::Algorithm::Raw => (0..points.len()).collect(),
+            downsample::Algorithm::Mean => {
+                let samples = downsample::mean(&points, nsample)
+                    .into_iter()
+                    .map(|range| if range.len() == 1 {
+                        Cow::Borrowed(&data[offset + range.start])
+                    } else {
+                        Cow::Owned(T::mean(
+                            &data.range((offset + range.start)..(offset + range.end))
+                                .collect::<Vec<&T>>()
+                        ))
+                    })
+                    .collect();
+                return Some(samples);
+            },
+        };
+
+        Some(indices.into_iter().map(|i| Cow::Borrowed(&data[offset + i])).collect())
+    }
+    /// members の各コンテナの期間内のデータを tick (時刻) 毎にまとめ、
+    /// GROUP_KEY の系列として返します (そのまま downsample できます)
+    pub fn aggregate(
+        &self,
+        members: &[(String, TimeRange)],
+        time_range: &TimeRange,
+        aggregation: Aggregation,
+    ) -> UsageCacheMap<T>
+        where T: Mean + Sum + Timed
+    {
+        let mut ticks: BTreeMap<i64, Vec<&T>> = BTreeMap::new();
+        for (container_name, member_range) in members {
+            let Some(range) = self.range(container_name, &member_range.intersect(time_range))
+            else {
+                continue;
+            };
+            for d in self.map[container_name].v.range(range) {
+                ticks.entry(d.time()).or_default().push(d);
             }
         }
-        // 最後の点を追加
-        samples.push(&data[n - 1]);
-
-        Some(samples)
+        let mut aggregated = UsageCacheMap::new(ticks.len().max(1));
+        for samples in ticks.values() {
+            aggregated.insert(GROUP_KEY.to_string(), match aggregation {
+                Aggregation::Sum => T::sum(samples),
+                Aggregation::Mean => T::mean(samples),
+            });
+        }
+        aggregated
     }
-    
 }
 
+#[derive(Clone, Default)]
 pub struct TimedCpuUsage {
-    pub time: String,
+    /// UNIX時間 (ミリ秒)
+    pub time: i64,
     pub percentage: Option<f32>,
-    //TODO 他のフィールドも
+    /// 以下は log::CpuUsage と同じく tick 毎の値です
+    pub user: Option<u64>,
+    pub kernel: Option<u64>,
+    pub throttled_time: Option<u64>,
+    pub throttle_ratio: Option<f32>,
+    pub percpu: Vec<f32>,
+}
+impl TimedCpuUsage {
+    /// /containers/{name}/cpu/{field} で返せるフィールド名
+    /// (percpu は配列なので別に扱います)
+    pub const FIELDS: &'static [&'static str] = &[
+        "user", "kernel", "throttledTime", "throttleRatio",
+    ];
+
+    /// /containers/{name}/cpu/{field} で返せるフィールド名ならばその名前
+    pub fn field_name(name: &str) -> Option<&'static str> {
+        Self::FIELDS.iter()
+            .find(|field| **field == name)
+            .copied()
+    }
+    /// フィールド名に対応する値
+    pub fn field(&self, name: &str) -> Option<f64> {
+        match name {
+            "user" => self.user.map(|v| v as f64),
+            "kernel" => self.kernel.map(|v| v as f64),
+            "throttledTime" => self.throttled_time.map(|v| v as f64),
+            "throttleRatio" => self.throttle_ratio.map(f64::from),
+            _ => None,
+        }
+    }
+}
+
+/// CPU毎の使用率を {"time":..,"percpu":[..]} の形式で出力します
+pub struct TimedPercpuUsage<'a> {
+    /// UNIX時間 (ミリ秒)
+    pub time: i64,
+    pub percpu: &'a [f32],
+}
+impl std::fmt::Display for TimedPercpuUsage<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{\"time\":\"{}\",\"percpu\":{}}}",
+            time::format_epoch_millis(self.time),
+            log::percpu_to_string(self.percpu),
+        )
+    }
 }
 impl std::fmt::Display for TimedCpuUsage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f, 
             "{{\"time\":\"{}\",\"percentage\":{}}}", 
-            self.time, 
+            time::format_epoch_millis(self.time), 
             option_to_string(self.percentage)
         )
     }
 }
+#[derive(Clone)]
 pub struct TimedMemoryUsage {
-    pub time: String,
+    /// UNIX時間 (ミリ秒)
+    pub time: i64,
     pub percentage: Option<f32>,
-    //TODO 他のフィールドも
+    pub used: Option<u64>,
+    pub breakdown: log::MemoryBreakdown,
+}
+impl TimedMemoryUsage {
+    /// /containers/{name}/memory/{field} で返せるフィールド名ならばその名前
+    pub fn field_name(name: &str) -> Option<&'static str> {
+        std::iter::once(&"used")
+            .chain(log::MemoryBreakdown::FIELDS)
+            .find(|field| **field == name)
+            .copied()
+    }
+    /// フィールド名に対応する値
+    pub fn field(&self, name: &str) -> Option<u64> {
+        match name {
+            "used" => self.used,
+            _ => self.breakdown.field(name).flatten(),
+        }
+    }
+}
+
+/// 1つのフィールドだけを {"time":..,"<name>":..} の形式で出力します
+pub struct TimedField<T> {
+    /// UNIX時間 (ミリ秒)
+    pub time: i64,
+    pub name: &'static str,
+    pub value: Option<T>,
+}
+impl<T: std::fmt::Display> std::fmt::Display for TimedField<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{\"time\":\"{}\",\"{}\":{}}}",
+            time::format_epoch_millis(self.time),
+            self.name,
+            option_to_string(self.value.as_ref()),
+        )
+    }
 }
 impl std::fmt::Display for TimedMemoryUsage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
             "{{\"time\":\"{}\",\"percentage\":{}}}",
-            self.time,
+            time::format_epoch_millis(self.time),
             option_to_string(self.percentage)
         )
     }
 }
 #[allow(non_snake_case)]
+#[derive(Clone)]
 pub struct TimedIoUsage {
-    pub time: String,
+    /// UNIX時間 (ミリ秒)
+    pub time: i64,
     pub readkBps: Option<u32>,
     pub writekBps: Option<u32>,
-    //TODO 他のフィールドも
+    pub readIops: Option<u32>,
+    pub writeIops: Option<u32>,
 }
 impl std::fmt::Display for TimedIoUsage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{{\"time\":\"{}\",\"readkBps\":{},\"writekBps\":{}}}",
-            self.time,
+            "{{\"time\":\"{}\",\"readkBps\":{},\"writekBps\":{},\"readIops\":{},\"writeIops\":{}}}",
+            time::format_epoch_millis(self.time),
             option_to_string(self.readkBps),
-            option_to_string(self.writekBps)
+            option_to_string(self.writekBps),
+            option_to_string(self.readIops),
+            option_to_string(self.writeIops),
         )
     }
 }
 #[allow(non_snake_case)]
+#[derive(Clone)]
 pub struct TimedNetUsage {
-    pub time: String,
+    /// UNIX時間 (ミリ秒)
+    pub time: i64,
     pub recvkBps: Option<u32>,
     pub sendkBps: Option<u32>,
 }
@@ -217,31 +418,677 @@ impl std::fmt::Display for TimedNetUsage {
         write!(
             f,
             "{{\"time\":\"{}\",\"recvkBps\":{},\"sendkBps\":{}}}",
-            self.time,
+            time::format_epoch_millis(self.time),
             option_to_string(self.recvkBps),
             option_to_string(self.sendkBps),
         )
     }
 }
 
+#[derive(Clone)]
+pub struct TimedPidsUsage {
+    /// UNIX時間 (ミリ秒)
+    pub time: i64,
+    pub percentage: Option<f32>,
+    pub current: Option<u64>,
+}
+impl std::fmt::Display for TimedPidsUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{\"time\":\"{}\",\"percentage\":{},\"current\":{}}}",
+            time::format_epoch_millis(self.time),
+            option_to_string(self.percentage),
+            option_to_string(self.current),
+        )
+    }
+}
+
+/// ロードアベレージ (ホストのみ)
+#[derive(Clone)]
+pub struct TimedLoadUsage {
+    /// UNIX時間 (ミリ秒)
+    pub time: i64,
+    pub load: host::LoadAverage,
+}
+impl std::fmt::Display for TimedLoadUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{\"time\":\"{}\",\"one\":{:.2},\"five\":{:.2},\"fifteen\":{:.2}}}",
+            time::format_epoch_millis(self.time),
+            self.load.one,
+            self.load.five,
+            self.load.fifteen,
+        )
+    }
+}
+
+/// マウントポイント毎のファイルシステムの容量 (ホストのみ)
+#[derive(Clone)]
+pub struct TimedFilesystemUsage {
+    /// UNIX時間 (ミリ秒)
+    pub time: i64,
+    pub filesystems: BTreeMap<String, host::FilesystemStats>,
+}
+impl TimedFilesystemUsage {
+    /// 最も使用率の高いファイルシステムの使用率 (ダウンサンプリングに使います)
+    pub fn max_percentage(&self) -> Option<f32> {
+        self.filesystems.values()
+            .filter_map(host::FilesystemStats::percentage)
+            .reduce(f32::max)
+    }
+}
+impl std::fmt::Display for TimedFilesystemUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let filesystems = self.filesystems.iter()
+            .map(|(mount, fs)| format!("{}:{}", json::stringify(mount.as_str()), fs))
+            .collect::<Vec<String>>()
+            .join(",");
+        write!(
+            f,
+            "{{\"time\":\"{}\",\"filesystems\":{{{}}}}}",
+            time::format_epoch_millis(self.time),
+            filesystems,
+        )
+    }
+}
+
+impl Timed for TimedCpuUsage {
+    fn time(&self) -> i64 { self.time }
+}
+impl Timed for TimedMemoryUsage {
+    fn time(&self) -> i64 { self.time }
+}
+impl Timed for TimedIoUsage {
+    fn time(&self) -> i64 { self.time }
+}
+impl Timed for TimedNetUsage {
+    fn time(&self) -> i64 { self.time }
+}
+impl Timed for TimedPidsUsage {
+    fn time(&self) -> i64 { self.time }
+}
+impl Timed for TimedLoadUsage {
+    fn time(&self) -> i64 { self.time }
+}
+impl Timed for TimedFilesystemUsage {
+    fn time(&self) -> i64 { self.time }
+}
+
+impl Mean for TimedCpuUsage {
+    fn mean(samples: &[&Self]) -> Self {
+        let mean_u64 = |f: fn(&Self) -> Option<u64>| mean_of(
+            samples.iter().map(|s| f(s).map(|v| v as f64))
+        ).map(|v| v.round() as u64);
+        let mean_f32 = |f: fn(&Self) -> Option<f32>| mean_of(
+            samples.iter().map(|s| f(s).map(f64::from))
+        ).map(|v| v as f32);
+        // CPU毎の値はCPUの数が変わった場合も、各CPUの値がある分だけで平均します
+        let ncpu = samples.iter().map(|s| s.percpu.len()).max().unwrap_or(0);
+        let percpu = (0..ncpu)
+            .map(|i| mean_of(samples.iter().map(|s| s.percpu.get(i).map(|v| *v as f64)))
+                .unwrap_or_default() as f32
+            )
+            .collect();
+        TimedCpuUsage {
+            time: mean_time(samples, |s| s.time),
+            percentage: mean_f32(|s| s.percentage),
+            user: mean_u64(|s| s.user),
+            kernel: mean_u64(|s| s.kernel),
+            throttled_time: mean_u64(|s| s.throttled_time),
+            throttle_ratio: mean_f32(|s| s.throttle_ratio),
+            percpu,
+        }
+    }
+}
+impl Mean for TimedMemoryUsage {
+    fn mean(samples: &[&Self]) -> Self {
+        let mean_u64 = |f: &dyn Fn(&Self) -> Option<u64>| mean_of(
+            samples.iter().map(|s| f(s).map(|v| v as f64))
+        ).map(|v| v.round() as u64);
+        TimedMemoryUsage {
+            time: mean_time(samples, |s| s.time),
+            percentage: mean_of(samples.iter().map(|s| s.percentage.map(f64::from)))
+                .map(|v| v as f32),
+            used: mean_u64(&|s| s.used),
+            breakdown: log::MemoryBreakdown::from_fields(
+                |name| mean_u64(&|s| s.breakdown.field(name).flatten())
+            ),
+        }
+    }
+}
+impl Mean for TimedIoUsage {
+    fn mean(samples: &[&Self]) -> Self {
+        TimedIoUsage {
+            time: mean_time(samples, |s| s.time),
+            readkBps: mean_of(samples.iter().map(|s| s.readkBps.map(f64::from)))
+                .map(|v| v.round() as u32),
+            writekBps: mean_of(samples.iter().map(|s| s.writekBps.map(f64::from)))
+                .map(|v| v.round() as u32),
+            readIops: mean_of(samples.iter().map(|s| s.readIops.map(f64::from)))
+                .map(|v| v.round() as u32),
+            writeIops: mean_of(samples.iter().map(|s| s.writeIops.map(f64::from)))
+                .map(|v| v.round() as u32),
+        }
+    }
+}
+impl Mean for TimedNetUsage {
+    fn mean(samples: &[&Self]) -> Self {
+        TimedNetUsage {
+            time: mean_time(samples, |s| s.time),
+            recvkBps: mean_of(samples.iter().map(|s| s.recvkBps.map(f64::from)))
+                .map(|v| v.round() as u32),
+            sendkBps: mean_of(samples.iter().map(|s| s.sendkBps.map(f64::from)))
+                .map(|v| v.round() as u32),
+        }
+    }
+}
+
+impl Mean for TimedPidsUsage {
+    fn mean(samples: &[&Self]) -> Self {
+        TimedPidsUsage {
+            time: mean_time(samples, |s| s.time),
+            percentage: mean_of(samples.iter().map(|s| s.percentage.map(f64::from)))
+                .map(|v| v as f32),
+            current: mean_of(samples.iter().map(|s| s.current.map(|v| v as f64)))
+                .map(|v| v.round() as u64),
+        }
+    }
+}
+
+impl Mean for TimedLoadUsage {
+    fn mean(samples: &[&Self]) -> Self {
+        let mean_f32 = |f: fn(&Self) -> f32| mean_of(
+            samples.iter().map(|s| Some(f64::from(f(s))))
+        ).unwrap_or_default() as f32;
+        TimedLoadUsage {
+            time: mean_time(samples, |s| s.time),
+            load: host::LoadAverage {
+                one: mean_f32(|s| s.load.one),
+                five: mean_f32(|s| s.load.five),
+                fifteen: mean_f32(|s| s.load.fifteen),
+            },
+        }
+    }
+}
+impl Mean for TimedFilesystemUsage {
+    /// マウントポイント毎に、値があるサンプルだけで平均します
+    fn mean(samples: &[&Self]) -> Self {
+        let mut mounts = samples.iter()
+            .flat_map(|s| s.filesystems.keys())
+            .collect::<Vec<&String>>();
+        mounts.sort();
+        mounts.dedup();
+        let filesystems = mounts.into_iter()
+            .map(|mount| {
+                let mean_u64 = |f: fn(&host::FilesystemStats) -> u64| mean_of(
+                    samples.iter().map(|s| s.filesystems.get(mount).map(|fs| f(fs) as f64))
+                ).unwrap_or_default().round() as u64;
+                (mount.clone(), host::FilesystemStats {
+                    total: mean_u64(|fs| fs.total),
+                    free: mean_u64(|fs| fs.free),
+                    available: mean_u64(|fs| fs.available),
+                })
+            })
+            .collect();
+        TimedFilesystemUsage {
+            time: mean_time(samples, |s| s.time),
+            filesystems,
+        }
+    }
+}
+
+impl Sum for TimedCpuUsage {
+    /// throttle_ratio は割合なので平均します
+    fn sum(samples: &[&Self]) -> Self {
+        let sum_u64 = |f: fn(&Self) -> Option<u64>| sum_of(
+            samples.iter().map(|s| f(s).map(|v| v as f64))
+        ).map(|v| v as u64);
+        let ncpu = samples.iter().map(|s| s.percpu.len()).max().unwrap_or(0);
+        let percpu = (0..ncpu)
+            .map(|i| samples.iter().filter_map(|s| s.percpu.get(i)).sum())
+            .collect();
+        TimedCpuUsage {
+            time: mean_time(samples, |s| s.time),
+            percentage: sum_of(samples.iter().map(|s| s.percentage.map(f64::from)))
+                .map(|v| v as f32),
+            user: sum_u64(|s| s.user),
+            kernel: sum_u64(|s| s.kernel),
+            throttled_time: sum_u64(|s| s.throttled_time),
+            throttle_ratio: mean_of(samples.iter().map(|s| s.throttle_ratio.map(f64::from)))
+                .map(|v| v as f32),
+            percpu,
+        }
+    }
+}
+impl Sum for TimedMemoryUsage {
+    /// percentage はコンテナ毎の上限に対する割合なので平均します
+    fn sum(samples: &[&Self]) -> Self {
+        let sum_u64 = |f: &dyn Fn(&Self) -> Option<u64>| sum_of(
+            samples.iter().map(|s| f(s).map(|v| v as f64))
+        ).map(|v| v as u64);
+        TimedMemoryUsage {
+            time: mean_time(samples, |s| s.time),
+            percentage: mean_of(samples.iter().map(|s| s.percentage.map(f64::from)))
+                .map(|v| v as f32),
+            used: sum_u64(&|s| s.used),
+            breakdown: log::MemoryBreakdown::from_fields(
+                |name| sum_u64(&|s| s.breakdown.field(name).flatten())
+            ),
+        }
+    }
+}
+impl Sum for TimedIoUsage {
+    fn sum(samples: &[&Self]) -> Self {
+        let sum_u32 = |f: fn(&Self) -> Option<u32>| sum_of(
+            samples.iter().map(|s| f(s).map(f64::from))
+        ).map(|v| v as u32);
+        TimedIoUsage {
+            time: mean_time(samples, |s| s.time),
+            readkBps: sum_u32(|s| s.readkBps),
+            writekBps: sum_u32(|s| s.writekBps),
+            readIops: sum_u32(|s| s.readIops),
+            writeIops: sum_u32(|s| s.writeIops),
+        }
+    }
+}
+impl Sum for TimedNetUsage {
+    fn sum(samples: &[&Self]) -> Self {
+        let sum_u32 = |f: fn(&Self) -> Option<u32>| sum_of(
+            samples.iter().map(|s| f(s).map(f64::from))
+        ).map(|v| v as u32);
+        TimedNetUsage {
+            time: mean_time(samples, |s| s.time),
+            recvkBps: sum_u32(|s| s.recvkBps),
+            sendkBps: sum_u32(|s| s.sendkBps),
+        }
+    }
+}
+impl Sum for TimedPidsUsage {
+    /// percentage はコンテナ毎の上限に対する割合なので平均します
+    fn sum(samples: &[&Self]) -> Self {
+        TimedPidsUsage {
+            time: mean_time(samples, |s| s.time),
+            percentage: mean_of(samples.iter().map(|s| s.percentage.map(f64::from)))
+                .map(|v| v as f32),
+            current: sum_of(samples.iter().map(|s| s.current.map(|v| v as f64)))
+                .map(|v| v as u64),
+        }
+    }
+}
+
+/// UsageCache::net_interfaces のキー
+/// (コンテナ名に "/" は使えないので区切りに使います)
+pub fn interface_key(container_name: &str, interface: &str) -> String {
+    format!("{}/{}", container_name, interface)
+}
+
+/// UsageCache::io_devices のキー ("major:minor" にも "/" は含まれません)
+pub fn device_key(container_name: &str, device: &str) -> String {
+    format!("{}/{}", container_name, device)
+}
+
+/// コンテナ毎の最新の tick のデータ (/metrics で使用します)
+/// stats は累積値、usage は前の tick との差分から計算した値です
+pub struct LatestUsage {
+    /// UNIX時間 (ミリ秒)
+    pub time: i64,
+    pub stats: log::Stats,
+    pub usage: log::Usage,
+}
+
+/// 同じ名前で記録されたコンテナ (ID) 毎の期間
+/// (作り直されたコンテナや名前が変更されたコンテナを区別するために使います)
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContainerSpan {
+    pub info: log::ContainerInfo,
+    /// 最初に記録された UNIX時間 (ミリ秒)
+    pub from: i64,
+    /// 最後に記録された UNIX時間 (ミリ秒)
+    pub to: i64,
+}
+impl std::fmt::Display for ContainerSpan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{{},\"from\":\"{}\",\"to\":\"{}\"}}",
+            self.info.json_fields(),
+            time::format_epoch_millis(self.from),
+            time::format_epoch_millis(self.to),
+        )
+    }
+}
+
+/// 収集ループの状態
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CollectorState {
+    /// 全コンテナの使用状況を取得できています
+    #[default]
+    Running,
+    /// 一部のコンテナの使用状況を取得できていません
+    Degraded,
+    /// Docker に接続できず、間隔を延ばしながら再試行しています
+    Disconnected,
+}
+impl CollectorState {
+    pub const ALL: [CollectorState; 3] = [
+        CollectorState::Running,
+        CollectorState::Degraded,
+        CollectorState::Disconnected,
+    ];
+}
+impl std::fmt::Display for CollectorState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CollectorState::Running => write!(f, "running"),
+            CollectorState::Degraded => write!(f, "degraded"),
+            CollectorState::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+/// 使用状況の収集にかかった時間と失敗したコンテナの数 (/metrics, /collector で使用します)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CollectionStats {
+    pub state: CollectorState,
+    /// 直近の tick の収集にかかった時間
+    pub duration: std::time::Duration,
+    /// 直近の tick で取得できたコンテナの数 (_host を除きます)
+    pub containers: usize,
+    /// 直近の tick で取得に失敗したコンテナの数
+    pub failures: usize,
+    /// 起動してから取得に失敗したコンテナの累計
+    pub failures_total: u64,
+    /// Docker に接続できなかった連続回数 (再試行の間隔に使います)
+    pub consecutive_errors: u32,
+    /// Docker に接続できなかった直近のエラー
+    pub last_error: Option<String>,
+    /// 最後にコンテナの一覧を取得できた時刻 (UNIX時間、ミリ秒)
+    pub last_success: Option<i64>,
+    /// Disconnected になった時刻 (UNIX時間、ミリ秒)
+    pub disconnected_since: Option<i64>,
+    /// 再試行を待つ間、コンテナを記録しなかった tick の累計
+    pub skipped_ticks_total: u64,
+    /// 最後に tick を終えた時刻 (UNIX時間、ミリ秒、/health で使用します)
+    pub last_tick: Option<i64>,
+}
+impl CollectionStats {
+    /// コンテナの一覧を取得できた tick を記録します
+    pub fn record(
+        &mut self,
+        duration: std::time::Duration,
+        containers: usize,
+        failures: usize,
+        time: i64,
+    ) {
+        self.state = if failures > 0 {
+            CollectorState::Degraded
+        } else {
+            CollectorState::Running
+        };
+        self.duration = duration;
+        self.containers = containers;
+        self.failures = failures;
+        self.failures_total += failures as u64;
+        self.consecutive_errors = 0;
+        self.last_error = None;
+        self.last_success = Some(time);
+        self.disconnected_since = None;
+    }
+
+    /// Docker に接続できなかった tick を記録します
+    pub fn record_error(&mut self, error: String, time: i64) {
+        self.state = CollectorState::Disconnected;
+        self.containers = 0;
+        self.failures = 0;
+        self.consecutive_errors = self.consecutive_errors.saturating_add(1);
+        self.last_error = Some(error);
+        self.disconnected_since.get_or_insert(time);
+    }
+
+    /// 再試行を待つために記録しなかった tick を数えます
+    pub fn record_skipped(&mut self) {
+        self.skipped_ticks_total += 1;
+    }
+
+    /// 次に Docker へ接続するまでの間隔を返します
+    /// 失敗が続く毎に tick の 1, 2, 4, ... 倍と延ばし、max_backoff で頭打ちにします
+    /// (tick の境界に合わせるため、常に tick の整数倍です)
+    pub fn retry_after(
+        &self,
+        tick: std::time::Duration,
+        max_backoff: std::time::Duration,
+    ) -> std::time::Duration {
+        let max_ticks = (max_backoff.as_millis() / tick.as_millis().max(1)).max(1) as u32;
+        let ticks = 1_u32
+            .checked_shl(self.consecutive_errors.saturating_sub(1))
+            .unwrap_or(u32::MAX)
+            .min(max_ticks);
+        tick * ticks
+    }
+}
+impl std::fmt::Display for CollectionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let optional_time = |time: Option<i64>| time
+            .map_or("null".to_string(), |t| format!("\"{}\"", time::format_epoch_millis(t)));
+        write!(
+            f,
+            "{{\"state\":\"{}\",\"durationMillis\":{},\"containers\":{},\"failures\":{},\
+             \"failuresTotal\":{},\"consecutiveErrors\":{},\"lastError\":{},\
+             \"lastSuccess\":{},\"disconnectedSince\":{},\"skippedTicksTotal\":{},\"lastTick\":{}}}",
+            self.state,
+            self.duration.as_millis(),
+            self.containers,
+            self.failures,
+            self.failures_total,
+            self.consecutive_errors,
+            self.last_error.as_deref().map_or("null".to_string(), json::stringify),
+            optional_time(self.last_success),
+            optional_time(self.disconnected_since),
+            self.skipped_ticks_total,
+            optional_time(self.last_tick),
+        )
+    }
+}
+
+/// 起動時に読んだログファイル1つ分の結果
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LogFileReport {
+    pub path: String,
+    /// 読み込んだ tick (行) の数
+    pub records: usize,
+    /// 壊れていて読み飛ばした、または書きかけとして切り詰めた tick (行) の数
+    pub skipped: usize,
+    /// 書きかけの末尾として切り詰めたバイト数
+    pub truncated_bytes: u64,
+    /// ファイルを読めなかった場合のエラー
+    pub error: Option<String>,
+}
+impl std::fmt::Display for LogFileReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{\"path\":{},\"records\":{},\"skipped\":{},\"truncatedBytes\":{},\"error\":{}}}",
+            json::stringify(self.path.as_str()),
+            self.records,
+            self.skipped,
+            self.truncated_bytes,
+            self.error.as_deref().map_or("null".to_string(), json::stringify),
+        )
+    }
+}
+
+/// 起動時のログの復旧と読み込みの結果 (/startup で使用します)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StartupReport {
+    /// 読み込みを終えた時刻 (UNIX時間、ミリ秒)
+    pub time: Option<i64>,
+    /// 復旧と読み込みにかかった時間
+    pub duration: std::time::Duration,
+    /// キャッシュに載せた tick の数
+    pub loaded: usize,
+    pub files: Vec<LogFileReport>,
+}
+impl StartupReport {
+    pub fn skipped(&self) -> usize {
+        self.files.iter().map(|f| f.skipped).sum()
+    }
+    pub fn truncated_bytes(&self) -> u64 {
+        self.files.iter().map(|f| f.truncated_bytes).sum()
+    }
+}
+impl std::fmt::Display for StartupReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let files = self.files.iter()
+            .map(LogFileReport::to_string)
+            .collect::<Vec<String>>()
+            .join(",");
+        write!(
+            f,
+            "{{\"time\":{},\"durationMillis\":{},\"loaded\":{},\"skipped\":{},\
+             \"truncatedBytes\":{},\"files\":[{}]}}",
+            self.time.map_or("null".to_string(), |t| format!("\"{}\"", time::format_epoch_millis(t))),
+            self.duration.as_millis(),
+            self.loaded,
+            self.skipped(),
+            self.truncated_bytes(),
+            files,
+        )
+    }
+}
+
+/// 1回の tick で記録された全コンテナの使用状況 (/stream で配信します)
+pub struct TickUsages {
+    /// UNIX時間 (ミリ秒)
+    pub time: i64,
+    pub usages: HashMap<String, log::Usage>,
+}
+
 pub struct UsageCache {
     pub cpu: UsageCacheMap<TimedCpuUsage>,
     pub memory: UsageCacheMap<TimedMemoryUsage>,
     pub io: UsageCacheMap<TimedIoUsage>,
+    /// ブロックデバイス毎の値 (キーは device_key で作ります)
+    pub io_devices: UsageCacheMap<TimedIoUsage>,
     pub net: UsageCacheMap<TimedNetUsage>,
+    /// ネットワークインターフェース毎の値 (キーは interface_key で作ります)
+    pub net_interfaces: UsageCacheMap<TimedNetUsage>,
+    pub pids: UsageCacheMap<TimedPidsUsage>,
+    /// ロードアベレージとファイルシステム (host::HOST_NAME のみ記録されます)
+    pub load: UsageCacheMap<TimedLoadUsage>,
+    pub filesystems: UsageCacheMap<TimedFilesystemUsage>,
+    /// コンテナのライフサイクルイベント
+    pub events: events::EventCache,
+    /// コンテナ名毎の、その名前で記録されたコンテナの履歴 (古い順)
+    pub containers: HashMap<String, Vec<ContainerSpan>>,
+    /// 直近の tick で記録されたコンテナのみを保持します
+    pub latest: HashMap<String, LatestUsage>,
+    pub collection: CollectionStats,
+    /// 起動時のログの復旧と読み込みの結果
+    pub startup: StartupReport,
+    /// supervisor が見守るワーカー毎の稼働状況
+    pub workers: BTreeMap<String, supervisor::WorkerHealth>,
+    /// キャッシュより古い期間をログファイルから読み込んだ直近の結果
+    pub range: Option<RangeCache>,
 }
 impl UsageCache {
-    fn new() -> Self {
+    pub fn new(max_length: usize) -> Self {
         UsageCache {
-            cpu: UsageCacheMap::<TimedCpuUsage>::new(),
-            memory: UsageCacheMap::<TimedMemoryUsage>::new(),
-            io: UsageCacheMap::<TimedIoUsage>::new(),
-            net: UsageCacheMap::<TimedNetUsage>::new(),
+            cpu: UsageCacheMap::<TimedCpuUsage>::new(max_length),
+            memory: UsageCacheMap::<TimedMemoryUsage>::new(max_length),
+            io: UsageCacheMap::<TimedIoUsage>::new(max_length),
+            io_devices: UsageCacheMap::<TimedIoUsage>::new(max_length),
+            net: UsageCacheMap::<TimedNetUsage>::new(max_length),
+            net_interfaces: UsageCacheMap::<TimedNetUsage>::new(max_length),
+            pids: UsageCacheMap::<TimedPidsUsage>::new(max_length),
+            load: UsageCacheMap::<TimedLoadUsage>::new(max_length),
+            filesystems: UsageCacheMap::<TimedFilesystemUsage>::new(max_length),
+            events: events::EventCache::new(max_length),
+            containers: HashMap::new(),
+            latest: HashMap::new(),
+            collection: CollectionStats::default(),
+            startup: StartupReport::default(),
+            workers: BTreeMap::new(),
+            range: None,
+        }
+    }
+
+    /// コンテナ名で記録されたコンテナの識別情報を追加します
+    /// 直前と同じIDならば期間を延ばし、キャッシュより古い期間は削除します
+    pub fn record_container(&mut self, name: &str, info: &log::ContainerInfo, time: i64) {
+        let earliest = self.cpu.earliest_time().unwrap_or(time);
+        let spans = self.containers.entry(name.to_string()).or_default();
+        match spans.last_mut() {
+            Some(last) if last.info.id == info.id => {
+                last.to = last.to.max(time);
+                last.info = info.clone();
+            },
+            _ => spans.push(ContainerSpan { info: info.clone(), from: time, to: time }),
         }
+        spans.retain(|span| span.to >= earliest);
+    }
+
+    /// コンテナ名で記録されたコンテナのうち、ID が id_prefix で始まるものの期間
+    /// (同じIDが複数回現れた場合は最初から最後までの期間)
+    pub fn container_id_range(&self, name: &str, id_prefix: &str) -> Option<TimeRange> {
+        let spans = self.containers.get(name)?
+            .iter()
+            .filter(|span| !id_prefix.is_empty() && span.info.id.starts_with(id_prefix));
+        let (from, to) = spans
+            .fold(None, |range: Option<(i64, i64)>, span| Some(match range {
+                Some((from, to)) => (from.min(span.from), to.max(span.to)),
+                None => (span.from, span.to),
+            }))?;
+        // TimeRange は to を含まないので最後のデータを含むように1ミリ秒延ばします
+        Some(TimeRange { from: Some(from), to: Some(to + 1) })
     }
+
+    /// ラベル label_key の値が label_value だったコンテナの (名前, 期間) の一覧
+    /// 同じ名前でもラベルが変わった場合は、一致していた期間のみを返します
+    pub fn group_members(&self, label_key: &str, label_value: &str) -> Vec<(String, TimeRange)> {
+        let mut members = self.containers.iter()
+            .flat_map(|(name, spans)| spans.iter()
+                .filter(|span| span.info.labels.get(label_key)
+                    .is_some_and(|value| value == label_value))
+                .map(|span| (
+                    name.clone(),
+                    TimeRange { from: Some(span.from), to: Some(span.to + 1) },
+                ))
+            )
+            .collect::<Vec<(String, TimeRange)>>();
+        members.sort_by_key(|(name, range)| (name.clone(), range.from));
+        members
+    }
+
+    /// ラベル label_key の値毎の、その値を持つコンテナ名の一覧 (ソート済み)
+    pub fn label_values(&self, label_key: &str) -> BTreeMap<String, Vec<String>> {
+        let mut values: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (name, spans) in &self.containers {
+            for value in spans.iter().filter_map(|span| span.info.labels.get(label_key)) {
+                let names = values.entry(value.clone()).or_default();
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+        }
+        values.values_mut().for_each(|names| names.sort());
+        values
+    }
+}
+
+/// ログファイルから読み込んだ期間のデータ
+///
+/// 同じ期間の要求が続いてもファイルを読み直さないよう、次の tick まで使い回します
+pub struct RangeCache {
+    pub time_range: TimeRange,
+    /// 読み込んだ時点の collection.last_tick
+    pub last_tick: Option<i64>,
+    pub usages: Arc<UsageCache>,
 }
 
 pub type SharedUsageCache = Arc<RwLock<UsageCache>>;
-pub fn create_shared_cache() -> SharedUsageCache {
-    Arc::new(RwLock::new(UsageCache::new()))
+/// コンテナ毎に最大 max_length 個のデータを保持するキャッシュを作成します
+pub fn create_shared_cache(max_length: usize) -> SharedUsageCache {
+    Arc::new(RwLock::new(UsageCache::new(max_length)))
 }
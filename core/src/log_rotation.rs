@@ -0,0 +1,360 @@
+use std::collections::{ BTreeMap, HashMap, };
+use std::path::{ Path, PathBuf, };
+
+use super::config;
+use super::error;
+use super::log;
+use super::log::custom_dump;
+use super::store;
+use super::time;
+
+/// 集計対象の (リソース種別, フィールド名)
+const SUMMARY_FIELDS: &[(&str, &str)] = &[
+    ("cpu", "percentage"),
+    ("cpu", "user"),
+    ("cpu", "kernel"),
+    ("cpu", "throttledTime"),
+    ("cpu", "throttleRatio"),
+    ("memory", "percentage"),
+    ("memory", "used"),
+    ("memory", "rss"),
+    ("memory", "cache"),
+    ("memory", "activeFile"),
+    ("memory", "inactiveFile"),
+    ("memory", "shmem"),
+    ("memory", "swap"),
+    ("memory", "kernelStack"),
+    ("memory", "slab"),
+    ("io", "readkBps"),
+    ("io", "writekBps"),
+    ("io", "readIops"),
+    ("io", "writeIops"),
+    ("net", "recvkBps"),
+    ("net", "sendkBps"),
+    ("pids", "percentage"),
+    ("pids", "current"),
+    ("load", "one"),
+    ("load", "five"),
+    ("load", "fifteen"),
+];
+
+/// 集計せずにそのまま引き継ぐコンテナの識別情報
+const IDENTITY_FIELDS: &[&str] = &["id", "image", "created", "labels"];
+
+/// ログファイルの階層
+/// daily は tick 毎の生データ、weekly/monthly はバケット毎の集計値です
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tier {
+    Daily,
+    /// 日次ログのセグメント (config.log_format が binary, both の場合)
+    DailySegment,
+    Weekly,
+    Monthly,
+}
+impl Tier {
+    fn file_name(&self) -> &'static str {
+        match self {
+            Tier::Daily => "log_daily",
+            Tier::DailySegment => "log_daily.seg",
+            Tier::Weekly => "log_weekly",
+            Tier::Monthly => "log_monthly",
+        }
+    }
+    /// 保持する世代数 (.1 〜 .N)
+    fn generations(&self, config: &config::Config) -> usize {
+        match self {
+            Tier::Daily | Tier::DailySegment => config.daily_generations,
+            Tier::Weekly => config.weekly_generations,
+            Tier::Monthly => config.monthly_generations,
+        }
+    }
+    /// 現在のファイルのパス
+    pub fn path(&self, config: &config::Config) -> PathBuf {
+        config.log_dir.join(self.file_name())
+    }
+    /// generation 世代前のファイルのパス (0 は現在のファイル)
+    pub fn generation_path(
+        &self,
+        config: &config::Config,
+        generation: usize,
+    ) -> PathBuf {
+        if generation == 0 {
+            self.path(config)
+        } else {
+            config.log_dir.join(format!("{}.{}", self.file_name(), generation))
+        }
+    }
+}
+
+/// 細かい階層・新しい世代から順にログファイルのパスを返します
+/// (存在しないファイルも含みます)
+///
+/// 日次ログは同じ世代のセグメントと json を続けて返します
+/// (log.format を途中で変えても、同じ日の両方を読めるようにします)
+pub fn log_files_finest_first(config: &config::Config) -> Vec<PathBuf> {
+    let daily = (0..=config.daily_generations)
+        .flat_map(|generation| [
+            Tier::DailySegment.generation_path(config, generation),
+            Tier::Daily.generation_path(config, generation),
+        ]);
+    daily
+        .chain([Tier::Weekly, Tier::Monthly]
+            .iter()
+            .flat_map(|tier| (0..=tier.generations(config))
+                .map(|generation| tier.generation_path(config, generation))
+            )
+        )
+        .collect()
+}
+
+/// 保持している世代のログファイルが含み得る最も古い時刻 (UNIX時間, ミリ秒)
+///
+/// today の 00:00 から、各階層の現在のファイルと保持世代数分の期間を遡ります
+/// (1か月は 31 日として数えます)
+pub fn retained_since(config: &config::Config, today: i64) -> i64 {
+    let days = [
+        config.daily_generations + 1,
+        (config.weekly_generations + 1) * 7,
+        (config.monthly_generations + 1) * 31,
+    ].into_iter().max().unwrap_or_default();
+    (today - days as i64) * time::SECONDS_PER_DAY * 1000
+}
+
+/// ファイルの最終更新日 (1970-01-01 からの日数, UTC)
+/// ファイルが無ければ None を返します
+pub fn last_written_day<T: AsRef<Path>>(
+    file_path: T,
+) -> Result<Option<i64>, error::Error> {
+    match std::fs::metadata(file_path) {
+        Ok(metadata) => {
+            let seconds = metadata.modified()?
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs() as i64;
+            Ok(Some(seconds.div_euclid(time::SECONDS_PER_DAY)))
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// file → file.1 → file.2 … と名前を付け替え、
+/// 保持世代数を超えたファイルを削除します
+fn rotate_generations(
+    config: &config::Config,
+    tier: Tier,
+) -> Result<bool, error::Error> {
+    if !std::fs::exists(tier.path(config))? {
+        return Ok(false);
+    }
+    let generations = tier.generations(config);
+    let oldest = tier.generation_path(config, generations);
+    if oldest.is_dir() {
+        std::fs::remove_dir_all(&oldest)?;
+    } else if std::fs::exists(&oldest)? {
+        std::fs::remove_file(&oldest)?;
+    }
+    for generation in (0..generations).rev() {
+        let from = tier.generation_path(config, generation);
+        if std::fs::exists(&from)? {
+            std::fs::rename(&from, tier.generation_path(config, generation + 1))?;
+        }
+    }
+    Ok(true)
+}
+
+/// 集計中のフィールド値
+#[derive(Debug, Clone, Copy)]
+struct Summary {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u64,
+}
+impl Summary {
+    /// 生データの値、または集計済みの {"min","avg","max","count"} を読みます
+    fn from_json(value: &json::JsonValue) -> Option<Self> {
+        if let Some(v) = value.as_f64() {
+            return Some(Summary { min: v, max: v, sum: v, count: 1 });
+        }
+        let count = value["count"].as_u64()?;
+        Some(Summary {
+            min: value["min"].as_f64()?,
+            max: value["max"].as_f64()?,
+            sum: value["avg"].as_f64()? * count as f64,
+            count,
+        })
+    }
+    fn merge(&mut self, other: &Summary) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+    fn to_json(self) -> json::JsonValue {
+        json::object! {
+            min: self.min,
+            avg: self.sum / self.count as f64,
+            max: self.max,
+            count: self.count,
+        }
+    }
+}
+
+type Bucket = HashMap<String, HashMap<(&'static str, &'static str), Summary>>;
+
+/// 生データ (log_daily, log_daily.seg) または集計済みデータ (log_weekly) のファイルを
+/// bucket_seconds 毎に min/avg/max へ集計し、集計結果を行毎に返します
+///
+/// 出力形式:
+/// {"time":"<バケット開始時刻>","seconds":<バケット幅>,
+///  "stats":{"<コンテナ名>":{"id":..,"image":..,"created":..,
+///   "cpu":{"percentage":{"min":..,"avg":..,"max":..,"count":..}},..}}}
+///
+/// id, image, created, labels はバケット内で最後に記録されたコンテナのものです
+pub fn summarize<T: AsRef<Path>>(
+    source_path: T,
+    bucket_seconds: u64,
+) -> Result<Vec<String>, error::Error> {
+    let bucket_seconds = bucket_seconds as i64;
+    let content = if store::is_segment(&source_path) {
+        log::export_json_lines(&source_path)?.join("\n")
+    } else {
+        std::fs::read_to_string(&source_path)?
+    };
+
+    let mut buckets: BTreeMap<i64, Bucket> = BTreeMap::new();
+    let mut identities: HashMap<(i64, String), json::JsonValue> = HashMap::new();
+    for line in content.lines() {
+        let Ok(json) = json::parse(line) else {
+            eprintln!("error in log json format: {}", line);
+            continue;
+        };
+        let Some(Ok(epoch_seconds)) = json["time"].as_str()
+            .map(time::parse_epoch_seconds)
+        else {
+            eprintln!("time entry not found: {}", line);
+            continue;
+        };
+        if epoch_seconds < 0 {
+            // docker api sometimes returns unix epoc ZERO (0001-01-01).
+            continue;
+        }
+
+        let start = epoch_seconds - epoch_seconds.rem_euclid(bucket_seconds);
+        let bucket = buckets
+            .entry(start)
+            .or_default();
+        for (container_name, usage) in json["stats"].entries() {
+            if usage["id"].is_string() {
+                let mut identity = json::JsonValue::new_object();
+                for key in IDENTITY_FIELDS {
+                    if usage.has_key(key) {
+                        identity[*key] = usage[*key].clone();
+                    }
+                }
+                identities.insert((start, container_name.to_string()), identity);
+            }
+            let container = bucket.entry(container_name.to_string())
+                .or_default();
+            for field in SUMMARY_FIELDS {
+                if let Some(summary) = Summary::from_json(&usage[field.0][field.1]) {
+                    container.entry(*field)
+                        .and_modify(|s| s.merge(&summary))
+                        .or_insert(summary);
+                }
+            }
+        }
+    }
+
+    let lines = buckets.into_iter()
+        .map(|(start, containers)| {
+            let mut stats = json::JsonValue::new_object();
+            for (container_name, fields) in containers {
+                let mut usage = identities.remove(&(start, container_name.clone()))
+                    .unwrap_or_else(json::JsonValue::new_object);
+                for ((resource, name), summary) in fields {
+                    if !usage.has_key(resource) {
+                        usage[resource] = json::JsonValue::new_object();
+                    }
+                    usage[resource][name] = summary.to_json();
+                }
+                stats[container_name] = usage;
+            }
+            custom_dump(&json::object! {
+                time: time::format_epoch_seconds(start),
+                seconds: bucket_seconds,
+                stats: stats,
+            })
+        })
+        .collect();
+
+    Ok(lines)
+}
+
+fn append_lines<T: AsRef<Path>>(
+    file_path: T,
+    lines: &[String],
+) -> Result<(), error::Error> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file_path)?;
+    for line in lines {
+        std::io::Write::write_all(&mut file, (line.to_string() + "\r\n").as_bytes())?;
+    }
+    Ok(())
+}
+
+/// 日付が last_day から today に変わった際のローテーションを行います
+///
+/// - 毎日 00:00: log_daily(.seg) → log_daily(.seg).1 とし、log_daily(.seg).1 を log_weekly に集計
+/// - 毎週月曜 00:00: log_weekly → log_weekly.1 とし、log_weekly.1 を log_monthly に集計
+/// - 毎月1日 00:00: log_monthly → log_monthly.1
+///
+/// 保持世代数を超えたファイルは削除します
+pub fn rotate(
+    config: &config::Config,
+    last_day: i64,
+    today: i64,
+) -> Result<(), error::Error> {
+    if last_day >= today {
+        return Ok(());
+    }
+
+    println!("rotating {}...", Tier::Daily.path(config).display());
+    let segment_rotated = rotate_generations(config, Tier::DailySegment)?;
+    let json_rotated = rotate_generations(config, Tier::Daily)?;
+    // log.format = "both" の場合は同じ内容なので、セグメントのみ集計します
+    let rotated = if segment_rotated {
+        Some(Tier::DailySegment)
+    } else if json_rotated {
+        Some(Tier::Daily)
+    } else {
+        None
+    };
+    if let Some(tier) = rotated {
+        let lines = summarize(
+            tier.generation_path(config, 1),
+            config.weekly_bucket_seconds,
+        )?;
+        append_lines(Tier::Weekly.path(config), &lines)?;
+    }
+
+    if time::week_from_days(last_day) != time::week_from_days(today) {
+        println!("rotating {}...", Tier::Weekly.path(config).display());
+        if rotate_generations(config, Tier::Weekly)? {
+            let lines = summarize(
+                Tier::Weekly.generation_path(config, 1),
+                config.monthly_bucket_seconds,
+            )?;
+            append_lines(Tier::Monthly.path(config), &lines)?;
+        }
+    }
+
+    if time::month_from_days(last_day) != time::month_from_days(today) {
+        println!("rotating {}...", Tier::Monthly.path(config).display());
+        rotate_generations(config, Tier::Monthly)?;
+    }
+
+    Ok(())
+}
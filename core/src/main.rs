@@ -1,28 +1,91 @@
+use std::process::ExitCode;
+use std::sync::Arc;
 
-mod error;
-mod log;
-mod server;
-mod log_cache;
+use cephylas::{ broadcast, config, error, events, log, log_cache, server, store, supervisor, };
 
-use log_cache::create_shared_cache;
-
-const MAX_LOG_LINES: usize = 8640;
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::from(supervisor::EXIT_SUCCESS),
+        Err(e @ error::Error::ConfigError(_)) => {
+            eprintln!("{}", e);
+            ExitCode::from(supervisor::EXIT_USAGE)
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(supervisor::EXIT_FAILURE)
+        },
+    }
+}
 
-fn main() -> Result<(), error::Error> {
+fn run() -> Result<(), error::Error> {
 
-    let log_cache = create_shared_cache();
+    let command_line = config::CommandLine::parse(std::env::args().skip(1))?;
+    if command_line.help {
+        print!("{}", config::usage());
+        return Ok(());
+    }
+    if let Some(json_log_path) = &command_line.convert_log {
+        let segment_path = store::segment_path_for(json_log_path);
+        let nticks = log::convert_json_log(json_log_path, &segment_path)?;
+        println!("{} ticks are written to {}.", nticks, segment_path.display());
+        return Ok(());
+    }
+    if let Some(segment_path) = &command_line.export_json {
+        for line in log::export_json_lines(segment_path)? {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+    let config = config::Config::load(&command_line, std::env::vars())?;
+    if command_line.print_config {
+        print!("{}", config);
+        return Ok(());
+    }
 
-    log::read_log(&log_cache)?;
+    supervisor::install_signal_handlers()?;
+    let shutdown = supervisor::create_shared_shutdown();
+    let log_cache = log_cache::create_shared_cache(config.max_log_length);
 
-    let server_cache = std::sync::Arc::clone(&log_cache);
-    let server_handle = std::thread::spawn(move || server::start_server(&server_cache));
+    log::read_log(&config, &log_cache)?;
+    events::read_event_log(&config, &log_cache)?;
+    let broadcaster = broadcast::create_shared_broadcaster(config.max_streams);
 
-    let logger_cache = std::sync::Arc::clone(&log_cache);
-    let logger_handle = std::thread::spawn(move || log::log_json(&logger_cache));
+    // 各ワーカーは異常終了すると supervisor が再起動します
+    let workers = vec![
+        {
+            let (config, log_cache, broadcaster, shutdown) = (
+                config.clone(), Arc::clone(&log_cache), Arc::clone(&broadcaster), Arc::clone(&shutdown),
+            );
+            supervisor::Worker::new(
+                supervisor::SERVER_WORKER,
+                move || server::start_server(&config, &log_cache, &broadcaster, &shutdown),
+            )
+        },
+        {
+            let (config, log_cache, shutdown) = (
+                config.clone(), Arc::clone(&log_cache), Arc::clone(&shutdown),
+            );
+            supervisor::Worker::new(
+                supervisor::EVENTS_WORKER,
+                move || events::watch_events(&config, &log_cache, &shutdown),
+            )
+        },
+        {
+            let (config, log_cache, broadcaster, shutdown) = (
+                config.clone(), Arc::clone(&log_cache), Arc::clone(&broadcaster), Arc::clone(&shutdown),
+            );
+            supervisor::Worker::new(
+                supervisor::LOGGER_WORKER,
+                move || log::log_json(&config, &log_cache, &broadcaster, &shutdown),
+            )
+        },
+    ];
 
-    logger_handle.join().expect("failed to join logger_handle");
-    server_handle.join().expect("failed to join server_handle");
+    // 収集中の tick と処理中の接続が終わるまで待ちます
+    let grace = config.collector_timeout.max(config.connection_timeout)
+        + std::time::Duration::from_secs(5);
+    supervisor::supervise(workers, &log_cache, &shutdown, grace)?;
+    println!("stopped.");
 
     Ok(())
 }
-
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+
+use super::log_cache::{ CollectionStats, CollectorState, LatestUsage };
+
+/// Prometheus のメトリクス1種類分の定義
+struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    value: fn(&LatestUsage) -> Option<f64>,
+}
+
+const METRICS: &[Metric] = &[
+    Metric {
+        name: "cephylas_container_cpu_percent",
+        help: "CPU usage of the container in percent of one core.",
+        kind: "gauge",
+        value: |l| l.usage.cpu.percentage.map(f64::from),
+    },
+    Metric {
+        name: "cephylas_container_cpu_seconds_total",
+        help: "Cumulative CPU time consumed by the container in seconds.",
+        kind: "counter",
+        // stats.cpu.total はミリ秒
+        value: |l| l.stats.cpu.total.map(|ms| ms as f64 / 1000.0),
+    },
+    Metric {
+        name: "cephylas_container_cpu_user_seconds_total",
+        help: "Cumulative CPU time consumed by the container in user mode in seconds.",
+        kind: "counter",
+        value: |l| l.stats.cpu.user.map(|ms| ms as f64 / 1000.0),
+    },
+    Metric {
+        name: "cephylas_container_cpu_kernel_seconds_total",
+        help: "Cumulative CPU time consumed by the container in kernel mode in seconds.",
+        kind: "counter",
+        value: |l| l.stats.cpu.kernel.map(|ms| ms as f64 / 1000.0),
+    },
+    Metric {
+        name: "cephylas_container_cpu_periods_total",
+        help: "Number of elapsed CFS enforcement periods of the container.",
+        kind: "counter",
+        value: |l| l.stats.cpu.periods.map(|n| n as f64),
+    },
+    Metric {
+        name: "cephylas_container_cpu_throttled_periods_total",
+        help: "Number of CFS periods in which the container was throttled.",
+        kind: "counter",
+        value: |l| l.stats.cpu.throttled_periods.map(|n| n as f64),
+    },
+    Metric {
+        name: "cephylas_container_cpu_throttled_seconds_total",
+        help: "Cumulative time the container was throttled in seconds.",
+        kind: "counter",
+        value: |l| l.stats.cpu.throttled_time.map(|ms| ms as f64 / 1000.0),
+    },
+    Metric {
+        name: "cephylas_container_memory_used_bytes",
+        help: "Memory used by the container excluding page cache in bytes.",
+        kind: "gauge",
+        value: |l| l.stats.memory.used.map(|b| b as f64),
+    },
+    Metric {
+        name: "cephylas_container_memory_limit_bytes",
+        help: "Memory limit of the container in bytes.",
+        kind: "gauge",
+        value: |l| l.stats.memory.available.map(|b| b as f64),
+    },
+    Metric {
+        name: "cephylas_container_memory_percent",
+        help: "Memory usage of the container in percent of the limit.",
+        kind: "gauge",
+        value: |l| l.usage.memory.percentage.map(f64::from),
+    },
+    Metric {
+        name: "cephylas_container_memory_rss_bytes",
+        help: "Anonymous memory (RSS) of the container in bytes.",
+        kind: "gauge",
+        value: |l| l.stats.memory.breakdown.rss.map(|b| b as f64),
+    },
+    Metric {
+        name: "cephylas_container_memory_cache_bytes",
+        help: "Page cache memory of the container in bytes.",
+        kind: "gauge",
+        value: |l| l.stats.memory.breakdown.cache.map(|b| b as f64),
+    },
+    Metric {
+        name: "cephylas_container_memory_swap_bytes",
+        help: "Swap used by the container in bytes.",
+        kind: "gauge",
+        value: |l| l.stats.memory.breakdown.swap.map(|b| b as f64),
+    },
+    Metric {
+        name: "cephylas_container_memory_major_page_faults_total",
+        help: "Cumulative major page faults of the container.",
+        kind: "counter",
+        value: |l| l.stats.memory.breakdown.pgmajfault.map(|n| n as f64),
+    },
+    Metric {
+        name: "cephylas_container_pids",
+        help: "Number of processes and threads in the container.",
+        kind: "gauge",
+        value: |l| l.stats.pids.current.map(|n| n as f64),
+    },
+    Metric {
+        name: "cephylas_container_pids_limit",
+        help: "Maximum number of processes and threads in the container.",
+        kind: "gauge",
+        value: |l| l.stats.pids.limit.map(|n| n as f64),
+    },
+    Metric {
+        name: "cephylas_container_blkio_read_bytes_total",
+        help: "Cumulative bytes read from block devices by the container.",
+        kind: "counter",
+        value: |l| l.stats.io.read.map(|b| b as f64),
+    },
+    Metric {
+        name: "cephylas_container_blkio_write_bytes_total",
+        help: "Cumulative bytes written to block devices by the container.",
+        kind: "counter",
+        value: |l| l.stats.io.write.map(|b| b as f64),
+    },
+    Metric {
+        name: "cephylas_container_blkio_read_ops_total",
+        help: "Cumulative read operations on block devices by the container.",
+        kind: "counter",
+        value: |l| l.stats.io.read_ops.map(|n| n as f64),
+    },
+    Metric {
+        name: "cephylas_container_blkio_write_ops_total",
+        help: "Cumulative write operations on block devices by the container.",
+        kind: "counter",
+        value: |l| l.stats.io.write_ops.map(|n| n as f64),
+    },
+    Metric {
+        name: "cephylas_container_blkio_read_bytes_per_second",
+        help: "Block device read rate of the container in bytes per second.",
+        kind: "gauge",
+        value: |l| l.usage.io.readkBps.map(|kb| kb as f64 * 1000.0),
+    },
+    Metric {
+        name: "cephylas_container_blkio_write_bytes_per_second",
+        help: "Block device write rate of the container in bytes per second.",
+        kind: "gauge",
+        value: |l| l.usage.io.writekBps.map(|kb| kb as f64 * 1000.0),
+    },
+    Metric {
+        name: "cephylas_container_network_receive_bytes_total",
+        help: "Cumulative bytes received by the container.",
+        kind: "counter",
+        value: |l| l.stats.net.recv.map(|b| b as f64),
+    },
+    Metric {
+        name: "cephylas_container_network_transmit_bytes_total",
+        help: "Cumulative bytes sent by the container.",
+        kind: "counter",
+        value: |l| l.stats.net.send.map(|b| b as f64),
+    },
+    Metric {
+        name: "cephylas_container_network_receive_packets_total",
+        help: "Cumulative packets received by the container.",
+        kind: "counter",
+        value: |l| l.stats.net.recv_packets.map(|p| p as f64),
+    },
+    Metric {
+        name: "cephylas_container_network_transmit_packets_total",
+        help: "Cumulative packets sent by the container.",
+        kind: "counter",
+        value: |l| l.stats.net.send_packets.map(|p| p as f64),
+    },
+    Metric {
+        name: "cephylas_container_network_receive_errors_total",
+        help: "Cumulative receive errors of the container.",
+        kind: "counter",
+        value: |l| l.stats.net.recv_errors.map(|e| e as f64),
+    },
+    Metric {
+        name: "cephylas_container_network_transmit_errors_total",
+        help: "Cumulative transmit errors of the container.",
+        kind: "counter",
+        value: |l| l.stats.net.send_errors.map(|e| e as f64),
+    },
+    Metric {
+        name: "cephylas_container_network_receive_packets_dropped_total",
+        help: "Cumulative received packets dropped by the container.",
+        kind: "counter",
+        value: |l| l.stats.net.recv_dropped.map(|d| d as f64),
+    },
+    Metric {
+        name: "cephylas_container_network_transmit_packets_dropped_total",
+        help: "Cumulative sent packets dropped by the container.",
+        kind: "counter",
+        value: |l| l.stats.net.send_dropped.map(|d| d as f64),
+    },
+    Metric {
+        name: "cephylas_container_network_receive_bytes_per_second",
+        help: "Network receive rate of the container in bytes per second.",
+        kind: "gauge",
+        value: |l| l.usage.net.recvkBps.map(|kb| kb as f64 * 1000.0),
+    },
+    Metric {
+        name: "cephylas_container_network_transmit_bytes_per_second",
+        help: "Network send rate of the container in bytes per second.",
+        kind: "gauge",
+        value: |l| l.usage.net.sendkBps.map(|kb| kb as f64 * 1000.0),
+    },
+    // 以下はホスト (_host) のみ
+    Metric {
+        name: "cephylas_host_load1",
+        help: "1-minute load average of the host.",
+        kind: "gauge",
+        value: |l| l.usage.host.as_ref().map(|h| h.load.one as f64),
+    },
+    Metric {
+        name: "cephylas_host_load5",
+        help: "5-minute load average of the host.",
+        kind: "gauge",
+        value: |l| l.usage.host.as_ref().map(|h| h.load.five as f64),
+    },
+    Metric {
+        name: "cephylas_host_load15",
+        help: "15-minute load average of the host.",
+        kind: "gauge",
+        value: |l| l.usage.host.as_ref().map(|h| h.load.fifteen as f64),
+    },
+];
+
+/// ラベル値に含まれる \ " 改行 をエスケープします
+pub fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// 最新の使用状況を Prometheus text exposition format (0.0.4) で出力します
+/// 値が無いコンテナの行は出力しません
+pub fn render(latest: &HashMap<String, LatestUsage>) -> String {
+    let mut container_names = latest.keys().collect::<Vec<&String>>();
+    container_names.sort();
+
+    let mut text = String::new();
+    for metric in METRICS {
+        text += &format!("# HELP {} {}\n", metric.name, metric.help);
+        text += &format!("# TYPE {} {}\n", metric.name, metric.kind);
+        for container_name in &container_names {
+            if let Some(value) = (metric.value)(&latest[*container_name]) {
+                text += &format!(
+                    "{}{{container=\"{}\"}} {}\n",
+                    metric.name,
+                    escape_label_value(container_name),
+                    value,
+                );
+            }
+        }
+    }
+    text
+}
+
+/// 使用状況の収集自体のメトリクスを出力します (ラベルはありません)
+pub fn render_collection(collection: &CollectionStats) -> String {
+    [
+        (
+            "cephylas_collection_duration_seconds",
+            "Time spent collecting stats of all containers in the last tick.",
+            "gauge",
+            collection.duration.as_secs_f64(),
+        ),
+        (
+            "cephylas_collection_containers",
+            "Number of containers whose stats were collected in the last tick.",
+            "gauge",
+            collection.containers as f64,
+        ),
+        (
+            "cephylas_collection_failures",
+            "Number of containers whose stats could not be collected in the last tick.",
+            "gauge",
+            collection.failures as f64,
+        ),
+        (
+            "cephylas_collection_failures_total",
+            "Cumulative number of failed stats collections of containers.",
+            "counter",
+            collection.failures_total as f64,
+        ),
+        (
+            "cephylas_collector_consecutive_errors",
+            "Number of consecutive failures to reach the Docker daemon.",
+            "gauge",
+            collection.consecutive_errors as f64,
+        ),
+        (
+            "cephylas_collection_skipped_ticks_total",
+            "Cumulative number of ticks skipped while waiting to reconnect to the Docker daemon.",
+            "counter",
+            collection.skipped_ticks_total as f64,
+        ),
+    ]
+        .iter()
+        .map(|(name, help, kind, value)| format!(
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+        ))
+        .chain(std::iter::once(render_collector_state(collection.state)))
+        .collect()
+}
+
+/// 収集ループの状態を、状態毎に 0 か 1 を取るゲージで出力します
+fn render_collector_state(state: CollectorState) -> String {
+    let name = "cephylas_collector_state";
+    let mut text = format!(
+        "# HELP {name} Current state of the stats collector.\n# TYPE {name} gauge\n"
+    );
+    for candidate in CollectorState::ALL {
+        text += &format!(
+            "{name}{{state=\"{}\"}} {}\n",
+            candidate,
+            u8::from(candidate == state),
+        );
+    }
+    text
+}
@@ -1,119 +1,320 @@
 
-use std::io::{ Read, Write };
+use std::io::Write;
 
 use crate::log_cache::SharedUsageCache;
 
+use super::broadcast;
+use super::config;
 use super::error;
+use super::events;
+use super::http;
+use super::log;
 use super::log_cache;
+use super::metrics;
+use super::supervisor;
+use super::thread_pool;
+use super::time;
 
 
-/// HTTPリクエストを一時的に記録する構造体
-/// 一時的で良いので参照を使う
-/// (大本はbuffer)
-#[allow(dead_code)] // http_versionは読み取るが使用しない
-struct Request<'a> {
-    method: &'a str,
-    uri: &'a str,
-    http_version: &'a str,
-}
-
-impl<'a> TryFrom<&'a str> for Request<'a> {
-    type Error = error::Error;
-    fn try_from(value: &'a str) -> Result<Self, Self::Error> {
-        if let [method, uri, http_version] = 
-            value.lines().next()
-            .map(|l| l.split(' '))
-            .ok_or("cannot find first line of request data")?
-            .collect::<Vec<&str>>()[..]
-        {
-            Ok(Request { method, uri, http_version })
-        } else {
-            Err(
-                error::Error::OtherError(
-                    "invalid format in the first line of reqest"
-                    .to_string()
-                )
-            )
-        }
-    }
-}
-
 enum StatusCode {
     Ok,
+    BadRequest,
     MethodNotAllowed,
     InternalServerError,
+    NoContent,
     NotFound,
+    ServiceUnavailable,
 }
 
-/// GET以外のリクエストが来た際には一律で405を返します
+/// サーバが受け付けるメソッド
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// GET/HEAD/OPTIONS以外のリクエストが来た際には一律で405を返します
 ///
 /// 一般的なルータは
 /// Fn(url: &str, stream: &mut TcpStream, log_cache: &SharedUsageCache)
 ///   -> Result<bool, error::Error>
 /// 型としていますが、エラーを返すだけなので簡略化します
 fn handle_method_not_allowed(
-    stream: &mut std::net::TcpStream,
+    stream: &mut impl Write,
+    method: &str,
 ) -> Result<StatusCode, error::Error> {
-    let response = "HTTP/1.1 405 MethodNotAllowed\r\n\r\n";
-    stream.write(response.as_bytes())?;
-    stream.flush()?;
+    write_error(
+        stream,
+        405,
+        "method_not_allowed",
+        &format!("method not allowed: {}", method),
+        &format!("Allow: {}\r\n", ALLOWED_METHODS),
+    )?;
     Ok(StatusCode::MethodNotAllowed)
 }
 
-/// ルータ内でエラーが発生などした場合には500を返します
-/// 
-/// 一般的なルータは
-/// Fn(url: &str, stream: &mut TcpStream, log_cache: &SharedUsageCache)
-///   -> Result<bool, error::Error>
-/// 型としていますが、エラーを返すだけなので簡略化します
-fn handle_generic_error(
-    stream: &mut std::net::TcpStream,
-    e: &error::Error,
+/// OPTIONS リクエストには受け付けるメソッドを返します
+fn handle_options(
+    stream: &mut impl Write,
 ) -> Result<StatusCode, error::Error> {
     let response = format!(
-        "HTTP/1.1 500 InternalError\r\n\r\n {}",
-        e.to_string(),
+        "HTTP/1.1 204 No Content\r\nAllow: {}\r\nConnection: close\r\n\r\n",
+        ALLOWED_METHODS,
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+    Ok(StatusCode::NoContent)
+}
+
+/// エラー応答を {"error":{"code":..., "message":...}} の形式で書き込みます
+/// extra_headers は "Name: value\r\n" の形式で追加するヘッダです
+fn write_error(
+    stream: &mut impl Write,
+    status: u16,
+    code: &str,
+    message: &str,
+    extra_headers: &str,
+) -> Result<(), error::Error> {
+    let body = format!(
+        "{{\"error\":{{\"code\":{},\"message\":{}}}}}",
+        json::stringify(code),
+        json::stringify(message),
+    );
+    let response = format!(
+        "HTTP/1.1 {} {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        http::reason_phrase(status),
+        extra_headers,
+        body.len(),
+        body,
     );
-    stream.write(response.as_bytes())?;
+    stream.write_all(response.as_bytes())?;
     stream.flush()?;
+    Ok(())
+}
 
-    Ok(StatusCode::InternalServerError)
+/// リクエストを解釈できなかった場合には
+/// http::read_request が決めたステータスコードを返します
+/// (code はステータスの説明を snake_case にしたものです)
+fn handle_request_error(
+    stream: &mut impl Write,
+    status: u16,
+    message: &str,
+) -> Result<StatusCode, error::Error> {
+    let code = http::reason_phrase(status).to_lowercase().replace(' ', "_");
+    write_error(stream, status, &code, message, "")?;
+    Ok(StatusCode::BadRequest)
+}
+
+/// 処理待ちの接続や /stream の接続が多すぎる場合には503を返します
+fn handle_service_unavailable(
+    stream: &mut impl Write,
+    message: &str,
+) -> Result<StatusCode, error::Error> {
+    write_error(stream, 503, "overloaded", message, "Retry-After: 1\r\n")?;
+    Ok(StatusCode::ServiceUnavailable)
 }
 
-/// どのルートにもマッチしなかった場合には404を返します
+/// /containers?include= で指定できるフィールド
+const CONTAINER_FIELDS: &[&str] = &["id", "image", "created", "labels", "history"];
+
+/// 使用率データのルートで共通のクエリパラメータ
+#[derive(Default)]
+struct UsageQuery {
+    time_range: log_cache::TimeRange,
+    downsample_option: log_cache::DownsampleOption,
+    /// 指定された場合は同じ名前で記録されたコンテナのうち、このIDのものだけを返します
+    container_id: Option<String>,
+    /// /groups でグループ内のコンテナの値をまとめる方法
+    aggregation: log_cache::Aggregation,
+}
+
+/// クエリパラメータから期間と間引き方法を読み取ります
+/// ?from=<RFC 3339|UNIX時間>&to=<RFC 3339|UNIX時間>&last=1h
+/// &algo=lttb|minmax|m4|mean|raw&n=512&id=<コンテナID (先頭一致)>&agg=sum|avg
+///
+/// last は to (省略時は現在時刻) から遡る期間で、from とは併用できません
+fn parse_usage_query(
+    query: &[(String, String)],
+) -> Result<UsageQuery, error::Error> {
+    let mut usage_query = UsageQuery::default();
+    let mut last = None;
+    let bad_query = |e: error::Error| error::Error::BadQuery(e.to_string());
+    for (key, value) in query {
+        let value = value.as_str();
+        match key.as_str() {
+            "from" => usage_query.time_range.from =
+                Some(time::parse_time_or_epoch_millis(value).map_err(bad_query)?),
+            "to" => usage_query.time_range.to =
+                Some(time::parse_time_or_epoch_millis(value).map_err(bad_query)?),
+            "last" => last = Some(time::parse_duration_millis(value).map_err(bad_query)?),
+            "algo" => usage_query.downsample_option.algorithm =
+                value.parse().map_err(bad_query)?,
+            "id" => usage_query.container_id = Some(value.to_string()),
+            "agg" => usage_query.aggregation = value.parse()
+                .map_err(error::Error::BadQuery)?,
+            "n" => usage_query.downsample_option.nsample = value.parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| error::Error::BadQuery(
+                    format!("n must be a positive integer: {}", value)
+                ))?,
+            _ => { /* ignore unknown parameters */ },
+        }
+    }
+
+    let time_range = &mut usage_query.time_range;
+    if let Some(last) = last {
+        if time_range.from.is_some() {
+            return Err(error::Error::BadQuery(
+                "last cannot be used together with from".to_string()
+            ));
+        }
+        let to = time_range.to.unwrap_or_else(time::now_epoch_millis);
+        time_range.from = Some(to.saturating_sub(last));
+    }
+    if let (Some(from), Some(to)) = (time_range.from, time_range.to) {
+        if from > to {
+            return Err(error::Error::BadQuery(
+                "from must not be later than to".to_string()
+            ));
+        }
+    }
+    Ok(usage_query)
+}
+
+/// 要求された期間がキャッシュより古ければログファイルから読み込み、
+/// そうでなければキャッシュを使って f を呼び出します
+///
+/// ログファイルから読む期間は保持期間に切り詰めて tick 単位に広げ、
+/// 読み込んだ結果は次の tick まで保持して同じ期間の要求ではファイルを読み直しません
+/// (?last= のように要求毎に from が変わっても、同じ tick の間は使い回します)
+fn with_usage_cache<R, F: Fn(&log_cache::UsageCache) -> R>(
+    config: &config::Config,
+    log_cache: &log_cache::SharedUsageCache,
+    time_range: &log_cache::TimeRange,
+    f: F,
+) -> Result<R, error::Error> {
+    let tick = (config.tick.as_millis() as i64).max(1);
+    let retained_range = log::retained_range(config, time_range);
+    let retained_range = log_cache::TimeRange {
+        from: retained_range.from.map(|from| from.div_euclid(tick) * tick),
+        to: retained_range.to.map(|to| (to + tick - 1).div_euclid(tick) * tick),
+    };
+    let last_tick = {
+        let lock = log_cache.read()?;
+        let cached_from = lock.cpu.earliest_time();
+        let older_than_cache = time_range.from
+            .is_some_and(|from| cached_from.is_none_or(|c| from < c));
+        if !older_than_cache {
+            return Ok(f(&lock));
+        }
+        let last_tick = lock.collection.last_tick;
+        if let Some(range) = lock.range.as_ref()
+            .filter(|r| r.time_range == retained_range && r.last_tick == last_tick)
+        {
+            return Ok(f(&range.usages));
+        }
+        last_tick
+    };
+    let usages = std::sync::Arc::new(log::read_log_range(config, &retained_range)?);
+    log_cache.write()?.range = Some(log_cache::RangeCache {
+        time_range: retained_range,
+        last_tick,
+        usages: std::sync::Arc::clone(&usages),
+    });
+    Ok(f(&usages))
+}
+
+/// エラーの種類に対応する HTTP ステータス
+fn error_status(e: &error::Error) -> (u16, StatusCode) {
+    match e {
+        error::Error::BadQuery(_) => (400, StatusCode::BadRequest),
+        error::Error::UnknownContainer(_)
+        | error::Error::NotFound(_) => (404, StatusCode::NotFound),
+        error::Error::DockerUnreachable(_)
+        | error::Error::DockerApiError { .. } => (503, StatusCode::ServiceUnavailable),
+        _ => (500, StatusCode::InternalServerError),
+    }
+}
+
+/// ルータ内で発生したエラーを、種類に応じたステータスと JSON で返します
 ///
 /// 一般的なルータは
 /// Fn(url: &str, stream: &mut TcpStream, log_cache: &SharedUsageCache)
 ///   -> Result<bool, error::Error>
 /// 型としていますが、エラーを返すだけなので簡略化します
-fn route_not_found(
-    stream: &mut std::net::TcpStream,
+fn handle_error(
+    stream: &mut impl Write,
+    e: &error::Error,
 ) -> Result<StatusCode, error::Error> {
-    let response = "HTTP/1.1 404 NotFound\r\n\r\n";
-    stream.write(response.as_bytes())?;
-    stream.flush()?;
+    let (status, status_code) = error_status(e);
+    write_error(stream, status, e.code(), &e.to_string(), "")?;
+    Ok(status_code)
+}
 
-    Ok(StatusCode::NotFound)
+/// ルートが StatusCode::NotFound を返した場合のエラー
+/// 記録されていないコンテナならば UnknownContainer、それ以外は NotFound です
+fn not_found_error(
+    log_cache: &log_cache::SharedUsageCache,
+    parts: &[&str],
+) -> Result<error::Error, error::Error> {
+    if let ["containers", container_name, ..] = parts {
+        let lock = log_cache.read()?;
+        if lock.cpu.get(container_name).is_none()
+            && !lock.containers.contains_key(*container_name)
+        {
+            return Ok(error::Error::UnknownContainer(container_name.to_string()));
+        }
+    }
+    Ok(error::Error::NotFound(format!("not found: /{}", parts.join("/"))))
 }
 
-/// リソース使用状況を記録しているコンテナの名前を
-/// アルファベット順に返します
+/// リソース使用状況を記録しているコンテナの名前をアルファベット順に返すルートです
 ///
-/// ルータは以下の型に統一して配列に格納し、
-/// ループを回して順番にマッチするか否か確認しています
-/// Fn(url: &str, stream: &mut TcpStream, log_cache: &SharedUsageCache)
-///   -> Result<bool, error::Error>
-/// 型としており、bool型はマッチしたか否かを返します
-/// 処理に失敗すればError型を返します
+/// ?include=id,image,created,labels,history を指定すると、名前の代わりに
+/// {"name":..,"id":..,..} のオブジェクトの配列を返します
+/// (id, image, created, labels は最後に記録されたコンテナ、history はその名前で
+/// 記録された全てのコンテナとその期間です)
 fn route_containers(
-    stream: &mut std::net::TcpStream,
+    stream: &mut impl Write,
     log_cache: &log_cache::SharedUsageCache,
+    query: &[(String, String)],
 ) -> Result<StatusCode, error::Error> {
-    let lock = log_cache.read().map_err(|e| e.to_string())?;
+    let include = query.iter()
+        .filter(|(key, _)| key == "include")
+        .flat_map(|(_, value)| value.split(','))
+        .filter(|field| !field.is_empty())
+        .collect::<Vec<&str>>();
+    if let Some(field) = include.iter()
+        .find(|field| !CONTAINER_FIELDS.contains(field))
+    {
+        return Err(error::Error::BadQuery(format!("unknown include field: {}", field)));
+    }
+
+    let lock = log_cache.read()?;
     let container_names = lock.cpu.container_names();
     let data = container_names
         .iter()
-        .map(|s| format!("\"{}\"", s))
+        .map(|name| {
+            if include.is_empty() {
+                return json::stringify(name.as_str());
+            }
+            let spans = lock.containers.get(*name)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            let mut fields = vec![format!("\"name\":{}", json::stringify(name.as_str()))];
+            let latest = spans.last().map(|span| &span.info);
+            for field in &include {
+                let value = match *field {
+                    "id" => latest.map(|info| json::stringify(info.id.as_str())),
+                    "image" => latest.map(|info| json::stringify(info.image.as_str())),
+                    "created" => latest
+                        .and_then(|info| info.created)
+                        .map(|c| format!("\"{}\"", time::format_epoch_seconds(c))),
+                    "labels" => latest.map(|info| log::labels_to_string(&info.labels)),
+                    _ => Some(data_to_json(spans.iter().collect())),
+                };
+                fields.push(format!("\"{}\":{}", field, value.unwrap_or("null".to_string())));
+            }
+            format!("{{{}}}", fields.join(","))
+        })
         .collect::<Vec<String>>()
         .join(",");
     let body = format!("[{}]", data);
@@ -122,45 +323,217 @@ fn route_containers(
         "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
         body_bytes.len(),
     );
-    stream.write(response.as_bytes())?;
-    stream.write(body_bytes)?;
+    stream.write_all(response.as_bytes())?;
+    stream.write_all(body_bytes)?;
     stream.flush()?;
 
     Ok(StatusCode::Ok)
 }
 
-/// 注意: かなり微妙な実装です
-/// ログは1日でローテーションされるので(運用の仕方次第......)
-/// 時刻のみ解釈して一日の始まりから何秒経ったかに変換します
-fn limited_convert_time_string_to_f32(
-    time_str: &str
-) -> Result<f32, error::Error> {
-    // ISO time string は YYYY/MM/ddTHH:mm:ss.nnnnn という形式
-    if let [_year_month_date, time] = 
-        time_str.split('T').collect::<Vec<&str>>()[..]
-    {
-        // 今はyear, month, dateは無視...
-        if let [hours, minutes, seconds] = 
-            time.split(':').collect::<Vec<&str>>()[..] 
-        {
-            //println!("h,m,s = {},{},{}", hours, minutes, seconds);
-            let seconds = str::parse::<f32>(&seconds.replace("Z",""))
-                .map_err(|e| e.to_string())?;
-            let minutes = str::parse::<f32>(minutes)
-                .map_err(|e| e.to_string())?;
-            let hours = str::parse::<f32>(hours)
-                .map_err(|e| e.to_string())?;
-            
-            return Ok(
-                ((hours * 60.0) + minutes) * 60.0 + seconds
-            )
+/// 最後の tick からこの回数分の tick が経つと /health は 503 を返します
+const HEALTH_STALE_TICKS: i64 = 3;
+
+/// 各ワーカーの稼働状況と最後に tick を終えた時刻を返します
+///
+/// 停止しているワーカーがあるか、tick が止まっている
+/// (最後の tick か logger の起動から HEALTH_STALE_TICKS 回分経った) 場合は 503 を返します
+fn route_health(
+    stream: &mut impl Write,
+    config: &config::Config,
+    log_cache: &log_cache::SharedUsageCache,
+) -> Result<StatusCode, error::Error> {
+    let lock = log_cache.read()?;
+    let last_tick = lock.collection.last_tick;
+    let logger_started = lock.workers.get(supervisor::LOGGER_WORKER)
+        .map(|health| health.started);
+    let stale = last_tick.max(logger_started)
+        .is_some_and(|time| {
+            time::now_epoch_millis() - time
+                > config.tick.as_millis() as i64 * HEALTH_STALE_TICKS
+        });
+    let healthy = !stale && lock.workers.values().all(|health| health.alive);
+    let workers = lock.workers.iter()
+        .map(|(name, health)| format!("{}:{}", json::stringify(name.as_str()), health))
+        .collect::<Vec<String>>()
+        .join(",");
+    let body = format!(
+        "{{\"status\":\"{}\",\"lastTick\":{},\"collector\":\"{}\",\"workers\":{{{}}}}}",
+        if healthy { "ok" } else { "unhealthy" },
+        last_tick.map_or("null".to_string(), |t| format!("\"{}\"", time::format_epoch_millis(t))),
+        lock.collection.state,
+        workers,
+    );
+    drop(lock);
+
+    let (status_line, status_code) = if healthy {
+        ("200 OK", StatusCode::Ok)
+    } else {
+        ("503 Service Unavailable", StatusCode::ServiceUnavailable)
+    };
+    let body_bytes = body.as_bytes();
+    let response = format!(
+        "HTTP/1.1 {}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+        status_line,
+        body_bytes.len(),
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.write_all(body_bytes)?;
+    stream.flush()?;
+
+    Ok(status_code)
+}
+
+/// 収集ループの状態 (running, degraded, disconnected) と直近の収集結果を返します
+fn route_collector(
+    stream: &mut impl Write,
+    log_cache: &log_cache::SharedUsageCache,
+) -> Result<StatusCode, error::Error> {
+    let body = log_cache.read()?
+        .collection
+        .to_string();
+    let body_bytes = body.as_bytes();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+        body_bytes.len(),
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.write_all(body_bytes)?;
+    stream.flush()?;
+
+    Ok(StatusCode::Ok)
+}
+
+/// 起動時のログの復旧と読み込みの結果を返します
+fn route_startup(
+    stream: &mut impl Write,
+    log_cache: &log_cache::SharedUsageCache,
+) -> Result<StatusCode, error::Error> {
+    let body = log_cache.read()?
+        .startup
+        .to_string();
+    let body_bytes = body.as_bytes();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+        body_bytes.len(),
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.write_all(body_bytes)?;
+    stream.flush()?;
+
+    Ok(StatusCode::Ok)
+}
+
+/// 各コンテナの最新の使用状況を Prometheus 形式で返します
+fn route_metrics(
+    stream: &mut impl Write,
+    log_cache: &log_cache::SharedUsageCache,
+) -> Result<StatusCode, error::Error> {
+    let lock = log_cache.read()?;
+    let body = metrics::render(&lock.latest)
+        + &metrics::render_collection(&lock.collection);
+    drop(lock);
+
+    let body_bytes = body.as_bytes();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\n\r\n",
+        body_bytes.len(),
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.write_all(body_bytes)?;
+    stream.flush()?;
+
+    Ok(StatusCode::Ok)
+}
+
+/// /stream の購読者毎に溜められる tick の数
+const STREAM_QUEUE_LENGTH: usize = 8;
+/// 配信が無い間、切断を検出するためにコメント行を送る間隔
+const STREAM_KEEP_ALIVE: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// tick 毎の使用状況を Server-Sent Events で配信するルートです
+/// container_name が None なら全コンテナ分を配信します
+///
+/// 接続を保持し続けるので、スレッドプールを占有しないよう
+/// ヘッダを返した後は専用のスレッドで配信します
+/// 書き込みに失敗するか、受け取りが追いつかずに購読が解除されると終了します
+fn route_event_stream(
+    stream: &mut std::net::TcpStream,
+    log_cache: &log_cache::SharedUsageCache,
+    broadcaster: &broadcast::SharedUsageBroadcaster,
+    container_name: Option<String>,
+    head_only: bool,
+) -> Result<StatusCode, error::Error> {
+    if let Some(container_name) = &container_name {
+        let lock = log_cache.read()?;
+        if lock.cpu.get(container_name).is_none() {
+            drop(lock);
+            return handle_error(stream, &error::Error::UnknownContainer(container_name.clone()));
         }
     }
-    Err(error::Error::OtherError("invalid time format".to_string()))
+
+    let response = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    if head_only {
+        stream.write_all(response.as_bytes())?;
+        stream.flush()?;
+        return Ok(StatusCode::Ok);
+    }
+    let receiver = match broadcaster.subscribe(STREAM_QUEUE_LENGTH) {
+        Ok(receiver) => receiver,
+        Err(_) => return handle_service_unavailable(stream, "too many streams"),
+    };
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+
+    let mut stream = stream.try_clone()?;
+    std::thread::Builder::new()
+        .name("stream".to_string())
+        .spawn(move || loop {
+            let event = match receiver.recv_timeout(STREAM_KEEP_ALIVE) {
+                Ok(tick) => match usage_event(&tick, container_name.as_deref()) {
+                    Some(event) => event,
+                    None => continue,
+                },
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) =>
+                    ": keep-alive\n\n".to_string(),
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return,
+            };
+            // 書き込めなければクライアントが切断したものとして終了します
+            // (Receiver が drop されるので次の配信で購読も解除されます)
+            if stream.write_all(event.as_bytes()).and_then(|_| stream.flush()).is_err() {
+                return;
+            }
+        })?;
+
+    Ok(StatusCode::Ok)
+}
+
+/// tick の使用状況を "usage" イベントに変換します
+/// container_name が指定されていて、その tick に含まれなければ None を返します
+fn usage_event(
+    tick: &log_cache::TickUsages,
+    container_name: Option<&str>,
+) -> Option<String> {
+    let time = time::format_epoch_millis(tick.time);
+    let data = match container_name {
+        Some(container_name) => {
+            let usage = tick.usages.get(container_name)?;
+            format!("{{\"time\":\"{}\",\"usage\":{}}}", time, usage)
+        },
+        None => {
+            let mut container_names = tick.usages.keys().collect::<Vec<&String>>();
+            container_names.sort();
+            let stats = container_names.iter()
+                .map(|name| format!("{}:{}", json::stringify(name.as_str()), tick.usages[*name]))
+                .collect::<Vec<String>>()
+                .join(",");
+            format!("{{\"time\":\"{}\",\"stats\":{{{}}}}}", time, stats)
+        },
+    };
+    Some(format!("id: {}\nevent: usage\ndata: {}\n\n", tick.time, data))
 }
 
-/// Vec<&T> 型の使用率データをjson文字列に変換します
-pub fn data_to_json<T: ToString>(data: Vec<&T>) -> String {
+/// Vec<T> 型の使用率データをjson文字列に変換します
+pub fn data_to_json<T: ToString>(data: Vec<T>) -> String {
     format!(
         "[{}]",
         data.iter()
@@ -170,48 +543,176 @@ pub fn data_to_json<T: ToString>(data: Vec<&T>) -> String {
     )
 }
 
-/// CPU/メモリ使用状況を返すルートです
-///
-/// ルータは以下の型に統一して配列に格納し、
-/// ループを回して順番にマッチするか否か確認しています
-/// Fn(url: &str, stream: &mut TcpStream, log_cache: &SharedUsageCache)
-///   -> Result<bool, error::Error>
-/// 型としており、bool型はマッチしたか否かを返します
-/// 処理に失敗すればError型を返します
+/// CPU/メモリ使用状況、プロセス数と、ホスト (_host) のみの
+/// ロードアベレージ (load)、ファイルシステムの容量 (filesystems) を返すルートです
 fn route_cpu_or_memory_usage(
-    stream: &mut std::net::TcpStream,
+    stream: &mut impl Write,
+    config: &config::Config,
     log_cache: &log_cache::SharedUsageCache,
     container_name: &str,
     resource_type: &str,
+    usage_query: &UsageQuery,
 ) -> Result<StatusCode, error::Error> {
-    let downsample_option = log_cache::DownsampleOption::default();
-    let lock = log_cache.read().map_err(|e| e.to_string())?;
-
-    let data = match resource_type {
-        "cpu" => lock.cpu
+    let time_range = &usage_query.time_range;
+    let downsample_option = &usage_query.downsample_option;
+    let data = with_usage_cache(config, log_cache, time_range, |cache| match resource_type {
+        "cpu" => cache.cpu
             .downsample(
                 container_name,
-                &downsample_option,
+                time_range,
+                downsample_option,
                 |c| (
-                    limited_convert_time_string_to_f32(&c.time)
-                        .unwrap(), 
-                    c.percentage.unwrap_or_default()
+                    c.time as f64, 
+                    c.percentage.unwrap_or_default() as f64
                 ),
             )
-            .map(|v| data_to_json(v)),
-        "memory" => lock.memory
+            .map(data_to_json),
+        "memory" => cache.memory
             .downsample(
                 container_name,
-                &downsample_option,
+                time_range,
+                downsample_option,
                 |m| (
-                    limited_convert_time_string_to_f32(&m.time)
-                        .unwrap(),
-                    m.percentage.unwrap_or_default(),
+                    m.time as f64,
+                    m.percentage.unwrap_or_default() as f64,
                 ),
             )
-            .map(|v| data_to_json(v)),
+            .map(data_to_json),
+        "pids" => cache.pids
+            .downsample(
+                container_name,
+                time_range,
+                downsample_option,
+                |p| (
+                    p.time as f64,
+                    p.current.unwrap_or_default() as f64,
+                ),
+            )
+            .map(data_to_json),
+        "load" => cache.load
+            .downsample(
+                container_name,
+                time_range,
+                downsample_option,
+                |l| (
+                    l.time as f64,
+                    l.load.one as f64,
+                ),
+            )
+            .map(data_to_json),
+        "filesystems" => cache.filesystems
+            .downsample(
+                container_name,
+                time_range,
+                downsample_option,
+                |f| (
+                    f.time as f64,
+                    f.max_percentage().unwrap_or_default() as f64,
+                ),
+            )
+            .map(data_to_json),
         _ => None,
+    })?;
+
+    if let Some(data) = data {
+        let body_bytes = data.as_bytes();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body_bytes.len(),
+        );
+        stream.write_all(response.as_bytes())?;
+        stream.write_all(body_bytes)?;
+        stream.flush()?;
+        return Ok(StatusCode::Ok);
+    }
+
+    Ok(StatusCode::NotFound)
+}
+
+/// コンテナのライフサイクルイベントを時刻順に返すルートです
+/// container_name を省略すると全コンテナのイベントを返します
+/// (終了したコンテナのイベントも返すので、該当が無くても空の配列を返します)
+fn route_events(
+    stream: &mut impl Write,
+    config: &config::Config,
+    log_cache: &log_cache::SharedUsageCache,
+    container_name: Option<&str>,
+    usage_query: &UsageQuery,
+) -> Result<StatusCode, error::Error> {
+    let time_range = &usage_query.time_range;
+    let cached = log_cache.read()?
+        .events.range(time_range, container_name);
+    let data = match cached {
+        Some(data) => data,
+        None => events::read_event_log_range(config, time_range, container_name)?,
+    };
+    let data = data_to_json(data);
+
+    let body_bytes = data.as_bytes();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body_bytes.len(),
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.write_all(body_bytes)?;
+    stream.flush()?;
+
+    Ok(StatusCode::Ok)
+}
+
+/// CPUの1フィールド、または percpu (CPU毎の使用率) を返すルートです
+/// (フィールド名は log_cache::TimedCpuUsage::FIELDS、それ以外は BadQuery を返します)
+fn route_cpu_field(
+    stream: &mut impl Write,
+    config: &config::Config,
+    log_cache: &log_cache::SharedUsageCache,
+    container_name: &str,
+    field: &str,
+    usage_query: &UsageQuery,
+) -> Result<StatusCode, error::Error> {
+    let field = match field {
+        "percpu" => None,
+        _ => match log_cache::TimedCpuUsage::field_name(field) {
+            Some(field) => Some(field),
+            None => return Err(error::Error::BadQuery(format!("unknown cpu field: {}", field))),
+        },
     };
+    let time_range = &usage_query.time_range;
+    let downsample_option = &usage_query.downsample_option;
+    let data = with_usage_cache(config, log_cache, time_range, |cache| cache.cpu
+        .downsample(
+            container_name,
+            time_range,
+            downsample_option,
+            // percpu は全体の使用率でダウンサンプリングします
+            |c| (
+                c.time as f64,
+                match field {
+                    Some(field) => c.field(field),
+                    None => c.percentage.map(f64::from),
+                }.unwrap_or_default(),
+            ),
+        )
+        .map(|data| match field {
+            Some(field) => data_to_json(
+                data.iter()
+                    .map(|c| log_cache::TimedField {
+                        time: c.time,
+                        name: field,
+                        value: c.field(field),
+                    })
+                    .collect()
+            ),
+            None => data_to_json(
+                data.iter()
+                    .map(|c| log_cache::TimedPercpuUsage {
+                        time: c.time,
+                        percpu: &c.percpu,
+                    })
+                    .collect()
+            ),
+        })
+    )?;
 
     if let Some(data) = data {
         let body_bytes = data.as_bytes();
@@ -219,8 +720,8 @@ fn route_cpu_or_memory_usage(
             "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
             body_bytes.len(),
         );
-        stream.write(response.as_bytes())?;
-        stream.write(body_bytes)?;
+        stream.write_all(response.as_bytes())?;
+        stream.write_all(body_bytes)?;
         stream.flush()?;
         return Ok(StatusCode::Ok);
     }
@@ -228,40 +729,103 @@ fn route_cpu_or_memory_usage(
     Ok(StatusCode::NotFound)
 }
 
+/// メモリの内訳の1フィールドを返すルートです
+/// (フィールド名は used と log::MemoryBreakdown::FIELDS、それ以外は BadQuery を返します)
+fn route_memory_field(
+    stream: &mut impl Write,
+    config: &config::Config,
+    log_cache: &log_cache::SharedUsageCache,
+    container_name: &str,
+    field: &str,
+    usage_query: &UsageQuery,
+) -> Result<StatusCode, error::Error> {
+    let Some(field) = log_cache::TimedMemoryUsage::field_name(field) else {
+        return Err(error::Error::BadQuery(format!("unknown memory field: {}", field)));
+    };
+    let time_range = &usage_query.time_range;
+    let downsample_option = &usage_query.downsample_option;
+    let data = with_usage_cache(config, log_cache, time_range, |cache| cache.memory
+        .downsample(
+            container_name,
+            time_range,
+            downsample_option,
+            |m| (
+                m.time as f64,
+                m.field(field).unwrap_or_default() as f64,
+            ),
+        )
+        .map(|data| data_to_json(
+            data.iter()
+                .map(|m| log_cache::TimedField {
+                    time: m.time,
+                    name: field,
+                    value: m.field(field),
+                })
+                .collect()
+        ))
+    )?;
+
+    if let Some(data) = data {
+        let body_bytes = data.as_bytes();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body_bytes.len(),
+        );
+        stream.write_all(response.as_bytes())?;
+        stream.write_all(body_bytes)?;
+        stream.flush()?;
+        return Ok(StatusCode::Ok);
+    }
+
+    Ok(StatusCode::NotFound)
+}
+
+/// /io/{read_or_write} に対応する TimedIoUsage のフィールド
+fn io_field(read_or_write: &str) -> Option<fn(&log_cache::TimedIoUsage) -> Option<u32>> {
+    match read_or_write {
+        "read" => Some(|r| r.readkBps),
+        "write" => Some(|w| w.writekBps),
+        "readIops" => Some(|r| r.readIops),
+        "writeIops" => Some(|w| w.writeIops),
+        _ => None,
+    }
+}
+
+/// ブロックデバイスIOの使用状況を返すルートです
+/// device ("major:minor") を指定するとそのデバイスの値、省略すると全デバイスの合計を返します
+/// read_or_write は read, write (kB/s) または readIops, writeIops です
 fn route_io_usage(
-    stream: &mut std::net::TcpStream,
+    stream: &mut impl Write,
+    config: &config::Config,
     log_cache: &log_cache::SharedUsageCache,
     container_name: &str,
+    device: Option<&str>,
     read_or_write: &str,
+    usage_query: &UsageQuery,
 ) -> Result<StatusCode, error::Error> {
-    let downsample_option = log_cache::DownsampleOption::default();
-    let lock = log_cache.read().map_err(|e| e.to_string())?;
-
-    let data = match read_or_write {
-        "read" => lock.io
-            .downsample(
-                container_name,
-                &downsample_option,
-                |r| (
-                    limited_convert_time_string_to_f32(&r.time)
-                        .unwrap(), 
-                    r.readkBps.unwrap_or_default() as f32
-                ),
-            )
-            .map(|v| data_to_json(v)),
-        "write" => lock.io
+    let time_range = &usage_query.time_range;
+    let downsample_option = &usage_query.downsample_option;
+    let key = match device {
+        Some(device) => log_cache::device_key(container_name, device),
+        None => container_name.to_string(),
+    };
+    let Some(value) = io_field(read_or_write) else {
+        return Ok(StatusCode::NotFound);
+    };
+    let data = with_usage_cache(config, log_cache, time_range, |cache| {
+        let io = if device.is_some() { &cache.io_devices } else { &cache.io };
+        io
             .downsample(
-                container_name,
-                &downsample_option,
-                |w| (
-                    limited_convert_time_string_to_f32(&w.time)
-                        .unwrap(),
-                    w.writekBps.unwrap_or_default() as f32,
+                &key,
+                time_range,
+                downsample_option,
+                |d| (
+                    d.time as f64,
+                    value(d).unwrap_or_default() as f64,
                 ),
             )
-            .map(|v| data_to_json(v)),
-        _ => None,
-    };
+            .map(data_to_json)
+    })?;
 
     if let Some(data) = data {
         let body_bytes = data.as_bytes();
@@ -269,8 +833,8 @@ fn route_io_usage(
             "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
             body_bytes.len()
         );
-        stream.write(response.as_bytes())?;
-        stream.write(body_bytes)?;
+        stream.write_all(response.as_bytes())?;
+        stream.write_all(body_bytes)?;
         stream.flush()?;
         return Ok(StatusCode::Ok);
     }
@@ -278,40 +842,51 @@ fn route_io_usage(
     Ok(StatusCode::NotFound)
 }
 
+/// ネットワーク使用状況を返すルートです
+/// interface を指定するとそのインターフェースの値、省略すると全インターフェースの合計を返します
 fn route_net_usage(
-    stream: &mut std::net::TcpStream,
+    stream: &mut impl Write,
+    config: &config::Config,
     log_cache: &SharedUsageCache,
     container_name: &str,
+    interface: Option<&str>,
     recv_or_send: &str,
+    usage_query: &UsageQuery,
 ) -> Result<StatusCode, error::Error> {
-    let downsample_option = log_cache::DownsampleOption::default();
-    let lock = log_cache.read().map_err(|e| e.to_string())?;
-
-    let data = match recv_or_send {
-        "recv" => lock.net
-            .downsample(
-                container_name,
-                &downsample_option,
-                |r| (
-                    limited_convert_time_string_to_f32(&r.time)
-                        .unwrap(), 
-                    r.recvkBps.unwrap_or_default() as f32
-                ),
-            )
-            .map(|v| data_to_json(v)),
-        "send" => lock.net
-            .downsample(
-                container_name,
-                &downsample_option,
-                |s| (
-                    limited_convert_time_string_to_f32(&s.time)
-                        .unwrap(),
-                    s.sendkBps.unwrap_or_default() as f32,
-                ),
-            )
-            .map(|v| data_to_json(v)),
-        _ => None,
+    let time_range = &usage_query.time_range;
+    let downsample_option = &usage_query.downsample_option;
+    let key = match interface {
+        Some(interface) => log_cache::interface_key(container_name, interface),
+        None => container_name.to_string(),
     };
+    let data = with_usage_cache(config, log_cache, time_range, |cache| {
+        let net = if interface.is_some() { &cache.net_interfaces } else { &cache.net };
+        match recv_or_send {
+            "recv" => net
+                .downsample(
+                    &key,
+                    time_range,
+                    downsample_option,
+                    |r| (
+                        r.time as f64, 
+                        r.recvkBps.unwrap_or_default() as f64
+                    ),
+                )
+                .map(data_to_json),
+            "send" => net
+                .downsample(
+                    &key,
+                    time_range,
+                    downsample_option,
+                    |s| (
+                        s.time as f64,
+                        s.sendkBps.unwrap_or_default() as f64,
+                    ),
+                )
+                .map(data_to_json),
+            _ => None,
+        }
+    })?;
 
     if let Some(data) = data {
         let body_bytes = data.as_bytes();
@@ -319,8 +894,8 @@ fn route_net_usage(
             "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
             body_bytes.len(),
         );
-        stream.write(response.as_bytes())?;
-        stream.write(body_bytes)?;
+        stream.write_all(response.as_bytes())?;
+        stream.write_all(body_bytes)?;
         stream.flush()?;
 
         return Ok(StatusCode::Ok);
@@ -329,41 +904,275 @@ fn route_net_usage(
     Ok(StatusCode::NotFound)
 }
 
+/// ラベル label_key の値毎に、その値を持つコンテナ名の一覧を返すルートです
+/// {"<label_value>":["<コンテナ名>",..],..}
+fn route_groups(
+    stream: &mut impl Write,
+    log_cache: &log_cache::SharedUsageCache,
+    label_key: &str,
+) -> Result<StatusCode, error::Error> {
+    let values = log_cache.read()?
+        .label_values(label_key);
+    let data = values.iter()
+        .map(|(value, names)| format!(
+            "{}:{}",
+            json::stringify(value.as_str()),
+            data_to_json(names.iter().map(|name| json::stringify(name.as_str())).collect()),
+        ))
+        .collect::<Vec<String>>()
+        .join(",");
+    let body = format!("{{{}}}", data);
+    let body_bytes = body.as_bytes();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body_bytes.len(),
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.write_all(body_bytes)?;
+    stream.flush()?;
+
+    Ok(StatusCode::Ok)
+}
+
+/// グループのコンテナの値を tick 毎にまとめてから間引き、json文字列に変換します
+/// (期間内にデータが無ければ空の配列です)
+fn aggregate_to_json<T, F>(
+    usages: &log_cache::UsageCacheMap<T>,
+    members: &[(String, log_cache::TimeRange)],
+    usage_query: &UsageQuery,
+    fxy: F,
+) -> String
+where
+    T: Clone + std::fmt::Display + log_cache::Mean + log_cache::Sum + log_cache::Timed,
+    F: Fn(&T) -> (f64, f64),
+{
+    usages.aggregate(members, &usage_query.time_range, usage_query.aggregation)
+        .downsample(
+            log_cache::GROUP_KEY,
+            &usage_query.time_range,
+            &usage_query.downsample_option,
+            fxy,
+        )
+        .map_or("[]".to_string(), data_to_json)
+}
+
+/// ラベル label_key の値が label_value のコンテナをまとめた使用状況を返すルートです
+/// resource は cpu, memory, pids, io/{read_or_write}, net/{recv_or_send} で、
+/// 同じ tick の値を ?agg=sum (既定) なら合計、?agg=avg なら平均します
+fn route_group_usage(
+    stream: &mut impl Write,
+    config: &config::Config,
+    log_cache: &log_cache::SharedUsageCache,
+    label_key: &str,
+    label_value: &str,
+    resource: &[&str],
+    usage_query: &UsageQuery,
+) -> Result<StatusCode, error::Error> {
+    let time_range = &usage_query.time_range;
+    let data = with_usage_cache(config, log_cache, time_range, |cache| {
+        let members = cache.group_members(label_key, label_value);
+        if members.is_empty() {
+            return None;
+        }
+        match resource {
+            ["cpu"] => Some(aggregate_to_json(
+                &cache.cpu, &members, usage_query,
+                |c| (c.time as f64, c.percentage.unwrap_or_default() as f64),
+            )),
+            ["memory"] => Some(aggregate_to_json(
+                &cache.memory, &members, usage_query,
+                |m| (m.time as f64, m.percentage.unwrap_or_default() as f64),
+            )),
+            ["pids"] => Some(aggregate_to_json(
+                &cache.pids, &members, usage_query,
+                |p| (p.time as f64, p.current.unwrap_or_default() as f64),
+            )),
+            ["io", read_or_write] => {
+                let value = io_field(read_or_write)?;
+                Some(aggregate_to_json(
+                    &cache.io, &members, usage_query,
+                    |d| (d.time as f64, value(d).unwrap_or_default() as f64),
+                ))
+            },
+            ["net", "recv"] => Some(aggregate_to_json(
+                &cache.net, &members, usage_query,
+                |r| (r.time as f64, r.recvkBps.unwrap_or_default() as f64),
+            )),
+            ["net", "send"] => Some(aggregate_to_json(
+                &cache.net, &members, usage_query,
+                |s| (s.time as f64, s.sendkBps.unwrap_or_default() as f64),
+            )),
+            _ => None,
+        }
+    })?;
+
+    if let Some(data) = data {
+        let body_bytes = data.as_bytes();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body_bytes.len(),
+        );
+        stream.write_all(response.as_bytes())?;
+        stream.write_all(body_bytes)?;
+        stream.flush()?;
+        return Ok(StatusCode::Ok);
+    }
+
+    Ok(StatusCode::NotFound)
+}
+
 fn handle_connection(
     stream: &mut std::net::TcpStream,
+    config: &config::Config,
     log_cache: &log_cache::SharedUsageCache,
+    broadcaster: &broadcast::SharedUsageBroadcaster,
 ) -> Result<(), error::Error> {
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer)?;
-
-    let request_data = String::from_utf8_lossy(&buffer[..]);
-    //println!("Request: {}", request_data);
+    let request = {
+        let mut reader = std::io::BufReader::new(&*stream);
+        http::read_request(&mut reader, &mut &*stream)
+    };
+    let request = match request {
+        Ok(Some(request)) => request,
+        // 何も送らずに閉じられた接続
+        Ok(None) => return Ok(()),
+        Err(http::RequestError::Status(code, message)) => {
+            handle_request_error(stream, code, &message)?;
+            return Ok(());
+        },
+        Err(e) => return Err(e.into()),
+    };
 
-    let request = Request::try_from(request_data.as_ref())?;
-    if request.method != "GET" {
-        handle_method_not_allowed(stream)?;
+    // /stream は接続を保持し続けるので専用のスレッドで配信します
+    let stream_target = match &request.path_segments[..] {
+        [s] if s == "stream" => Some(None),
+        [c, container_name, s] if c == "containers" && s == "stream" =>
+            Some(Some(container_name.clone())),
+        _ => None,
+    };
+    if let (Some(container_name), "GET" | "HEAD") = (stream_target, request.method.as_str()) {
+        route_event_stream(
+            stream, log_cache, broadcaster, container_name, request.method == "HEAD",
+        )?;
         return Ok(());
     }
 
+    match request.method.as_str() {
+        "GET" => route(stream, config, log_cache, &request),
+        // HEAD は GET と同じルートを使い、本文だけを捨てます
+        "HEAD" => route(&mut http::HeadWriter::new(&mut *stream), config, log_cache, &request),
+        "OPTIONS" => handle_options(stream).map(|_| ()),
+        method => handle_method_not_allowed(stream, method).map(|_| ()),
+    }
+}
+
+/// パスに対応するルートを呼び出します
+///
+/// 各ルートは書き込んだ応答のステータスを返し、データが無ければ
+/// StatusCode::NotFound を返します (ここで json の 404 を書き込みます)
+/// 処理に失敗した場合はエラーを返し、エラーの種類に応じたステータスで応答します
+fn route(
+    stream: &mut impl Write,
+    config: &config::Config,
+    log_cache: &log_cache::SharedUsageCache,
+    request: &http::Request,
+) -> Result<(), error::Error> {
+    let mut usage_query = match parse_usage_query(&request.query) {
+        Ok(usage_query) => usage_query,
+        Err(e) => {
+            handle_error(stream, &e)?;
+            return Ok(());
+        },
+    };
+
     // match式を使った単純なものに書き直せそう
-    let parts: Vec<&str> = request.uri.split('/')
-        .filter(|s| !s.is_empty())
-        .collect();
+    let parts = request.path_segments.iter()
+        .map(String::as_str)
+        .collect::<Vec<&str>>();
+    // id が指定されたらそのコンテナが記録されていた期間に絞ります
+    if let (Some(id), ["containers", container_name, resource_type, ..]) =
+        (&usage_query.container_id, &parts[..])
+    {
+        if *resource_type != "events" {
+            let id_range = log_cache.read()?
+                .container_id_range(container_name, id);
+            match id_range {
+                Some(id_range) =>
+                    usage_query.time_range = usage_query.time_range.intersect(&id_range),
+                None => {
+                    handle_error(stream, &error::Error::NotFound(
+                        format!("no container with id {} was recorded as {}", id, container_name)
+                    ))?;
+                    return Ok(());
+                },
+            }
+        }
+    }
+
     let result = match &parts[..] {
         ["containers"] => 
-            route_containers(stream, log_cache),
+            route_containers(stream, log_cache, &request.query),
+        ["metrics"] =>
+            route_metrics(stream, log_cache),
+        ["collector"] =>
+            route_collector(stream, log_cache),
+        ["startup"] =>
+            route_startup(stream, log_cache),
+        ["health"] =>
+            route_health(stream, config, log_cache),
+        ["events"] =>
+            route_events(stream, config, log_cache, None, &usage_query),
+        ["containers", container_name, "events"] =>
+            route_events(
+                stream, config, log_cache, Some(container_name), &usage_query,
+            ),
         ["containers", container_name, resource_type] =>
-            route_cpu_or_memory_usage(stream, log_cache, container_name, resource_type),
+            route_cpu_or_memory_usage(
+                stream, config, log_cache, container_name, resource_type,
+                &usage_query,
+            ),
+        ["containers", container_name, "cpu", field] =>
+            route_cpu_field(
+                stream, config, log_cache, container_name, field,
+                &usage_query,
+            ),
+        ["containers", container_name, "memory", field] =>
+            route_memory_field(
+                stream, config, log_cache, container_name, field,
+                &usage_query,
+            ),
         ["containers", container_name, "io", read_or_write] =>
-            route_io_usage(stream, log_cache, container_name, read_or_write),
+            route_io_usage(
+                stream, config, log_cache, container_name, None, read_or_write,
+                &usage_query,
+            ),
+        ["containers", container_name, "io", device, read_or_write] =>
+            route_io_usage(
+                stream, config, log_cache, container_name, Some(device),
+                read_or_write, &usage_query,
+            ),
         ["containers", container_name, "net", recv_or_send] =>
-            route_net_usage(stream, log_cache, container_name, recv_or_send),
-        _ => route_not_found(stream)
+            route_net_usage(
+                stream, config, log_cache, container_name, None, recv_or_send,
+                &usage_query,
+            ),
+        ["containers", container_name, "net", interface, recv_or_send] =>
+            route_net_usage(
+                stream, config, log_cache, container_name, Some(interface),
+                recv_or_send, &usage_query,
+            ),
+        ["groups", label_key] =>
+            route_groups(stream, log_cache, label_key),
+        ["groups", label_key, label_value, resource @ ..] =>
+            route_group_usage(
+                stream, config, log_cache, label_key, label_value, resource,
+                &usage_query,
+            ),
+        _ => Ok(StatusCode::NotFound)
     };
 
     match result {
-        Ok(StatusCode::NotFound) => route_not_found(stream)?,
-        Err(e) => handle_generic_error(stream, &e)?,
+        Ok(StatusCode::NotFound) => handle_error(stream, &not_found_error(log_cache, &parts)?)?,
+        Err(e) => handle_error(stream, &e)?,
         _ => { /* do nothing... */ StatusCode::Ok }
     };
 
@@ -371,15 +1180,85 @@ fn handle_connection(
 }
 
 pub fn start_server(
-    log_cache: &log_cache::SharedUsageCache
+    config: &config::Config,
+    log_cache: &log_cache::SharedUsageCache,
+    broadcaster: &broadcast::SharedUsageBroadcaster,
+    shutdown: &supervisor::SharedShutdown,
+) -> Result<(), error::Error> {
+    let listener = std::net::TcpListener::bind(&config.listen)?;
+    serve(listener, config, log_cache, broadcaster, shutdown)
+}
+
+/// listener で受け付けた接続をスレッドプールで並行して処理します
+///
+/// 接続毎に読み書きのタイムアウトを設定するので、
+/// 遅いクライアントがスレッドを占有し続けることはありません
+/// 接続毎のエラーはログに出力するだけで、待ち受けは続けます
+/// 終了が要求されると、処理中の接続を終えてから待ち受けを閉じて戻ります
+pub fn serve(
+    listener: std::net::TcpListener,
+    config: &config::Config,
+    log_cache: &log_cache::SharedUsageCache,
+    broadcaster: &broadcast::SharedUsageBroadcaster,
+    shutdown: &supervisor::SharedShutdown,
 ) -> Result<(), error::Error> {
-    let listener = std::net::TcpListener::bind("0.0.0.0:7878")?;
+    let pool = thread_pool::ThreadPool::new(
+        "server", config.workers, config.queue_length
+    )?;
+    let config = std::sync::Arc::new(config.clone());
+
+    // accept で止まっているので、自分に接続して起こします
+    let mut wake_addr = listener.local_addr()?;
+    if wake_addr.ip().is_unspecified() {
+        wake_addr.set_ip(match wake_addr {
+            std::net::SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+            std::net::SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    shutdown.on_request("server", move || {
+        let _ = std::net::TcpStream::connect(wake_addr);
+    });
 
     for stream in listener.incoming() {
-        let mut stream = stream?;
-        handle_connection(&mut stream, log_cache)?;
+        if shutdown.is_requested() {
+            break;
+        }
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("failed to accept connection: {}", e);
+                continue;
+            },
+        };
+        let peer = stream.peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+        let timeout = Some(config.connection_timeout);
+        if let Err(e) = stream.set_read_timeout(timeout)
+            .and_then(|_| stream.set_write_timeout(timeout))
+        {
+            eprintln!("connection error ({}): {}", peer, e);
+            continue;
+        }
+        // 待ち行列が一杯の場合に503を返すための複製
+        let mut rejected_stream = stream.try_clone();
+
+        let config = std::sync::Arc::clone(&config);
+        let log_cache = std::sync::Arc::clone(log_cache);
+        let broadcaster = std::sync::Arc::clone(broadcaster);
+        let job_peer = peer.clone();
+        let result = pool.execute(move || {
+            if let Err(e) = handle_connection(&mut stream, &config, &log_cache, &broadcaster) {
+                eprintln!("connection error ({}): {}", job_peer, e);
+            }
+        });
+        if let Err(e) = result {
+            eprintln!("connection rejected ({}): {}", peer, e);
+            if let Ok(stream) = rejected_stream.as_mut() {
+                let _ = handle_service_unavailable(stream, "too many pending connections");
+            }
+        }
     }
 
     Ok(())
 }
-
@@ -0,0 +1,879 @@
+use std::collections::HashMap;
+use std::io::{ Read, Seek, Write, };
+use std::path::{ Path, PathBuf, };
+
+use super::error;
+use super::host;
+use super::log;
+use super::time;
+
+//
+// 日次ログのバイナリ形式 (セグメント)
+//
+// セグメントは以下の3つのファイルを持つディレクトリです
+// - records: ヘッダと固定長のレコード (tick 毎にコンテナ1つにつき1つ以上)
+// - index:   tick 毎の時刻と、その tick のレコードの位置
+// - strings: コンテナの識別情報やデバイス名などの文字列表
+//
+// 追記は strings → records → index の順に行い、index に載った tick のみを読みます
+// レコードと文字列には CRC-32 を付け、fsync 前に止まって index だけが残った場合も
+// 起動時の recover で書きかけの末尾として切り詰めます
+//
+
+/// セグメント内のファイル名
+pub const RECORDS_FILE: &str = "records";
+pub const INDEX_FILE: &str = "index";
+pub const STRINGS_FILE: &str = "strings";
+
+const MAGIC: &[u8; 4] = b"CPHS";
+/// 書き込む形式のバージョン
+/// (1 はレコードと文字列に checksum が無い形式で、読み込みと追記のみ対応します)
+const VERSION: u16 = 2;
+const CHECKSUM_VERSION: u16 = 2;
+/// records のヘッダの大きさ
+/// magic (4) version (2) record_size (2) slots (2) reserved (6) created (8) reserved (8)
+const HEADER_SIZE: u64 = 32;
+/// レコード1つが持つ値の数
+const SLOTS: usize = 46;
+/// kind (1) reserved (3) container (4) key (4) checksum (4)
+const RECORD_HEADER_SIZE: usize = 16;
+/// レコード1つの大きさ
+pub const RECORD_SIZE: usize = RECORD_HEADER_SIZE + SLOTS * 8;
+/// time (8) first_record (4) count (2) millis (2)
+const INDEX_ENTRY_SIZE: usize = 16;
+/// 値が無い (None) ことを表す値
+const NONE: u64 = u64::MAX;
+
+/// レコードの種類
+///
+/// Container はコンテナ全体の値 (cpu, memory, io, net, pids, load) で、
+/// 他はその後に続くコンテナ毎の内訳です
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RecordKind {
+    Container = 0,
+    /// key は最初の CPU の番号で、値は CPU 毎の使用率です
+    PerCpu = 1,
+    /// key はデバイス名の文字列表の番号です
+    IoDevice = 2,
+    /// key はインターフェース名の文字列表の番号です
+    NetInterface = 3,
+    /// key はマウントポイントの文字列表の番号です
+    Filesystem = 4,
+}
+impl RecordKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(RecordKind::Container),
+            1 => Some(RecordKind::PerCpu),
+            2 => Some(RecordKind::IoDevice),
+            3 => Some(RecordKind::NetInterface),
+            4 => Some(RecordKind::Filesystem),
+            _ => None,
+        }
+    }
+}
+
+/// 固定長のレコード1つ
+///
+/// 値は全て u64 で保存し、f32 はビット列、None は NONE とします
+struct Record {
+    kind: RecordKind,
+    /// コンテナの識別情報の文字列表の番号
+    container: u32,
+    key: u32,
+    slots: Vec<u64>,
+}
+impl Record {
+    fn new(kind: RecordKind, container: u32, key: u32) -> Self {
+        Record { kind, container, key, slots: Vec::with_capacity(SLOTS) }
+    }
+
+    fn push(&mut self, value: Option<u64>) {
+        debug_assert!(self.slots.len() < SLOTS);
+        self.slots.push(value.unwrap_or(NONE));
+    }
+    fn push_u32(&mut self, value: Option<u32>) {
+        self.push(value.map(u64::from));
+    }
+    fn push_f32(&mut self, value: Option<f32>) {
+        self.push(value.map(|v| u64::from(v.to_bits())));
+    }
+
+    /// checksum はヘッダの checksum 以外と値の全体に対して計算します
+    fn write_to(&self, buffer: &mut Vec<u8>) {
+        let start = buffer.len();
+        buffer.push(self.kind as u8);
+        buffer.extend_from_slice(&[0; 3]);
+        buffer.extend_from_slice(&self.container.to_le_bytes());
+        buffer.extend_from_slice(&self.key.to_le_bytes());
+        buffer.extend_from_slice(&[0; 4]);
+        for i in 0..SLOTS {
+            buffer.extend_from_slice(&self.slots.get(i).copied().unwrap_or(NONE).to_le_bytes());
+        }
+        let record = &buffer[start..];
+        let checksum = crc32(&[&record[..12], &record[RECORD_HEADER_SIZE..]]);
+        buffer[start + 12..start + RECORD_HEADER_SIZE].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    fn read_from(bytes: &[u8], verify: bool) -> Result<Self, error::Error> {
+        if verify
+            && crc32(&[&bytes[..12], &bytes[RECORD_HEADER_SIZE..RECORD_SIZE]]) != read_u32(&bytes[12..16])
+        {
+            return Err(error::Error::ParseError("record checksum mismatch".to_string()));
+        }
+        let kind = RecordKind::from_u8(bytes[0])
+            .ok_or_else(|| error::Error::ParseError(format!("unknown record kind: {}", bytes[0])))?;
+        Ok(Record {
+            kind,
+            container: read_u32(&bytes[4..8]),
+            key: read_u32(&bytes[8..12]),
+            slots: bytes[RECORD_HEADER_SIZE..RECORD_SIZE]
+                .chunks_exact(8)
+                .map(read_u64)
+                .collect(),
+        })
+    }
+
+    fn reader(&self) -> SlotReader<'_> {
+        SlotReader { slots: self.slots.iter() }
+    }
+}
+
+/// レコードの値を先頭から順に読みます
+struct SlotReader<'a> {
+    slots: std::slice::Iter<'a, u64>,
+}
+impl SlotReader<'_> {
+    fn u64(&mut self) -> Option<u64> {
+        self.slots.next().copied().filter(|v| *v != NONE)
+    }
+    fn u32(&mut self) -> Option<u32> {
+        self.u64().and_then(|v| u32::try_from(v).ok())
+    }
+    fn u8(&mut self) -> Option<u8> {
+        self.u64().and_then(|v| u8::try_from(v).ok())
+    }
+    fn f32(&mut self) -> Option<f32> {
+        self.u32().map(f32::from_bits)
+    }
+}
+
+/// CRC-32 (IEEE 802.3) の表
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(chunks: &[&[u8]]) -> u32 {
+    let mut crc = !0_u32;
+    for chunk in chunks {
+        for byte in *chunk {
+            crc = CRC32_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8);
+        }
+    }
+    !crc
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes(bytes[..2].try_into().expect("2 bytes"))
+}
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().expect("4 bytes"))
+}
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().expect("8 bytes"))
+}
+
+fn push_io(record: &mut Record, io: &log::IoUsage) {
+    record.push(io.readkB);
+    record.push(io.writekB);
+    record.push_u32(io.readkBps);
+    record.push_u32(io.writekBps);
+    record.push_u32(io.readIops);
+    record.push_u32(io.writeIops);
+}
+fn read_io(slots: &mut SlotReader) -> log::IoUsage {
+    log::IoUsage {
+        readkB: slots.u64(),
+        writekB: slots.u64(),
+        readkBps: slots.u32(),
+        writekBps: slots.u32(),
+        readIops: slots.u32(),
+        writeIops: slots.u32(),
+        devices: Default::default(),
+    }
+}
+
+fn push_net(record: &mut Record, net: &log::NetUsage) {
+    record.push(net.recvkB);
+    record.push(net.sendkB);
+    record.push_u32(net.recvkBps);
+    record.push_u32(net.sendkBps);
+    record.push(net.recvPackets);
+    record.push(net.sendPackets);
+    record.push(net.recvErrors);
+    record.push(net.sendErrors);
+    record.push(net.recvDropped);
+    record.push(net.sendDropped);
+}
+fn read_net(slots: &mut SlotReader) -> log::NetUsage {
+    log::NetUsage {
+        recvkB: slots.u64(),
+        sendkB: slots.u64(),
+        recvkBps: slots.u32(),
+        sendkBps: slots.u32(),
+        recvPackets: slots.u64(),
+        sendPackets: slots.u64(),
+        recvErrors: slots.u64(),
+        sendErrors: slots.u64(),
+        recvDropped: slots.u64(),
+        sendDropped: slots.u64(),
+        interfaces: Default::default(),
+    }
+}
+
+/// コンテナ全体の値のレコード
+fn container_record(container: u32, usage: &log::Usage) -> Record {
+    let mut record = Record::new(RecordKind::Container, container, 0);
+    let cpu = &usage.cpu;
+    record.push_f32(cpu.percentage);
+    record.push(cpu.total);
+    record.push(cpu.system);
+    record.push(cpu.ncpu.map(u64::from));
+    record.push(cpu.user);
+    record.push(cpu.kernel);
+    record.push(cpu.periods);
+    record.push(cpu.throttled_periods);
+    record.push(cpu.throttled_time);
+    record.push_f32(cpu.throttle_ratio);
+
+    let memory = &usage.memory;
+    record.push_f32(memory.percentage);
+    record.push(memory.used);
+    record.push(memory.available);
+    let breakdown = &memory.breakdown;
+    for value in [
+        breakdown.rss, breakdown.cache, breakdown.active_file, breakdown.inactive_file,
+        breakdown.shmem, breakdown.swap, breakdown.kernel_stack, breakdown.slab,
+        breakdown.pgfault, breakdown.pgmajfault, breakdown.failcnt,
+    ] {
+        record.push(value);
+    }
+
+    push_io(&mut record, &usage.io);
+    push_net(&mut record, &usage.net);
+
+    record.push_f32(usage.pids.percentage);
+    record.push(usage.pids.current);
+    record.push(usage.pids.limit);
+
+    let load = usage.host.as_ref().map(|host| host.load);
+    record.push_f32(load.map(|l| l.one));
+    record.push_f32(load.map(|l| l.five));
+    record.push_f32(load.map(|l| l.fifteen));
+    record
+}
+
+/// コンテナ全体の値のレコードから使用状況を作ります (内訳は後のレコードで埋めます)
+fn container_usage(record: &Record, info: Option<log::ContainerInfo>) -> log::Usage {
+    let mut slots = record.reader();
+    let cpu = log::CpuUsage {
+        percentage: slots.f32(),
+        total: slots.u64(),
+        system: slots.u64(),
+        ncpu: slots.u8(),
+        user: slots.u64(),
+        kernel: slots.u64(),
+        periods: slots.u64(),
+        throttled_periods: slots.u64(),
+        throttled_time: slots.u64(),
+        throttle_ratio: slots.f32(),
+        percpu: vec![],
+    };
+    let memory = log::MemoryUsage {
+        percentage: slots.f32(),
+        used: slots.u64(),
+        available: slots.u64(),
+        breakdown: log::MemoryBreakdown {
+            rss: slots.u64(),
+            cache: slots.u64(),
+            active_file: slots.u64(),
+            inactive_file: slots.u64(),
+            shmem: slots.u64(),
+            swap: slots.u64(),
+            kernel_stack: slots.u64(),
+            slab: slots.u64(),
+            pgfault: slots.u64(),
+            pgmajfault: slots.u64(),
+            failcnt: slots.u64(),
+        },
+    };
+    let io = read_io(&mut slots);
+    let net = read_net(&mut slots);
+    let pids = log::PidsUsage {
+        percentage: slots.f32(),
+        current: slots.u64(),
+        limit: slots.u64(),
+    };
+    let host = match (slots.f32(), slots.f32(), slots.f32()) {
+        (Some(one), Some(five), Some(fifteen)) => Some(host::HostUsage {
+            load: host::LoadAverage { one, five, fifteen },
+            filesystems: Default::default(),
+        }),
+        _ => None,
+    };
+    log::Usage { info, cpu, memory, io, net, pids, host }
+}
+
+/// コンテナの識別情報を文字列表に入れる形 ({"name":..,"id":..} の json) にします
+fn identity(name: &str, info: Option<&log::ContainerInfo>) -> String {
+    match info {
+        Some(info) => format!("{{\"name\":{},{}}}", json::stringify(name), info.json_fields()),
+        None => format!("{{\"name\":{}}}", json::stringify(name)),
+    }
+}
+
+fn parse_identity(identity: &str) -> Result<(String, Option<log::ContainerInfo>), error::Error> {
+    let json = json::parse(identity)?;
+    let name = json["name"].as_str()
+        .ok_or_else(|| error::Error::ParseError(format!("container name is missing: {}", identity)))?;
+    Ok((name.to_string(), log::ContainerInfo::from_json(&json)))
+}
+
+/// 1 tick 分の使用状況
+#[derive(Debug)]
+pub struct Tick {
+    /// UNIX時間 (ミリ秒)
+    pub time: i64,
+    /// tick の間隔 (ミリ秒)
+    pub millis: u16,
+    pub usages: HashMap<String, log::Usage>,
+}
+
+/// index の1項目
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    time: i64,
+    first_record: u32,
+    count: u16,
+    millis: u16,
+}
+impl IndexEntry {
+    fn write_to(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.time.to_le_bytes());
+        buffer.extend_from_slice(&self.first_record.to_le_bytes());
+        buffer.extend_from_slice(&self.count.to_le_bytes());
+        buffer.extend_from_slice(&self.millis.to_le_bytes());
+    }
+    fn read_from(bytes: &[u8]) -> Self {
+        IndexEntry {
+            time: read_u64(&bytes[0..8]) as i64,
+            first_record: read_u32(&bytes[8..12]),
+            count: read_u16(&bytes[12..14]),
+            millis: read_u16(&bytes[14..16]),
+        }
+    }
+    fn end_record(&self) -> u64 {
+        u64::from(self.first_record) + u64::from(self.count)
+    }
+}
+
+fn header(created: i64) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(HEADER_SIZE as usize);
+    buffer.extend_from_slice(MAGIC);
+    buffer.extend_from_slice(&VERSION.to_le_bytes());
+    buffer.extend_from_slice(&(RECORD_SIZE as u16).to_le_bytes());
+    buffer.extend_from_slice(&(SLOTS as u16).to_le_bytes());
+    buffer.extend_from_slice(&[0; 6]);
+    buffer.extend_from_slice(&created.to_le_bytes());
+    buffer.extend_from_slice(&[0; 8]);
+    buffer
+}
+
+/// records のヘッダを確認し、形式のバージョンを返します
+fn check_header(records: &mut std::fs::File, path: &Path) -> Result<u16, error::Error> {
+    let mut buffer = [0; HEADER_SIZE as usize];
+    records.seek(std::io::SeekFrom::Start(0))?;
+    records.read_exact(&mut buffer)
+        .map_err(|_| error::Error::ParseError(format!("{}: header is truncated", path.display())))?;
+    if &buffer[0..4] != MAGIC {
+        return Err(error::Error::ParseError(format!("{}: not a log segment", path.display())));
+    }
+    let version = read_u16(&buffer[4..6]);
+    if !(1..=VERSION).contains(&version) {
+        return Err(error::Error::ParseError(
+            format!("{}: unsupported segment version {}", path.display(), version),
+        ));
+    }
+    if usize::from(read_u16(&buffer[6..8])) != RECORD_SIZE
+        || usize::from(read_u16(&buffer[8..10])) != SLOTS
+    {
+        return Err(error::Error::ParseError(
+            format!("{}: unexpected record layout", path.display()),
+        ));
+    }
+    Ok(version)
+}
+
+/// 文字列表の1項目の前に付ける長さ (と checksum) の大きさ
+fn string_prefix_size(version: u16) -> usize {
+    if version >= CHECKSUM_VERSION { 8 } else { 4 }
+}
+
+/// 文字列表の1項目 (長さ (u32), checksum (u32), UTF-8 の本体)
+fn write_string(version: u16, string: &str, buffer: &mut Vec<u8>) {
+    buffer.extend_from_slice(&(string.len() as u32).to_le_bytes());
+    if version >= CHECKSUM_VERSION {
+        buffer.extend_from_slice(&crc32(&[string.as_bytes()]).to_le_bytes());
+    }
+    buffer.extend_from_slice(string.as_bytes());
+}
+
+/// 文字列表を読みます
+/// 最後の項目が途中で切れているか checksum が合わない場合は書きかけの末尾として無視します
+/// (返り値の2つ目は読めた部分のバイト数です)
+///
+/// 途中の項目が壊れている場合はエラーを返します
+/// (以降の文字列の番号が分からなくなり、切り詰めると番号を使い回してしまうため)
+fn read_strings(path: &Path, version: u16) -> Result<(Vec<String>, u64), error::Error> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e.into()),
+    };
+    let prefix_size = string_prefix_size(version);
+    let mut strings = vec![];
+    let mut offset = 0;
+    while offset + prefix_size <= bytes.len() {
+        let length = read_u32(&bytes[offset..]) as usize;
+        let end = offset + prefix_size + length;
+        let Some(body) = bytes.get(offset + prefix_size..end) else { break };
+        let string = std::str::from_utf8(body).ok()
+            .filter(|_| version < CHECKSUM_VERSION || crc32(&[body]) == read_u32(&bytes[offset + 4..]));
+        match string {
+            Some(string) => strings.push(string.to_string()),
+            None if end == bytes.len() => break,
+            None => return Err(error::Error::ParseError(format!(
+                "{}: string {} is corrupted", path.display(), strings.len(),
+            ))),
+        }
+        offset = end;
+    }
+    Ok((strings, offset as u64))
+}
+
+/// index を読みます (途中で切れた末尾の項目は無視します)
+fn read_index(path: &Path) -> Result<Vec<IndexEntry>, error::Error> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e.into()),
+    };
+    Ok(bytes.chunks_exact(INDEX_ENTRY_SIZE).map(IndexEntry::read_from).collect())
+}
+
+/// セグメントへ tick を追記します
+///
+/// 開く際に、最後に書き終えた tick より後ろの書きかけのデータを切り詰めます
+pub struct SegmentWriter {
+    records: std::fs::File,
+    index: std::fs::File,
+    strings: std::fs::File,
+    /// 既存のセグメントの形式のバージョン (新しいセグメントは VERSION)
+    version: u16,
+    string_ids: HashMap<String, u32>,
+    nstrings: u32,
+    nrecords: u64,
+}
+impl SegmentWriter {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, error::Error> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let open = |name: &str| std::fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(name));
+
+        let records_path = dir.join(RECORDS_FILE);
+        let mut records = open(RECORDS_FILE)?;
+        let version = if records.metadata()?.len() == 0 {
+            records.write_all(&header(time::now_epoch_millis()))?;
+            VERSION
+        } else {
+            check_header(&mut records, &records_path)?
+        };
+
+        let index_path = dir.join(INDEX_FILE);
+        let index = open(INDEX_FILE)?;
+        let entries = read_index(&index_path)?;
+        index.set_len((entries.len() * INDEX_ENTRY_SIZE) as u64)?;
+        let nrecords = entries.last().map_or(0, IndexEntry::end_record);
+        let records_len = HEADER_SIZE + nrecords * RECORD_SIZE as u64;
+        if records.metadata()?.len() < records_len {
+            return Err(error::Error::ParseError(
+                format!("{}: records are shorter than the index", records_path.display()),
+            ));
+        }
+        records.set_len(records_len)?;
+
+        let strings = open(STRINGS_FILE)?;
+        let (known, strings_len) = read_strings(&dir.join(STRINGS_FILE), version)?;
+        strings.set_len(strings_len)?;
+        let nstrings = known.len() as u32;
+        let mut string_ids = HashMap::new();
+        for (i, string) in known.into_iter().enumerate() {
+            string_ids.entry(string).or_insert(i as u32);
+        }
+
+        Ok(SegmentWriter { records, index, strings, version, string_ids, nstrings, nrecords })
+    }
+
+    /// 文字列表の番号を返します (無ければ new_strings に追加します)
+    fn intern(&mut self, string: String, new_strings: &mut Vec<u8>) -> u32 {
+        if let Some(id) = self.string_ids.get(&string) {
+            return *id;
+        }
+        write_string(self.version, &string, new_strings);
+        let id = self.nstrings;
+        self.nstrings += 1;
+        self.string_ids.insert(string, id);
+        id
+    }
+
+    /// 1 tick 分の使用状況を追記します
+    pub fn append(
+        &mut self,
+        time: i64,
+        millis: u16,
+        usages: &HashMap<String, log::Usage>,
+    ) -> Result<(), error::Error> {
+        let mut new_strings = vec![];
+        let mut records = vec![];
+        let mut names = usages.keys().collect::<Vec<&String>>();
+        names.sort();
+        for name in names {
+            let usage = &usages[name];
+            let container = self.intern(identity(name, usage.info.as_ref()), &mut new_strings);
+            records.push(container_record(container, usage));
+            for (i, chunk) in usage.cpu.percpu.chunks(SLOTS).enumerate() {
+                let mut record = Record::new(RecordKind::PerCpu, container, (i * SLOTS) as u32);
+                for percentage in chunk {
+                    record.push_f32(Some(*percentage));
+                }
+                records.push(record);
+            }
+            for (device, io) in &usage.io.devices {
+                let key = self.intern(device.clone(), &mut new_strings);
+                let mut record = Record::new(RecordKind::IoDevice, container, key);
+                push_io(&mut record, io);
+                records.push(record);
+            }
+            for (interface, net) in &usage.net.interfaces {
+                let key = self.intern(interface.clone(), &mut new_strings);
+                let mut record = Record::new(RecordKind::NetInterface, container, key);
+                push_net(&mut record, net);
+                records.push(record);
+            }
+            if let Some(host) = &usage.host {
+                for (mount, fs) in &host.filesystems {
+                    let key = self.intern(mount.clone(), &mut new_strings);
+                    let mut record = Record::new(RecordKind::Filesystem, container, key);
+                    record.push(Some(fs.total));
+                    record.push(Some(fs.free));
+                    record.push(Some(fs.available));
+                    records.push(record);
+                }
+            }
+        }
+        let entry = IndexEntry {
+            time,
+            first_record: u32::try_from(self.nrecords)
+                .map_err(|_| error::Error::OtherError("log segment is full".to_string()))?,
+            count: u16::try_from(records.len())
+                .map_err(|_| error::Error::OtherError("too many records in a tick".to_string()))?,
+            millis,
+        };
+
+        let mut buffer = Vec::with_capacity(records.len() * RECORD_SIZE);
+        for record in &records {
+            record.write_to(&mut buffer);
+        }
+        self.strings.write_all(&new_strings)?;
+        self.records.write_all(&buffer)?;
+        let mut index_buffer = Vec::with_capacity(INDEX_ENTRY_SIZE);
+        entry.write_to(&mut index_buffer);
+        self.index.write_all(&index_buffer)?;
+        self.nrecords += records.len() as u64;
+
+        Ok(())
+    }
+
+    /// 書き込んだ内容をディスクへ書き出します
+    pub fn sync(&self) -> Result<(), error::Error> {
+        self.strings.sync_all()?;
+        self.records.sync_all()?;
+        self.index.sync_all()?;
+        Ok(())
+    }
+}
+
+/// path がセグメントのディレクトリかどうか
+pub fn is_segment<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().join(RECORDS_FILE).is_file()
+}
+
+/// 文字列表の index 番目の文字列を返します
+fn lookup(strings: &[String], index: u32) -> Result<&String, error::Error> {
+    strings.get(index as usize)
+        .ok_or_else(|| error::Error::ParseError(format!("unknown string {}", index)))
+}
+
+/// レコードを tick 毎の使用状況へ戻します
+struct TickDecoder {
+    strings: Vec<String>,
+    /// checksum を確認するかどうか (形式のバージョン 1 には checksum がありません)
+    verify: bool,
+    /// 文字列表の番号毎の、読み込み済みのコンテナの識別情報
+    identities: HashMap<u32, (String, Option<log::ContainerInfo>)>,
+}
+impl TickDecoder {
+    fn open(dir: &Path, version: u16) -> Result<Self, error::Error> {
+        let (strings, _) = read_strings(&dir.join(STRINGS_FILE), version)?;
+        Ok(TickDecoder {
+            strings,
+            verify: version >= CHECKSUM_VERSION,
+            identities: HashMap::new(),
+        })
+    }
+
+    /// 1 tick 分のレコードを読みます
+    /// checksum が合わない場合や、文字列表に無い文字列を指している場合はエラーを返します
+    fn decode(&mut self, bytes: &[u8]) -> Result<HashMap<String, log::Usage>, error::Error> {
+        let mut usages: HashMap<String, log::Usage> = HashMap::new();
+        for bytes in bytes.chunks_exact(RECORD_SIZE) {
+            let record = Record::read_from(bytes, self.verify)?;
+            let (name, info) = match self.identities.entry(record.container) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) =>
+                    entry.insert(parse_identity(lookup(&self.strings, record.container)?)?),
+            };
+            if record.kind == RecordKind::Container {
+                usages.insert(name.clone(), container_usage(&record, info.clone()));
+                continue;
+            }
+            let Some(usage) = usages.get_mut(name) else {
+                return Err(error::Error::ParseError(
+                    format!("record for {} precedes the container", name),
+                ));
+            };
+            let mut slots = record.reader();
+            match record.kind {
+                RecordKind::Container => {},
+                RecordKind::PerCpu => {
+                    let percpu = &mut usage.cpu.percpu;
+                    percpu.truncate(record.key as usize);
+                    while let Some(percentage) = slots.f32() {
+                        percpu.push(percentage);
+                    }
+                },
+                RecordKind::IoDevice => {
+                    let device = lookup(&self.strings, record.key)?.clone();
+                    usage.io.devices.insert(device, read_io(&mut slots));
+                },
+                RecordKind::NetInterface => {
+                    let interface = lookup(&self.strings, record.key)?.clone();
+                    usage.net.interfaces.insert(interface, read_net(&mut slots));
+                },
+                RecordKind::Filesystem => {
+                    let (Some(total), Some(free), Some(available)) = (slots.u64(), slots.u64(), slots.u64())
+                    else { continue };
+                    let mount = lookup(&self.strings, record.key)?.clone();
+                    if let Some(host) = usage.host.as_mut() {
+                        host.filesystems.insert(mount, host::FilesystemStats { total, free, available });
+                    }
+                },
+            }
+        }
+        Ok(usages)
+    }
+}
+
+/// read_segment の結果
+#[derive(Debug, Default)]
+pub struct SegmentRead {
+    pub ticks: Vec<Tick>,
+    /// checksum が合わないなどで読み飛ばした tick の数
+    pub skipped: usize,
+}
+
+/// 時刻が [from, before) の範囲の tick をセグメントから読みます
+///
+/// index を二分探索して範囲のレコードだけを読むので、
+/// 読む量はファイル全体ではなく範囲の長さに比例します
+/// 壊れた tick は読み飛ばし、その数を返します
+pub fn read_segment<P: AsRef<Path>>(
+    dir: P,
+    from: i64,
+    before: Option<i64>,
+) -> Result<SegmentRead, error::Error> {
+    let dir = dir.as_ref();
+    let records_path = dir.join(RECORDS_FILE);
+    let mut records = std::fs::File::open(&records_path)?;
+    let version = check_header(&mut records, &records_path)?;
+    let entries = read_index(&dir.join(INDEX_FILE))?;
+
+    let start = entries.partition_point(|e| e.time < from);
+    let end = before.map_or(entries.len(), |b| entries.partition_point(|e| e.time < b));
+    if start >= end {
+        return Ok(SegmentRead::default());
+    }
+    let mut decoder = TickDecoder::open(dir, version)?;
+
+    // records が index より短い (書きかけの) 場合は読めた分のみ使います
+    let first_record = u64::from(entries[start].first_record);
+    let nrecords = entries[end - 1].end_record().saturating_sub(first_record);
+    records.seek(std::io::SeekFrom::Start(HEADER_SIZE + first_record * RECORD_SIZE as u64))?;
+    let mut bytes = vec![];
+    records.take(nrecords * RECORD_SIZE as u64).read_to_end(&mut bytes)?;
+
+    let mut read = SegmentRead::default();
+    for entry in &entries[start..end] {
+        let decoded = u64::from(entry.first_record).checked_sub(first_record)
+            .map(|offset| offset as usize * RECORD_SIZE)
+            .and_then(|offset| bytes.get(offset..offset + usize::from(entry.count) * RECORD_SIZE))
+            .ok_or_else(|| error::Error::ParseError("records are missing".to_string()))
+            .and_then(|bytes| decoder.decode(bytes));
+        match decoded {
+            Ok(usages) => read.ticks.push(Tick { time: entry.time, millis: entry.millis, usages }),
+            Err(e) => {
+                eprintln!(
+                    "{}: skipped the tick at {}: {}",
+                    dir.display(), time::format_epoch_millis(entry.time), e,
+                );
+                read.skipped += 1;
+            },
+        }
+    }
+
+    Ok(read)
+}
+
+/// recover の結果
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recovery {
+    /// 書きかけ、または壊れていて切り詰めた tick の数
+    pub skipped: usize,
+    /// 切り詰めたバイト数 (records, index, strings の合計)
+    pub truncated_bytes: u64,
+}
+
+/// file を length に切り詰め、切り詰めたバイト数を返します
+fn truncate(file: &std::fs::File, length: u64) -> Result<u64, error::Error> {
+    let current = file.metadata()?.len();
+    if current <= length {
+        return Ok(0);
+    }
+    file.set_len(length)?;
+    Ok(current - length)
+}
+
+/// 起動時に、セグメントの書きかけの末尾を切り詰めます
+///
+/// index の項目が前の項目のレコードの続きを指していない所以降を除き、
+/// 末尾の tick から順に読める (checksum が合う) tick が見つかるまで切り詰めます
+/// (途中の tick の破損は切り詰めず、読み込み時に読み飛ばします)
+///
+/// 文字列表の途中が壊れている場合は、切り詰めずにエラーを返します
+pub fn recover<P: AsRef<Path>>(dir: P) -> Result<Recovery, error::Error> {
+    let dir = dir.as_ref();
+    let open = |name: &str| std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(name));
+    let records_path = dir.join(RECORDS_FILE);
+    let mut records = open(RECORDS_FILE)?;
+    let version = check_header(&mut records, &records_path)?;
+    let mut recovery = Recovery::default();
+
+    let strings = open(STRINGS_FILE)?;
+    let (_, strings_len) = read_strings(&dir.join(STRINGS_FILE), version)?;
+    recovery.truncated_bytes += truncate(&strings, strings_len)?;
+
+    let index = open(INDEX_FILE)?;
+    let mut entries = read_index(&dir.join(INDEX_FILE))?;
+    let records_in_file = records.metadata()?.len().saturating_sub(HEADER_SIZE) / RECORD_SIZE as u64;
+    let mut chained = 0;
+    let mut end_record = 0;
+    let mut last_time = i64::MIN;
+    for entry in &entries {
+        if u64::from(entry.first_record) != end_record
+            || entry.time < last_time
+            || entry.end_record() > records_in_file
+        {
+            break;
+        }
+        end_record = entry.end_record();
+        last_time = entry.time;
+        chained += 1;
+    }
+    recovery.skipped += entries.len() - chained;
+    entries.truncate(chained);
+
+    let mut decoder = TickDecoder::open(dir, version)?;
+    while let Some(entry) = entries.last() {
+        let mut bytes = vec![0; usize::from(entry.count) * RECORD_SIZE];
+        records.seek(std::io::SeekFrom::Start(
+            HEADER_SIZE + u64::from(entry.first_record) * RECORD_SIZE as u64,
+        ))?;
+        records.read_exact(&mut bytes)?;
+        match decoder.decode(&bytes) {
+            Ok(_) => break,
+            Err(e) => {
+                eprintln!(
+                    "{}: dropped the torn tick at {}: {}",
+                    dir.display(), time::format_epoch_millis(entry.time), e,
+                );
+                recovery.skipped += 1;
+                entries.pop();
+            },
+        }
+    }
+
+    let end_record = entries.last().map_or(0, IndexEntry::end_record);
+    recovery.truncated_bytes += truncate(&index, (entries.len() * INDEX_ENTRY_SIZE) as u64)?;
+    recovery.truncated_bytes += truncate(&records, HEADER_SIZE + end_record * RECORD_SIZE as u64)?;
+    if recovery.truncated_bytes > 0 {
+        strings.sync_all()?;
+        records.sync_all()?;
+        index.sync_all()?;
+    }
+
+    Ok(recovery)
+}
+
+/// json 形式のログに対応するセグメントのパスを返します
+/// (log_daily → log_daily.seg, log_daily.N → log_daily.seg.N)
+pub fn segment_path_for<P: AsRef<Path>>(json_log: P) -> PathBuf {
+    let json_log = json_log.as_ref();
+    let name = json_log.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let segment_name = match name.rsplit_once('.') {
+        Some((stem, generation)) if generation.parse::<usize>().is_ok() =>
+            format!("{}.seg.{}", stem, generation),
+        _ => format!("{}.seg", name),
+    };
+    json_log.with_file_name(segment_name)
+}
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::sync::atomic::{ AtomicBool, AtomicI32, Ordering, };
+use std::sync::{ Arc, Mutex, };
+use std::time::{ Duration, Instant, };
+
+use super::error;
+use super::log_cache;
+use super::time;
+
+/// 正常に終了した場合の終了コード
+pub const EXIT_SUCCESS: u8 = 0;
+/// 起動に失敗した場合や、終了処理が時間内に終わらなかった場合の終了コード
+pub const EXIT_FAILURE: u8 = 1;
+/// 設定やコマンドライン引数が不正な場合の終了コード
+pub const EXIT_USAGE: u8 = 2;
+
+/// main が起動するワーカーの名前
+pub const LOGGER_WORKER: &str = "logger";
+pub const SERVER_WORKER: &str = "server";
+pub const EVENTS_WORKER: &str = "events";
+
+/// ワーカーの終了とシグナルを確認する間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// ワーカーを再起動するまでの待ち時間の上限 (1, 2, 4, ... 秒と延ばします)
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// これより長く動いていたワーカーは、再起動の待ち時間を最初からやり直します
+const STABLE_RUNTIME: Duration = Duration::from_secs(60);
+
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
+
+/// 受け取ったシグナルの番号 (0 は未受信)
+static RECEIVED_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+#[cfg(unix)]
+extern "C" {
+    fn signal(signum: std::ffi::c_int, handler: usize) -> usize;
+}
+
+/// シグナルハンドラではフラグを立てるだけで、終了処理は supervise で行います
+#[cfg(unix)]
+extern "C" fn handle_signal(signum: std::ffi::c_int) {
+    RECEIVED_SIGNAL.store(signum, Ordering::SeqCst);
+}
+
+/// SIGINT と SIGTERM を受け取れるようにします
+#[cfg(unix)]
+pub fn install_signal_handlers() -> Result<(), error::Error> {
+    const SIG_ERR: usize = usize::MAX;
+    for signum in [SIGINT, SIGTERM] {
+        let handler = handle_signal as extern "C" fn(std::ffi::c_int) as usize;
+        // SAFETY: ハンドラはアトミック変数への書き込みのみ行うので async-signal-safe です
+        if unsafe { signal(signum, handler) } == SIG_ERR {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn install_signal_handlers() -> Result<(), error::Error> {
+    Ok(())
+}
+
+/// 受け取った SIGINT または SIGTERM の番号を返します
+pub fn received_signal() -> Option<i32> {
+    match RECEIVED_SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signum => Some(signum),
+    }
+}
+
+/// 受け取ったシグナルの番号を返し、未受信の状態に戻します
+/// (supervise が終了要求に変えたシグナルで、次の supervise が終了しないようにします)
+fn take_received_signal() -> Option<i32> {
+    match RECEIVED_SIGNAL.swap(0, Ordering::SeqCst) {
+        0 => None,
+        signum => Some(signum),
+    }
+}
+
+type ShutdownHook = Box<dyn FnOnce() + Send>;
+
+/// 終了要求を各ワーカーへ伝えます
+///
+/// ワーカーは is_requested を確認するか sleep で待ち、要求されたら処理中の
+/// tick などを終えてから戻ります
+/// 接続待ちなどで止まっているワーカーは on_request で起こす処理を登録します
+#[derive(Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    hooks: Mutex<HashMap<String, ShutdownHook>>,
+}
+pub type SharedShutdown = Arc<Shutdown>;
+pub fn create_shared_shutdown() -> SharedShutdown {
+    Arc::new(Shutdown::default())
+}
+impl Shutdown {
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// 終了を要求し、登録された処理を呼び出します
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        let hooks = self.hooks.lock()
+            .map(|mut hooks| hooks.drain().collect::<Vec<(String, ShutdownHook)>>())
+            .unwrap_or_default();
+        for (_, hook) in hooks {
+            hook();
+        }
+    }
+
+    /// 終了が要求された時に呼び出す処理を登録します
+    /// 同じ名前の処理は置き換え、既に要求されていればすぐに呼び出します
+    pub fn on_request<F: FnOnce() + Send + 'static>(&self, name: &str, hook: F) {
+        if let Ok(mut hooks) = self.hooks.lock() {
+            if !self.is_requested() {
+                hooks.insert(name.to_string(), Box::new(hook));
+                return;
+            }
+        }
+        hook();
+    }
+
+    /// duration だけ待ちます
+    /// 途中で終了が要求されたら待つのをやめて true を返します
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        loop {
+            if self.is_requested() {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            std::thread::sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+}
+
+/// ワーカーの稼働状況 (/health で使用します)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WorkerHealth {
+    pub alive: bool,
+    /// 最後に起動した時刻 (UNIX時間、ミリ秒)
+    pub started: i64,
+    pub restarts: u32,
+    /// 最後に異常終了した際のエラー
+    pub last_error: Option<String>,
+}
+impl std::fmt::Display for WorkerHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{\"alive\":{},\"started\":\"{}\",\"restarts\":{},\"lastError\":{}}}",
+            self.alive,
+            time::format_epoch_millis(self.started),
+            self.restarts,
+            self.last_error.as_deref().map_or("null".to_string(), json::stringify),
+        )
+    }
+}
+
+type WorkerFn = Arc<dyn Fn() -> Result<(), error::Error> + Send + Sync>;
+
+/// supervise が起動し、異常終了したら再起動するワーカー
+pub struct Worker {
+    name: String,
+    run: WorkerFn,
+}
+impl Worker {
+    pub fn new<F>(name: &str, run: F) -> Self
+        where F: Fn() -> Result<(), error::Error> + Send + Sync + 'static
+    {
+        Worker { name: name.to_string(), run: Arc::new(run) }
+    }
+}
+
+/// 起動中のワーカー
+struct RunningWorker {
+    worker: Worker,
+    handle: Option<std::thread::JoinHandle<Result<(), error::Error>>>,
+    started: Instant,
+    /// 再起動を待っている場合の再起動する時刻
+    restart_at: Option<Instant>,
+    /// 連続して異常終了した回数 (再起動の待ち時間に使います)
+    failures: u32,
+}
+
+fn spawn_worker(worker: &Worker) -> Result<std::thread::JoinHandle<Result<(), error::Error>>, error::Error> {
+    let run = Arc::clone(&worker.run);
+    let handle = std::thread::Builder::new()
+        .name(worker.name.clone())
+        .spawn(move || run())?;
+    Ok(handle)
+}
+
+fn set_health(
+    log_cache: &log_cache::SharedUsageCache,
+    name: &str,
+    f: impl FnOnce(&mut WorkerHealth),
+) {
+    // ワーカーが panic してロックが poison されても状態は更新します
+    let mut lock = log_cache.write().unwrap_or_else(|e| e.into_inner());
+    f(lock.workers.entry(name.to_string()).or_default());
+}
+
+/// panic の内容を文字列にします
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    payload.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// ワーカーを起動して、終了要求があるまで見守ります
+///
+/// エラーや panic で終了した (または要求が無いのに戻った) ワーカーは
+/// 1, 2, 4, ... 秒 (MAX_RESTART_DELAY まで) 待って再起動します
+/// SIGINT, SIGTERM を受け取るか shutdown.request が呼ばれると
+/// grace の間ワーカーの終了を待ち、終わらなかった場合はエラーを返します
+pub fn supervise(
+    workers: Vec<Worker>,
+    log_cache: &log_cache::SharedUsageCache,
+    shutdown: &SharedShutdown,
+    grace: Duration,
+) -> Result<(), error::Error> {
+    let mut running = Vec::with_capacity(workers.len());
+    for worker in workers {
+        let handle = spawn_worker(&worker)?;
+        set_health(log_cache, &worker.name, |health| {
+            health.alive = true;
+            health.started = time::now_epoch_millis();
+        });
+        running.push(RunningWorker {
+            worker,
+            handle: Some(handle),
+            started: Instant::now(),
+            restart_at: None,
+            failures: 0,
+        });
+    }
+
+    while !shutdown.is_requested() {
+        if let Some(signum) = take_received_signal() {
+            println!("received signal {}, shutting down...", signum);
+            shutdown.request();
+            break;
+        }
+        for running in running.iter_mut() {
+            let name = running.worker.name.clone();
+            if running.handle.as_ref().is_some_and(|handle| handle.is_finished()) {
+                let result = running.handle.take()
+                    .expect("finished worker should have a handle")
+                    .join();
+                if shutdown.is_requested() {
+                    break;
+                }
+                let message = match result {
+                    Ok(Ok(())) => "stopped unexpectedly".to_string(),
+                    Ok(Err(e)) => e.to_string(),
+                    Err(payload) => {
+                        // 再起動したワーカーがロックを取れるようにします
+                        log_cache.clear_poison();
+                        format!("panicked: {}", panic_message(payload))
+                    },
+                };
+                if running.started.elapsed() >= STABLE_RUNTIME {
+                    running.failures = 0;
+                }
+                let delay = Duration::from_secs(1_u64 << running.failures.min(6))
+                    .min(MAX_RESTART_DELAY);
+                running.failures += 1;
+                eprintln!("{} {} (restarting in {} secs)", name, message, delay.as_secs());
+                set_health(log_cache, &name, |health| {
+                    health.alive = false;
+                    health.last_error = Some(message);
+                });
+                running.restart_at = Some(Instant::now() + delay);
+            }
+            if running.restart_at.is_some_and(|at| Instant::now() >= at) {
+                running.restart_at = None;
+                match spawn_worker(&running.worker) {
+                    Ok(handle) => {
+                        running.handle = Some(handle);
+                        running.started = Instant::now();
+                        set_health(log_cache, &name, |health| {
+                            health.alive = true;
+                            health.started = time::now_epoch_millis();
+                            health.restarts += 1;
+                        });
+                    },
+                    Err(e) => {
+                        eprintln!("failed to restart {}: {}", name, e);
+                        running.restart_at = Some(Instant::now() + MAX_RESTART_DELAY);
+                    },
+                }
+            }
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    // 処理中の tick や接続を終えるまで待ちます
+    let deadline = Instant::now() + grace;
+    let mut stuck = vec![];
+    for running in running {
+        let Some(handle) = running.handle else { continue };
+        while !handle.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        if !handle.is_finished() {
+            stuck.push(running.worker.name);
+            continue;
+        }
+        match handle.join() {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => eprintln!("{} stopped with error: {}", running.worker.name, e),
+            Err(payload) => eprintln!("{} panicked: {}", running.worker.name, panic_message(payload)),
+        }
+        set_health(log_cache, &running.worker.name, |health| health.alive = false);
+    }
+    if !stuck.is_empty() {
+        return Err(format!("workers did not stop in time: {}", stuck.join(", ")).into());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,24 @@
+
+use cephylas::broadcast::Broadcaster;
+
+#[test]
+fn publish_to_subscribers() {
+
+    let broadcaster = Broadcaster::<u32>::new(2);
+    let first = broadcaster.subscribe(4).expect("first subscriber");
+    let second = broadcaster.subscribe(1).expect("second subscriber");
+    assert!(broadcaster.subscribe(1).is_err(), "subscribers are limited");
+
+    assert_eq!(broadcaster.publish(1), 2);
+    // second の待ち行列 (長さ1) が一杯なので取り除かれます
+    assert_eq!(broadcaster.publish(2), 1);
+    assert_eq!(first.try_iter().collect::<Vec<u32>>(), vec![1, 2]);
+    assert_eq!(second.recv(), Ok(1));
+    assert!(second.recv().is_err(), "slow subscriber is disconnected");
+
+    // 切断された購読者も取り除かれ、空いた枠に購読できます
+    drop(first);
+    assert_eq!(broadcaster.publish(3), 0);
+    assert_eq!(broadcaster.subscriber_count(), 0);
+    assert!(broadcaster.subscribe(1).is_ok());
+}
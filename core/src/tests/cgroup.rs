@@ -0,0 +1,168 @@
+
+use std::path::{ Path, PathBuf };
+
+use cephylas::cgroup;
+
+const CONTAINER_ID: &str = "0123456789abcdef";
+const PID: u32 = 4242;
+
+fn write(path: PathBuf, contents: &str) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, contents).unwrap();
+}
+
+/// 一時ディレクトリに偽の /sys/fs/cgroup と /proc を作ります
+fn fake_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir()
+        .join(format!("cephylas-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let scope = format!("system.slice/docker-{}.scope", CONTAINER_ID);
+    let cgroup_dir = root.join("cgroup").join(&scope);
+    write(cgroup_dir.join("cpu.stat"), "\
+usage_usec 2500000
+user_usec 2000000
+system_usec 500000
+nr_periods 10
+nr_throttled 4
+throttled_usec 300000
+");
+    write(cgroup_dir.join("memory.current"), "104857600\n");
+    write(cgroup_dir.join("memory.max"), "max\n");
+    write(cgroup_dir.join("memory.stat"), "\
+anon 52428800
+file 41943040
+inactive_file 20971520
+active_file 20971520
+shmem 1048576
+slab 4096
+pgfault 100
+pgmajfault 3
+");
+    write(cgroup_dir.join("memory.swap.current"), "8192\n");
+    write(cgroup_dir.join("memory.events"), "low 0\nhigh 0\nmax 7\noom 0\noom_kill 0\n");
+    write(cgroup_dir.join("pids.current"), "12\n");
+    write(cgroup_dir.join("pids.max"), "max\n");
+    write(cgroup_dir.join("io.stat"), "\
+8:0 rbytes=1000 wbytes=2000 rios=1 wios=2 dbytes=0 dios=0
+8:16 rbytes=500 wbytes=0 rios=1 wios=0 dbytes=0 dios=0
+");
+
+    let proc_dir = root.join("proc");
+    // guest は user に含まれているので system には加えません
+    write(proc_dir.join("stat"), "\
+cpu  100 0 50 800 50 0 0 0 20 0
+cpu0 50 0 25 400 25 0 0 0 10 0
+cpu1 50 0 25 400 25 0 0 0 10 0
+intr 12345
+ctxt 67890
+");
+    write(proc_dir.join("meminfo"), "\
+MemTotal:        2048000 kB
+MemFree:         1024000 kB
+");
+    write(proc_dir.join(PID.to_string()).join("cgroup"), &format!("0::/{}\n", scope));
+    write(proc_dir.join(PID.to_string()).join("net/dev"), "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:       0       0    0    0    0     0          0         0        0       0    0    0    0     0       0          0
+  eth0:    3000      30    1    2    0     0          0         0     4000      40    3    4    0     0       0          0
+  eth1:     500       5    0    0    0     0          0         0      600       6    0    1    0     0       0          0
+");
+    root
+}
+
+#[test]
+fn parse_cgroup_files() {
+
+    assert_eq!(cgroup::parse_single_value("max\n"), None);
+    assert_eq!(cgroup::parse_single_value("123\n"), Some(123));
+    assert_eq!(
+        cgroup::parse_flat_keyed("usage_usec 10\nuser_usec 7\n").get("user_usec"),
+        Some(&7),
+    );
+    assert!(cgroup::parse_io_stat("").is_empty());
+    let devices = cgroup::parse_io_stat("8:0 rbytes=1 wbytes=2 rios=3\n8:16 rbytes=4 wbytes=5\n");
+    assert_eq!(devices.keys().collect::<Vec<&String>>(), vec!["8:0", "8:16"]);
+    assert_eq!((devices["8:0"].read, devices["8:0"].write, devices["8:0"].read_ops), (Some(1), Some(2), Some(3)));
+    assert_eq!(devices["8:16"].read_ops, None);
+    let interfaces = cgroup::parse_net_dev(
+        "h\nh\n    lo: 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9\n eth1: 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16\n"
+    );
+    assert_eq!(interfaces.keys().collect::<Vec<&String>>(), vec!["eth1"]);
+    let eth1 = &interfaces["eth1"];
+    assert_eq!((eth1.recv, eth1.recv_packets, eth1.recv_errors, eth1.recv_dropped), (Some(1), Some(2), Some(3), Some(4)));
+    assert_eq!((eth1.send, eth1.send_packets, eth1.send_errors, eth1.send_dropped), (Some(9), Some(10), Some(11), Some(12)));
+}
+
+#[test]
+fn read_stats_from_fake_sysfs() {
+
+    let root = fake_root("cgroup");
+    let (cgroup_root, proc_root) = (root.join("cgroup"), root.join("proc"));
+
+    let host = cgroup::read_host_stats(&proc_root).expect("host stats should be read");
+    assert_eq!(host.system, Some(1000 * 10));
+    assert_eq!(host.ncpu, Some(2));
+    assert_eq!(host.memory_total, Some(2048000 * 1024));
+
+    // /proc/<pid>/cgroup からも、ドライバの既定の場所からも見つかります
+    let expected = cgroup_root.join(format!("system.slice/docker-{}.scope", CONTAINER_ID));
+    assert_eq!(
+        cgroup::find_cgroup_dir(&cgroup_root, &proc_root, CONTAINER_ID, Some(PID)),
+        Some(expected.clone()),
+    );
+    assert_eq!(
+        cgroup::find_cgroup_dir(&cgroup_root, &proc_root, CONTAINER_ID, None),
+        Some(expected.clone()),
+    );
+    assert_eq!(cgroup::find_cgroup_dir(&cgroup_root, &proc_root, "unknown", None), None);
+
+    let stats = cgroup::read_stats(&expected, &proc_root, Some(PID), &host)
+        .expect("stats should be read");
+    assert!(stats.time.is_some());
+    assert_eq!(stats.cpu.total, Some(2500));
+    assert_eq!(stats.cpu.system, Some(10000));
+    assert_eq!(stats.cpu.ncpu, Some(2));
+    assert_eq!(stats.cpu.user, Some(2000));
+    assert_eq!(stats.cpu.kernel, Some(500));
+    assert_eq!(stats.cpu.periods, Some(10));
+    assert_eq!(stats.cpu.throttled_periods, Some(4));
+    assert_eq!(stats.cpu.throttled_time, Some(300));
+    assert!(stats.cpu.percpu.is_empty());
+    assert_eq!(stats.memory.used, Some(104857600 - 20971520));
+    // memory.max が "max" ならホストのメモリ量
+    assert_eq!(stats.memory.available, Some(2048000 * 1024));
+    let breakdown = &stats.memory.breakdown;
+    assert_eq!(breakdown.rss, Some(52428800));
+    assert_eq!(breakdown.cache, Some(41943040));
+    assert_eq!(breakdown.inactive_file, Some(20971520));
+    assert_eq!(breakdown.shmem, Some(1048576));
+    assert_eq!(breakdown.swap, Some(8192));
+    assert_eq!(breakdown.pgmajfault, Some(3));
+    assert_eq!(breakdown.failcnt, Some(7));
+    assert_eq!(breakdown.kernel_stack, None);
+    assert_eq!(stats.pids.current, Some(12));
+    assert_eq!(stats.pids.limit, None);
+    assert_eq!(stats.io.read, Some(1500));
+    assert_eq!(stats.io.write, Some(2000));
+    assert_eq!(stats.io.read_ops, Some(2));
+    assert_eq!(stats.io.write_ops, Some(2));
+    assert_eq!(stats.io.devices.keys().collect::<Vec<&String>>(), vec!["8:0", "8:16"]);
+    assert_eq!(stats.io.devices["8:16"].read, Some(500));
+    // ループバック以外の全インターフェースの合計
+    assert_eq!(stats.net.recv, Some(3500));
+    assert_eq!(stats.net.send, Some(4600));
+    assert_eq!(stats.net.recv_errors, Some(1));
+    assert_eq!(stats.net.send_dropped, Some(5));
+    assert_eq!(stats.net.interfaces.keys().collect::<Vec<&String>>(), vec!["eth0", "eth1"]);
+    assert_eq!(stats.net.interfaces["eth1"].send, Some(600));
+
+    // PIDが分からなければネットワークの値は None
+    let stats = cgroup::read_stats(&expected, &proc_root, None, &host).unwrap();
+    assert_eq!(stats.net.recv, None);
+
+    assert!(cgroup::read_stats(Path::new("/nonexistent"), &proc_root, None, &host).is_err());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,83 @@
+
+use cephylas::config::{ CommandLine, Config };
+
+fn args(list: &[&str]) -> Vec<String> {
+    list.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn file_values_are_applied() {
+
+    let mut config = Config::default();
+    config.apply_file(r#"
+# comment line
+[docker]
+socket = "/tmp/docker.sock" # trailing comment
+
+[log]
+dir = "/tmp/cephylas"
+tick_secs = 5
+"#).unwrap();
+
+    assert_eq!(config.docker_socket, std::path::PathBuf::from("/tmp/docker.sock"));
+    assert_eq!(config.daily_log_path(), std::path::PathBuf::from("/tmp/cephylas/log_daily"));
+    assert_eq!(config.tick, std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn command_line_overrides_env_and_env_overrides_default() {
+
+    let command_line = CommandLine::parse(args(&[
+        "--config", "/nonexistent/cephylas.toml",
+    ])).unwrap();
+    // an explicitly given config file must exist
+    assert!(Config::load(&command_line, Vec::new()).is_err());
+
+    let command_line = CommandLine::parse(args(&[
+        "--server-listen=127.0.0.1:9000",
+        "--cache-max-length", "100",
+    ])).unwrap();
+    let env = vec![
+        ("CEPHYLAS_SERVER_LISTEN".to_string(), "127.0.0.1:8000".to_string()),
+        ("CEPHYLAS_LOG_TICK_SECS".to_string(), "30".to_string()),
+    ];
+    let config = Config::load(&command_line, env).unwrap();
+
+    assert_eq!(config.listen, "127.0.0.1:9000");
+    assert_eq!(config.max_log_length, 100);
+    assert_eq!(config.tick, std::time::Duration::from_secs(30));
+    assert_eq!(config.log_dir, Config::default().log_dir);
+}
+
+#[test]
+fn invalid_values_are_rejected() {
+
+    let mut config = Config::default();
+    assert!(config.apply_file("[log]\ntick_secs = ten").is_err());
+    assert!(config.apply_file("[nosuch]\nkey = 1").is_err());
+    assert!(CommandLine::parse(args(&["--no-such-option", "1"])).is_err());
+    assert!(config.set("collector.backend", "podman").is_err());
+
+    config.set("log.tick_secs", "0").unwrap();
+    assert!(config.validate().is_err());
+
+    let mut config = Config::default();
+    config.set("server.listen", "localhost").unwrap();
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn printed_config_can_be_read_back() {
+
+    let mut config = Config::default();
+    config.set("log.dir", "/var/log/cephylas").unwrap();
+    config.set("log.tick_secs", "20").unwrap();
+    config.set("collector.backend", "cgroup").unwrap();
+    config.set("log.labels", "com.docker.compose.project, team,").unwrap();
+    assert_eq!(config.labels, vec!["com.docker.compose.project", "team"]);
+
+    let mut read_back = Config::default();
+    read_back.apply_file(&config.to_string()).unwrap();
+    assert_eq!(read_back.to_string(), config.to_string());
+    assert_eq!(read_back.labels, config.labels);
+}
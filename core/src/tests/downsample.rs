@@ -0,0 +1,201 @@
+
+use cephylas::downsample::{ self, Algorithm };
+use cephylas::log_cache::{ DownsampleOption, TimeRange, TimedCpuUsage, UsageCacheMap };
+
+/// y = sin(x / 10) に一点だけ大きなスパイクを加えた系列
+fn spiky_series(n: usize, spike_at: usize) -> Vec<(f64, f64)> {
+    (0..n)
+        .map(|i| {
+            let y = if i == spike_at { 100.0 } else { (i as f64 / 10.0).sin() };
+            (i as f64, y)
+        })
+        .collect()
+}
+
+fn is_strictly_increasing(indices: &[usize]) -> bool {
+    indices.windows(2).all(|w| w[0] < w[1])
+}
+
+#[test]
+fn lttb_keeps_endpoints_size_and_spikes() {
+
+    let points = spiky_series(1000, 637);
+    let indices = downsample::lttb(&points, 50);
+
+    assert_eq!(indices.len(), 50);
+    assert_eq!(indices.first(), Some(&0));
+    assert_eq!(indices.last(), Some(&999));
+    assert!(is_strictly_increasing(&indices));
+    assert!(indices.contains(&637));
+}
+
+#[test]
+fn lttb_selects_one_point_per_bucket() {
+
+    // 10 points in 3 inner buckets: [1,3), [3,6), [6,9)
+    let points = (0..10)
+        .map(|i| (i as f64, if i % 2 == 0 { 0.0 } else { 1.0 }))
+        .collect::<Vec<(f64, f64)>>();
+    let indices = downsample::lttb(&points, 5);
+
+    assert_eq!(indices.len(), 5);
+    assert!((1..3).contains(&indices[1]));
+    assert!((3..6).contains(&indices[2]));
+    assert!((6..9).contains(&indices[3]));
+}
+
+#[test]
+fn lttb_on_a_straight_line_is_harmless() {
+
+    let points = (0..100).map(|i| (i as f64, 2.0 * i as f64)).collect::<Vec<_>>();
+    let indices = downsample::lttb(&points, 10);
+    assert_eq!(indices.len(), 10);
+    assert!(is_strictly_increasing(&indices));
+
+    // fewer points than requested are returned as is
+    assert_eq!(downsample::lttb(&points[..5], 10), vec![0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn min_max_keeps_extremes_of_every_bucket() {
+
+    let points = spiky_series(1000, 10);
+    let indices = downsample::min_max(&points, 100);
+
+    assert!(indices.len() <= 100);
+    assert!(is_strictly_increasing(&indices));
+    assert!(indices.contains(&10));
+    let global_min = (0..1000)
+        .min_by(|a, b| points[*a].1.total_cmp(&points[*b].1))
+        .unwrap();
+    assert!(indices.contains(&global_min));
+}
+
+#[test]
+fn m4_keeps_first_and_last_of_every_bucket() {
+
+    let points = spiky_series(1000, 500);
+    let indices = downsample::m4(&points, 40);
+
+    assert!(indices.len() <= 40);
+    assert!(is_strictly_increasing(&indices));
+    for range in downsample::buckets(1000, 10) {
+        assert!(indices.contains(&range.start));
+        assert!(indices.contains(&(range.end - 1)));
+    }
+    assert!(indices.contains(&500));
+}
+
+#[test]
+fn bucket_selection_never_exceeds_nsample() {
+
+    let points = spiky_series(1000, 500);
+    for nsample in 0..4 {
+        let indices = downsample::m4(&points, nsample);
+        assert!(indices.len() <= nsample, "m4 {}: {:?}", nsample, indices);
+        assert!(is_strictly_increasing(&indices));
+    }
+    for nsample in 0..2 {
+        let indices = downsample::min_max(&points, nsample);
+        assert!(indices.len() <= nsample, "minmax {}: {:?}", nsample, indices);
+    }
+    assert_eq!(downsample::m4(&points, 1), vec![0]);
+}
+
+#[test]
+fn mean_buckets_cover_all_points() {
+
+    let points = spiky_series(1001, 0);
+    let ranges = downsample::mean(&points, 100);
+
+    assert_eq!(ranges.len(), 100);
+    assert_eq!(ranges.first().map(|r| r.start), Some(0));
+    assert_eq!(ranges.last().map(|r| r.end), Some(1001));
+    assert!(ranges.windows(2).all(|w| w[0].end == w[1].start));
+}
+
+#[test]
+fn algorithm_names_are_parsed() {
+
+    assert_eq!("lttb".parse::<Algorithm>().unwrap(), Algorithm::Lttb);
+    assert_eq!("minmax".parse::<Algorithm>().unwrap(), Algorithm::MinMax);
+    assert_eq!("m4".parse::<Algorithm>().unwrap(), Algorithm::M4);
+    assert_eq!("mean".parse::<Algorithm>().unwrap(), Algorithm::Mean);
+    assert_eq!("raw".parse::<Algorithm>().unwrap(), Algorithm::Raw);
+    assert!("nearest".parse::<Algorithm>().is_err());
+}
+
+#[test]
+fn cache_map_downsamples_with_selected_algorithm() {
+
+    let mut cache = UsageCacheMap::<TimedCpuUsage>::new(100);
+    for i in 0..100 {
+        cache.insert("app".to_string(), TimedCpuUsage {
+            time: 1_729_209_600_000 + i * 1000,
+            percentage: Some(i as f32),
+            ..Default::default()
+        });
+    }
+    let fxy = |c: &TimedCpuUsage| (c.time as f64, c.percentage.unwrap_or_default() as f64);
+
+    let raw = cache.downsample(
+        "app",
+        &TimeRange::default(),
+        &DownsampleOption { nsample: 10, algorithm: Algorithm::Raw },
+        fxy,
+    ).unwrap();
+    assert_eq!(raw.len(), 100);
+
+    let mean = cache.downsample(
+        "app",
+        &TimeRange::default(),
+        &DownsampleOption { nsample: 10, algorithm: Algorithm::Mean },
+        fxy,
+    ).unwrap();
+    assert_eq!(mean.len(), 10);
+    assert_eq!(mean[0].percentage, Some(4.5));
+    assert_eq!(mean[9].percentage, Some(94.5));
+    assert_eq!(mean[0].time, 1_729_209_600_000 + 4500);
+    assert!(mean[0].to_string().starts_with("{\"time\":\"2024-10-18T00:00:04.500Z\""));
+
+    assert!(
+        cache.downsample("nosuch", &TimeRange::default(), &DownsampleOption::default(), fxy)
+            .is_none()
+    );
+}
+
+#[test]
+fn downsampling_runs_only_over_the_selected_range() {
+
+    let start = 1_729_209_600_000;
+    let mut cache = UsageCacheMap::<TimedCpuUsage>::new(1000);
+    for i in 0..1000 {
+        cache.insert("app".to_string(), TimedCpuUsage {
+            time: start + i * 10_000,
+            percentage: Some(if i == 100 { 100.0 } else { 1.0 }),
+            ..Default::default()
+        });
+    }
+    let fxy = |c: &TimedCpuUsage| (c.time as f64, c.percentage.unwrap_or_default() as f64);
+
+    // [from, to) is found by binary search, including bounds between samples
+    let range = TimeRange { from: Some(start + 200 * 10_000), to: Some(start + 300 * 10_000 + 1) };
+    assert_eq!(cache.range("app", &range), Some(200..301));
+    let range = TimeRange { from: Some(start + 5), to: None };
+    assert_eq!(cache.range("app", &range), Some(1..1000));
+    let range = TimeRange { from: Some(start + 2_000 * 10_000), to: None };
+    assert_eq!(cache.range("app", &range), Some(1000..1000));
+    assert_eq!(cache.earliest_time(), Some(start));
+
+    let range = TimeRange { from: Some(start + 500 * 10_000), to: None };
+    let sampled = cache.downsample(
+        "app",
+        &range,
+        &DownsampleOption { nsample: 50, algorithm: Algorithm::Lttb },
+        fxy,
+    ).unwrap();
+    assert_eq!(sampled.len(), 50);
+    assert_eq!(sampled[0].time, start + 500 * 10_000);
+    assert!(sampled.iter().all(|c| range.contains(c.time)));
+    assert!(sampled.iter().all(|c| c.percentage == Some(1.0)));
+}
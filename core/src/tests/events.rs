@@ -0,0 +1,96 @@
+
+use cephylas::events::{ self, ContainerEvent, EventCache };
+use cephylas::log_cache::TimeRange;
+
+fn event(time: i64, container: &str, action: &str) -> ContainerEvent {
+    ContainerEvent {
+        time,
+        container: container.to_string(),
+        id: format!("{}-id", container),
+        action: action.to_string(),
+        exit_code: None,
+        health: None,
+    }
+}
+
+#[test]
+fn parse_docker_events() {
+
+    let die = json::parse(r#"{"status":"die","id":"abc","Type":"container","Action":"die",
+        "Actor":{"ID":"abc","Attributes":{"exitCode":"137","image":"nginx","name":"web"}},
+        "scope":"local","time":1729209600,"timeNano":1729209600123456789}"#).unwrap();
+    let (nanos, event) = events::parse_docker_event(&die).unwrap();
+    assert_eq!(nanos, 1729209600123456789);
+    assert_eq!(event.time, 1729209600123);
+    assert_eq!(event.container, "web");
+    assert_eq!(event.action, "die");
+    assert_eq!(event.exit_code, Some(137));
+
+    let health = json::parse(r#"{"Type":"container","Action":"health_status: unhealthy",
+        "Actor":{"ID":"abc","Attributes":{"name":"web"}},"time":1729209600}"#).unwrap();
+    let (_, event) = events::parse_docker_event(&health).unwrap();
+    assert_eq!(event.action, "health_status");
+    assert_eq!(event.health.as_deref(), Some("unhealthy"));
+
+    // 記録しない種類のイベント
+    let exec = json::parse(r#"{"Type":"container","Action":"exec_start: sh",
+        "Actor":{"ID":"abc","Attributes":{"name":"web"}},"time":1729209600}"#).unwrap();
+    assert!(events::parse_docker_event(&exec).is_none());
+
+    // イベントログの形式で往復できます
+    let (_, event) = events::parse_docker_event(&die).unwrap();
+    let logged = json::parse(&event.to_string()).unwrap();
+    assert_eq!(events::json_to_event(&logged), Some(event));
+}
+
+#[test]
+fn read_chunked_response_lines() {
+
+    let line1 = r#"{"Action":"start"}"#;
+    let line2 = r#"{"Action":"die"}"#;
+    // 2つ目のイベントは2つのチャンクに分かれています
+    let body = format!(
+        "{:x}\r\n{}\n\r\n{:x}\r\n{}\r\n{:x}\r\n{}\n\r\n0\r\n\r\n",
+        line1.len() + 1, line1,
+        5, &line2[..5],
+        line2.len() - 5 + 1, &line2[5..],
+    );
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+        body,
+    );
+    let mut lines = Vec::new();
+    events::read_response_lines(
+        &mut std::io::Cursor::new(response),
+        |line| { lines.push(line.to_string()); Ok(()) },
+    ).unwrap();
+    assert_eq!(lines, vec![line1, line2]);
+
+    let error = "HTTP/1.1 500 Internal Server Error\r\n\r\n";
+    assert!(events::read_response_lines(
+        &mut std::io::Cursor::new(error),
+        |_| Ok(()),
+    ).is_err());
+}
+
+#[test]
+fn event_cache_range() {
+
+    let mut cache = EventCache::new(3);
+    cache.push(event(1000, "web", "start"));
+    cache.push(event(2000, "db", "start"));
+    cache.push(event(3000, "web", "die"));
+
+    let all = cache.range(&TimeRange::default(), None).unwrap();
+    assert_eq!(all.len(), 3);
+    let web = cache.range(&TimeRange::default(), Some("web")).unwrap();
+    assert_eq!(web.iter().map(|e| e.action.as_str()).collect::<Vec<_>>(), vec!["start", "die"]);
+    let range = TimeRange { from: Some(1500), to: Some(2500) };
+    assert_eq!(cache.range(&range, None).unwrap(), vec![event(2000, "db", "start")]);
+
+    // 古いイベントを捨てた後は、キャッシュより古い期間はイベントログから読みます
+    cache.push(event(4000, "web", "start"));
+    assert!(cache.range(&TimeRange::default(), None).is_none());
+    assert!(cache.range(&TimeRange { from: Some(1000), to: None }, None).is_none());
+    assert_eq!(cache.range(&TimeRange { from: Some(2000), to: None }, None).unwrap().len(), 3);
+}
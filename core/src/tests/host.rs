@@ -0,0 +1,121 @@
+
+use std::collections::BTreeMap;
+use std::path::{ Path, PathBuf };
+
+use cephylas::host;
+use cephylas::log::Usage;
+
+use super::TempDir;
+
+/// 偽の /proc (src/tests/fixtures/proc)
+fn fixture_proc() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("src/tests/fixtures/proc")
+}
+
+#[test]
+fn parse_proc_fixtures() {
+
+    let cpu = host::parse_stat(include_str!("fixtures/proc/stat"));
+    // guest, guest_nice は user, nice に含まれているので数えません
+    assert_eq!(cpu.system, Some(99_000));
+    assert_eq!(cpu.total, Some(17_000));
+    assert_eq!(cpu.user, Some(11_000));
+    assert_eq!(cpu.kernel, Some(6_000));
+    assert_eq!(cpu.ncpu, Some(2));
+    assert_eq!(cpu.percpu, vec![8_500, 8_500]);
+
+    let memory = host::parse_meminfo(include_str!("fixtures/proc/meminfo"));
+    assert_eq!(memory.used, Some(2_000_000 * 1024));
+    assert_eq!(memory.available, Some(8_000_000 * 1024));
+    assert_eq!(memory.breakdown.rss, Some(600_000 * 1024));
+    assert_eq!(memory.breakdown.cache, Some(3_000_000 * 1024));
+    assert_eq!(memory.breakdown.active_file, Some(1_500_000 * 1024));
+    assert_eq!(memory.breakdown.swap, Some(500_000 * 1024));
+    assert_eq!(memory.breakdown.pgfault, None);
+
+    let (load, processes) = host::parse_loadavg(include_str!("fixtures/proc/loadavg"))
+        .expect("loadavg should be parsed");
+    assert_eq!(load, host::LoadAverage { one: 0.52, five: 0.40, fifteen: 0.31 });
+    assert_eq!(processes, 412);
+    assert_eq!(host::parse_loadavg("garbage"), None);
+
+    // パーティションと loop デバイスは除きます
+    let devices = host::parse_diskstats(include_str!("fixtures/proc/diskstats"));
+    assert_eq!(devices.keys().collect::<Vec<&String>>(), vec!["253:0", "259:0", "8:0"]);
+    let sda = &devices["8:0"];
+    assert_eq!((sda.read, sda.write), (Some(40_000 * 512), Some(80_000 * 512)));
+    assert_eq!((sda.read_ops, sda.write_ops), (Some(1_000), Some(2_000)));
+}
+
+#[test]
+fn read_host_stats_from_fake_proc() {
+
+    let mounts = [PathBuf::from("/"), PathBuf::from("/nonexistent-cephylas-mount")];
+    let stats = host::read_stats(&fixture_proc(), &mounts).expect("host stats should be read");
+
+    assert!(stats.time.is_some());
+    assert_eq!(stats.cpu.total, Some(17_000));
+    assert_eq!(stats.io.read_ops, Some(1_000 + 300 + 50));
+    // lo と veth を除いたインターフェースの合計
+    assert_eq!(stats.net.interfaces.keys().collect::<Vec<&String>>(), vec!["docker0", "eth0"]);
+    assert_eq!(stats.net.recv, Some(5_200_000));
+    assert_eq!(stats.net.send, Some(3_100_000));
+    assert_eq!((stats.pids.current, stats.pids.limit), (Some(412), Some(4_194_304)));
+
+    let host = stats.host.expect("host usage should be set");
+    assert_eq!(host.load.one, 0.52);
+    // 読めないマウントポイントは除きます
+    assert_eq!(host.filesystems.keys().collect::<Vec<&String>>(), vec!["/"]);
+    assert!(host.filesystems["/"].total > 0);
+}
+
+#[test]
+fn host_usage_is_logged_and_summarized() {
+
+    let dir = TempDir::new("host");
+    let config = dir.config();
+
+    let line = |time: &str, one: f32| {
+        let usage = Usage {
+            host: Some(host::HostUsage {
+                load: host::LoadAverage { one, five: 0.5, fifteen: 0.25 },
+                filesystems: BTreeMap::from([("/".to_string(), host::FilesystemStats {
+                    total: 1000,
+                    free: 400,
+                    available: 300,
+                })]),
+            }),
+            ..Default::default()
+        };
+        format!(
+            "{{\"time\":\"{}\",\"millis\":10000,\"stats\":{{\"{}\":{}}}}}\r\n",
+            time, host::HOST_NAME, usage,
+        )
+    };
+    let daily = [line("2024-10-18T00:00:00Z", 1.0), line("2024-10-18T00:00:10Z", 2.0)].concat();
+    std::fs::write(config.daily_log_path(), daily).unwrap();
+
+    let usage_cache = cephylas::log::read_log_range(
+        &config,
+        &cephylas::log_cache::TimeRange::default(),
+    ).unwrap();
+    let all = cephylas::log_cache::TimeRange::default();
+    let raw = cephylas::log_cache::DownsampleOption {
+        algorithm: cephylas::downsample::Algorithm::Raw,
+        ..Default::default()
+    };
+    let load = usage_cache.load
+        .downsample(host::HOST_NAME, &all, &raw, |l| (l.time as f64, l.load.one as f64))
+        .expect("load should be cached");
+    assert_eq!(load.iter().map(|l| l.load.one).collect::<Vec<f32>>(), vec![1.0, 2.0]);
+    let filesystems = usage_cache.filesystems
+        .downsample(host::HOST_NAME, &all, &raw, |f| (f.time as f64, 0.0))
+        .unwrap();
+    let root = filesystems[0].filesystems["/"];
+    assert_eq!((root.total, root.used(), root.available), (1000, 600, 300));
+    assert_eq!(filesystems[0].max_percentage().map(|p| p.round()), Some(67.0));
+
+    let lines = cephylas::log_rotation::summarize(config.daily_log_path(), 60).unwrap();
+    let summary = json::parse(&lines[0]).unwrap();
+    assert_eq!(summary["stats"][host::HOST_NAME]["load"]["one"]["avg"], 1.5);
+}
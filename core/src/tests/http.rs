@@ -0,0 +1,119 @@
+
+use std::io::Cursor;
+
+use cephylas::http::{ self, RequestError };
+
+fn read(data: &str) -> (Result<Option<http::Request>, RequestError>, String) {
+    let mut reader = Cursor::new(data.as_bytes().to_vec());
+    let mut written = Vec::new();
+    let result = http::read_request(&mut reader, &mut written);
+    (result, String::from_utf8(written).unwrap())
+}
+
+fn status_of(data: &str) -> u16 {
+    match read(data).0 {
+        Err(RequestError::Status(code, _)) => code,
+        other => panic!("expected error status, got {:?}", other),
+    }
+}
+
+#[test]
+fn parse_request_with_headers_and_query() {
+
+    let (request, _) = read(
+        "GET /containers/my%20app/cpu?last=1h&from=2024-10-18T21:34:56+09:00&x HTTP/1.1\r\n\
+         Host: localhost\r\n\
+         User-Agent:   curl/8.0 \r\n\
+         \r\n"
+    );
+    let request = request.unwrap().expect("request should be read");
+    assert_eq!(request.method, "GET");
+    assert_eq!(request.path_segments, vec!["containers", "my app", "cpu"]);
+    assert_eq!(request.query, vec![
+        ("last".to_string(), "1h".to_string()),
+        ("from".to_string(), "2024-10-18T21:34:56+09:00".to_string()),
+        ("x".to_string(), "".to_string()),
+    ]);
+    assert_eq!(request.header("user-agent"), Some("curl/8.0"));
+    assert_eq!(request.header("HOST"), Some("localhost"));
+    assert!(request.body.is_empty());
+
+    // %2F はセグメントの区切りになりません
+    let (request, _) = read("GET /containers/a%2Fb//cpu HTTP/1.0\n\n");
+    assert_eq!(
+        request.unwrap().unwrap().path_segments,
+        vec!["containers", "a/b", "cpu"],
+    );
+
+    // absolute-form と asterisk-form
+    let (request, _) = read("GET http://localhost:7878/metrics?n=1 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(request.unwrap().unwrap().path_segments, vec!["metrics"]);
+    let (request, _) = read("OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(request.unwrap().unwrap().path_segments.is_empty());
+
+    // 何も受け取らずに閉じられた接続
+    assert!(read("").0.unwrap().is_none());
+}
+
+#[test]
+fn parse_request_body_and_expect() {
+
+    let (request, written) = read(
+        "POST /x HTTP/1.1\r\nHost: h\r\nContent-Length: 5\r\n\r\nhello, extra"
+    );
+    assert_eq!(request.unwrap().unwrap().body, b"hello");
+    assert_eq!(written, "");
+
+    let (request, written) = read(
+        "POST /x HTTP/1.1\r\nHost: h\r\nExpect: 100-Continue\r\nContent-Length: 2\r\n\r\nok"
+    );
+    assert_eq!(request.unwrap().unwrap().body, b"ok");
+    assert_eq!(written, "HTTP/1.1 100 Continue\r\n\r\n");
+
+    assert_eq!(status_of("POST /x HTTP/1.1\r\nHost: h\r\nExpect: magic\r\n\r\n"), 417);
+    assert_eq!(status_of("POST /x HTTP/1.1\r\nHost: h\r\nContent-Length: 5\r\n\r\nab"), 400);
+    assert_eq!(status_of("POST /x HTTP/1.1\r\nHost: h\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n"), 400);
+    assert_eq!(status_of("POST /x HTTP/1.1\r\nHost: h\r\nContent-Length: -1\r\n\r\n"), 400);
+    assert_eq!(status_of("POST /x HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked\r\n\r\n"), 501);
+
+    // 大きすぎる本文には 100 Continue を返しません
+    let (request, written) = read(&format!(
+        "POST /x HTTP/1.1\r\nHost: h\r\nExpect: 100-continue\r\nContent-Length: {}\r\n\r\n",
+        http::MAX_BODY_SIZE + 1,
+    ));
+    assert!(matches!(request, Err(RequestError::Status(413, _))));
+    assert_eq!(written, "");
+}
+
+#[test]
+fn reject_invalid_requests() {
+
+    assert_eq!(status_of("GET /\r\n\r\n"), 400);
+    assert_eq!(status_of("GET  / HTTP/1.1\r\nHost: h\r\n\r\n"), 400);
+    assert_eq!(status_of("GET / HTTP/2.0\r\nHost: h\r\n\r\n"), 505);
+    assert_eq!(status_of("GET / HTTP/1.1\r\n\r\n"), 400);
+    assert_eq!(status_of("GET / HTTP/1.1\r\nHost: h\r\nBad Header: x\r\n\r\n"), 400);
+    assert_eq!(status_of("GET / HTTP/1.1\r\nHost: h\r\nX: a\r\n b\r\n\r\n"), 400);
+    assert_eq!(status_of("GET /a%zz HTTP/1.1\r\nHost: h\r\n\r\n"), 400);
+    assert_eq!(status_of("GET /a?x=%ff HTTP/1.1\r\nHost: h\r\n\r\n"), 400);
+    assert_eq!(status_of("GET * HTTP/1.1\r\nHost: h\r\n\r\n"), 400);
+    assert_eq!(status_of("GET / HTTP/1.1\r\nHost: h"), 400);
+    assert_eq!(status_of(&format!(
+        "GET / HTTP/1.1\r\nHost: h\r\nX: {}\r\n\r\n",
+        "a".repeat(http::MAX_HEADER_SIZE),
+    )), 431);
+}
+
+#[test]
+fn head_writer_drops_body() {
+
+    let mut written = Vec::new();
+    {
+        use std::io::Write;
+        let mut writer = http::HeadWriter::new(&mut written);
+        writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r").unwrap();
+        writer.write_all(b"\n\r\nbody").unwrap();
+        writer.write_all(b"more").unwrap();
+    }
+    assert_eq!(written, b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n");
+}
@@ -0,0 +1,425 @@
+
+use std::collections::BTreeMap;
+use std::io::{ BufRead, Write };
+use std::time::{ Duration, Instant };
+
+use cephylas::config::Config;
+use cephylas::log::{ self, CpuUsage, IoUsage, MemoryBreakdown, MemoryUsage, NetUsage, Usage, };
+use cephylas::log_cache::{ self, CollectionStats, CollectorState, Mean, Timed, TimedIoUsage, UsageCache, UsageCacheMap, };
+use cephylas::thread_pool::ThreadPool;
+
+use super::{ TempDir, daily_log_line, };
+
+/// 応答しないコンテナがタイムアウトするまで待つ時間
+const HANG: Duration = Duration::from_secs(3);
+
+fn stats_body(time: &str) -> String {
+    format!(
+        "{{\"read\":\"{}\",\"id\":\"0123\",\"cpu_stats\":{{\"cpu_usage\":{{\"total_usage\":1000}},\
+         \"system_cpu_usage\":100000,\"online_cpus\":2}},\"memory_stats\":{{\"usage\":100,\"limit\":1000}}}}",
+        time,
+    )
+}
+
+/// 偽の Docker API を一時ディレクトリの Unix ソケットで起動します
+/// "hang" は応答せず、"zero" は時刻がゼロの stats を返します
+fn start_fake_docker(name: &str) -> std::path::PathBuf {
+    let socket_path = std::env::temp_dir()
+        .join(format!("cephylas-{}-{}.sock", name, std::process::id()));
+    let _ = std::fs::remove_file(&socket_path);
+    let listener = std::os::unix::net::UnixListener::bind(&socket_path)
+        .expect("fake docker socket should be bound");
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            std::thread::spawn(move || {
+                let mut request_line = String::new();
+                std::io::BufReader::new(&stream).read_line(&mut request_line).unwrap();
+                let path = request_line.split_whitespace().nth(1).unwrap_or_default().to_string();
+                let body = if path.starts_with("/containers/json") {
+                    let containers = ["web", "db", "hang", "zero"].iter()
+                        .map(|name| format!(
+                            "{{\"Id\":\"{0}-id\",\"Names\":[\"/{0}\"],\"Image\":\"img\",\"Created\":1}}",
+                            name,
+                        ))
+                        .collect::<Vec<String>>();
+                    format!("[{}]", containers.join(","))
+                } else if path.starts_with("/containers/hang/") {
+                    std::thread::sleep(HANG);
+                    return;
+                } else if path.starts_with("/containers/zero/") {
+                    stats_body("0001-01-01T00:00:00Z")
+                } else {
+                    stats_body("2024-10-18T00:00:00Z")
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{}\r\n",
+                    body,
+                );
+            });
+        }
+    });
+    socket_path
+}
+
+/// どのリクエストにも同じ応答を返す Docker の代わりです
+/// response が空ならば、再起動中の Docker のように何も返さずに切断します
+fn start_static_docker(name: &str, response: &'static str) -> std::path::PathBuf {
+    let socket_path = std::env::temp_dir()
+        .join(format!("cephylas-{}-{}.sock", name, std::process::id()));
+    let _ = std::fs::remove_file(&socket_path);
+    let listener = std::os::unix::net::UnixListener::bind(&socket_path)
+        .expect("fake docker socket should be bound");
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let mut request_line = String::new();
+            let _ = std::io::BufReader::new(&stream).read_line(&mut request_line);
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    socket_path
+}
+
+#[test]
+fn failing_containers_become_gaps() {
+
+    let config = Config {
+        docker_socket: start_fake_docker("collect"),
+        collector_workers: 4,
+        collector_timeout: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let pool = ThreadPool::new("test-collector", config.collector_workers, 16).unwrap();
+
+    let start = Instant::now();
+    let (stats, failures) = log::get_containers_stats(&config, &pool)
+        .expect("one failing container must not fail the whole tick");
+    // 応答しないコンテナはタイムアウトで打ち切られます
+    assert!(start.elapsed() < HANG, "{:?}", start.elapsed());
+
+    let mut names = stats.keys().collect::<Vec<&String>>();
+    names.sort();
+    assert_eq!(names, vec!["db", "web"]);
+    assert_eq!(failures, 2);
+    assert_eq!(stats["web"].info.id, "web-id");
+    assert_eq!(stats["web"].time.as_deref(), Some("2024-10-18T00:00:00Z"));
+}
+
+#[test]
+fn logger_stops_after_current_tick() {
+
+    let dir = TempDir::new("graceful");
+    let config = Config {
+        docker_socket: start_fake_docker("graceful"),
+        proc_root: std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/tests/fixtures/proc"),
+        collector_timeout: Duration::from_millis(200),
+        tick: Duration::from_secs(1),
+        ..dir.config()
+    };
+    let cache = cephylas::log_cache::create_shared_cache(10);
+    let broadcaster = cephylas::broadcast::create_shared_broadcaster(1);
+    let shutdown = cephylas::supervisor::create_shared_shutdown();
+    let handle = {
+        let (cache, shutdown) = (std::sync::Arc::clone(&cache), std::sync::Arc::clone(&shutdown));
+        std::thread::spawn(move || log::log_json(&config, &cache, &broadcaster, &shutdown))
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while cache.read().unwrap().collection.last_tick.is_none() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert!(cache.read().unwrap().collection.last_tick.is_some(), "a tick should be finished");
+    assert_eq!(cache.read().unwrap().collection.containers, 2);
+
+    let started = Instant::now();
+    shutdown.request();
+    handle.join().unwrap().expect("logger should stop gracefully");
+    assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+}
+
+#[test]
+fn docker_outage_backs_off_and_recovers() {
+
+    let tick = Duration::from_secs(10);
+    let max_backoff = Duration::from_secs(60);
+    let pool = ThreadPool::new("test-outage", 2, 16).unwrap();
+    let mut collection = CollectionStats::default();
+
+    // ソケットが無い場合も、接続が切られる場合も同じく Disconnected です
+    let missing = Config {
+        docker_socket: std::env::temp_dir().join("cephylas-missing.sock"),
+        collector_timeout: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let dropping = Config {
+        docker_socket: start_static_docker("dropping", ""),
+        ..missing.clone()
+    };
+    let mut retries = vec![];
+    for (time, config) in [&missing, &dropping, &dropping, &dropping, &dropping].iter().enumerate() {
+        let e = log::get_containers_stats(config, &pool)
+            .expect_err("unreachable docker should be an error");
+        assert_eq!(e.code(), "docker_unreachable", "{}", e);
+        collection.record_error(e.to_string(), time as i64 * 1000);
+        retries.push(collection.retry_after(tick, max_backoff).as_secs());
+    }
+    assert_eq!(collection.state, CollectorState::Disconnected);
+    assert_eq!(collection.consecutive_errors, 5);
+    assert_eq!(collection.disconnected_since, Some(0));
+    assert!(collection.last_error.is_some());
+    // tick の倍数で延ばし、上限で頭打ちになります
+    assert_eq!(retries, vec![10, 20, 40, 60, 60]);
+
+    let recovered = Config {
+        docker_socket: start_fake_docker("recovered"),
+        ..missing.clone()
+    };
+    let (stats, failures) = log::get_containers_stats(&recovered, &pool).unwrap();
+    collection.record(Duration::from_millis(10), stats.len(), failures, 5000);
+    // hang と zero は取得できないので Degraded です
+    assert_eq!(collection.state, CollectorState::Degraded);
+    assert_eq!(collection.consecutive_errors, 0);
+    assert_eq!(collection.last_success, Some(5000));
+    assert_eq!(collection.disconnected_since, None);
+    assert_eq!(collection.retry_after(tick, max_backoff), tick);
+
+    collection.record(Duration::from_millis(10), 2, 0, 15000);
+    assert_eq!(collection.state, CollectorState::Running);
+    assert!(collection.to_string().contains("\"state\":\"running\""));
+}
+
+#[test]
+fn docker_api_errors_are_classified() {
+
+    let config = Config {
+        docker_socket: start_static_docker(
+            "api-error",
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Type: application/json\r\n\r\n\
+             {\"message\":\"daemon is shutting down\"}",
+        ),
+        collector_timeout: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let pool = ThreadPool::new("test-api-error", 1, 4).unwrap();
+    let e = log::get_containers_stats(&config, &pool).expect_err("status 500 should be an error");
+    match &e {
+        cephylas::error::Error::DockerApiError { status, message } => {
+            assert_eq!(*status, 500);
+            assert_eq!(message, "daemon is shutting down");
+        },
+        e => panic!("unexpected error: {:?}", e),
+    }
+    assert_eq!(e.code(), "docker_api_error");
+
+    let garbage = Config {
+        docker_socket: start_static_docker("garbage", "HTTP/1.1 200 OK\r\n\r\n[{"),
+        ..config
+    };
+    let e = log::get_containers_stats(&garbage, &pool).expect_err("broken json should be an error");
+    assert_eq!(e.code(), "parse_error", "{}", e);
+}
+
+#[test]
+fn startup_recovers_torn_daily_log() {
+
+    let dir = TempDir::new("recover");
+    let config = dir.config();
+
+    let now = cephylas::time::now_epoch_millis();
+    let line = |time: &str| format!(
+        "{{\"time\":\"{}\",\"millis\":10000,\"stats\":{{\"app\":{}}}}}\r\n",
+        time, log::Usage::default(),
+    );
+    let torn = &line(&cephylas::time::format_epoch_millis(now))[..40];
+    let daily = [
+        line(&cephylas::time::format_epoch_millis(now - 30_000)),
+        // 時刻がゼロの行は読み飛ばし、後の行も読みます
+        line("0001-01-01T00:00:00Z"),
+        line(&cephylas::time::format_epoch_millis(now - 10_000)),
+        torn.to_string(),
+    ].concat();
+    std::fs::write(config.daily_log_path(), &daily).unwrap();
+    // ヘッダが壊れたセグメントは退避して、新しいセグメントに追記できるようにします
+    std::fs::create_dir_all(config.daily_segment_path()).unwrap();
+    std::fs::write(config.daily_segment_path().join(cephylas::store::RECORDS_FILE), b"NOPE").unwrap();
+
+    let cache = cephylas::log_cache::create_shared_cache(8640);
+    log::read_log(&config, &cache).unwrap();
+
+    let content = std::fs::read_to_string(config.daily_log_path()).unwrap();
+    assert_eq!(content.len(), daily.len() - torn.len());
+    assert!(!config.daily_segment_path().exists());
+
+    let lock = cache.read().unwrap();
+    let startup = &lock.startup;
+    assert_eq!(startup.loaded, 2);
+    assert_eq!(startup.skipped(), 2);
+    assert_eq!(startup.truncated_bytes(), torn.len() as u64);
+    let report = json::parse(&startup.to_string()).unwrap();
+    let daily_report = &report["files"][0];
+    assert_eq!(daily_report["path"], config.daily_log_path().display().to_string());
+    assert_eq!((daily_report["records"].as_usize(), daily_report["skipped"].as_usize()), (Some(2), Some(2)));
+    assert!(report["files"][1]["error"].as_str().unwrap().contains("moved to"));
+}
+
+/// lines を日次ログに書き、全期間を read_log_range で読みます
+fn read_daily_log(dir: &TempDir, lines: &[String]) -> UsageCache {
+    let config = dir.config();
+    std::fs::write(config.daily_log_path(), lines.concat()).unwrap();
+    log::read_log_range(&config, &cephylas::log_cache::TimeRange::default()).unwrap()
+}
+
+/// key のデータを間引かずに返します
+fn raw<T: Clone + Mean + Timed + ToString>(cache: &UsageCacheMap<T>, key: &str) -> Option<Vec<T>> {
+    cache.downsample(
+        key,
+        &cephylas::log_cache::TimeRange::default(),
+        &cephylas::log_cache::DownsampleOption {
+            algorithm: cephylas::downsample::Algorithm::Raw,
+            ..Default::default()
+        },
+        |t| (t.time() as f64, 0.0),
+    ).map(|data| data.into_iter().map(|t| t.into_owned()).collect())
+}
+
+#[test]
+fn read_log_range_keeps_network_interfaces() {
+
+    let dir = TempDir::new("read_log_interfaces");
+    let interface = |recv, send| NetUsage {
+        recvkBps: Some(recv),
+        sendkBps: Some(send),
+        ..Default::default()
+    };
+    let usage = Usage {
+        net: NetUsage {
+            recvPackets: Some(10),
+            interfaces: BTreeMap::from([
+                ("eth0".to_string(), interface(1, 3)),
+                ("eth1".to_string(), interface(2, 1)),
+            ]),
+            ..interface(3, 4)
+        },
+        ..Default::default()
+    };
+    let usage_cache = read_daily_log(&dir, &[daily_log_line("2024-10-18T00:00:00Z", "app", &usage)]);
+
+    let recv = |key: &str| raw(&usage_cache.net_interfaces, key)
+        .map(|data| data.iter().map(|n| n.recvkBps).collect::<Vec<_>>());
+    assert_eq!(recv(&cephylas::log_cache::interface_key("app", "eth0")), Some(vec![Some(1)]));
+    assert_eq!(recv(&cephylas::log_cache::interface_key("app", "eth1")), Some(vec![Some(2)]));
+    assert_eq!(recv("app/eth2"), None);
+}
+
+#[test]
+fn read_log_range_keeps_memory_breakdown() {
+
+    let dir = TempDir::new("read_log_memory");
+    let usage = Usage {
+        memory: MemoryUsage {
+            percentage: Some(1.0),
+            used: Some(100),
+            available: None,
+            breakdown: MemoryBreakdown {
+                rss: Some(60),
+                cache: Some(40),
+                swap: Some(5),
+                pgmajfault: Some(2),
+                ..Default::default()
+            },
+        },
+        ..Default::default()
+    };
+    let usage_cache = read_daily_log(&dir, &[daily_log_line("2024-10-18T00:00:00Z", "app", &usage)]);
+
+    let data = raw(&usage_cache.memory, "app").unwrap();
+    assert_eq!(data[0].field("used"), Some(100));
+    assert_eq!(data[0].field("rss"), Some(60));
+    assert_eq!(data[0].field("cache"), Some(40));
+    assert_eq!(data[0].field("swap"), Some(5));
+    assert_eq!(data[0].field("pgmajfault"), Some(2));
+    assert_eq!(data[0].field("slab"), None);
+    assert_eq!(cephylas::log_cache::TimedMemoryUsage::field_name("activeFile"), Some("activeFile"));
+    assert_eq!(cephylas::log_cache::TimedMemoryUsage::field_name("percentage"), None);
+}
+
+#[test]
+fn read_log_range_keeps_cpu_throttling() {
+
+    let dir = TempDir::new("read_log_cpu");
+    let usage = |ratio: f32, core: f32| Usage {
+        cpu: CpuUsage {
+            percentage: Some(1.0),
+            user: Some(30),
+            kernel: Some(10),
+            periods: Some(100),
+            throttled_periods: Some(25),
+            throttled_time: Some(40),
+            throttle_ratio: Some(ratio),
+            percpu: vec![core, 0.5],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let usage_cache = read_daily_log(&dir, &[
+        daily_log_line("2024-10-18T00:00:00Z", "app", &usage(25.0, 1.5)),
+        daily_log_line("2024-10-18T00:00:10Z", "app", &usage(75.0, 2.5)),
+    ]);
+
+    let data = raw(&usage_cache.cpu, "app").unwrap();
+    assert_eq!(data.len(), 2);
+    assert_eq!(data[0].field("throttleRatio"), Some(25.0));
+    assert_eq!(data[0].field("user"), Some(30.0));
+    assert_eq!(data[0].field("kernel"), Some(10.0));
+    assert_eq!(data[0].field("throttledTime"), Some(40.0));
+    assert_eq!(data[0].percpu, vec![1.5, 0.5]);
+
+    // every field and every core is averaged separately
+    let data = usage_cache.cpu.downsample(
+        "app",
+        &cephylas::log_cache::TimeRange::default(),
+        &cephylas::log_cache::DownsampleOption {
+            nsample: 1,
+            algorithm: cephylas::downsample::Algorithm::Mean,
+        },
+        |c| (c.time as f64, c.percentage.unwrap_or_default() as f64),
+    ).unwrap();
+    assert_eq!(data.len(), 1);
+    assert_eq!(data[0].field("throttleRatio"), Some(50.0));
+    assert_eq!(data[0].percpu, vec![2.0, 0.5]);
+    assert_eq!(cephylas::log_cache::TimedCpuUsage::field_name("percentage"), None);
+}
+
+#[test]
+fn read_log_range_keeps_block_devices() {
+
+    let dir = TempDir::new("read_log_devices");
+    let device = |read, write, read_iops| IoUsage {
+        readkBps: Some(read),
+        writekBps: Some(write),
+        readIops: read_iops,
+        ..Default::default()
+    };
+    let usage = Usage {
+        io: IoUsage {
+            writeIops: Some(6),
+            devices: BTreeMap::from([
+                ("8:0".to_string(), device(1, 4, Some(2))),
+                ("8:16".to_string(), device(2, 0, Some(3))),
+            ]),
+            ..device(3, 4, Some(5))
+        },
+        ..Default::default()
+    };
+    let usage_cache = read_daily_log(&dir, &[daily_log_line("2024-10-18T00:00:00Z", "app", &usage)]);
+
+    let read = |cache: &UsageCacheMap<TimedIoUsage>, key: &str| raw(cache, key)
+        .map(|data| data.iter().map(|d| (d.readkBps, d.readIops)).collect::<Vec<_>>());
+    assert_eq!(read(&usage_cache.io, "app"), Some(vec![(Some(3), Some(5))]));
+    let device = |name: &str| log_cache::device_key("app", name);
+    assert_eq!(read(&usage_cache.io_devices, &device("8:0")), Some(vec![(Some(1), Some(2))]));
+    assert_eq!(read(&usage_cache.io_devices, &device("8:16")), Some(vec![(Some(2), Some(3))]));
+    assert_eq!(read(&usage_cache.io_devices, &device("8:32")), None);
+}
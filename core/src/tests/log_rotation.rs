@@ -0,0 +1,210 @@
+
+use cephylas::config::Config;
+use cephylas::log_rotation::{ rotate, summarize };
+use cephylas::time;
+
+use super::TempDir;
+
+fn daily_line(time: &str, cpu: f32) -> String {
+    format!(
+        "{{\"time\":\"{}\",\"millis\":10000,\"stats\":{{\"app\":\
+         {{\"cpu\":{{\"percentage\":{:.2}}},\"memory\":{{\"percentage\":1.00,\"used\":100}},\
+         \"io\":{{\"readkBps\":0,\"writekBps\":0}},\"net\":{{\"recvkBps\":1,\"sendkBps\":2}},\
+         \"pids\":{{\"percentage\":10.00,\"current\":{},\"limit\":100}}}}}}}}\r\n",
+        time, cpu, 10.0 + cpu,
+    )
+}
+
+#[test]
+fn civil_date_round_trip() {
+
+    for days in [-1, 0, 1, 59, 365, 10_957, 19_000, 20_000] {
+        let (y, m, d) = time::civil_from_days(days);
+        assert_eq!(time::days_from_civil(y, m, d), days);
+    }
+    assert_eq!(time::civil_from_days(0), (1970, 1, 1));
+    assert_eq!(
+        time::parse_epoch_seconds("2024-02-29T12:34:56.123456789Z").unwrap(),
+        time::days_from_civil(2024, 2, 29) * 86_400 + 45_296,
+    );
+    assert_eq!(time::format_epoch_seconds(86_400 + 61), "1970-01-02T00:01:01Z");
+}
+
+#[test]
+fn summarize_into_min_avg_max_buckets() {
+
+    let dir = TempDir::new("summarize");
+    let config = dir.config();
+    let path = config.log_dir.join("log_daily");
+    let content = [
+        daily_line("2024-10-14T00:00:05.1Z", 1.0),
+        daily_line("2024-10-14T00:02:00.1Z", 3.0),
+        daily_line("2024-10-14T00:05:00.1Z", 10.0),
+    ].concat();
+    std::fs::write(&path, content).unwrap();
+
+    let lines = summarize(&path, 300).unwrap();
+    assert_eq!(lines.len(), 2);
+
+    let first = json::parse(&lines[0]).unwrap();
+    assert_eq!(first["time"], "2024-10-14T00:00:00Z");
+    assert_eq!(first["seconds"], 300);
+    let cpu = &first["stats"]["app"]["cpu"]["percentage"];
+    assert_eq!(cpu["min"].as_f64(), Some(1.0));
+    assert_eq!(cpu["avg"].as_f64(), Some(2.0));
+    assert_eq!(cpu["max"].as_f64(), Some(3.0));
+    assert_eq!(cpu["count"].as_u64(), Some(2));
+    let pids = &first["stats"]["app"]["pids"]["current"];
+    assert_eq!(pids["min"].as_f64(), Some(11.0));
+    assert_eq!(pids["max"].as_f64(), Some(13.0));
+
+    // summarized lines can be summarized again into coarser buckets
+    std::fs::write(&path, lines.join("\r\n")).unwrap();
+    let coarse = summarize(&path, 3600).unwrap();
+    assert_eq!(coarse.len(), 1);
+    let cpu = &json::parse(&coarse[0]).unwrap()["stats"]["app"]["cpu"]["percentage"];
+    assert_eq!(cpu["min"].as_f64(), Some(1.0));
+    assert_eq!(cpu["max"].as_f64(), Some(10.0));
+    assert_eq!(cpu["count"].as_u64(), Some(3));
+}
+
+#[test]
+fn rotate_renames_summarizes_and_deletes_old_generations() {
+
+    let dir = TempDir::new("rotate");
+    let config = dir.config();
+    let log_dir = config.log_dir.clone();
+    // 2024-10-13 is a Sunday
+    let sunday = time::days_from_civil(2024, 10, 13);
+
+    std::fs::write(log_dir.join("log_daily"), daily_line("2024-10-11T10:00:00Z", 1.0)).unwrap();
+    rotate(&config, sunday - 2, sunday - 1).unwrap();
+    std::fs::write(log_dir.join("log_daily"), daily_line("2024-10-12T10:00:00Z", 2.0)).unwrap();
+    rotate(&config, sunday - 1, sunday).unwrap();
+    assert!(log_dir.join("log_daily.2").exists());
+
+    // Monday: daily is rotated once more and weekly is rolled up into monthly
+    std::fs::write(log_dir.join("log_daily"), daily_line("2024-10-13T10:00:00Z", 3.0)).unwrap();
+    rotate(&config, sunday, sunday + 1).unwrap();
+
+    assert!(!log_dir.join("log_daily").exists());
+    assert!(log_dir.join("log_daily.1").exists());
+    assert!(log_dir.join("log_daily.2").exists());
+    assert!(!log_dir.join("log_daily.3").exists());
+    assert!(!log_dir.join("log_weekly").exists());
+
+    let weekly = std::fs::read_to_string(log_dir.join("log_weekly.1")).unwrap();
+    assert_eq!(weekly.lines().count(), 3);
+    let monthly = std::fs::read_to_string(log_dir.join("log_monthly")).unwrap();
+    assert_eq!(monthly.lines().count(), 3);
+}
+
+#[test]
+fn read_log_fills_older_window_from_coarser_tier() {
+
+    let dir = TempDir::new("read_log");
+    let config = Config {
+        tick: std::time::Duration::from_secs(10),
+        max_log_length: 8640,
+        ..dir.config()
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH).unwrap()
+        .as_secs() as i64;
+
+    // raw samples for the last 10 minutes
+    let daily = (0..60)
+        .map(|i| daily_line(&time::format_epoch_seconds(now - 600 + i * 10), 1.0))
+        .collect::<String>();
+    std::fs::write(config.log_dir.join("log_daily"), daily).unwrap();
+    // 5 min buckets for the last 2 hours; buckets overlapping log_daily are ignored
+    let weekly_source = config.log_dir.join("weekly_source");
+    let raw = (0..720)
+        .map(|i| daily_line(&time::format_epoch_seconds(now - 7200 + i * 10), 2.0))
+        .collect::<String>();
+    std::fs::write(&weekly_source, raw).unwrap();
+    let weekly = summarize(&weekly_source, 300).unwrap().join("\r\n");
+    std::fs::write(config.log_dir.join("log_weekly"), weekly).unwrap();
+
+    let log_cache = cephylas::log_cache::create_shared_cache(config.max_log_length);
+    cephylas::log::read_log(&config, &log_cache).unwrap();
+
+    let lock = log_cache.read().unwrap();
+    let data = lock.cpu.downsample(
+        "app",
+        &cephylas::log_cache::TimeRange::default(),
+        &cephylas::log_cache::DownsampleOption {
+            nsample: usize::MAX,
+            algorithm: cephylas::downsample::Algorithm::Raw,
+        },
+        |c| (0.0, c.percentage.unwrap_or_default() as f64),
+    ).unwrap();
+    let coarse = data.iter().filter(|c| c.percentage == Some(2.0)).count();
+    let fine = data.iter().filter(|c| c.percentage == Some(1.0)).count();
+    assert_eq!(fine, 60);
+    assert!((21..=23).contains(&coarse), "coarse = {}", coarse);
+    assert!(data.iter().take(coarse).all(|c| c.percentage == Some(2.0)));
+}
+
+#[test]
+fn read_log_range_scans_files_for_old_windows() {
+
+    let dir = TempDir::new("read_log_range");
+    let config = dir.config();
+    let daily = (0..6)
+        .map(|i| daily_line(&format!("2024-10-18T00:00:{}0Z", i), i as f32))
+        .collect::<String>();
+    std::fs::write(config.log_dir.join("log_daily"), daily).unwrap();
+
+    let from = time::parse_epoch_millis("2024-10-18T00:00:10Z").unwrap();
+    let to = time::parse_epoch_millis("2024-10-18T00:00:40Z").unwrap();
+    let usage_cache = cephylas::log::read_log_range(
+        &config,
+        &cephylas::log_cache::TimeRange { from: Some(from), to: Some(to) },
+    ).unwrap();
+
+    assert_eq!(usage_cache.cpu.earliest_time(), Some(from));
+    let data = usage_cache.cpu.downsample(
+        "app",
+        &cephylas::log_cache::TimeRange::default(),
+        &cephylas::log_cache::DownsampleOption::default(),
+        |c| (c.time as f64, c.percentage.unwrap_or_default() as f64),
+    ).unwrap();
+    let values = data.iter().map(|c| c.percentage).collect::<Vec<_>>();
+    assert_eq!(values, vec![Some(1.0), Some(2.0), Some(3.0)]);
+}
+
+#[test]
+fn summarize_keeps_container_identity() {
+
+    let dir = TempDir::new("summarize_identity");
+    let config = dir.config();
+    let path = config.log_dir.join("log_daily");
+    let line = |time: &str, id: &str| format!(
+        "{{\"time\":\"{}\",\"millis\":10000,\"stats\":{{\"app\":\
+         {{\"id\":\"{}\",\"image\":\"nginx\",\"created\":\"2024-10-13T00:00:00Z\",\
+         \"cpu\":{{\"percentage\":1.00}}}}}}}}\r\n",
+        time, id,
+    );
+    let content = [
+        line("2024-10-14T00:00:05Z", "old"),
+        line("2024-10-14T00:02:00Z", "new"),
+    ].concat();
+    std::fs::write(&path, content).unwrap();
+
+    // the last container in the bucket is kept
+    let lines = summarize(&path, 300).unwrap();
+    let app = &json::parse(&lines[0]).unwrap()["stats"]["app"];
+    assert_eq!(app["id"], "new");
+    assert_eq!(app["image"], "nginx");
+    assert_eq!(app["created"], "2024-10-13T00:00:00Z");
+    assert_eq!(app["cpu"]["percentage"]["count"].as_u64(), Some(2));
+
+    let usage_cache = cephylas::log::read_log_range(
+        &config,
+        &cephylas::log_cache::TimeRange::default(),
+    ).unwrap();
+    let spans = &usage_cache.containers["app"];
+    assert_eq!(spans.iter().map(|s| s.info.id.as_str()).collect::<Vec<_>>(), vec!["old", "new"]);
+    assert_eq!(spans[0].info.created, Some(time::parse_epoch_seconds("2024-10-13T00:00:00Z").unwrap()));
+}
@@ -0,0 +1,67 @@
+
+use std::collections::HashMap;
+
+use cephylas::log::{ Stats, Usage };
+use cephylas::log_cache::LatestUsage;
+use cephylas::metrics;
+
+fn latest_usage() -> LatestUsage {
+    let mut stats = Stats::default();
+    stats.cpu.total = Some(12_345);
+    stats.memory.used = Some(1_000_000);
+    stats.memory.available = Some(4_000_000);
+    stats.net.recv = Some(2048);
+    let mut usage = Usage::default();
+    usage.cpu.percentage = Some(12.5);
+    usage.net.recvkBps = Some(3);
+
+    LatestUsage { time: 0, stats, usage }
+}
+
+#[test]
+fn render_has_help_type_and_values() {
+
+    let mut latest = HashMap::new();
+    latest.insert("app".to_string(), latest_usage());
+    let text = metrics::render(&latest);
+
+    assert!(text.contains("# HELP cephylas_container_cpu_percent "));
+    assert!(text.contains("# TYPE cephylas_container_cpu_percent gauge\n"));
+    assert!(text.contains("# TYPE cephylas_container_cpu_seconds_total counter\n"));
+    assert!(text.contains("cephylas_container_cpu_percent{container=\"app\"} 12.5\n"));
+    assert!(text.contains("cephylas_container_cpu_seconds_total{container=\"app\"} 12.345\n"));
+    assert!(text.contains("cephylas_container_memory_limit_bytes{container=\"app\"} 4000000\n"));
+    assert!(text.contains("cephylas_container_network_receive_bytes_total{container=\"app\"} 2048\n"));
+    assert!(text.contains("cephylas_container_network_receive_bytes_per_second{container=\"app\"} 3000\n"));
+    // missing values are not rendered
+    assert!(!text.contains("cephylas_container_blkio_read_bytes_total{"));
+    assert!(text.ends_with('\n'));
+}
+
+#[test]
+fn container_names_are_escaped() {
+
+    assert_eq!(metrics::escape_label_value(r#"a"b\c"#), r#"a\"b\\c"#);
+    assert_eq!(metrics::escape_label_value("a\nb"), "a\\nb");
+
+    let mut latest = HashMap::new();
+    latest.insert("we\"ird\\name".to_string(), latest_usage());
+    let text = metrics::render(&latest);
+    assert!(text.contains("cephylas_container_cpu_percent{container=\"we\\\"ird\\\\name\"} 12.5\n"));
+}
+
+#[test]
+fn render_collection_stats() {
+
+    let mut collection = cephylas::log_cache::CollectionStats::default();
+    collection.record(std::time::Duration::from_millis(250), 3, 1, 0);
+    collection.record(std::time::Duration::from_millis(500), 2, 2, 10_000);
+    let text = metrics::render_collection(&collection);
+
+    assert!(text.contains("# TYPE cephylas_collection_duration_seconds gauge\n"));
+    assert!(text.contains("cephylas_collection_duration_seconds 0.5\n"));
+    assert!(text.contains("cephylas_collection_containers 2\n"));
+    assert!(text.contains("cephylas_collection_failures 2\n"));
+    assert!(text.contains("# TYPE cephylas_collection_failures_total counter\n"));
+    assert!(text.contains("cephylas_collection_failures_total 3\n"));
+}
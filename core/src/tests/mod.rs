@@ -0,0 +1,52 @@
+mod broadcast;
+mod cgroup;
+mod config;
+mod downsample;
+mod events;
+mod host;
+mod http;
+mod log;
+mod log_rotation;
+mod metrics;
+mod server;
+mod store;
+mod supervisor;
+mod time;
+
+/// 日次ログの1行 (コンテナ name の使用状況 usage のみを記録した tick)
+pub fn daily_log_line(time: &str, name: &str, usage: &cephylas::log::Usage) -> String {
+    format!(
+        "{{\"time\":\"{}\",\"millis\":10000,\"stats\":{{\"{}\":{}}}}}\r\n",
+        time, name, usage,
+    )
+}
+
+/// テスト用の一時ディレクトリ (drop すると中身ごと削除します)
+pub struct TempDir {
+    pub path: std::path::PathBuf,
+}
+impl TempDir {
+    /// cephylas-<name>-<pid> を空の状態で作ります
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir()
+            .join(format!("cephylas-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    /// このディレクトリにログを書く設定
+    /// (ローテーションを確かめやすいよう、日次ログは 2 世代のみ保持します)
+    pub fn config(&self) -> cephylas::config::Config {
+        cephylas::config::Config {
+            log_dir: self.path.clone(),
+            daily_generations: 2,
+            ..Default::default()
+        }
+    }
+}
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
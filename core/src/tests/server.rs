@@ -0,0 +1,688 @@
+
+use std::collections::{ BTreeMap, HashMap };
+use std::io::{ BufRead, Read, Write };
+use std::time::{ Duration, Instant };
+
+use cephylas::config::Config;
+use cephylas::broadcast;
+use cephylas::log::{ IoUsage, Usage };
+use cephylas::log_cache;
+use cephylas::server;
+use cephylas::supervisor;
+
+use super::{ TempDir, daily_log_line, };
+
+struct TestServer {
+    addr: std::net::SocketAddr,
+    cache: log_cache::SharedUsageCache,
+    broadcaster: broadcast::SharedUsageBroadcaster,
+    shutdown: supervisor::SharedShutdown,
+    handle: std::thread::JoinHandle<Result<(), cephylas::error::Error>>,
+}
+
+/// 空いているポートでサーバを起動します
+fn start_test_server(workers: usize) -> TestServer {
+    start_test_server_with(Config {
+        workers,
+        ..Default::default()
+    })
+}
+
+/// config で空いているポートでサーバを起動します
+fn start_test_server_with(config: Config) -> TestServer {
+    let config = Config {
+        queue_length: 64,
+        connection_timeout: Duration::from_millis(500),
+        max_streams: 2,
+        ..config
+    };
+    let cache = log_cache::create_shared_cache(config.max_log_length);
+    let broadcaster = broadcast::create_shared_broadcaster(config.max_streams);
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")
+        .expect("listener should be bound");
+    let addr = listener.local_addr().expect("listener should have an address");
+    let server_cache = std::sync::Arc::clone(&cache);
+    let server_broadcaster = std::sync::Arc::clone(&broadcaster);
+    let shutdown = supervisor::create_shared_shutdown();
+    let server_shutdown = std::sync::Arc::clone(&shutdown);
+    let handle = std::thread::spawn(
+        move || server::serve(listener, &config, &server_cache, &server_broadcaster, &server_shutdown)
+    );
+    TestServer { addr, cache, broadcaster, shutdown, handle }
+}
+
+/// lines を日次ログに書いたディレクトリでサーバを起動します
+/// (キャッシュは空なので、?from=0 を付けた要求はログファイルから読みます)
+fn start_log_server(dir: &TempDir, lines: &[String]) -> TestServer {
+    std::fs::write(dir.path.join("log_daily"), lines.concat()).unwrap();
+    start_test_server_with(Config {
+        workers: 2,
+        log_dir: dir.path.clone(),
+        ..Default::default()
+    })
+}
+
+/// 保持期間に含まれる直近の時刻
+fn recent_time() -> String {
+    cephylas::time::format_epoch_seconds(cephylas::time::now_epoch_millis() / 1000 - 60)
+}
+
+/// path の応答の配列から、各要素の field の値を取り出します
+fn field_values(addr: std::net::SocketAddr, path: &str, field: &str) -> Vec<f64> {
+    let response = get(addr, path);
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let body = json::parse(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+    body.members().map(|v| v[field].as_f64().unwrap()).collect()
+}
+
+fn get(addr: std::net::SocketAddr, path: &str) -> String {
+    let mut stream = std::net::TcpStream::connect(addr)
+        .expect("server should accept connection");
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).expect("response should be read");
+    response
+}
+
+#[test]
+fn slow_clients_do_not_block_others() {
+
+    let workers = 4;
+    let addr = start_test_server(workers).addr;
+
+    // 何も送らないクライアントでスレッドを全て埋めます
+    let slow_clients = (0..workers * 3)
+        .map(|_| std::net::TcpStream::connect(addr).expect("slow client should connect"))
+        .collect::<Vec<_>>();
+    // 途中までしか送らないクライアント
+    let mut partial = std::net::TcpStream::connect(addr).unwrap();
+    partial.write_all(b"GET /contai").unwrap();
+
+    let start = Instant::now();
+    let response = get(addr, "/containers");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    // タイムアウト (500ms) で遅いクライアントが切断されるので、
+    // 全員分を順番に待つよりも十分早く応答されます
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    drop(slow_clients);
+    drop(partial);
+}
+
+#[test]
+fn server_survives_broken_connections() {
+
+    let addr = start_test_server(2).addr;
+
+    for _ in 0..8 {
+        // 接続してすぐ切断する / 不正なリクエストを送る
+        drop(std::net::TcpStream::connect(addr).unwrap());
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.write_all(b"garbage\r\n\r\n").unwrap();
+    }
+
+    let response = get(addr, "/containers");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+}
+
+#[test]
+fn head_and_options_requests() {
+
+    let addr = start_test_server(2).addr;
+
+    let response = get(addr, "/containers");
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.write_all(b"HEAD /containers HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut head = String::new();
+    stream.read_to_string(&mut head).unwrap();
+    let (header, _) = response.split_once("\r\n\r\n").unwrap();
+    assert_eq!(head, format!("{}\r\n\r\n", header));
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.write_all(b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut options = String::new();
+    stream.read_to_string(&mut options).unwrap();
+    assert!(options.starts_with("HTTP/1.1 204"), "{}", options);
+    assert!(options.contains("Allow: GET, HEAD, OPTIONS\r\n"));
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.write_all(b"DELETE /containers HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut rejected = String::new();
+    stream.read_to_string(&mut rejected).unwrap();
+    assert!(rejected.starts_with("HTTP/1.1 405"), "{}", rejected);
+    assert!(rejected.contains("Allow: GET, HEAD, OPTIONS\r\n"));
+}
+
+/// 空行までの1イベント分を読み取ります
+fn read_event(reader: &mut impl BufRead) -> String {
+    let mut event = String::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).expect("event should be read");
+        if line == "\n" || line == "\r\n" || line.is_empty() {
+            return event;
+        }
+        event += &line;
+    }
+}
+
+#[test]
+fn stream_new_samples() {
+
+    let server = start_test_server(2);
+    server.cache.write().unwrap().cpu.insert(
+        "app".to_string(),
+        log_cache::TimedCpuUsage { time: 0, percentage: Some(1.0), ..Default::default() },
+    );
+
+    let connect = |path: &str| {
+        let mut stream = std::net::TcpStream::connect(server.addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut reader = std::io::BufReader::new(stream);
+        let header = read_event(&mut reader);
+        (header, reader)
+    };
+    let (header, mut all) = connect("/stream");
+    assert!(header.starts_with("HTTP/1.1 200"), "{}", header);
+    assert!(header.contains("Content-Type: text/event-stream\r\n"));
+    let (header, mut app) = connect("/containers/app/stream");
+    assert!(header.starts_with("HTTP/1.1 200"), "{}", header);
+
+    // 購読者数の上限 (max_streams = 2) を超えると503
+    let (header, _) = connect("/stream");
+    assert!(header.starts_with("HTTP/1.1 503"), "{}", header);
+    // 記録されていないコンテナは404
+    assert!(get(server.addr, "/containers/none/stream").starts_with("HTTP/1.1 404"));
+
+    let mut usages = HashMap::new();
+    let mut usage = Usage::default();
+    usage.cpu.percentage = Some(12.5);
+    usages.insert("app".to_string(), usage);
+    usages.insert("db".to_string(), Usage::default());
+    let delivered = server.broadcaster.publish(std::sync::Arc::new(log_cache::TickUsages {
+        time: 1_729_254_896_000,
+        usages,
+    }));
+    assert_eq!(delivered, 2);
+
+    let event = read_event(&mut all);
+    assert!(event.starts_with("id: 1729254896000\nevent: usage\ndata: "), "{}", event);
+    assert!(event.contains("\"time\":\"2024-10-18T12:34:56Z\""));
+    assert!(event.contains("\"app\":{\"cpu\":{\"percentage\":12.5"));
+    assert!(event.contains("\"db\":"));
+
+    let event = read_event(&mut app);
+    assert!(event.contains("data: {\"time\":\"2024-10-18T12:34:56Z\",\"usage\":{\"cpu\":{\"percentage\":12.5"), "{}", event);
+    assert!(!event.contains("db"));
+
+    // 切断した購読者は次の配信で取り除かれます
+    drop(all);
+    drop(app);
+    let start = std::time::Instant::now();
+    while server.broadcaster.subscriber_count() > 0 {
+        server.broadcaster.publish(std::sync::Arc::new(log_cache::TickUsages {
+            time: 0,
+            usages: HashMap::from([("app".to_string(), Usage::default())]),
+        }));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn serve_container_events() {
+
+    let server = start_test_server(2);
+    {
+        let mut lock = server.cache.write().unwrap();
+        for (time, container, action) in [(1000, "web", "start"), (2000, "db", "start"), (3000, "web", "die")] {
+            lock.events.push(cephylas::events::ContainerEvent {
+                time,
+                container: container.to_string(),
+                id: String::new(),
+                action: action.to_string(),
+                exit_code: if action == "die" { Some(137) } else { None },
+                health: None,
+            });
+        }
+    }
+
+    let body = |response: String| json::parse(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+    let events = body(get(server.addr, "/events"));
+    assert_eq!(events.len(), 3);
+    let web = body(get(server.addr, "/containers/web/events?from=1.5"));
+    assert_eq!(web.len(), 1);
+    assert_eq!(web[0]["action"], "die");
+    assert_eq!(web[0]["exitCode"], 137);
+    // 終了したコンテナでも 404 にはなりません
+    let gone = get(server.addr, "/containers/gone/events");
+    assert!(gone.starts_with("HTTP/1.1 200"), "{}", gone);
+    assert_eq!(body(gone).len(), 0);
+}
+
+#[test]
+fn report_collector_state() {
+
+    let server = start_test_server(2);
+    server.cache.write().unwrap()
+        .collection
+        .record_error("connection refused".to_string(), 1000);
+
+    let response = get(server.addr, "/collector");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let collector = json::parse(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+    assert_eq!(collector["state"], "disconnected");
+    assert_eq!(collector["consecutiveErrors"], 1);
+    assert_eq!(collector["lastError"], "connection refused");
+    assert!(collector["lastSuccess"].is_null());
+
+    let metrics = get(server.addr, "/metrics");
+    assert!(metrics.contains("cephylas_collector_state{state=\"disconnected\"} 1\n"), "{}", metrics);
+    assert!(metrics.contains("cephylas_collector_state{state=\"running\"} 0\n"), "{}", metrics);
+
+    server.cache.write().unwrap().startup.files.push(cephylas::log_cache::LogFileReport {
+        path: "log/log_daily".to_string(),
+        records: 10,
+        skipped: 1,
+        truncated_bytes: 40,
+        error: None,
+    });
+    let response = get(server.addr, "/startup");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let startup = json::parse(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+    assert_eq!(startup["skipped"], 1);
+    assert_eq!(startup["truncatedBytes"], 40);
+    assert_eq!(startup["files"][0]["path"], "log/log_daily");
+}
+
+#[test]
+fn report_worker_health() {
+
+    let server = start_test_server(2);
+    {
+        let mut lock = server.cache.write().unwrap();
+        lock.collection.last_tick = Some(cephylas::time::now_epoch_millis());
+        for name in [supervisor::LOGGER_WORKER, supervisor::SERVER_WORKER] {
+            lock.workers.insert(name.to_string(), supervisor::WorkerHealth {
+                alive: true,
+                started: cephylas::time::now_epoch_millis(),
+                ..Default::default()
+            });
+        }
+    }
+    let body = |response: &str| json::parse(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+
+    let response = get(server.addr, "/health");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let health = body(&response);
+    assert_eq!(health["status"], "ok");
+    assert_eq!(health["collector"], "running");
+    assert_eq!(health["workers"]["logger"]["alive"], true);
+    assert!(health["lastTick"].is_string());
+
+    // 停止したワーカーがあれば 503 です
+    server.cache.write().unwrap()
+        .workers.get_mut(supervisor::LOGGER_WORKER).unwrap()
+        .alive = false;
+    let response = get(server.addr, "/health");
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+    assert_eq!(body(&response)["status"], "unhealthy");
+
+    // tick が止まっている場合も 503 です
+    {
+        let mut lock = server.cache.write().unwrap();
+        lock.workers.get_mut(supervisor::LOGGER_WORKER).unwrap().alive = true;
+        lock.workers.get_mut(supervisor::LOGGER_WORKER).unwrap().started = 0;
+        lock.collection.last_tick = Some(0);
+    }
+    let response = get(server.addr, "/health");
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+}
+
+#[test]
+fn server_stops_on_shutdown() {
+
+    let server = start_test_server(2);
+    assert!(get(server.addr, "/containers").starts_with("HTTP/1.1 200"));
+
+    let started = Instant::now();
+    server.shutdown.request();
+    server.handle.join().unwrap().expect("server should stop gracefully");
+    assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+    // 待ち受けは閉じられています
+    assert!(std::net::TcpStream::connect(server.addr).is_err());
+}
+
+#[test]
+fn errors_are_returned_as_json() {
+
+    let server = start_test_server(2);
+    let error_of = |response: &str| {
+        let (header, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(header.contains("Content-Type: application/json"), "{}", header);
+        let body = json::parse(body).unwrap();
+        (header.split_whitespace().nth(1).unwrap().to_string(), body["error"]["code"].to_string())
+    };
+
+    assert_eq!(
+        error_of(&get(server.addr, "/containers?include=size")),
+        ("400".to_string(), "bad_query".to_string()),
+    );
+    assert_eq!(
+        error_of(&get(server.addr, "/containers/web/cpu?algo=best")),
+        ("400".to_string(), "bad_query".to_string()),
+    );
+    assert_eq!(
+        error_of(&get(server.addr, "/containers/none/cpu")),
+        ("404".to_string(), "unknown_container".to_string()),
+    );
+    assert_eq!(
+        error_of(&get(server.addr, "/nowhere")),
+        ("404".to_string(), "not_found".to_string()),
+    );
+
+    let mut stream = std::net::TcpStream::connect(server.addr).unwrap();
+    write!(stream, "DELETE /containers HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.contains("Allow: GET, HEAD, OPTIONS\r\n"), "{}", response);
+    assert_eq!(error_of(&response), ("405".to_string(), "method_not_allowed".to_string()));
+
+    // ロックを持ったスレッドが panic すると 500 になります
+    let cache = std::sync::Arc::clone(&server.cache);
+    let _ = std::thread::spawn(move || {
+        let _lock = cache.write().unwrap();
+        panic!("poison the cache");
+    }).join();
+    let response = get(server.addr, "/containers");
+    assert_eq!(error_of(&response), ("500".to_string(), "lock_poisoned".to_string()));
+    assert!(response.contains("\"message\":\"lock poisoned"), "{}", response);
+}
+
+#[test]
+fn distinguish_recreated_containers_by_id() {
+
+    let server = start_test_server(2);
+    {
+        let mut lock = server.cache.write().unwrap();
+        for i in 0..6 {
+            let time = 1_000_000 + i * 10_000;
+            // 同じ名前で3回目の tick からコンテナが作り直されています
+            let id = if i < 3 { "aaaa1111" } else { "bbbb2222" };
+            lock.cpu.insert("web".to_string(), log_cache::TimedCpuUsage {
+                time,
+                percentage: Some(i as f32),
+                ..Default::default()
+            });
+            let info = cephylas::log::ContainerInfo {
+                id: id.to_string(),
+                image: "nginx".to_string(),
+                created: Some(1_000),
+                ..Default::default()
+            };
+            lock.record_container("web", &info, time);
+        }
+    }
+
+    let body = |response: String| json::parse(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+    let percentages = |path: &str| body(get(server.addr, path)).members()
+        .map(|c| c["percentage"].as_f64().unwrap())
+        .collect::<Vec<f64>>();
+    assert_eq!(percentages("/containers/web/cpu"), vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+    assert_eq!(percentages("/containers/web/cpu?id=aaaa"), vec![0.0, 1.0, 2.0]);
+    assert_eq!(percentages("/containers/web/cpu?id=bbbb2222"), vec![3.0, 4.0, 5.0]);
+    let unknown = get(server.addr, "/containers/web/cpu?id=cccc");
+    assert!(unknown.starts_with("HTTP/1.1 404"), "{}", unknown);
+
+    let containers = body(get(server.addr, "/containers?include=id,image,created,history"));
+    assert_eq!(containers[0]["name"], "web");
+    assert_eq!(containers[0]["id"], "bbbb2222");
+    assert_eq!(containers[0]["image"], "nginx");
+    assert_eq!(containers[0]["created"], "1970-01-01T00:16:40Z");
+    assert_eq!(containers[0]["history"].len(), 2);
+    assert_eq!(containers[0]["history"][0]["id"], "aaaa1111");
+    assert_eq!(body(get(server.addr, "/containers"))[0], "web");
+    let rejected = get(server.addr, "/containers?include=size");
+    assert!(rejected.starts_with("HTTP/1.1 400"), "{}", rejected);
+}
+
+#[test]
+fn aggregate_usage_by_label() {
+
+    let server = start_test_server(2);
+    {
+        let mut lock = server.cache.write().unwrap();
+        let members = [("shop-web", "shop", 10.0), ("shop-db", "shop", 30.0), ("blog-web", "blog", 50.0)];
+        for i in 0..4 {
+            let time = 1_000_000 + i * 10_000;
+            for (name, project, percentage) in members {
+                // shop-db は2回目の tick から記録されています
+                if name == "shop-db" && i == 0 {
+                    continue;
+                }
+                lock.cpu.insert(name.to_string(), log_cache::TimedCpuUsage {
+                    time,
+                    percentage: Some(percentage + i as f32),
+                    ..Default::default()
+                });
+                lock.memory.insert(name.to_string(), log_cache::TimedMemoryUsage {
+                    time,
+                    percentage: Some(percentage + i as f32),
+                    used: Some(100),
+                    breakdown: Default::default(),
+                });
+                let info = cephylas::log::ContainerInfo {
+                    id: name.to_string(),
+                    labels: BTreeMap::from([(
+                        "com.docker.compose.project".to_string(),
+                        project.to_string(),
+                    )]),
+                    ..Default::default()
+                };
+                lock.record_container(name, &info, time);
+            }
+        }
+    }
+
+    let body = |response: String| json::parse(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+    let percentages = |path: &str| body(get(server.addr, path)).members()
+        .map(|c| c["percentage"].as_f64().unwrap())
+        .collect::<Vec<f64>>();
+    let group = "/groups/com.docker.compose.project/shop/cpu";
+    assert_eq!(percentages(group), vec![10.0, 42.0, 44.0, 46.0]);
+    assert_eq!(percentages(&format!("{}?agg=avg", group)), vec![10.0, 21.0, 22.0, 23.0]);
+    assert_eq!(percentages("/groups/com.docker.compose.project/blog/cpu"), vec![50.0, 51.0, 52.0, 53.0]);
+    // メモリの使用率はコンテナ毎の上限に対する割合なので、合計でも平均します
+    let memory = body(get(server.addr, "/groups/com.docker.compose.project/shop/memory"));
+    assert_eq!(
+        memory.members().map(|m| m["percentage"].as_f64().unwrap()).collect::<Vec<f64>>(),
+        vec![10.0, 21.0, 22.0, 23.0],
+    );
+
+    let groups = body(get(server.addr, "/groups/com.docker.compose.project"));
+    assert_eq!(groups["shop"][0], "shop-db");
+    assert_eq!(groups["shop"][1], "shop-web");
+    assert_eq!(groups["blog"].len(), 1);
+    let containers = body(get(server.addr, "/containers?include=labels"));
+    assert_eq!(containers[0]["labels"]["com.docker.compose.project"], "blog");
+
+    let unknown = get(server.addr, "/groups/com.docker.compose.project/none/cpu");
+    assert!(unknown.starts_with("HTTP/1.1 404"), "{}", unknown);
+    let rejected = get(server.addr, &format!("{}?agg=max", group));
+    assert!(rejected.starts_with("HTTP/1.1 400"), "{}", rejected);
+}
+
+#[test]
+fn old_ranges_are_read_from_log_files_once() {
+
+    let dir = TempDir::new("server-range");
+    let log_dir = dir.path.clone();
+    let line = |time: std::time::SystemTime, cpu: f32| format!(
+        "{{\"time\":\"{}\",\"millis\":10000,\"stats\":{{\"web\":{}}}}}\r\n",
+        cephylas::time::format_time(&time),
+        Usage {
+            cpu: cephylas::log::CpuUsage { percentage: Some(cpu), ..Default::default() },
+            ..Default::default()
+        },
+    );
+    let two_days_ago = std::time::SystemTime::now() - Duration::from_secs(2 * 86_400);
+    std::fs::write(log_dir.join("log_daily.2"), [
+        line(two_days_ago, 1.0),
+        line(two_days_ago + Duration::from_secs(10), 2.0),
+    ].concat()).unwrap();
+    // 保持期間より古い記録
+    std::fs::write(log_dir.join("log_monthly.1"), line(std::time::UNIX_EPOCH, 9.0)).unwrap();
+
+    let server = start_test_server_with(Config {
+        workers: 2,
+        log_dir: log_dir.clone(),
+        tick: Duration::from_secs(10),
+        ..Default::default()
+    });
+    let body = |response: String| json::parse(response.split_once("\r\n\r\n").unwrap().1).unwrap();
+    let percentages = |path: &str| body(get(server.addr, path)).members()
+        .map(|c| c["percentage"].as_f64().unwrap())
+        .collect::<Vec<f64>>();
+
+    // from=0 でも保持期間のみを読みます
+    assert_eq!(percentages("/containers/web/cpu?from=0&algo=raw"), vec![1.0, 2.0]);
+
+    // 同じ tick に含まれる from (?last= のように要求毎に変わる from) は、
+    // 次の tick までファイルを読み直しません
+    let hour_before = two_days_ago.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() / 10 * 10 - 3600;
+    let path = |millis: u32| format!("/containers/web/cpu?from={}.{:03}&algo=raw", hour_before, millis);
+    assert_eq!(percentages(&path(1)), vec![1.0, 2.0]);
+    std::fs::remove_dir_all(&log_dir).unwrap();
+    assert_eq!(percentages(&path(2)), vec![1.0, 2.0]);
+    assert_eq!(percentages(&path(900)), vec![1.0, 2.0]);
+    server.cache.write().unwrap().collection.last_tick = Some(1);
+    let response = get(server.addr, &path(2));
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+}
+
+#[test]
+fn serve_network_interfaces() {
+
+    let dir = TempDir::new("server-net");
+    let interface = |recv, send| cephylas::log::NetUsage {
+        recvkBps: Some(recv),
+        sendkBps: Some(send),
+        ..Default::default()
+    };
+    let usage = Usage {
+        net: cephylas::log::NetUsage {
+            interfaces: BTreeMap::from([
+                ("eth0".to_string(), interface(1, 3)),
+                ("eth1".to_string(), interface(2, 1)),
+            ]),
+            ..interface(3, 4)
+        },
+        ..Default::default()
+    };
+    let server = start_log_server(&dir, &[daily_log_line(&recent_time(), "web", &usage)]);
+
+    // インターフェースを省略すると全インターフェースの合計を返します
+    assert_eq!(field_values(server.addr, "/containers/web/net/recv?from=0", "recvkBps"), vec![3.0]);
+    assert_eq!(field_values(server.addr, "/containers/web/net/send?from=0", "sendkBps"), vec![4.0]);
+    assert_eq!(field_values(server.addr, "/containers/web/net/eth0/recv?from=0", "recvkBps"), vec![1.0]);
+    assert_eq!(field_values(server.addr, "/containers/web/net/eth1/send?from=0", "sendkBps"), vec![1.0]);
+    let unknown = get(server.addr, "/containers/web/net/eth2/recv?from=0");
+    assert!(unknown.starts_with("HTTP/1.1 404"), "{}", unknown);
+}
+
+#[test]
+fn serve_memory_fields() {
+
+    let dir = TempDir::new("server-memory");
+    let usage = Usage {
+        memory: cephylas::log::MemoryUsage {
+            percentage: Some(10.0),
+            used: Some(100),
+            available: Some(1000),
+            breakdown: cephylas::log::MemoryBreakdown {
+                rss: Some(60),
+                cache: Some(40),
+                ..Default::default()
+            },
+        },
+        ..Default::default()
+    };
+    let server = start_log_server(&dir, &[daily_log_line(&recent_time(), "web", &usage)]);
+
+    assert_eq!(field_values(server.addr, "/containers/web/memory/used?from=0", "used"), vec![100.0]);
+    assert_eq!(field_values(server.addr, "/containers/web/memory/rss?from=0", "rss"), vec![60.0]);
+    // 内訳に無いフィールドは要求の誤りです
+    let rejected = get(server.addr, "/containers/web/memory/percentage?from=0");
+    assert!(rejected.starts_with("HTTP/1.1 400"), "{}", rejected);
+    assert!(rejected.contains("\"code\":\"bad_query\""), "{}", rejected);
+}
+
+#[test]
+fn serve_cpu_fields() {
+
+    let dir = TempDir::new("server-cpu");
+    let usage = Usage {
+        cpu: cephylas::log::CpuUsage {
+            percentage: Some(150.0),
+            throttled_time: Some(40),
+            throttle_ratio: Some(25.0),
+            percpu: vec![100.0, 50.0],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let server = start_log_server(&dir, &[daily_log_line(&recent_time(), "web", &usage)]);
+
+    assert_eq!(
+        field_values(server.addr, "/containers/web/cpu/throttleRatio?from=0", "throttleRatio"),
+        vec![25.0],
+    );
+    assert_eq!(
+        field_values(server.addr, "/containers/web/cpu/throttledTime?from=0", "throttledTime"),
+        vec![40.0],
+    );
+    let percpu = get(server.addr, "/containers/web/cpu/percpu?from=0");
+    let percpu = json::parse(percpu.split_once("\r\n\r\n").unwrap().1).unwrap();
+    assert_eq!(percpu[0]["percpu"][0].as_f64(), Some(100.0));
+    assert_eq!(percpu[0]["percpu"][1].as_f64(), Some(50.0));
+    // 記録していないフィールドは要求の誤りです
+    let rejected = get(server.addr, "/containers/web/cpu/percentage?from=0");
+    assert!(rejected.starts_with("HTTP/1.1 400"), "{}", rejected);
+    assert!(rejected.contains("\"code\":\"bad_query\""), "{}", rejected);
+}
+
+#[test]
+fn serve_block_devices() {
+
+    let dir = TempDir::new("server-io");
+    let device = |read, write| IoUsage {
+        readkBps: Some(read),
+        writekBps: Some(write),
+        readIops: Some(read * 10),
+        writeIops: Some(write * 10),
+        ..Default::default()
+    };
+    let usage = Usage {
+        io: IoUsage {
+            devices: BTreeMap::from([
+                ("8:0".to_string(), device(1, 4)),
+                ("8:16".to_string(), device(2, 0)),
+            ]),
+            ..device(3, 4)
+        },
+        ..Default::default()
+    };
+    let server = start_log_server(&dir, &[daily_log_line(&recent_time(), "web", &usage)]);
+
+    // デバイスを省略すると全デバイスの合計を返します
+    assert_eq!(field_values(server.addr, "/containers/web/io/read?from=0", "readkBps"), vec![3.0]);
+    assert_eq!(field_values(server.addr, "/containers/web/io/8:0/read?from=0", "readkBps"), vec![1.0]);
+    assert_eq!(field_values(server.addr, "/containers/web/io/8:0/write?from=0", "writekBps"), vec![4.0]);
+    assert_eq!(field_values(server.addr, "/containers/web/io/8:16/readIops?from=0", "readIops"), vec![20.0]);
+    // ":" はパーセントエンコードされていても同じデバイスです
+    assert_eq!(field_values(server.addr, "/containers/web/io/8%3A16/writeIops?from=0", "writeIops"), vec![0.0]);
+    let unknown = get(server.addr, "/containers/web/io/8:32/read?from=0");
+    assert!(unknown.starts_with("HTTP/1.1 404"), "{}", unknown);
+}
@@ -0,0 +1,260 @@
+
+use std::collections::{ BTreeMap, HashMap, };
+
+use cephylas::host;
+use cephylas::log::{ ContainerInfo, CpuUsage, IoUsage, NetUsage, Usage, };
+use cephylas::store;
+
+use super::TempDir;
+
+fn usages(cpu: f32) -> HashMap<String, Usage> {
+    let web = Usage {
+        info: Some(ContainerInfo {
+            id: "abc123".to_string(),
+            image: "nginx:latest".to_string(),
+            created: Some(1_700_000_000),
+            labels: BTreeMap::from([("com.docker.compose.service".to_string(), "web".to_string())]),
+        }),
+        cpu: CpuUsage {
+            percentage: Some(cpu),
+            total: Some(1_000),
+            ncpu: Some(64),
+            throttle_ratio: None,
+            // 1 レコードに収まらない数の CPU
+            percpu: (0..64).map(|i| i as f32 / 4.0).collect(),
+            ..Default::default()
+        },
+        io: IoUsage {
+            readkBps: Some(12),
+            devices: BTreeMap::from([("8:0".to_string(), IoUsage {
+                readkBps: Some(12),
+                ..Default::default()
+            })]),
+            ..Default::default()
+        },
+        net: NetUsage {
+            recvkB: Some(u64::MAX - 1),
+            interfaces: BTreeMap::from([("eth0".to_string(), NetUsage {
+                recvkB: Some(3),
+                ..Default::default()
+            })]),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let host = Usage {
+        host: Some(host::HostUsage {
+            load: host::LoadAverage { one: cpu, five: 0.5, fifteen: 0.25 },
+            filesystems: BTreeMap::from([("/".to_string(), host::FilesystemStats {
+                total: 1000,
+                free: 400,
+                available: 300,
+            })]),
+        }),
+        ..Default::default()
+    };
+    HashMap::from([
+        ("web".to_string(), web),
+        (host::HOST_NAME.to_string(), host),
+        ("old".to_string(), Usage::default()),
+    ])
+}
+
+#[test]
+fn segment_round_trip_and_seek() {
+
+    let dir = TempDir::new("store-round-trip");
+    let config = dir.config();
+    let segment = config.daily_segment_path();
+    let mut writer = store::SegmentWriter::open(&segment).unwrap();
+    for (i, cpu) in [1.5, 2.5].into_iter().enumerate() {
+        writer.append(1_000 + i as i64 * 10_000, 10_000, &usages(cpu)).unwrap();
+    }
+    drop(writer);
+    let strings_len = std::fs::metadata(segment.join(store::STRINGS_FILE)).unwrap().len();
+
+    // 開き直しても続きに追記し、既知の文字列は文字列表に追加しません
+    store::SegmentWriter::open(&segment).unwrap().append(21_000, 10_000, &usages(3.5)).unwrap();
+    assert!(store::is_segment(&segment));
+    assert_eq!(
+        std::fs::metadata(segment.join(store::STRINGS_FILE)).unwrap().len(),
+        strings_len,
+    );
+
+    let ticks = store::read_segment(&segment, i64::MIN, None).unwrap().ticks;
+    assert_eq!(ticks.iter().map(|t| t.time).collect::<Vec<i64>>(), vec![1_000, 11_000, 21_000]);
+    assert_eq!(ticks[0].millis, 10_000);
+    for (tick, cpu) in ticks.iter().zip([1.5, 2.5, 3.5]) {
+        let expected = usages(cpu);
+        assert_eq!(tick.usages.len(), expected.len());
+        for (name, usage) in &expected {
+            assert_eq!(tick.usages[name].to_string(), usage.to_string());
+        }
+    }
+    assert_eq!(ticks[1].usages["web"].cpu.percpu.len(), 64);
+    assert!(ticks[1].usages["old"].info.is_none());
+
+    // index から範囲の tick のみを読みます
+    let ticks = store::read_segment(&segment, 5_000, Some(21_000)).unwrap().ticks;
+    assert_eq!(ticks.iter().map(|t| t.time).collect::<Vec<i64>>(), vec![11_000]);
+    assert!(store::read_segment(&segment, 30_000, None).unwrap().ticks.is_empty());
+
+    // 書きかけの tick は読まず、次に開いた際に切り詰めます
+    let mut records = std::fs::OpenOptions::new()
+        .append(true)
+        .open(segment.join(store::RECORDS_FILE))
+        .unwrap();
+    std::io::Write::write_all(&mut records, &[0xff; store::RECORD_SIZE / 2]).unwrap();
+    let mut index = std::fs::OpenOptions::new()
+        .append(true)
+        .open(segment.join(store::INDEX_FILE))
+        .unwrap();
+    std::io::Write::write_all(&mut index, &[0xff; 5]).unwrap();
+    assert_eq!(store::read_segment(&segment, i64::MIN, None).unwrap().ticks.len(), 3);
+    store::SegmentWriter::open(&segment).unwrap().append(31_000, 10_000, &usages(4.5)).unwrap();
+    let ticks = store::read_segment(&segment, 31_000, None).unwrap().ticks;
+    assert_eq!(ticks[0].usages["web"].cpu.percentage, Some(4.5));
+
+    std::fs::create_dir_all(config.log_dir.join("broken")).unwrap();
+    std::fs::write(config.log_dir.join("broken").join(store::RECORDS_FILE), b"NOPE").unwrap();
+    let error = store::read_segment(config.log_dir.join("broken"), i64::MIN, None).unwrap_err();
+    assert_eq!(error.code(), "parse_error");
+}
+
+#[test]
+fn recover_torn_segment_tail() {
+
+    let dir = TempDir::new("store-recover");
+    let config = dir.config();
+    let segment = config.daily_segment_path();
+    let mut writer = store::SegmentWriter::open(&segment).unwrap();
+    for i in 0..4 {
+        writer.append(i * 10_000, 10_000, &usages(i as f32)).unwrap();
+    }
+    drop(writer);
+    let records_path = segment.join(store::RECORDS_FILE);
+    let records = std::fs::read(&records_path).unwrap();
+    let records_per_tick = (records.len() - 32) / store::RECORD_SIZE / 4;
+
+    // 2 tick 目の値を壊すと、その tick のみ読み飛ばします
+    let mut broken = records.clone();
+    let second = 32 + records_per_tick * store::RECORD_SIZE + 100;
+    broken[second] ^= 0xff;
+    // 最後の tick のレコードは fsync されずに消えた (ゼロ埋めされた) ことにします
+    let last = 32 + records_per_tick * 3 * store::RECORD_SIZE;
+    broken[last..].fill(0);
+    std::fs::write(&records_path, &broken).unwrap();
+    // 書きかけの文字列と index の項目
+    let mut strings = std::fs::OpenOptions::new()
+        .append(true)
+        .open(segment.join(store::STRINGS_FILE))
+        .unwrap();
+    std::io::Write::write_all(&mut strings, &[10, 0, 0, 0, 1, 2]).unwrap();
+    let mut index = std::fs::OpenOptions::new()
+        .append(true)
+        .open(segment.join(store::INDEX_FILE))
+        .unwrap();
+    std::io::Write::write_all(&mut index, &[0; 7]).unwrap();
+
+    let read = store::read_segment(&segment, i64::MIN, None).unwrap();
+    assert_eq!(read.ticks.iter().map(|t| t.time).collect::<Vec<i64>>(), vec![0, 20_000]);
+    assert_eq!(read.skipped, 2);
+
+    // 末尾の壊れた tick と書きかけの部分のみ切り詰めます
+    let recovery = store::recover(&segment).unwrap();
+    assert_eq!(recovery.skipped, 1);
+    assert_eq!(
+        recovery.truncated_bytes,
+        (records_per_tick * store::RECORD_SIZE + 6 + 7 + 16) as u64,
+    );
+    assert_eq!(store::recover(&segment).unwrap(), store::Recovery::default());
+
+    store::SegmentWriter::open(&segment).unwrap().append(40_000, 10_000, &usages(4.0)).unwrap();
+    let read = store::read_segment(&segment, i64::MIN, None).unwrap();
+    assert_eq!(read.ticks.iter().map(|t| t.time).collect::<Vec<i64>>(), vec![0, 20_000, 40_000]);
+    assert_eq!(read.ticks[2].usages["web"].cpu.percentage, Some(4.0));
+    assert_eq!(read.skipped, 1);
+}
+
+#[test]
+fn corrupted_string_table_is_not_truncated() {
+
+    let dir = TempDir::new("store-strings");
+    let config = dir.config();
+    let segment = config.daily_segment_path();
+    store::SegmentWriter::open(&segment).unwrap().append(0, 10_000, &usages(1.0)).unwrap();
+    let strings_path = segment.join(store::STRINGS_FILE);
+    let strings = std::fs::read(&strings_path).unwrap();
+
+    // 最後の文字列の checksum が合わなければ、書きかけの末尾として切り詰めます
+    let mut torn = strings.clone();
+    *torn.last_mut().unwrap() ^= 0xff;
+    std::fs::write(&strings_path, &torn).unwrap();
+    let recovery = store::recover(&segment).unwrap();
+    assert!(recovery.truncated_bytes > 0);
+    assert!(std::fs::metadata(&strings_path).unwrap().len() < strings.len() as u64);
+
+    // 途中の文字列が壊れていると、以降の番号を使い回さないよう開けません
+    let mut broken = strings.clone();
+    broken[8] ^= 0xff;
+    std::fs::write(&strings_path, &broken).unwrap();
+    assert_eq!(store::recover(&segment).unwrap_err().code(), "parse_error");
+    assert!(store::SegmentWriter::open(&segment).is_err());
+    assert_eq!(std::fs::read(&strings_path).unwrap(), broken);
+}
+
+#[test]
+fn convert_json_log_and_rotate_segments() {
+
+    let dir = TempDir::new("store-convert");
+    let config = dir.config();
+    assert_eq!(
+        store::segment_path_for(config.log_dir.join("log_daily.3")),
+        config.log_dir.join("log_daily.seg.3"),
+    );
+    assert_eq!(store::segment_path_for(config.daily_log_path()), config.daily_segment_path());
+
+    let line = |time: &str, cpu: f32| format!(
+        "{{\"time\":\"{}\",\"millis\":10000,\"stats\":{{\"web\":{}}}}}\r\n",
+        time, usages(cpu)["web"],
+    );
+    let json_log = config.log_dir.join("log_daily.1");
+    std::fs::write(&json_log, [
+        line("2024-10-12T10:00:00Z", 1.0),
+        line("2024-10-12T10:00:10Z", 2.0),
+    ].concat()).unwrap();
+
+    let segment = store::segment_path_for(&json_log);
+    assert_eq!(cephylas::log::convert_json_log(&json_log, &segment).unwrap(), 2);
+    // 変換済みのセグメントには追記しません
+    assert!(cephylas::log::convert_json_log(&json_log, &segment).is_err());
+
+    let exported = cephylas::log::export_json_lines(&segment).unwrap();
+    let original = std::fs::read_to_string(&json_log).unwrap();
+    assert_eq!(exported, original.lines().collect::<Vec<&str>>());
+
+    // json を消しても、セグメントから同じ期間を読めます
+    std::fs::remove_file(&json_log).unwrap();
+    let all = cephylas::log_cache::TimeRange::default();
+    let usage_cache = cephylas::log::read_log_range(&config, &all).unwrap();
+    let raw = cephylas::log_cache::DownsampleOption {
+        algorithm: cephylas::downsample::Algorithm::Raw,
+        ..Default::default()
+    };
+    let cpu = usage_cache.cpu
+        .downsample("web", &all, &raw, |c| (c.time as f64, c.percentage.unwrap_or_default() as f64))
+        .unwrap();
+    assert_eq!(cpu.iter().map(|c| c.percentage).collect::<Vec<Option<f32>>>(), vec![Some(1.0), Some(2.0)]);
+
+    // セグメントも世代を付け替え、log_weekly に集計します
+    let today = cephylas::time::days_from_civil(2024, 10, 15);
+    store::SegmentWriter::open(config.daily_segment_path()).unwrap().append(today * 86_400_000, 10_000, &usages(5.0)).unwrap();
+    cephylas::log_rotation::rotate(&config, today, today + 1).unwrap();
+    assert!(!config.daily_segment_path().exists());
+    assert!(store::is_segment(config.log_dir.join("log_daily.seg.1")));
+    assert!(store::is_segment(config.log_dir.join("log_daily.seg.2")));
+    let weekly = std::fs::read_to_string(config.log_dir.join("log_weekly")).unwrap();
+    let summary = json::parse(weekly.lines().last().unwrap()).unwrap();
+    assert_eq!(summary["stats"]["web"]["cpu"]["percentage"]["avg"], 5.0);
+    assert_eq!(summary["stats"]["web"]["id"], "abc123");
+}
@@ -0,0 +1,142 @@
+
+use std::sync::{ Arc, Mutex, };
+use std::sync::atomic::{ AtomicU32, Ordering };
+use std::time::{ Duration, Instant };
+
+use cephylas::log_cache;
+use cephylas::supervisor::{ self, Worker };
+
+#[cfg(unix)]
+extern "C" {
+    fn raise(signum: std::ffi::c_int) -> std::ffi::c_int;
+}
+
+/// f が true を返すまで待ちます
+fn wait_until(timeout: Duration, f: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if f() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+/// シグナルのフラグはプロセスで共有されるので、supervise を見守るテストを順に実行します
+static SUPERVISE_LOCK: Mutex<()> = Mutex::new(());
+
+#[test]
+fn restart_crashed_workers_and_stop_on_request() {
+
+    let _guard = SUPERVISE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let cache = log_cache::create_shared_cache(10);
+    let shutdown = supervisor::create_shared_shutdown();
+    let runs = Arc::new(AtomicU32::new(0));
+
+    let flaky = {
+        let (cache, shutdown, runs) = (Arc::clone(&cache), Arc::clone(&shutdown), Arc::clone(&runs));
+        Worker::new("flaky", move || {
+            // 初回はロックを持ったまま panic します
+            if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                let _lock = cache.write().unwrap();
+                panic!("first run fails");
+            }
+            shutdown.sleep(Duration::from_secs(60));
+            Ok(())
+        })
+    };
+    let steady = {
+        let shutdown = Arc::clone(&shutdown);
+        Worker::new("steady", move || {
+            shutdown.sleep(Duration::from_secs(60));
+            Ok(())
+        })
+    };
+    let supervisor_cache = Arc::clone(&cache);
+    let supervisor_shutdown = Arc::clone(&shutdown);
+    let handle = std::thread::spawn(move || supervisor::supervise(
+        vec![flaky, steady], &supervisor_cache, &supervisor_shutdown, Duration::from_secs(2),
+    ));
+
+    // 再起動したワーカーが動き始めるまで待ちます
+    let restarted = wait_until(Duration::from_secs(5), || {
+        runs.load(Ordering::SeqCst) == 2
+            && cache.read().is_ok_and(|lock| lock.workers.get("flaky")
+                .is_some_and(|health| health.restarts == 1 && health.alive))
+    });
+    assert!(restarted, "flaky worker should be restarted");
+    {
+        let lock = cache.read().expect("poisoned lock should be cleared");
+        assert_eq!(lock.workers["flaky"].last_error.as_deref(), Some("panicked: first run fails"));
+        assert_eq!(lock.workers["steady"].restarts, 0);
+    }
+
+    shutdown.request();
+    handle.join().unwrap().expect("workers should stop gracefully");
+    assert!(cache.read().unwrap().workers.values().all(|health| !health.alive));
+}
+
+#[test]
+#[cfg(unix)]
+fn stop_on_signal() {
+
+    let _guard = SUPERVISE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let cache = log_cache::create_shared_cache(10);
+    let shutdown = supervisor::create_shared_shutdown();
+    let worker = {
+        let shutdown = Arc::clone(&shutdown);
+        Worker::new("worker", move || {
+            shutdown.sleep(Duration::from_secs(60));
+            Ok(())
+        })
+    };
+
+    supervisor::install_signal_handlers().unwrap();
+    // SAFETY: SIGTERM のハンドラは登録済みなので、プロセスは終了しません
+    unsafe { raise(15) };
+    assert_eq!(supervisor::received_signal(), Some(15));
+
+    supervisor::supervise(vec![worker], &cache, &shutdown, Duration::from_secs(2))
+        .expect("workers should stop gracefully");
+    assert!(shutdown.is_requested());
+    // 終了要求に変えたシグナルは、次の supervise に残しません
+    assert_eq!(supervisor::received_signal(), None);
+}
+
+#[test]
+fn stuck_worker_fails_shutdown() {
+
+    let cache = log_cache::create_shared_cache(10);
+    let shutdown = supervisor::create_shared_shutdown();
+    let stuck = Worker::new("stuck", || {
+        std::thread::sleep(Duration::from_secs(1));
+        Ok(())
+    });
+    shutdown.request();
+
+    let e = supervisor::supervise(vec![stuck], &cache, &shutdown, Duration::from_millis(200))
+        .expect_err("stuck worker should be reported");
+    assert!(e.to_string().contains("stuck"), "{}", e);
+}
+
+#[test]
+fn shutdown_hooks_run_once() {
+
+    let shutdown = supervisor::create_shared_shutdown();
+    let calls = Arc::new(AtomicU32::new(0));
+    for _ in 0..2 {
+        let calls = Arc::clone(&calls);
+        // 同じ名前の処理は置き換えられます
+        shutdown.on_request("hook", move || { calls.fetch_add(1, Ordering::SeqCst); });
+    }
+    assert!(!shutdown.sleep(Duration::from_millis(10)));
+
+    shutdown.request();
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert!(shutdown.sleep(Duration::from_secs(60)));
+    // 要求された後に登録した処理はすぐに呼び出します
+    let late = Arc::clone(&calls);
+    shutdown.on_request("late", move || { late.fetch_add(1, Ordering::SeqCst); });
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
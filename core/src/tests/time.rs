@@ -6,7 +6,82 @@ fn convert_and_back_now() {
 
     let now = std::time::SystemTime::now();
     
-    let converted = cephylas::time::format_time(&now);
-    let converted_back = cephylas::time::parse_time(&converted);
+    let converted = time::format_time(&now);
+    let converted_back = time::parse_time(&converted)
+        .expect("formatted time should be parsed");
     assert!(now == converted_back);
 }
+
+#[test]
+fn parse_docker_time_and_offsets() {
+
+    let millis = time::parse_epoch_millis("2024-10-18T12:34:56.123456789Z").unwrap();
+    assert_eq!(millis, 1_729_254_896_123);
+    assert_eq!(
+        time::parse_epoch_millis("2024-10-18T21:34:56.123+09:00").unwrap(),
+        millis,
+    );
+    assert_eq!(
+        time::parse_epoch_millis("2024-10-18 07:04:56.123-05:30").unwrap(),
+        millis,
+    );
+    // docker sometimes returns this zero time
+    assert!(time::parse_epoch_millis("0001-01-01T00:00:00Z").unwrap() < 0);
+}
+
+#[test]
+fn format_keeps_precision_and_handles_dates_before_epoch() {
+
+    assert_eq!(time::format_epoch_millis(1_729_254_896_123), "2024-10-18T12:34:56.123Z");
+    assert_eq!(time::format_epoch_millis(0), "1970-01-01T00:00:00Z");
+    assert_eq!(time::format_epoch_millis(-1), "1969-12-31T23:59:59.999Z");
+
+    let before_epoch = time::parse_time("1969-12-31T23:59:59.5Z").unwrap();
+    assert_eq!(time::format_time(&before_epoch), "1969-12-31T23:59:59.500Z");
+    let nanos = time::parse_time("2024-10-18T12:34:56.000000001Z").unwrap();
+    assert_eq!(time::format_time(&nanos), "2024-10-18T12:34:56.000000001Z");
+}
+
+#[test]
+fn invalid_times_are_rejected() {
+
+    for invalid in [
+        "",
+        "2024-10-18",
+        "2024-10-18T12:34:56",
+        "2024-13-18T12:34:56Z",
+        "2024-02-31T00:00:00Z",
+        "2023-02-29T00:00:00Z",
+        "2100-02-29T00:00:00Z",
+        "2024-04-31T00:00:00Z",
+        "2024-10-18T25:34:56Z",
+        "2024-10-18T12:34:56.Z",
+        "2024-10-18T12:34:56.1234567890Z",
+        "2024-10-18T12:34:56+0900",
+        "2024/10/18T12:34:56Z",
+    ] {
+        assert!(time::parse_time(invalid).is_err(), "{}", invalid);
+    }
+    for leap_day in ["2024-02-29T00:00:00Z", "2000-02-29T00:00:00Z"] {
+        assert_eq!(time::format_time(&time::parse_time(leap_day).unwrap()), leap_day);
+    }
+}
+
+#[test]
+fn parse_relative_durations_and_epoch_times() {
+
+    assert_eq!(time::parse_duration_millis("90s").unwrap(), 90_000);
+    assert_eq!(time::parse_duration_millis("1h").unwrap(), 3_600_000);
+    assert_eq!(time::parse_duration_millis("7d").unwrap(), 604_800_000);
+    assert!(time::parse_duration_millis("1").is_err());
+    assert!(time::parse_duration_millis("h").is_err());
+    assert!(time::parse_duration_millis("1y").is_err());
+
+    assert_eq!(time::parse_time_or_epoch_millis("1729254896").unwrap(), 1_729_254_896_000);
+    assert_eq!(time::parse_time_or_epoch_millis("1729254896.5").unwrap(), 1_729_254_896_500);
+    assert_eq!(
+        time::parse_time_or_epoch_millis("2024-10-18T12:34:56Z").unwrap(),
+        1_729_254_896_000,
+    );
+    assert!(time::parse_time_or_epoch_millis("1.2.3").is_err());
+}
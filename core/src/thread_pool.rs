@@ -0,0 +1,80 @@
+use std::sync::{ mpsc, Arc, Mutex, };
+
+use super::error;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// 固定数のスレッドで処理を実行するスレッドプール
+///
+/// 待ち行列の長さにも上限があり、溢れた場合は execute がエラーを返します
+/// 処理中に panic してもスレッドは終了せず、次の処理を待ちます
+pub struct ThreadPool {
+    workers: Vec<std::thread::JoinHandle<()>>,
+    sender: Option<mpsc::SyncSender<Job>>,
+}
+impl ThreadPool {
+    pub fn new(
+        name: &str,
+        size: usize,
+        queue_length: usize,
+    ) -> Result<Self, error::Error> {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_length);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let mut workers = Vec::with_capacity(size);
+        for i in 0..size.max(1) {
+            let receiver = Arc::clone(&receiver);
+            let worker = std::thread::Builder::new()
+                .name(format!("{}-{}", name, i))
+                .spawn(move || loop {
+                    // 受信中だけロックを取ります
+                    let job = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return,
+                    };
+                    match job {
+                        Ok(job) => {
+                            let result = std::panic::catch_unwind(
+                                std::panic::AssertUnwindSafe(job)
+                            );
+                            if result.is_err() {
+                                eprintln!(
+                                    "job panicked in {}",
+                                    std::thread::current().name().unwrap_or("worker"),
+                                );
+                            }
+                        },
+                        // ThreadPool が drop された
+                        Err(_) => return,
+                    }
+                })?;
+            workers.push(worker);
+        }
+
+        Ok(ThreadPool { workers, sender: Some(sender) })
+    }
+
+    /// 処理を待ち行列に追加します
+    /// 待ち行列が一杯ならば処理を捨ててエラーを返します
+    pub fn execute<F: FnOnce() + Send + 'static>(
+        &self,
+        f: F,
+    ) -> Result<(), error::Error> {
+        let sender = self.sender.as_ref()
+            .ok_or("thread pool is already stopped")?;
+        sender.try_send(Box::new(f)).map_err(|e| match e {
+            mpsc::TrySendError::Full(_) => "thread pool queue is full",
+            mpsc::TrySendError::Disconnected(_) => "thread pool is already stopped",
+        })?;
+        Ok(())
+    }
+}
+impl Drop for ThreadPool {
+    /// 待ち行列に残った処理を終えてからスレッドを終了します
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
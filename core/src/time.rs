@@ -0,0 +1,252 @@
+use super::error;
+
+pub const SECONDS_PER_DAY: i64 = 86_400;
+
+/// 1970-01-01 からの日数を (年, 月, 日) に変換します
+/// (http://howardhinnant.github.io/date_algorithms.html)
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+/// (年, 月, 日) を 1970-01-01 からの日数に変換します
+pub fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let m = m as i64;
+    let doy = (153 * if m > 2 { m - 3 } else { m + 9 } + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// その月の日数 (閏年は4で割り切れて100で割り切れない年と400で割り切れる年)
+fn days_in_month(y: i64, m: u32) -> u32 {
+    match m {
+        2 if (y % 4 == 0 && y % 100 != 0) || y % 400 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// 月曜日始まりの週番号 (1970-01-01 は木曜日)
+pub fn week_from_days(days: i64) -> i64 {
+    (days + 3).div_euclid(7)
+}
+
+/// 0年1月からの通算月
+pub fn month_from_days(days: i64) -> i64 {
+    let (y, m, _) = civil_from_days(days);
+    y * 12 + (m as i64 - 1)
+}
+
+/// RFC 3339 形式の時刻を UNIX時間の (秒, ナノ秒) に変換します
+///
+/// "2024-10-18T12:34:56.123456789Z" や "2024-10-18T21:34:56+09:00" のような
+/// 文字列を受け付けます (秒の小数部は最大9桁、日付と時刻の区切りは T/t/空白)
+fn parse_epoch(time_str: &str) -> Result<(i64, u32), error::Error> {
+    let invalid = || error::Error::ParseError(
+        format!("invalid time format: {}", time_str)
+    );
+    let bytes = time_str.as_bytes();
+    let number = |range: std::ops::Range<usize>| -> Result<i64, error::Error> {
+        let digits = time_str.get(range).ok_or_else(invalid)?;
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        digits.parse::<i64>().map_err(|_| invalid())
+    };
+    let expect = |index: usize, chars: &[u8]| -> Result<(), error::Error> {
+        match bytes.get(index) {
+            Some(b) if chars.contains(b) => Ok(()),
+            _ => Err(invalid()),
+        }
+    };
+
+    // YYYY-MM-DDTHH:MM:SS
+    expect(4, b"-")?;
+    expect(7, b"-")?;
+    expect(10, b"Tt ")?;
+    expect(13, b":")?;
+    expect(16, b":")?;
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hours, minutes, seconds) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month as u32) as i64
+        || hours > 23 || minutes > 59 || seconds > 60
+    {
+        return Err(invalid());
+    }
+
+    // .fraction
+    let mut index = 19;
+    let mut nanos = 0_u32;
+    if bytes.get(index) == Some(&b'.') {
+        let fraction = time_str[index + 1..].bytes()
+            .take_while(|b| b.is_ascii_digit())
+            .count();
+        if fraction == 0 || fraction > 9 {
+            return Err(invalid());
+        }
+        nanos = number(index + 1..index + 1 + fraction)? as u32
+            * 10_u32.pow(9 - fraction as u32);
+        index += 1 + fraction;
+    }
+
+    // Z | +HH:MM | -HH:MM
+    let offset_seconds = match bytes.get(index) {
+        Some(b'Z') | Some(b'z') if bytes.len() == index + 1 => 0,
+        Some(sign @ (b'+' | b'-')) if bytes.len() == index + 6 => {
+            expect(index + 3, b":")?;
+            let offset = (number(index + 1..index + 3)? * 60
+                + number(index + 4..index + 6)?) * 60;
+            if *sign == b'+' { offset } else { -offset }
+        },
+        _ => return Err(invalid()),
+    };
+
+    let days = days_from_civil(year, month as u32, day as u32);
+    let epoch_seconds = days * SECONDS_PER_DAY
+        + (hours * 60 + minutes) * 60 + seconds
+        - offset_seconds;
+    Ok((epoch_seconds, nanos))
+}
+
+/// UNIX時間の (秒, ナノ秒) を RFC 3339 (UTC) 形式に変換します
+/// 小数部はナノ秒の精度を保てる最短の桁数 (0, 3, 6, 9桁) で出力します
+fn format_epoch(epoch_seconds: i64, nanos: u32) -> String {
+    let days = epoch_seconds.div_euclid(SECONDS_PER_DAY);
+    let seconds = epoch_seconds.rem_euclid(SECONDS_PER_DAY);
+    let (y, m, d) = civil_from_days(days);
+    let fraction = if nanos == 0 {
+        "".to_string()
+    } else if nanos.is_multiple_of(1_000_000) {
+        format!(".{:03}", nanos / 1_000_000)
+    } else if nanos.is_multiple_of(1_000) {
+        format!(".{:06}", nanos / 1_000)
+    } else {
+        format!(".{:09}", nanos)
+    };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}Z",
+        y, m, d,
+        seconds / 3600, seconds / 60 % 60, seconds % 60,
+        fraction,
+    )
+}
+
+/// SystemTime を UNIX時間の (秒, ナノ秒) に変換します
+/// (1970年より前の時刻は秒が負、ナノ秒は常に正になります)
+fn to_epoch(time: &std::time::SystemTime) -> (i64, u32) {
+    match time.duration_since(std::time::UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(e) => {
+            let d = e.duration();
+            if d.subsec_nanos() == 0 {
+                (-(d.as_secs() as i64), 0)
+            } else {
+                (-(d.as_secs() as i64) - 1, 1_000_000_000 - d.subsec_nanos())
+            }
+        },
+    }
+}
+
+fn from_epoch(epoch_seconds: i64, nanos: u32) -> std::time::SystemTime {
+    let nanos = std::time::Duration::from_nanos(nanos as u64);
+    if epoch_seconds >= 0 {
+        std::time::UNIX_EPOCH
+            + std::time::Duration::from_secs(epoch_seconds as u64)
+            + nanos
+    } else {
+        std::time::UNIX_EPOCH
+            - std::time::Duration::from_secs(epoch_seconds.unsigned_abs())
+            + nanos
+    }
+}
+
+/// SystemTime を RFC 3339 (UTC) 形式の文字列に変換します
+pub fn format_time(time: &std::time::SystemTime) -> String {
+    let (epoch_seconds, nanos) = to_epoch(time);
+    format_epoch(epoch_seconds, nanos)
+}
+
+/// RFC 3339 形式の文字列を SystemTime に変換します
+pub fn parse_time(time_str: &str) -> Result<std::time::SystemTime, error::Error> {
+    let (epoch_seconds, nanos) = parse_epoch(time_str)?;
+    Ok(from_epoch(epoch_seconds, nanos))
+}
+
+/// RFC 3339 形式の文字列を UNIX時間 (ミリ秒, 切り捨て) に変換します
+pub fn parse_epoch_millis(time_str: &str) -> Result<i64, error::Error> {
+    let (epoch_seconds, nanos) = parse_epoch(time_str)?;
+    Ok(epoch_seconds * 1000 + (nanos / 1_000_000) as i64)
+}
+
+/// UNIX時間 (ミリ秒) を RFC 3339 (UTC) 形式に変換します
+pub fn format_epoch_millis(epoch_millis: i64) -> String {
+    format_epoch(
+        epoch_millis.div_euclid(1000),
+        epoch_millis.rem_euclid(1000) as u32 * 1_000_000,
+    )
+}
+
+/// RFC 3339 形式の文字列を UNIX時間 (秒, 切り捨て) に変換します
+pub fn parse_epoch_seconds(time_str: &str) -> Result<i64, error::Error> {
+    Ok(parse_epoch(time_str)?.0)
+}
+
+/// UNIX時間 (秒) を RFC 3339 (UTC) 形式に変換します
+pub fn format_epoch_seconds(epoch_seconds: i64) -> String {
+    format_epoch(epoch_seconds, 0)
+}
+
+/// 現在時刻の UNIX時間 (ミリ秒)
+pub fn now_epoch_millis() -> i64 {
+    let (epoch_seconds, nanos) = to_epoch(&std::time::SystemTime::now());
+    epoch_seconds * 1000 + (nanos / 1_000_000) as i64
+}
+
+/// "90s", "15m", "1h", "7d", "2w" のような期間をミリ秒に変換します
+pub fn parse_duration_millis(duration_str: &str) -> Result<i64, error::Error> {
+    let invalid = || error::Error::ParseError(
+        format!("invalid duration: {}", duration_str)
+    );
+    let split = duration_str.find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (number, unit) = duration_str.split_at(split);
+    let number = number.parse::<i64>().map_err(|_| invalid())?;
+    let unit_millis = match unit {
+        "s" => 1_000,
+        "m" => 60_000,
+        "h" => 3_600_000,
+        "d" => SECONDS_PER_DAY * 1_000,
+        "w" => 7 * SECONDS_PER_DAY * 1_000,
+        _ => return Err(invalid()),
+    };
+    number.checked_mul(unit_millis).ok_or_else(invalid)
+}
+
+/// RFC 3339 形式、または UNIX時間 (秒, 小数可) の文字列を
+/// UNIX時間 (ミリ秒) に変換します
+pub fn parse_time_or_epoch_millis(time_str: &str) -> Result<i64, error::Error> {
+    if !time_str.is_empty()
+        && time_str.bytes().all(|b| b.is_ascii_digit() || b == b'.' || b == b'-')
+    {
+        return time_str.parse::<f64>()
+            .ok()
+            .filter(|seconds| seconds.is_finite())
+            .map(|seconds| (seconds * 1000.0).floor() as i64)
+            .ok_or_else(|| error::Error::ParseError(
+                format!("invalid epoch time: {}", time_str)
+            ));
+    }
+    parse_epoch_millis(time_str)
+}